@@ -1,3 +1,4 @@
+mod matrix;
 mod objects;
 mod vec3;
 
@@ -40,13 +41,11 @@ fn ray_color_d(r: Ray, scene: &Scene, depth: usize) -> Rgb<f32> {
         return (Vec3::from_rgb(ray_color_d(next, scene, depth - 1)) * cm).to_rgb();
     }
     // eprintln!("Sky");
-    let unit_direction = r.direction.unit();
-    let t = 0.5 * (unit_direction.y + 1.0);
-    return Rgb([(1.0 - t) + t * 0.5, (1 as f32 - t) + t * 0.7, 1.0]); //(1.0-t)*color(1.0, 1.0, 1.0) + t*color(0.5, 0.7, 1.0);
+    return scene.sky_color(r.direction).to_rgb();
 }
 
 #[allow(unused_imports)]
-use std::{process::Command, time::Instant};
+use std::{path::Path, process::Command, time::Instant};
 
 use crate::{
     objects::{
@@ -63,6 +62,7 @@ fn test_run(
     viewport: Viewport,
     ray_color: impl Fn(Ray, &Scene, usize) -> Rgb<f32> + std::marker::Send + std::marker::Copy + 'static,
     scene: &Scene,
+    video: Option<&str>,
 ) -> Vec<Img> {
     println!(
         "Rendering {} samples",
@@ -74,8 +74,13 @@ fn test_run(
     let before = Instant::now();
 
     let rt = tokio::runtime::Runtime::new().unwrap();
-    let future = viewport::render_multi(viewport.to_owned(), ray_color, scene.to_owned());
-    let img = rt.block_on(future);
+    let future = viewport::render_multi(
+        viewport.to_owned(),
+        ray_color,
+        scene.to_owned(),
+        video.map(Path::new),
+    );
+    let img = rt.block_on(future).unwrap();
     write_img_f32(&img[0], f_name);
 
     println!(
@@ -558,26 +563,6 @@ fn main() {
         viewport,
         ray_color_d,
         &_scene,
+        None,
     );
-
-    // Command::new("mkdir").arg("-p").arg("/tmp/video").spawn().expect("Failed to execute mkdir");
-    // for (i, img) in video.iter().enumerate(){
-    //     write_img_f32(img.to_vec(), format!("/tmp/video/img{:0>5}.png", i))
-    // }
-
-    // match Command::new("rm").arg("video.mp4").spawn(){
-    //     Ok(_) => println!("Removed video.mp4"),
-    //     Err(e) => println!("Error: {}", e)
-    // };
-    // let mut command = Command::new("ffmpeg");
-    // command.arg("-framerate").arg("10")
-    // // .arg("-pattern_type").arg("glob")
-    // .arg("-r").arg("5")
-    // .arg("video.mp4");
-    // command.arg("-i").arg("/tmp/video/img%05d.png");
-    // // for i in in_files{
-    // //     command.arg("-i").arg(i);
-    // // }
-    // println!("Command: {:?}", command);
-    // command.spawn().expect("Failed to execute ffmpeg");
 }
@@ -0,0 +1,167 @@
+use std::ops::Mul;
+
+use crate::vec3::vec3::Vec3;
+
+/// 4x4 affine transform, `m[row][column]` with points as columns, so `a * b` applies `b` first.
+/// The last row stays `0 0 0 1`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Matrix4 {
+    pub m: [[f32; 4]; 4],
+}
+
+impl Matrix4 {
+    pub const IDENTITY: Matrix4 = Matrix4 {
+        m: [
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ],
+    };
+
+    /// Rows of the upper 3x3 part and the translation
+    pub fn new(rows: [[f32; 3]; 3], translation: Vec3) -> Self {
+        let t = [translation.x, translation.y, translation.z];
+        let mut m = Self::IDENTITY;
+        for i in 0..3 {
+            m.m[i][..3].copy_from_slice(&rows[i]);
+            m.m[i][3] = t[i];
+        }
+        m
+    }
+
+    pub fn scaling(s: Vec3) -> Self {
+        Self::new(
+            [[s.x, 0.0, 0.0], [0.0, s.y, 0.0], [0.0, 0.0, s.z]],
+            Vec3::new(0.0, 0.0, 0.0),
+        )
+    }
+
+    pub fn transform_point(&self, p: Vec3) -> Vec3 {
+        self.transform_vector(p) + Vec3::new(self.m[0][3], self.m[1][3], self.m[2][3])
+    }
+
+    /// Directions and offsets, without the translation
+    pub fn transform_vector(&self, v: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        )
+    }
+
+    pub fn transpose(&self) -> Self {
+        let mut t = *self;
+        for i in 0..4 {
+            for j in 0..4 {
+                t.m[i][j] = self.m[j][i];
+            }
+        }
+        t
+    }
+
+    pub fn determinant(&self) -> f32 {
+        let m = &self.m;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    /// `None` when the transform flattens space
+    pub fn inverse(&self) -> Option<Self> {
+        let det = self.determinant();
+        let m = &self.m;
+        // compared to the volume of the box spanned by the rows, the largest determinant rows
+        // of these lengths can have, so tiny but regular transforms stay invertible
+        let volume: f32 = m[..3]
+            .iter()
+            .map(|row| (row[0] * row[0] + row[1] * row[1] + row[2] * row[2]).sqrt())
+            .product();
+        if det.abs() <= 1e-6 * volume {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| {
+            (m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]) * inv_det
+        };
+        let rows = [
+            [
+                cofactor(1, 2, 1, 2),
+                -cofactor(0, 2, 1, 2),
+                cofactor(0, 1, 1, 2),
+            ],
+            [
+                -cofactor(1, 2, 0, 2),
+                cofactor(0, 2, 0, 2),
+                -cofactor(0, 1, 0, 2),
+            ],
+            [
+                cofactor(1, 2, 0, 1),
+                -cofactor(0, 2, 0, 1),
+                cofactor(0, 1, 0, 1),
+            ],
+        ];
+        let linear = Self::new(rows, Vec3::new(0.0, 0.0, 0.0));
+        let t = linear.transform_vector(Vec3::new(m[0][3], m[1][3], m[2][3]));
+        Some(Self::new(rows, -t))
+    }
+}
+
+impl Mul for Matrix4 {
+    type Output = Matrix4;
+
+    fn mul(self, rhs: Matrix4) -> Matrix4 {
+        let mut out = Matrix4 { m: [[0.0; 4]; 4] };
+        for i in 0..4 {
+            for j in 0..4 {
+                out.m[i][j] = (0..4).map(|k| self.m[i][k] * rhs.m[k][j]).sum();
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vec3::vec3::Vec3;
+
+    use super::Matrix4;
+
+    #[test]
+    fn inverse_and_normals() {
+        let m = Matrix4::new(
+            [[2.0, 0.3, 0.0], [0.0, 1.0, -0.4], [0.1, 0.0, 0.5]],
+            Vec3::new(-1.0, 4.0, 2.0),
+        );
+        let inv = m.inverse().unwrap();
+        let p = Vec3::new(0.7, -0.2, 1.5);
+        assert!((inv.transform_point(m.transform_point(p)) - p).length() < 1e-5);
+        let id = m * inv;
+        for i in 0..4 {
+            for j in 0..4 {
+                let e = if i == j { 1.0 } else { 0.0 };
+                assert!((id.m[i][j] - e).abs() < 1e-5);
+            }
+        }
+        // normals transformed by the inverse transpose stay perpendicular to transformed tangents
+        let n = Vec3::new(0.0, 0.0, 1.0);
+        let normals = inv.transpose();
+        for t in [Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)] {
+            let tangent = m.transform_vector(t);
+            assert!(normals.transform_vector(n).dot(tangent).abs() < 1e-5);
+        }
+        assert!(Matrix4::scaling(Vec3::new(1.0, 0.0, 1.0))
+            .inverse()
+            .is_none());
+        // tiny transforms are regular, only flat ones are singular
+        let tiny = Matrix4::scaling(Vec3::new(1e-4, 1e-4, 1e-4));
+        let inv = tiny.inverse().unwrap();
+        assert!((inv.transform_point(tiny.transform_point(p)) - p).length() < 1e-5);
+        let flat = Matrix4::new(
+            [[1e-4, 2e-4, 0.0], [2e-4, 4e-4, 0.0], [0.0, 0.0, 1e-4]],
+            Vec3::new(0.0, 0.0, 0.0),
+        );
+        assert!(flat.inverse().is_none());
+    }
+}
@@ -37,7 +37,7 @@ impl From<&Instance> for IAABB {
             Vec3::new(x.max, y.max, z.max),
         ]
         .iter()
-        .map(|v| v.rotated(val.getr()))
+        .map(|v| val.transform_point(*v))
         .collect();
         let mut max_x = points[0].x;
         let mut max_y = points[0].y;
@@ -60,15 +60,15 @@ impl From<&Instance> for IAABB {
             x: Interval {
                 min: min_x,
                 max: max_x,
-            } + val.gett().x,
+            },
             y: Interval {
                 min: min_y,
                 max: max_y,
-            } + val.gett().y,
+            },
             z: Interval {
                 min: min_z,
                 max: max_z,
-            } + val.gett().z,
+            },
             instances: vec![val.to_owned()],
             aabbs: vec![],
         }
@@ -3,6 +3,7 @@ use std::iter::zip;
 use rand::{thread_rng, Rng};
 
 use crate::{
+    matrix::Matrix4,
     texture::texture::ImageTexture,
     vec3::{ray::Ray, vec3::Vec3},
 };
@@ -33,6 +34,13 @@ pub struct Instance {
     pub saabb: AABB,
     translation: Vec3,
     rotation: Vec3,
+    // scale along the axes of the objects, applied before the rotation
+    scale: Vec3,
+    // applied to the objects before the scale, rotation and translation
+    matrix: Matrix4,
+    // the scale and matrix together and back, `None` when they flatten the objects
+    to_world: Matrix4,
+    to_object: Option<Matrix4>,
 
     pub dist_fn: &'static DistFn,
     pub density: f32,
@@ -47,11 +55,16 @@ impl std::fmt::Debug for Instance {
             .field("saabb", &self.saabb)
             .field("translation", &self.translation)
             .field("rotation", &self.rotation)
+            .field("scale", &self.scale)
+            .field("matrix", &self.matrix)
             .finish()
     }
 }
 impl PartialEq for Instance {
     fn eq(&self, other: &Self) -> bool {
+        if self.scale != other.scale || self.matrix != other.matrix {
+            return false;
+        }
         if self.rotation != other.rotation || self.translation != other.translation {
             eprintln!("diff rot/trans");
             eprintln!(
@@ -180,6 +193,10 @@ impl Instance {
                 y: 0.0,
                 z: 0.0,
             },
+            scale: Vec3::new(1.0, 1.0, 1.0),
+            matrix: Matrix4::IDENTITY,
+            to_world: Matrix4::IDENTITY,
+            to_object: Some(Matrix4::IDENTITY),
             dist_fn: &surface,
             density: 0.0,
         }
@@ -201,6 +218,10 @@ impl Instance {
                 y: 0.0,
                 z: 0.0,
             },
+            scale: Vec3::new(1.0, 1.0, 1.0),
+            matrix: Matrix4::IDENTITY,
+            to_world: Matrix4::IDENTITY,
+            to_object: Some(Matrix4::IDENTITY),
             dist_fn: &surface,
             density: 0.0,
         }
@@ -222,6 +243,10 @@ impl Instance {
                 y: 0.0,
                 z: 0.0,
             },
+            scale: Vec3::new(1.0, 1.0, 1.0),
+            matrix: Matrix4::IDENTITY,
+            to_world: Matrix4::IDENTITY,
+            to_object: Some(Matrix4::IDENTITY),
             dist_fn: &surface,
             density: 0.0,
         }
@@ -240,6 +265,39 @@ impl Instance {
     pub fn gett(&self) -> Vec3 {
         return self.translation.to_owned();
     }
+
+    /// Same scale along every axis
+    pub fn set_scale(&mut self, scale: f32) {
+        self.set_scale_axes(Vec3::new(scale, scale, scale));
+    }
+    /// Scale along the x, y and z axes of the objects
+    pub fn set_scale_axes(&mut self, scale: Vec3) {
+        self.scale = scale;
+        self.update();
+    }
+    pub fn gets(&self) -> Vec3 {
+        self.scale
+    }
+
+    /// Affine transform of the objects applied before the scale, rotation and translation,
+    /// e.g. to shear them or to bring an imported model into the scene's axes
+    pub fn set_matrix(&mut self, matrix: Matrix4) {
+        self.matrix = matrix;
+        self.update();
+    }
+    pub fn getm(&self) -> Matrix4 {
+        self.matrix
+    }
+
+    fn update(&mut self) {
+        self.to_world = Matrix4::scaling(self.scale) * self.matrix;
+        self.to_object = self.to_world.inverse();
+    }
+
+    /// Point of the objects moved to where the instance puts it in the world
+    pub fn transform_point(&self, p: Vec3) -> Vec3 {
+        self.to_world.transform_point(p).rotated(self.rotation) + self.translation
+    }
 }
 
 impl Object for Instance {
@@ -254,8 +312,11 @@ impl Object for Instance {
         maxt: f32,
     ) -> Option<super::Hit> {
         //change to local
+        let to_object = self.to_object?;
         let mut r = Ray::new_with_time(r.origin - self.translation, r.direction, r.time)
             .rotated(-self.rotation);
+        r.origin = to_object.transform_point(r.origin);
+        r.direction = to_object.transform_vector(r.direction);
 
         //check
         let mut min_hit = None;
@@ -300,9 +361,10 @@ impl Object for Instance {
                     None => return None,
                 }
             }
-            hit.point.rotate(self.rotation);
-            hit.point += self.translation;
+            hit.point = self.transform_point(hit.point);
 
+            // normals take the inverse transpose to stay perpendicular to the surface
+            hit.normal = to_object.transpose().transform_vector(hit.normal).unit();
             hit.normal.rotate(self.rotation);
             return Some(hit);
         }
@@ -315,15 +377,17 @@ mod tests {
     use image::Rgb;
 
     use crate::{
+        matrix::Matrix4,
         objects::{
             aabb::IAABB,
             instance::{const_density, Instance},
             materials::{Material, METALLIC_M, SCATTER_M},
             quad::Quad,
             sphere::Sphere,
+            Object,
         },
         texture::texture::ImageTexture,
-        vec3::vec3::Vec3,
+        vec3::{ray::Ray, vec3::Vec3},
         viewport::{async_render, ray_color::ray_color_bg_color, Scene, Viewport},
         write_img::img_writer::write_img_f32,
     };
@@ -879,4 +943,49 @@ mod tests {
         ));
         write_img_f32(&img, "out/volume_test.png".to_string());
     }
+
+    #[test]
+    fn scale_and_matrix() {
+        let unit_sphere =
+            || Instance::new_sphere(vec![Sphere::new(Vec3::new(0.0, 0.0, 0.0), 1.0, None, None)]);
+        let mut ellipsoid = unit_sphere();
+        ellipsoid.set_scale_axes(Vec3::new(2.0, 0.5, 1.0));
+        ellipsoid.translate(Vec3::new(0.0, 0.0, 5.0));
+        let aabb = IAABB::from(&ellipsoid);
+        assert!((aabb.x.min + 2.0).abs() < 1e-4 && (aabb.x.max - 2.0).abs() < 1e-4);
+        assert!((aabb.y.min + 0.5).abs() < 1e-4 && (aabb.y.max - 0.5).abs() < 1e-4);
+        assert!((aabb.z.min - 4.0).abs() < 1e-4 && (aabb.z.max - 6.0).abs() < 1e-4);
+
+        let r = Ray::new(Vec3::new(1.0, 0.2, 0.0), Vec3::new(0.0, 0.0, 1.0));
+        let hit = ellipsoid.collision_normal(r, 0.001, 100.0).unwrap();
+        // on the surface x²/4 + y²/0.25 + (z - 5)² = 1
+        let z = 5.0 - (1.0f32 - 0.25 - 0.16).sqrt();
+        assert!(
+            (hit.point - Vec3::new(1.0, 0.2, z)).length() < 1e-4,
+            "{:?}",
+            hit.point
+        );
+        assert!((hit.t - z).abs() < 1e-4);
+        // the normal is the gradient of the surface, not the scaled sphere normal
+        let gradient = Vec3::new(1.0 / 4.0, 0.2 / 0.25, z - 5.0).unit();
+        assert!(hit.normal.dot(gradient) > 0.9999, "{:?}", hit.normal);
+
+        // the matrix comes before the scale, rotation and translation
+        ellipsoid.set_matrix(Matrix4::new(
+            [[1.0, 1.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            Vec3::new(0.0, 0.0, 0.0),
+        ));
+        ellipsoid.set_scale(2.0);
+        let p = ellipsoid.transform_point(Vec3::new(0.5, 0.5, 0.0));
+        assert!((p - Vec3::new(2.0, 1.0, 5.0)).length() < 1e-5, "{:?}", p);
+        // flattened instances can't be hit, tiny ones can
+        ellipsoid.set_scale_axes(Vec3::new(1.0, 0.0, 1.0));
+        assert!(ellipsoid.collision_normal(r, 0.001, 100.0).is_none());
+        let mut speck = unit_sphere();
+        speck.set_scale(1e-4);
+        speck.translate(Vec3::new(0.0, 0.0, 0.01));
+        let r = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0));
+        let hit = speck.collision_normal(r, 0.001, 100.0).unwrap();
+        assert!((hit.t - 0.0099).abs() < 1e-6, "{}", hit.t);
+    }
 }
@@ -63,19 +63,7 @@ impl Object for Quad {
             t: t,
             normal: self.normal,
             point: point,
-            col_mod: self.texture.color_at(
-                if alfa != 1.0 {
-                    (alfa * self.texture.row as f32).floor() as usize
-                } else {
-                    self.texture.row - 1
-                },
-                if beta != 1.0 {
-                    (beta * self.texture.col as f32).floor() as usize
-                } else {
-                    self.texture.col - 1
-                },
-                point,
-            ),
+            col_mod: self.texture.color_at(alfa, beta, point),
             mat: self.mat,
         })
     }
@@ -134,15 +134,12 @@ impl Object for Sphere {
         debug_assert!(u <= 1.0 && v >= 0.0, "U too big");
         debug_assert!(v <= 1.0 && v >= 0.0, "V too big");
 
-        let tex_x = (u * (self.texture.row - 1) as f32).floor() as usize;
-        let tex_y = (v * (self.texture.col - 1) as f32).floor() as usize;
-
         Some(Hit {
             t: x,
             normal: normal,
             point: r.at(x),
             mat: self.mat,
-            col_mod: self.texture.color_at(tex_x, tex_y, r.at(x)) * self.col_mod,
+            col_mod: self.texture.color_at(u, v, r.at(x)) * self.col_mod,
         })
     }
 }
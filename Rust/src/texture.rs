@@ -14,7 +14,8 @@ pub mod texture {
     use crate::{vec3::vec3::Vec3, viewport::errors};
 
     pub trait Texture {
-        fn color_at(&self, x: usize, y: usize, p: Vec3) -> Vec3;
+        /// Color at texture coordinates `u` and `v` in [0, 1] and at point `p` in space
+        fn color_at(&self, u: f32, v: f32, p: Vec3) -> Vec3;
     }
 
     #[derive(Clone, Debug)]
@@ -256,13 +257,35 @@ pub mod texture {
             })
         }
     }
+    impl ImageTexture {
+        // the image repeats outside of [0, 1]
+        fn texel(&self, x: isize, y: isize) -> Vec3 {
+            let x = x.rem_euclid(self.row as isize) as usize;
+            let y = y.rem_euclid(self.col as isize) as usize;
+            self.img[y * self.row + x]
+        }
+
+        /// Bilinear interpolation between the centers of the four nearest texels
+        pub fn bilinear(&self, u: f32, v: f32) -> Vec3 {
+            let x = u * self.row as f32 - 0.5;
+            let y = v * self.col as f32 - 0.5;
+            let (x0, y0) = (x.floor(), y.floor());
+            let (dx, dy) = (x - x0, y - y0);
+            let (x0, y0) = (x0 as isize, y0 as isize);
+
+            self.texel(x0, y0) * ((1.0 - dx) * (1.0 - dy))
+                + self.texel(x0 + 1, y0) * (dx * (1.0 - dy))
+                + self.texel(x0, y0 + 1) * ((1.0 - dx) * dy)
+                + self.texel(x0 + 1, y0 + 1) * (dx * dy)
+        }
+    }
     impl Texture for ImageTexture {
-        fn color_at(&self, x: usize, y: usize, p: Vec3) -> Vec3 {
+        fn color_at(&self, u: f32, v: f32, p: Vec3) -> Vec3 {
             let noise_mult = match &self.noise {
                 Some(n) => n.noise(p / self.noise_scale),
                 None => 1.0,
             };
-            self.img[y * self.row + x] * noise_mult
+            self.bilinear(u, v) * noise_mult
         }
     }
     impl Into<JsonValue> for ImageTexture {
@@ -274,4 +297,23 @@ pub mod texture {
             }
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{ImageTexture, Texture};
+        use crate::vec3::vec3::Vec3;
+
+        #[test]
+        fn bilinear_repeats() {
+            let black = Vec3::new(0.0, 0.0, 0.0);
+            let white = Vec3::new(1.0, 1.0, 1.0);
+            let tex = ImageTexture::new(vec![black, white], 2, 1);
+            // texel centers keep their color, between them and across the edge it blends
+            assert!(tex.color_at(0.25, 0.5, black) == black);
+            assert!(tex.color_at(0.75, 0.5, black) == white);
+            assert!(tex.color_at(0.5, 0.5, black) == white * 0.5);
+            assert!(tex.color_at(0.0, 0.5, black) == white * 0.5);
+            assert!(tex.color_at(1.0, 0.5, black) == white * 0.5);
+        }
+    }
 }
@@ -19,6 +19,16 @@ pub mod vec3 {
             (*self - *other).close_to_zero()
         }
     }
+    impl From<[f32; 3]> for Vec3 {
+        fn from([x, y, z]: [f32; 3]) -> Self {
+            Self { x, y, z }
+        }
+    }
+    impl From<Vec3> for [f32; 3] {
+        fn from(v: Vec3) -> Self {
+            [v.x, v.y, v.z]
+        }
+    }
     impl Into<JsonValue> for Vec3 {
         fn into(self) -> JsonValue {
             json::object! {
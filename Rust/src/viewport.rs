@@ -28,20 +28,23 @@ pub mod errors {
 pub mod ray_color;
 
 use std::iter::zip;
+use std::path::Path;
 
 use crate::objects::aabb::IAABB;
 use crate::objects::instance::Instance;
 use crate::objects::quad::Quad;
 use crate::objects::{sphere::Sphere, Object, NO_HIT};
 use crate::objects::{Hit, Interval};
+use crate::write_img::img_writer::to_rgb8;
 use crate::{
     objects::aabb::{QuadAABB, AABB},
     vec3::{ray::Ray, vec3::Vec3},
 };
-use image::Rgb;
+use image::{ImageResult, Rgb};
 use indicatif::{ProgressBar, ProgressStyle};
 use json::JsonValue;
 use rand::Rng;
+use raytracing_shared::{sky::PreethamSky, video::VideoWriter};
 
 pub type Img = Vec<Vec<Rgb<f32>>>;
 
@@ -85,6 +88,8 @@ pub struct Scene {
     pub instances: Vec<Instance>,
     pub iaabb: IAABB,
     pub background_color: Vec3,
+    // seen by `ray_color_gradient` instead of the gradient
+    pub sky: Option<PreethamSky>,
 }
 impl Scene {
     pub fn new_sphere(spheres: Vec<Sphere>) -> Scene {
@@ -98,6 +103,7 @@ impl Scene {
                 y: 0.0,
                 z: 0.0,
             },
+            sky: None,
             instances: vec![],
             iaabb: IAABB::empty(),
         }
@@ -113,6 +119,7 @@ impl Scene {
                 y: 0.0,
                 z: 0.0,
             },
+            sky: None,
             instances: vec![],
             iaabb: IAABB::empty(),
         }
@@ -131,8 +138,21 @@ impl Scene {
                 y: 0.0,
                 z: 0.0,
             },
+            sky: None,
         }
     }
+    pub fn with_sky(mut self, sky: PreethamSky) -> Self {
+        self.sky = Some(sky);
+        self
+    }
+    /// Color of rays that miss everything, the sky if there is one or a white to blue gradient
+    pub fn sky_color(&self, dir: Vec3) -> Vec3 {
+        if let Some(sky) = &self.sky {
+            return sky.radiance(dir).into();
+        }
+        let t = 0.5 * (dir.unit().y + 1.0);
+        Vec3::new((1.0 - t) + t * 0.5, (1.0 - t) + t * 0.7, 1.0)
+    }
     pub fn collision_normal(&self, r: Ray, mint: f32, maxt: f32) -> Option<Hit> {
         let mut min_hit = None;
         let s_hit = self.aabb.collision_normal(r, mint, maxt);
@@ -246,26 +266,45 @@ pub async fn async_render(
 
     return img;
 }
+/// Renders `number_of_frames` frames from `start_frame` on. With a `.gif` or `.y4m` path every
+/// frame is also written into that video as soon as it's done, playing at `fps`.
+/// Starting after frame 0 continues the video that is already there
 pub async fn render_multi(
     viewport: Viewport,
     ray_color: impl Fn(Ray, &Scene, usize) -> Rgb<f32> + std::marker::Send + std::marker::Copy + 'static,
     scene: Scene,
-) -> Vec<Img> {
+    video_path: Option<&Path>,
+) -> ImageResult<Vec<Img>> {
     let mut viewport: Viewport = viewport.clone();
+    let mut writer = match video_path {
+        Some(path) => Some(VideoWriter::create(
+            path,
+            viewport.width as u32,
+            viewport.height as u32,
+            viewport.fps,
+            viewport.start_frame,
+        )?),
+        None => None,
+    };
     let mut video = Vec::new();
     for i in viewport.start_frame..viewport.number_of_frames + viewport.start_frame {
         viewport.frame = i;
         viewport.msg = format!("Rendering, num: {}", i);
-        video.push(
-            async_render(
-                Box::new(viewport.clone()),
-                ray_color,
-                Box::new(scene.clone()),
-            )
-            .await,
-        );
+        let img = async_render(
+            Box::new(viewport.clone()),
+            ray_color,
+            Box::new(scene.clone()),
+        )
+        .await;
+        if let Some(writer) = writer.as_mut() {
+            writer.write_frame(&to_rgb8(&img))?;
+        }
+        video.push(img);
     }
-    return video;
+    if let Some(writer) = writer {
+        writer.finish()?;
+    }
+    return Ok(video);
 }
 async fn render_row(
     viewport: &Viewport,
@@ -518,8 +557,11 @@ impl Viewport {
 
 #[cfg(test)]
 mod tests {
-    use super::{Object, Ray, Rgb, Scene, Sphere, Vec3, Viewport, NO_HIT};
+    use std::path::Path;
+
+    use super::{render_multi, Object, Ray, Rgb, Scene, Sphere, Vec3, Viewport, NO_HIT};
     use crate::write_img::img_writer::write_img_f32;
+    use raytracing_shared::video::Y4mWriter;
 
     fn ray_color(r: Ray, scene: &Scene, _: usize) -> Rgb<f32> {
         let mint = 0.0;
@@ -617,6 +659,48 @@ mod tests {
 
         write_img_f32(&img, "out/viewport_object.png".to_string());
     }
+
+    #[test]
+    pub fn video_frames() {
+        let scene = Scene::new_sphere(vec![Sphere::new_moving(
+            Vec3::new(0.0, 0.0, -2.0),
+            0.5,
+            None,
+            None,
+            Vec3::new(1.0, 0.0, 0.0),
+        )]);
+        let mut viewport =
+            Viewport::new_from_res(8, 6, 4, 4, 2.0, None, None, None, None, None, None);
+        viewport.fps = 4.0;
+        viewport.number_of_frames = 3;
+        std::fs::create_dir_all("test_out").unwrap();
+        let path = Path::new("test_out/video_frames.y4m");
+        let runtime = tokio::runtime::Builder::new_multi_thread().build().unwrap();
+        let frames = runtime
+            .block_on(render_multi(
+                viewport.clone(),
+                ray_color,
+                scene.clone(),
+                Some(path),
+            ))
+            .unwrap();
+        assert_eq!(frames.len(), 3);
+        let header = "YUV4MPEG2 W8 H6 F4:1 Ip A1:1 C444\n";
+        let frame_size = Y4mWriter::<Vec<u8>>::frame_size(8, 6);
+        let bytes = std::fs::read(path).unwrap();
+        assert!(bytes.starts_with(header.as_bytes()));
+        assert_eq!(bytes.len() as u64, header.len() as u64 + 3 * frame_size);
+
+        // going on from frame 2 replaces the last frame and adds one
+        viewport.start_frame = 2;
+        viewport.number_of_frames = 2;
+        let frames = runtime
+            .block_on(render_multi(viewport, ray_color, scene, Some(path)))
+            .unwrap();
+        assert_eq!(frames.len(), 2);
+        let bytes = std::fs::read(path).unwrap();
+        assert_eq!(bytes.len() as u64, header.len() as u64 + 4 * frame_size);
+    }
 }
 
 #[cfg(test)]
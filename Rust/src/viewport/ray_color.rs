@@ -35,9 +35,7 @@ pub fn ray_color_gradient(r: Ray, scene: &Scene, depth: usize) -> Rgb<f32> {
         return (Vec3::from_rgb(ray_color_gradient(next, scene, depth - 1)) * cm).to_rgb();
     }
     // eprintln!("Sky");
-    let unit_direction = r.direction.unit();
-    let t = 0.5 * (unit_direction.y + 1.0);
-    return Rgb([(1.0 - t) + t * 0.5, (1 as f32 - t) + t * 0.7, 1.0]);
+    return scene.sky_color(r.direction).to_rgb();
 }
 
 pub fn ray_color_bg_color(r: Ray, scene: &Scene, depth: usize) -> Rgb<f32> {
@@ -94,6 +92,7 @@ pub fn ray_color_bg_color(r: Ray, scene: &Scene, depth: usize) -> Rgb<f32> {
 #[cfg(test)]
 mod tests {
     use image::Rgb;
+    use raytracing_shared::sky::PreethamSky;
 
     use crate::{
         objects::{
@@ -102,8 +101,13 @@ mod tests {
             sphere::Sphere,
         },
         texture::texture::ImageTexture,
+        vec3::ray::Ray,
         vec3::vec3::Vec3,
-        viewport::{async_render, ray_color::ray_color_bg_color, Scene, Viewport},
+        viewport::{
+            async_render,
+            ray_color::{ray_color_bg_color, ray_color_gradient},
+            Scene, Viewport,
+        },
         write_img::img_writer::write_img_f32,
     };
 
@@ -516,4 +520,25 @@ mod tests {
 
         write_img_f32(&img, "out/light_box_test.png".to_string());
     }
+
+    #[test]
+    fn gradient_sees_the_sky() {
+        let scene = Scene::new_sphere(vec![Sphere::new(
+            Vec3::new(0.0, 0.0, -3.0),
+            0.5,
+            None,
+            Some(SCATTER_M),
+        )]);
+        let up = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        // without a sky the zenith is the light blue end of the gradient
+        let zenith = Vec3::from_rgb(ray_color_gradient(up, &scene, 10));
+        assert!(zenith == Vec3::new(0.5, 0.7, 1.0), "{:?}", zenith);
+
+        let sky = PreethamSky::new(Vec3::new(0.0, 0.5, 1.0), 3.0, Vec3::new(0.3, 0.3, 0.3));
+        let scene = scene.with_sky(sky.clone());
+        let zenith = Vec3::from_rgb(ray_color_gradient(up, &scene, 10));
+        assert!(zenith == sky.radiance(up.direction).into(), "{:?}", zenith);
+        let sun = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.5, 1.0));
+        assert!(Vec3::from_rgb(ray_color_gradient(sun, &scene, 10)).x > 100.0 * zenith.x);
+    }
 }
@@ -1,9 +1,9 @@
 #[allow(dead_code)]
 pub mod img_writer {
 
-    use image::{ImageBuffer, Rgb};
+    use image::{ImageBuffer, Rgb, RgbImage};
 
-    pub fn write_img_f32(arr: &Vec<Vec<Rgb<f32>>>, filename: String) {
+    pub fn to_rgb8(arr: &Vec<Vec<Rgb<f32>>>) -> RgbImage {
         let mut img = ImageBuffer::new(arr[0].len() as u32, arr.len() as u32);
 
         for (x, y, pix) in img.enumerate_pixels_mut() {
@@ -14,8 +14,11 @@ pub mod img_writer {
                 (rgb.0[2] * 255.0).clamp(0.0, 255.0).round() as u8,
             ]);
         }
+        img
+    }
 
-        img.save(filename).unwrap()
+    pub fn write_img_f32(arr: &Vec<Vec<Rgb<f32>>>, filename: String) {
+        to_rgb8(arr).save(filename).unwrap()
     }
 
     pub fn write_img(arr: Vec<Vec<Rgb<u8>>>, filename: String) {
@@ -0,0 +1,700 @@
+use std::{ops::Range, path::Path, sync::Arc};
+
+use image::{ImageResult, RgbImage};
+use indicatif::{ProgressBar, ProgressStyle};
+use raytracing_shared::video::VideoWriter;
+
+use crate::{
+    objects::{instance::Instance, light::Light},
+    quaternions::Quaternion,
+    vec3::vec3::Vec3,
+    viewport::{camera::Camera, scene::Scene, Viewport},
+};
+
+/// Values keyframes can be set for
+pub trait Animatable: Clone {
+    fn lerp(&self, other: &Self, t: f32) -> Self;
+    /// `self` moved by `scale` times the way from `from` to `to`, places the Bezier handles
+    fn offset(&self, from: &Self, to: &Self, scale: f32) -> Self;
+}
+
+impl Animatable for f32 {
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+    fn offset(&self, from: &Self, to: &Self, scale: f32) -> Self {
+        self + (to - from) * scale
+    }
+}
+
+impl Animatable for Vec3 {
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        *self + (*other - *self) * t
+    }
+    fn offset(&self, from: &Self, to: &Self, scale: f32) -> Self {
+        *self + (*to - *from) * scale
+    }
+}
+
+impl Animatable for Quaternion {
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        self.slerp(other, t)
+    }
+    fn offset(&self, from: &Self, to: &Self, scale: f32) -> Self {
+        // keep all three on the same side, q and -q are the same rotation
+        let side = |q: &Quaternion| {
+            if q.dot(self) < 0.0 {
+                q * -1.0
+            } else {
+                q.to_owned()
+            }
+        };
+        let (from, to) = (side(from), side(to));
+        (self + &(&(&to + &(&from * -1.0)) * scale)).normalized()
+    }
+}
+
+/// How a keyframe moves on to the next one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    /// Holds the value until the next keyframe
+    Step,
+    Linear,
+    /// Smooth curve through the keyframes, the handles follow the neighbouring keyframes
+    Bezier,
+}
+
+#[derive(Debug, Clone)]
+pub struct Keyframe<T> {
+    pub time: f32,
+    pub value: T,
+    pub interpolation: Interpolation,
+}
+
+/// Value changing over time, set by keyframes sorted by time.
+/// Holds the first and last values before and after the keyframes
+#[derive(Debug, Clone)]
+pub struct Track<T> {
+    keys: Vec<Keyframe<T>>,
+}
+
+impl<T: Animatable> Track<T> {
+    /// Track starting with `value` at time 0
+    pub fn new(value: T) -> Self {
+        Self {
+            keys: vec![Keyframe {
+                time: 0.0,
+                value,
+                interpolation: Interpolation::Linear,
+            }],
+        }
+    }
+
+    /// Adds a keyframe, replacing the one at the same time
+    pub fn with_key(mut self, time: f32, value: T, interpolation: Interpolation) -> Self {
+        let key = Keyframe {
+            time,
+            value,
+            interpolation,
+        };
+        match self.keys.binary_search_by(|k| k.time.total_cmp(&time)) {
+            Ok(i) => self.keys[i] = key,
+            Err(i) => self.keys.insert(i, key),
+        }
+        self
+    }
+
+    pub fn keys(&self) -> &[Keyframe<T>] {
+        &self.keys
+    }
+
+    /// Time of the last keyframe
+    pub fn end(&self) -> f32 {
+        self.keys.last().map_or(0.0, |k| k.time)
+    }
+
+    pub fn sample(&self, time: f32) -> T {
+        let i = self.keys.partition_point(|k| k.time <= time);
+        if i == 0 {
+            return self.keys[0].value.clone();
+        }
+        if i == self.keys.len() {
+            return self.keys[i - 1].value.clone();
+        }
+        let (a, b) = (&self.keys[i - 1], &self.keys[i]);
+        let t = (time - a.time) / (b.time - a.time);
+        match a.interpolation {
+            Interpolation::Step => a.value.clone(),
+            Interpolation::Linear => a.value.lerp(&b.value, t),
+            Interpolation::Bezier => {
+                let before = &self.keys[i.saturating_sub(2)].value;
+                let after = &self.keys[(i + 1).min(self.keys.len() - 1)].value;
+                // Catmull-Rom tangents as cubic Bezier handles
+                let c0 = a.value.offset(before, &b.value, 1.0 / 6.0);
+                let c1 = b.value.offset(after, &a.value, 1.0 / 6.0);
+                // de Casteljau
+                let p01 = a.value.lerp(&c0, t);
+                let p12 = c0.lerp(&c1, t);
+                let p23 = c1.lerp(&b.value, t);
+                let p012 = p01.lerp(&p12, t);
+                let p123 = p12.lerp(&p23, t);
+                p012.lerp(&p123, t)
+            }
+        }
+    }
+}
+
+/// Values at evenly spaced times over the shutter interval [0, 1], linear in between.
+/// Deforming objects use them to change shape within one frame, rays see them at their own time
+#[derive(Debug, Clone)]
+pub struct Steps<T> {
+    values: Vec<T>,
+}
+
+impl<T: Animatable> Steps<T> {
+    /// The first value is at time 0 and the last one at time 1
+    pub fn new(values: Vec<T>) -> Self {
+        assert!(!values.is_empty(), "steps need at least one value");
+        Self { values }
+    }
+
+    pub fn constant(value: T) -> Self {
+        Self::new(vec![value])
+    }
+
+    pub fn values(&self) -> &[T] {
+        &self.values
+    }
+
+    pub fn at(&self, time: f32) -> T {
+        let (i, t) = segment(self.values.len(), time);
+        if t == 0.0 {
+            return self.values[i].clone();
+        }
+        self.values[i].lerp(&self.values[i + 1], t)
+    }
+
+    /// Values the linear motion between `t0` and `t1` is bounded by
+    pub fn during(&self, t0: f32, t1: f32) -> Vec<T> {
+        times_between(self.values.len(), t0, t1)
+            .into_iter()
+            .map(|t| self.at(t))
+            .collect()
+    }
+}
+
+/// Segment of `keys` evenly spaced keys over [0, 1] that `time` is in and how far along it is.
+/// Times outside of [0, 1] hold the first and last keys
+pub(crate) fn segment(keys: usize, time: f32) -> (usize, f32) {
+    if keys < 2 {
+        return (0, 0.0);
+    }
+    let x = time.clamp(0.0, 1.0) * (keys - 1) as f32;
+    let i = (x as usize).min(keys - 2);
+    let t = x - i as f32;
+    if t >= 1.0 {
+        return (i + 1, 0.0);
+    }
+    (i, t)
+}
+
+/// `t0`, the times of the keys in between and `t1`, a linear motion is at its extremes at these
+pub(crate) fn times_between(keys: usize, t0: f32, t1: f32) -> Vec<f32> {
+    let (t0, t1) = (t0.clamp(0.0, 1.0), t1.clamp(0.0, 1.0));
+    let mut times = vec![t0];
+    if keys >= 2 {
+        let n = (keys - 1) as f32;
+        times.extend(
+            (1..keys - 1)
+                .map(|k| k as f32 / n)
+                .filter(|&t| t > t0 && t < t1),
+        );
+    }
+    times.push(t1);
+    times
+}
+
+/// Instance moving over time. Materials and textures change by building the instance anew
+/// for every point in time with `from_fn`
+#[derive(Clone)]
+pub struct AnimatedInstance {
+    build: Arc<dyn Fn(f32) -> Instance + Send + Sync>,
+    position: Option<Track<Vec3>>,
+    rotation: Option<Track<Quaternion>>,
+    scale: Option<Track<f32>>,
+}
+
+impl AnimatedInstance {
+    pub fn new(instance: Instance) -> Self {
+        Self::from_fn(move |_| instance.clone())
+    }
+    /// Instance built by `build` from the time, for animated materials and textures
+    pub fn from_fn(build: impl Fn(f32) -> Instance + Send + Sync + 'static) -> Self {
+        Self {
+            build: Arc::new(build),
+            position: None,
+            rotation: None,
+            scale: None,
+        }
+    }
+    pub fn with_position(mut self, position: Track<Vec3>) -> Self {
+        self.position = Some(position);
+        self
+    }
+    pub fn with_rotation(mut self, rotation: Track<Quaternion>) -> Self {
+        self.rotation = Some(rotation);
+        self
+    }
+    pub fn with_scale(mut self, scale: Track<f32>) -> Self {
+        self.scale = Some(scale);
+        self
+    }
+
+    /// The instance at `time`, the tracks replace its own transform
+    pub fn at(&self, time: f32) -> Instance {
+        let mut instance = (self.build)(time);
+        if let Some(position) = &self.position {
+            instance.set_position(position.sample(time));
+        }
+        if let Some(rotation) = &self.rotation {
+            instance.set_rotation(rotation.sample(time));
+        }
+        if let Some(scale) = &self.scale {
+            instance.set_scale(scale.sample(time));
+        }
+        instance
+    }
+}
+
+/// Camera looking from `origin` at `target`, focused on the target unless a focus distance is set
+#[derive(Clone)]
+pub struct AnimatedCamera {
+    aspect: f32,
+    vup: Vec3,
+    origin: Track<Vec3>,
+    target: Track<Vec3>,
+    vfov: Track<f32>,
+    aperture: Track<f32>,
+    focus_distance: Option<Track<f32>>,
+}
+
+impl AnimatedCamera {
+    pub fn new(
+        aspect: f32,
+        origin: Track<Vec3>,
+        vup: Vec3,
+        target: Track<Vec3>,
+        vfov: Track<f32>,
+    ) -> Self {
+        Self {
+            aspect,
+            vup,
+            origin,
+            target,
+            vfov,
+            aperture: Track::new(0.0),
+            focus_distance: None,
+        }
+    }
+    /// Radius of the lens
+    pub fn with_aperture(mut self, aperture: Track<f32>) -> Self {
+        self.aperture = aperture;
+        self
+    }
+    pub fn with_focus_distance(mut self, focus_distance: Track<f32>) -> Self {
+        self.focus_distance = Some(focus_distance);
+        self
+    }
+
+    pub fn at(&self, time: f32) -> Camera {
+        let origin = self.origin.sample(time);
+        let to_target = self.target.sample(time) - origin;
+        let focus_distance = match &self.focus_distance {
+            Some(d) => d.sample(time),
+            None => to_target.length(),
+        };
+        Camera::new(
+            self.aspect,
+            origin,
+            self.vup,
+            to_target.unit(),
+            self.vfov.sample(time),
+            self.aperture.sample(time),
+        )
+        .with_focus_distance(focus_distance)
+    }
+}
+
+/// Scene and camera changing over time, frames are `1 / fps` apart. Instances move from
+/// their place in one frame at time 0 to the next one at time 1, so the shutter of the
+/// viewport is a part of the time between frames
+#[derive(Clone)]
+pub struct Animation {
+    camera: AnimatedCamera,
+    instances: Vec<AnimatedInstance>,
+    lights: Vec<Arc<dyn Light + Send + Sync>>,
+    mint: f32,
+    maxt: f32,
+    pub fps: f32,
+}
+
+impl Animation {
+    pub fn new(
+        camera: AnimatedCamera,
+        instances: Vec<AnimatedInstance>,
+        mint: f32,
+        maxt: f32,
+        fps: f32,
+    ) -> Self {
+        Self {
+            camera,
+            instances,
+            lights: vec![],
+            mint,
+            maxt,
+            fps,
+        }
+    }
+    pub fn with_lights(mut self, lights: Vec<Arc<dyn Light + Send + Sync>>) -> Self {
+        self.lights = lights;
+        self
+    }
+
+    pub fn time(&self, frame: usize) -> f32 {
+        frame as f32 / self.fps
+    }
+
+    /// Camera and scene of `frame`
+    pub fn frame(&self, frame: usize) -> (Camera, Scene) {
+        let time = self.time(frame);
+        let next = self.time(frame + 1);
+        let instances = self
+            .instances
+            .iter()
+            .map(|i| {
+                let mut instance = i.at(time);
+                // moves on to the next frame over the time of the shutter
+                if i.position.is_some() || i.rotation.is_some() {
+                    let end = i.at(next);
+                    instance.set_motion(end.gett(), end.getr());
+                }
+                instance
+            })
+            .collect();
+        let scene = Scene::new(instances, self.mint, self.maxt).with_lights(self.lights.clone());
+        (self.camera.at(time), scene)
+    }
+
+    fn render_frame(&self, vp: &Viewport, frame: usize) -> RgbImage {
+        let (cam, scene) = self.frame(frame);
+        vp.clone().with_camera(cam).with_scene(scene).render()
+    }
+
+    // progress over all frames up to the end of `frames`, starting at its first one
+    fn progress(frames: &Range<usize>) -> ProgressBar {
+        let pb = ProgressBar::new(frames.end as u64);
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template("{msg} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")
+                .unwrap()
+                .progress_chars("#C-"),
+        );
+        pb.set_position(frames.start as u64);
+        pb.reset_eta();
+        pb
+    }
+
+    /// Renders `frames` with the settings of `vp` to numbered images `<prefix>0001.png`, ...
+    pub(crate) fn render_frames(
+        &self,
+        vp: &Viewport,
+        frames: Range<usize>,
+        prefix: &str,
+    ) -> ImageResult<()> {
+        let pb = Self::progress(&frames);
+        for frame in frames {
+            pb.set_message(format!("frame {}", frame));
+            self.render_frame(vp, frame)
+                .save(format!("{}{:04}.png", prefix, frame))?;
+            pb.inc(1);
+        }
+        pb.finish();
+        Ok(())
+    }
+
+    /// Renders `frames` with the settings of `vp` into a video at `path` playing at `fps`,
+    /// `.gif` or `.y4m` by the extension. Starting after frame 0 continues the video
+    /// already at `path`, keeping the frames before the start
+    pub(crate) fn render_video(
+        &self,
+        vp: &Viewport,
+        frames: Range<usize>,
+        path: impl AsRef<Path>,
+    ) -> ImageResult<()> {
+        let path = path.as_ref();
+        let start = frames.start;
+        let pb = Self::progress(&frames);
+        let mut video = None;
+        for frame in frames {
+            pb.set_message(format!("frame {}", frame));
+            let img = self.render_frame(vp, frame);
+            if video.is_none() {
+                video = Some(VideoWriter::create(
+                    path,
+                    img.width(),
+                    img.height(),
+                    self.fps,
+                    start,
+                )?);
+            }
+            video.as_mut().unwrap().write_frame(&img)?;
+            pb.inc(1);
+        }
+        pb.finish();
+        match video {
+            Some(video) => video.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{f32::consts::PI, fs::File, io::BufReader, sync::Arc};
+
+    use image::{codecs::gif::GifDecoder, AnimationDecoder, ImageResult};
+
+    use crate::{
+        objects::{
+            instance::Instance,
+            light::{Light, PointLight},
+            material::LAMBERTIAN,
+            quad::Quad,
+            texture::ConstColorTexture,
+        },
+        quaternions::{Quaternion, ZERO_ROTATION},
+        rotation::Rotation,
+        vec3::{ray::Ray, vec3::Vec3},
+        viewport::{ray_color::next_event_ray_color, scene::Scene, Viewport},
+    };
+
+    use super::{AnimatedCamera, AnimatedInstance, Animation, Interpolation, Steps, Track};
+
+    #[test]
+    fn evenly_spaced_steps() {
+        let steps = Steps::new(vec![0.0, 4.0, 2.0]);
+        assert_eq!(steps.at(0.25), 2.0);
+        assert_eq!(steps.at(0.5), 4.0);
+        assert_eq!(steps.at(0.75), 3.0);
+        assert_eq!(steps.at(-1.0), 0.0);
+        assert_eq!(steps.at(2.0), 2.0);
+        // the key in between bounds the motion
+        assert_eq!(steps.during(0.25, 0.75), vec![2.0, 4.0, 3.0]);
+        assert_eq!(steps.during(0.0, 0.5), vec![0.0, 4.0]);
+        assert_eq!(Steps::constant(1.0).at(0.3), 1.0);
+    }
+
+    #[test]
+    fn linear_and_step_tracks() {
+        let track = Track::new(0.0)
+            .with_key(2.0, 4.0, Interpolation::Step)
+            .with_key(1.0, 2.0, Interpolation::Linear)
+            .with_key(3.0, 10.0, Interpolation::Linear);
+        assert_eq!(track.sample(-1.0), 0.0);
+        assert_eq!(track.sample(0.5), 1.0);
+        assert_eq!(track.sample(1.5), 3.0);
+        assert_eq!(track.sample(2.9), 4.0);
+        assert_eq!(track.sample(3.0), 10.0);
+        assert_eq!(track.sample(7.0), 10.0);
+        assert_eq!(track.end(), 3.0);
+    }
+
+    #[test]
+    fn bezier_tracks_are_smooth() {
+        let track = Track::new(Vec3::ZERO)
+            .with_key(0.0, Vec3::ZERO, Interpolation::Bezier)
+            .with_key(1.0, Vec3::new(1.0, 2.0, 0.0), Interpolation::Bezier)
+            .with_key(2.0, Vec3::new(3.0, 0.0, 1.0), Interpolation::Bezier)
+            .with_key(3.0, Vec3::new(4.0, 1.0, 1.0), Interpolation::Linear);
+        // the curve goes through every keyframe
+        for k in track.keys() {
+            assert_eq!(track.sample(k.time), k.value);
+        }
+        // and has no kinks at the inner ones
+        for time in [1.0, 2.0] {
+            let h = 1e-3;
+            let before = (track.sample(time) - track.sample(time - h)) / h;
+            let after = (track.sample(time + h) - track.sample(time)) / h;
+            assert!((before - after).length() < 0.05, "{:?} {:?}", before, after);
+        }
+    }
+
+    #[test]
+    fn rotation_tracks_slerp() {
+        let quarter = Quaternion::new_from_axis(PI / 2.0, Vec3::UP);
+        let track = Track::new(ZERO_ROTATION).with_key(1.0, quarter, Interpolation::Linear);
+        let eighth = Quaternion::new_from_axis(PI / 4.0, Vec3::UP);
+        let half_way = track.sample(0.5);
+        assert!(
+            (half_way.dot(&eighth).abs() - 1.0).abs() < 1e-5,
+            "{:?}",
+            half_way
+        );
+        // bezier rotations stay rotations
+        let track = Track::new(ZERO_ROTATION)
+            .with_key(0.0, ZERO_ROTATION, Interpolation::Bezier)
+            .with_key(1.0, eighth, Interpolation::Bezier)
+            .with_key(
+                2.0,
+                Quaternion::new_from_axis(PI, Vec3::RIGHT),
+                Interpolation::Bezier,
+            );
+        for i in 0..20 {
+            let q = track.sample(i as f32 * 0.1);
+            assert!((q.len() - 1.0).abs() < 1e-4);
+        }
+    }
+
+    fn unit_box() -> Instance {
+        Instance::new_box(
+            Vec3::new(-0.5, -0.5, -0.5),
+            Vec3::new(0.5, 0.5, 0.5),
+            Arc::new(ConstColorTexture::new(Vec3::new(0.8, 0.3, 0.2), Vec3::ZERO)),
+            LAMBERTIAN.clone(),
+        )
+    }
+
+    #[test]
+    fn transformed_instance_hits() {
+        let turn = Quaternion::new_from_axis(PI / 6.0, Vec3::UP);
+        let instance = AnimatedInstance::new(unit_box())
+            .with_position(Track::new(Vec3::ZERO).with_key(
+                1.0,
+                Vec3::FORWARD * 4.0,
+                Interpolation::Linear,
+            ))
+            .with_rotation(Track::new(turn.clone()))
+            .with_scale(Track::new(1.0).with_key(1.0, 2.0, Interpolation::Linear))
+            .at(1.0);
+        let scene = Scene::new(vec![instance], 0.001, 100.0);
+        // the ray along the axis hits the middle of the rotated front face of the box
+        let (hit, _) = scene.get_hit(Ray::new(Vec3::ZERO, Vec3::FORWARD)).unwrap();
+        let normal = turn.rotate(&Vec3::BACKWARD);
+        let front = Vec3::FORWARD * 4.0 + normal;
+        assert!((hit.p - front).dot(normal).abs() < 1e-4, "{:?}", hit.p);
+        assert!(hit.p.x.abs() < 1e-4 && hit.p.y.abs() < 1e-4, "{:?}", hit.p);
+        assert!(hit.ng.unit().dot(normal) > 1.0 - 1e-5);
+        assert_eq!(hit.r.direction, Vec3::FORWARD);
+    }
+
+    #[test]
+    fn animated_camera() {
+        let cam = AnimatedCamera::new(
+            1.0,
+            Track::new(Vec3::ZERO).with_key(1.0, Vec3::UP, Interpolation::Linear),
+            Vec3::UP,
+            Track::new(Vec3::FORWARD * 2.0),
+            Track::new(40.0),
+        )
+        .with_aperture(Track::new(0.1));
+        let (origin, focus) = (cam.at(0.5).origin, cam.at(0.5).focus_distance());
+        assert_eq!(origin, Vec3::UP * 0.5);
+        assert!((focus - (Vec3::FORWARD * 2.0 - origin).length()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn animation_test() -> ImageResult<()> {
+        const WIDTH: usize = 64;
+        const HEIGHT: usize = 48;
+        let tint = Track::new(Vec3::new(0.8, 0.2, 0.2)).with_key(
+            1.0,
+            Vec3::new(0.2, 0.2, 0.8),
+            Interpolation::Linear,
+        );
+        let spinning_box = AnimatedInstance::from_fn(move |time| {
+            Instance::new_box(
+                Vec3::new(-0.5, -0.5, -0.5),
+                Vec3::new(0.5, 0.5, 0.5),
+                Arc::new(ConstColorTexture::new(tint.sample(time), Vec3::ZERO)),
+                LAMBERTIAN.clone(),
+            )
+        })
+        .with_position(Track::new(Vec3::new(0.0, 0.0, 3.0)))
+        .with_rotation(
+            Track::new(ZERO_ROTATION)
+                .with_key(0.0, ZERO_ROTATION, Interpolation::Bezier)
+                .with_key(
+                    1.0,
+                    Quaternion::new_from_axis(PI * 0.75, Vec3::UP),
+                    Interpolation::Bezier,
+                ),
+        )
+        .with_scale(Track::new(0.6).with_key(1.0, 1.2, Interpolation::Bezier));
+        let floor = AnimatedInstance::new(Instance::new(Arc::new([Arc::new(Quad::new(
+            Vec3::new(-5.0, -1.0, -2.0),
+            Vec3::new(10.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 10.0),
+            LAMBERTIAN.clone(),
+            Vec3::ZERO,
+            Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.5, Vec3::ZERO)),
+        ))])));
+        let camera = AnimatedCamera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Track::new(Vec3::new(-1.0, 0.5, 0.0)).with_key(
+                1.0,
+                Vec3::new(1.0, 1.0, 0.0),
+                Interpolation::Linear,
+            ),
+            Vec3::UP,
+            Track::new(Vec3::new(0.0, 0.0, 3.0)),
+            Track::new(60.0).with_key(1.0, 45.0, Interpolation::Linear),
+        );
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![Arc::new(PointLight::new(
+            Vec3::new(0.0, 3.0, 1.0),
+            Vec3::WHITE * 30.0,
+        ))];
+        let animation = Animation::new(camera, vec![spinning_box, floor], 0.001, 1000.0, 4.0)
+            .with_lights(lights);
+        let (cam, scene) = animation.frame(0);
+        let vp = Viewport::new(
+            cam,
+            scene,
+            Arc::new(next_event_ray_color),
+            WIDTH,
+            HEIGHT,
+            4,
+            4,
+            Vec3::new(0.4, 0.5, 0.7),
+            2.2,
+        );
+        animation.render_frames(&vp, 0..5, "test_out/animation_test_")?;
+
+        // the same frames as videos, the gif continued after stopping at frame 3
+        let gif = "test_out/animation_test.gif";
+        animation.render_video(&vp, 0..3, gif)?;
+        animation.render_video(&vp, 3..5, gif)?;
+        let decoder = GifDecoder::new(BufReader::new(File::open(gif)?))?;
+        let frames = decoder.into_frames().collect_frames()?;
+        assert_eq!(frames.len(), 5);
+        // 4 frames per second
+        assert_eq!(frames[4].delay().numer_denom_ms(), (250, 1));
+        animation.render_video(&vp, 0..5, "test_out/animation_test.y4m")
+    }
+
+    #[test]
+    fn frames_follow_fps() {
+        let camera = AnimatedCamera::new(
+            1.0,
+            Track::new(Vec3::ZERO),
+            Vec3::UP,
+            Track::new(Vec3::FORWARD),
+            Track::new(60.0),
+        );
+        let moving = AnimatedInstance::new(Instance::new(Arc::new([]))).with_position(
+            Track::new(Vec3::ZERO).with_key(2.0, Vec3::UP * 2.0, Interpolation::Linear),
+        );
+        let animation = Animation::new(camera, vec![moving.clone()], 0.001, 1000.0, 24.0);
+        assert_eq!(animation.time(12), 0.5);
+        assert_eq!(moving.at(animation.time(12)).gett(), Vec3::UP * 0.5);
+    }
+}
@@ -1,9 +1,12 @@
+pub mod animation;
+pub mod matrix;
 pub mod objects;
 pub mod onb;
 #[allow(unused)]
 pub mod postprocessing;
 pub mod quaternions;
 pub mod rotation;
+pub mod sampler;
 pub mod vec3;
 pub mod viewport;
 pub mod write_img;
@@ -12,9 +15,43 @@ pub mod write_img;
 #[allow(unused)]
 pub mod tests;
 
-use image::ImageResult;
+use std::process::ExitCode;
 
-fn main() -> ImageResult<()> {
-    println!("Hello, world!");
-    Ok(())
+use postprocessing::PostChain;
+
+const USAGE: &str = "usage: raytracing post <input> <output> <effects> [gamma]
+  applies post effects to an image, e.g.
+  raytracing post render.png graded.png \"bloom threshold=1 | vignette strength=0.5\"
+  effects: bloom threshold intensity radius | vignette strength | chromatic_aberration strength
+         | grain amount seed | white_balance temperature tint | lut path gamma
+  gamma: of images with 8 or 16 bits per channel, 2.2 by default";
+
+fn post(args: &[String]) -> Result<(), String> {
+    let (input, output, spec) = match args {
+        [input, output, spec] | [input, output, spec, _] => (input, output, spec),
+        _ => return Err(USAGE.to_string()),
+    };
+    let gamma = match args.get(3) {
+        Some(g) => g.parse().map_err(|e| format!("gamma {}: {}", g, e))?,
+        None => 2.2,
+    };
+    let chain = PostChain::parse(spec)?;
+    chain
+        .apply_to_file(input, output, gamma)
+        .map_err(|e| format!("{}: {}", input, e))
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("post") => post(&args[1..]),
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
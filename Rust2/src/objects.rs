@@ -1,13 +1,22 @@
+use std::sync::Arc;
+
 use crate::vec3::ray::Ray;
 
-use self::{aabb::Interval, hit::Hit};
+use self::{aabb::Interval, hit::Hit, material::Material, shapes::Surface};
 
 pub mod aabb;
+pub mod csg;
+pub mod detail;
 pub mod hit;
 pub mod instance;
+pub mod light;
 pub mod material;
+pub mod prototype;
 pub mod quad;
+pub mod sdf;
+pub mod shapes;
 pub mod sphere;
+pub mod subdivision;
 pub mod texture;
 pub mod triangle;
 
@@ -15,9 +24,39 @@ pub trait CloneObject: Clone + Object {}
 
 pub trait Object {
     fn get_aabb(&self) -> (Interval, Interval, Interval);
+    /// Bounds for rays with times between `t0` and `t1`, the whole bounds for objects that don't move
+    fn get_aabb_during(&self, _t0: f32, _t1: f32) -> (Interval, Interval, Interval) {
+        self.get_aabb()
+    }
     fn get_hit(&self, r: Ray, mint: f32, maxt: f32) -> Option<Hit>;
+    /// Closest hit and the part that should shade it, `None` when it's the object itself
+    fn get_hit_part(
+        &self,
+        r: Ray,
+        mint: f32,
+        maxt: f32,
+    ) -> Option<(Hit, Option<Arc<dyn Object + Send + Sync>>)> {
+        self.get_hit(r, mint, maxt).map(|h| (h, None))
+    }
     fn reflect(&self, h: &Hit) -> Ray;
     fn generator_pdf(&self, h: &Hit, r: &Ray) -> f32;
     fn material_pdf(&self, h: &Hit, r: &Ray) -> f32;
     fn color(&self, h: &Hit) -> texture::ColorResult;
+    fn is_specular(&self, _h: &Hit) -> bool {
+        false
+    }
+    /// Material shading the object, the scene numbers them for the material id AOV
+    fn material(&self) -> Option<&Arc<dyn Material + Send + Sync>> {
+        None
+    }
+    /// Address of the object for the object id AOV, wrappers made while hitting give the one they wrap
+    fn identity(&self) -> *const () {
+        self as *const Self as *const ()
+    }
+    /// Calls `f` with every part that can shade hits of a composite object like `Csg`, nested parts included
+    fn parts(&self, _f: &mut dyn FnMut(&Arc<dyn Object + Send + Sync>)) {}
+    /// The object as a surface to sample light on when its texture glows
+    fn emitter(self: Arc<Self>) -> Option<Arc<dyn Surface + Send + Sync>> {
+        None
+    }
 }
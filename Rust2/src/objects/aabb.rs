@@ -1,16 +1,13 @@
-use std::{
-    ops::{Add, AddAssign},
-    sync::Arc,
-};
+use std::ops::{Add, AddAssign};
 
 use rand::random;
 
-use crate::{
-    vec3::{ray::Ray, vec3::Vec3},
-    viewport::scene::Scene,
-};
+use crate::vec3::{ray::Ray, vec3::Vec3};
 
-use super::{hit::Hit, instance::Instance, Object};
+use super::{
+    hit::{Hit, HitObject},
+    instance::Instance,
+};
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Interval {
@@ -137,41 +134,74 @@ impl Distribution<Axis> for Standard {
         }
     }
 }
+/// Number of parts the shutter interval is split into for the bounds of moving objects
+pub const TIME_STEPS: usize = 8;
+
+/// Time step a ray with `time` is in, times outside of [0, 1] use the first and last ones
+pub(crate) fn time_step(time: f32) -> usize {
+    ((time.clamp(0.0, 1.0) * TIME_STEPS as f32) as usize).min(TIME_STEPS - 1)
+}
+
+/// What the leaves of the bounding volume hierarchy hold
+pub trait Bounded: Clone {
+    /// Leaf node around `self`
+    fn leaf(&self) -> AABB<Self>;
+    /// Closest hit and the object that was hit
+    fn closest_hit(&self, r: Ray, mint: f32, maxt: f32) -> Option<(Hit, HitObject<'_>)>;
+}
+
 #[derive(Clone)]
-pub struct AABB {
+pub struct AABB<T = Instance> {
     pub(crate) x: Interval,
     pub(crate) y: Interval,
     pub(crate) z: Interval,
-    pub(crate) instances: Vec<Instance>,
-    pub(crate) aabbs: Vec<AABB>,
+    // bounds during each of the `TIME_STEPS`, empty when nothing inside moves
+    pub(crate) steps: Vec<(Interval, Interval, Interval)>,
+    // only in the leaves
+    pub(crate) instances: Vec<T>,
+    pub(crate) aabbs: Vec<AABB<T>>,
 }
-impl AABB {
-    pub fn new(mut instances: Vec<Instance>) -> Self {
-        if instances.len() == 0 {
+impl<T: Bounded> AABB<T> {
+    pub fn new(instances: Vec<T>) -> Self {
+        Self::from_leaves(instances.iter().map(|i| i.leaf()).collect())
+    }
+
+    fn from_leaves(mut leaves: Vec<AABB<T>>) -> Self {
+        if leaves.len() == 0 {
             return Self::empty();
         }
-        if instances.len() == 1 {
-            return instances[0].get_aabb();
+        if leaves.len() == 1 {
+            return leaves.pop().unwrap();
         }
         let axis = random::<Axis>();
         match axis {
-            Axis::X => instances
-                .sort_unstable_by(|s, oth| (s.get_aabb().x.max).total_cmp(&(oth.get_aabb().x.max))),
-            Axis::Y => instances
-                .sort_unstable_by(|s, oth| (s.get_aabb().y.max).total_cmp(&(oth.get_aabb().y.max))),
-            Axis::Z => instances
-                .sort_unstable_by(|s, oth| (s.get_aabb().z.max).total_cmp(&(oth.get_aabb().z.max))),
+            Axis::X => leaves.sort_unstable_by(|s, oth| (s.x.max).total_cmp(&(oth.x.max))),
+            Axis::Y => leaves.sort_unstable_by(|s, oth| (s.y.max).total_cmp(&(oth.y.max))),
+            Axis::Z => leaves.sort_unstable_by(|s, oth| (s.z.max).total_cmp(&(oth.z.max))),
         }
-        let len = instances.len() / 2;
+        let len = leaves.len() / 2;
+
+        let rest = leaves.split_off(len);
+        let aabb1 = Self::from_leaves(leaves);
+        let aabb2 = Self::from_leaves(rest);
 
-        let aabb1 = Self::new(instances[0..len].to_vec());
-        let aabb2 = Self::new(instances[len..].to_vec());
+        let steps = if aabb1.steps.is_empty() && aabb2.steps.is_empty() {
+            vec![]
+        } else {
+            (0..TIME_STEPS)
+                .map(|i| {
+                    let (a, b) = (aabb1.bounds_during(i), aabb2.bounds_during(i));
+                    (a.0 + b.0, a.1 + b.1, a.2 + b.2)
+                })
+                .collect()
+        };
 
         return AABB {
             x: aabb1.x + aabb2.x,
             y: aabb1.y + aabb2.y,
             z: aabb1.z + aabb2.z,
-            instances: instances,
+            steps,
+            instances: vec![],
             aabbs: vec![aabb1, aabb2],
         };
     }
@@ -184,37 +214,56 @@ impl AABB {
             x: Interval { min: 0.0, max: 0.0 },
             y: Interval { min: 0.0, max: 0.0 },
             z: Interval { min: 0.0, max: 0.0 },
+            steps: vec![],
             instances: vec![],
             aabbs: vec![],
         }
     }
 
-    pub(crate) fn get_hit(
-        &self,
-        r: Ray,
-        s: &Scene,
-    ) -> Option<(Hit, Arc<dyn Object + Send + Sync>)> {
-        let x_hit = match self.x.intersect(r.direction.x, r.origin.x) {
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty() && self.aabbs.is_empty()
+    }
+
+    /// Bounds during the time step `i`
+    pub(crate) fn bounds_during(&self, i: usize) -> (Interval, Interval, Interval) {
+        match self.steps.get(i) {
+            Some(b) => *b,
+            None => (self.x, self.y, self.z),
+        }
+    }
+
+    /// Calls `f` with everything in the leaves
+    pub(crate) fn for_each(&self, f: &mut dyn FnMut(&T)) {
+        self.instances.iter().for_each(&mut *f);
+        for a in self.aabbs.iter() {
+            a.for_each(f);
+        }
+    }
+
+    pub(crate) fn get_hit(&self, r: Ray, mint: f32, maxt: f32) -> Option<(Hit, HitObject<'_>)> {
+        // only where things are at the time of the ray
+        let (x, y, z) = self.bounds_during(time_step(r.time));
+        let x_hit = match x.intersect(r.direction.x, r.origin.x) {
             Some(n) => n,
             None => return None,
         };
-        let y_hit = match self.y.intersect(r.direction.y, r.origin.y) {
+        let y_hit = match y.intersect(r.direction.y, r.origin.y) {
             Some(n) => n,
             None => return None,
         };
-        let z_hit = match self.z.intersect(r.direction.z, r.origin.z) {
+        let z_hit = match z.intersect(r.direction.z, r.origin.z) {
             Some(n) => n,
             None => return None,
         };
-        let min = maxf(maxf(x_hit.min, y_hit.min), maxf(z_hit.min, s.mint));
-        let max = minf(minf(x_hit.max, y_hit.max), minf(z_hit.max, s.maxt));
+        let min = maxf(maxf(x_hit.min, y_hit.min), maxf(z_hit.min, mint));
+        let max = minf(minf(x_hit.max, y_hit.max), minf(z_hit.max, maxt));
 
         if min > max {
             return None;
         }
         if self.aabbs.len() > 0 {
             let mut min_h = None;
-            for h in self.aabbs.iter().map(|a| a.get_hit(r, s)) {
+            for h in self.aabbs.iter().map(|a| a.get_hit(r, mint, maxt)) {
                 if h.is_none() {
                     continue;
                 }
@@ -230,7 +279,7 @@ impl AABB {
             return min_h;
         }
         let mut min_h = None;
-        for h in self.instances.iter().map(|i| i.get_hit(r, s)) {
+        for h in self.instances.iter().map(|i| i.closest_hit(r, mint, maxt)) {
             if h.is_none() {
                 continue;
             }
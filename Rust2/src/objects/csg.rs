@@ -0,0 +1,404 @@
+use std::sync::Arc;
+
+use crate::vec3::ray::Ray;
+
+use super::{
+    aabb::{maxf, minf, Interval},
+    hit::Hit,
+    material::Material,
+    texture::ColorResult,
+    Object,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CsgOp {
+    Union,
+    Intersection,
+    // inside the first operand but not the second
+    Difference,
+}
+
+impl CsgOp {
+    fn inside(&self, a: bool, b: bool) -> bool {
+        match self {
+            CsgOp::Union => a || b,
+            CsgOp::Intersection => a && b,
+            CsgOp::Difference => a && !b,
+        }
+    }
+}
+
+/// Solid combined from two closed objects with outward normals.
+/// Hits are shaded by the operand they come from, see `Object::get_hit_part`
+#[derive(Clone)]
+pub struct Csg {
+    pub op: CsgOp,
+    pub a: Arc<dyn Object + Send + Sync>,
+    pub b: Arc<dyn Object + Send + Sync>,
+}
+
+// crossings closer than this are the same surface
+const STEP: f32 = 1e-4;
+
+type PartHit = (Hit, Option<Arc<dyn Object + Send + Sync>>);
+
+impl Csg {
+    pub fn new(
+        op: CsgOp,
+        a: Arc<dyn Object + Send + Sync>,
+        b: Arc<dyn Object + Send + Sync>,
+    ) -> Self {
+        Self { op, a, b }
+    }
+
+    pub fn union(a: Arc<dyn Object + Send + Sync>, b: Arc<dyn Object + Send + Sync>) -> Self {
+        Self::new(CsgOp::Union, a, b)
+    }
+
+    pub fn intersection(
+        a: Arc<dyn Object + Send + Sync>,
+        b: Arc<dyn Object + Send + Sync>,
+    ) -> Self {
+        Self::new(CsgOp::Intersection, a, b)
+    }
+
+    pub fn difference(a: Arc<dyn Object + Send + Sync>, b: Arc<dyn Object + Send + Sync>) -> Self {
+        Self::new(CsgOp::Difference, a, b)
+    }
+
+    fn combine(
+        &self,
+        a: (Interval, Interval, Interval),
+        b: (Interval, Interval, Interval),
+    ) -> (Interval, Interval, Interval) {
+        let overlap = |a: Interval, b: Interval| Interval {
+            min: maxf(a.min, b.min),
+            max: minf(a.max, b.max),
+        };
+        match self.op {
+            CsgOp::Union => (a.0 + b.0, a.1 + b.1, a.2 + b.2),
+            CsgOp::Intersection => (overlap(a.0, b.0), overlap(a.1, b.1), overlap(a.2, b.2)),
+            CsgOp::Difference => a,
+        }
+    }
+}
+
+// next crossing of the surface of `o` and the part of it that was hit
+fn crossing(o: &Arc<dyn Object + Send + Sync>, r: Ray, mint: f32, maxt: f32) -> Option<PartHit> {
+    o.get_hit_part(r, mint, maxt)
+        .map(|(h, part)| (h, Some(part.unwrap_or_else(|| o.clone()))))
+}
+
+// leaving the solid through the surface
+fn exits(h: &Hit) -> bool {
+    h.r.direction.dot(h.ng) > 0.0
+}
+
+impl Object for Csg {
+    fn get_aabb(&self) -> (Interval, Interval, Interval) {
+        self.combine(self.a.get_aabb(), self.b.get_aabb())
+    }
+
+    fn get_aabb_during(&self, t0: f32, t1: f32) -> (Interval, Interval, Interval) {
+        self.combine(
+            self.a.get_aabb_during(t0, t1),
+            self.b.get_aabb_during(t0, t1),
+        )
+    }
+
+    fn get_hit(&self, r: Ray, mint: f32, maxt: f32) -> Option<Hit> {
+        self.get_hit_part(r, mint, maxt).map(|(h, _)| h)
+    }
+
+    fn get_hit_part(&self, r: Ray, mint: f32, maxt: f32) -> Option<PartHit> {
+        // the first crossing of a closed surface tells if the ray starts inside, it can be past
+        // `maxt` for short rays like shadow rays
+        let start = |o: &Arc<dyn Object + Send + Sync>| {
+            let first = crossing(o, r, mint, f32::INFINITY);
+            let inside = first.as_ref().is_some_and(|(h, _)| exits(h));
+            (inside, first.filter(|(h, _)| h.t <= maxt))
+        };
+        let (mut inside_a, mut next_a) = start(&self.a);
+        let (mut inside_b, mut next_b) = start(&self.b);
+        loop {
+            let before = self.op.inside(inside_a, inside_b);
+            let from_a = match (&next_a, &next_b) {
+                (None, None) => return None,
+                (Some(a), Some(b)) => a.0.t <= b.0.t,
+                (a, _) => a.is_some(),
+            };
+            let (h, part) = if from_a {
+                inside_a = !inside_a;
+                next_a.take().unwrap()
+            } else {
+                inside_b = !inside_b;
+                next_b.take().unwrap()
+            };
+            if self.op.inside(inside_a, inside_b) != before {
+                let mut h = h;
+                // the subtracted solid is seen from the inside
+                if !from_a && self.op == CsgOp::Difference {
+                    h.n = -h.n;
+                    h.ng = -h.ng;
+                }
+                return Some((h, part));
+            }
+            if from_a {
+                next_a = crossing(&self.a, r, h.t + STEP, maxt);
+            } else {
+                next_b = crossing(&self.b, r, h.t + STEP, maxt);
+            }
+        }
+    }
+
+    // only used when the hit wasn't found through `get_hit_part`, shades like the first operand
+    fn reflect(&self, h: &Hit) -> Ray {
+        self.a.reflect(h)
+    }
+
+    fn generator_pdf(&self, h: &Hit, r: &Ray) -> f32 {
+        self.a.generator_pdf(h, r)
+    }
+
+    fn material_pdf(&self, h: &Hit, r: &Ray) -> f32 {
+        self.a.material_pdf(h, r)
+    }
+
+    fn color(&self, h: &Hit) -> ColorResult {
+        self.a.color(h)
+    }
+
+    fn is_specular(&self, h: &Hit) -> bool {
+        self.a.is_specular(h)
+    }
+
+    fn material(&self) -> Option<&Arc<dyn Material + Send + Sync>> {
+        self.a.material()
+    }
+
+    fn parts(&self, f: &mut dyn FnMut(&Arc<dyn Object + Send + Sync>)) {
+        for part in [&self.a, &self.b] {
+            f(part);
+            part.parts(f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        objects::{
+            detail::{Detailed, NormalMap},
+            instance::Instance,
+            light::{Light, PointLight},
+            material::{LAMBERTIAN, MIRROR},
+            quad::Quad,
+            shapes::{Cylinder, Shape},
+            sphere::Sphere,
+            texture::ConstColorTexture,
+            Object,
+        },
+        vec3::{ray::Ray, vec3::Vec3},
+        viewport::{camera::Camera, ray_color::next_event_ray_color, scene::Scene, Viewport},
+    };
+
+    use super::Csg;
+
+    fn sphere(origin: Vec3, radius: f32) -> Arc<dyn Object + Send + Sync> {
+        Arc::new(Sphere {
+            origin,
+            radius,
+            mat: LAMBERTIAN.clone(),
+            texture: Arc::new(ConstColorTexture::new(Vec3::new(0.8, 0.4, 0.2), Vec3::ZERO)),
+        })
+    }
+
+    // closed cylinder through `center` along `axis`
+    fn rod(center: Vec3, axis: Vec3, radius: f32) -> Arc<dyn Object + Send + Sync> {
+        Arc::new(Shape::new(
+            Cylinder::new(radius, 4.0, true),
+            center - axis * 2.0,
+            axis,
+            MIRROR.clone(),
+            Arc::new(ConstColorTexture::new(Vec3::new(0.3, 0.6, 0.9), Vec3::ZERO)),
+        ))
+    }
+
+    fn id<T: ?Sized>(m: &Arc<T>) -> usize {
+        Arc::as_ptr(m) as *const () as usize
+    }
+
+    #[test]
+    fn sphere_with_hole() {
+        let center = Vec3::FORWARD * 5.0;
+        let drilled = Csg::difference(sphere(center, 1.0), rod(center, Vec3::FORWARD, 0.3));
+        // straight through the hole
+        assert!(drilled
+            .get_hit(Ray::new(Vec3::ZERO, Vec3::FORWARD), 0.001, 100.0)
+            .is_none());
+        let h = drilled
+            .get_hit(
+                Ray::new(Vec3::new(0.5, 0.0, 0.0), Vec3::FORWARD),
+                0.001,
+                100.0,
+            )
+            .unwrap();
+        assert!((h.t - (5.0 - 0.75f32.sqrt())).abs() < 1e-4);
+
+        // across the sphere the walls of the hole face into it
+        let r = Ray::new(center + Vec3::new(3.0, 0.0, 0.0), Vec3::new(-1.0, 0.0, 0.0));
+        let (h, part) = drilled.get_hit_part(r, 0.001, 100.0).unwrap();
+        assert!((h.t - 2.0).abs() < 1e-4 && h.ng.x > 0.9999);
+        assert_eq!(id(part.unwrap().material().unwrap()), id(&LAMBERTIAN));
+        let (h, part) = drilled.get_hit_part(r, 2.1, 100.0).unwrap();
+        assert!((h.t - 2.7).abs() < 1e-4, "{}", h.t);
+        assert!(h.ng.x < -0.9999 && h.n.x < -0.9999, "{:?}", h.ng);
+        // shaded by the cylinder that was subtracted
+        assert_eq!(id(part.unwrap().material().unwrap()), id(&MIRROR));
+        let h = drilled.get_hit(r, 2.8, 100.0).unwrap();
+        assert!((h.t - 3.3).abs() < 1e-4 && h.ng.x > 0.9999);
+
+        // a short ray from the hole ends before leaving the sphere but still hits the wall
+        let r = Ray::new(center, Vec3::new(1.0, 0.0, 0.0));
+        let h = drilled.get_hit(r, 0.001, 0.5).unwrap();
+        assert!((h.t - 0.3).abs() < 1e-4 && h.ng.x < -0.9999);
+        assert!(drilled.get_hit(r, 0.001, 0.25).is_none());
+    }
+
+    #[test]
+    fn wrappers_keep_the_shading_part() {
+        let center = Vec3::FORWARD * 5.0;
+        let drilled = Csg::difference(sphere(center, 1.0), rod(center, Vec3::FORWARD, 0.3));
+        let flat = Arc::new(ConstColorTexture::new(Vec3::new(0.5, 0.5, 1.0), Vec3::ZERO));
+        let detailed = Detailed::new(Arc::new(drilled), Arc::new(NormalMap::new(flat, 1.0)));
+        let r = Ray::new(center + Vec3::new(3.0, 0.0, 0.0), Vec3::new(-1.0, 0.0, 0.0));
+        let (_, part) = detailed.get_hit_part(r, 2.1, 100.0).unwrap();
+        assert_eq!(id(part.unwrap().material().unwrap()), id(&MIRROR));
+
+        // an instance material replaces the mirror, the hole keeps the texture of the rod
+        let mut instance = Instance::new(Arc::new([Arc::new(detailed)]));
+        instance.set_material(LAMBERTIAN.clone());
+        let scene = Scene::new(vec![instance], 0.001, 100.0);
+        let (h, object) = scene.get_hit(Ray::new(r.at(2.1), r.direction)).unwrap();
+        assert!((h.t - 0.6).abs() < 1e-4, "{}", h.t);
+        assert!(!object.is_specular(&h));
+        assert_eq!(object.color(&h).multiplied, Vec3::new(0.3, 0.6, 0.9));
+    }
+
+    #[test]
+    fn lens_and_union() {
+        let lens = Csg::intersection(
+            sphere(Vec3::FORWARD * 0.6, 1.0),
+            rod(Vec3::ZERO, Vec3::UP, 0.7),
+        );
+        let lens = Csg::intersection(Arc::new(lens), sphere(-Vec3::FORWARD * 0.6, 1.0));
+        let h = lens
+            .get_hit(Ray::new(-Vec3::FORWARD * 5.0, Vec3::FORWARD), 0.001, 100.0)
+            .unwrap();
+        assert!((h.t - 4.6).abs() < 1e-4 && h.ng.z < -0.9999);
+        // trimmed by the cylinder, and the spheres don't overlap this far out
+        let off = |x: f32, y: f32| Ray::new(Vec3::new(x, y, -5.0), Vec3::FORWARD);
+        assert!(lens.get_hit(off(0.65, 0.0), 0.001, 100.0).is_some());
+        assert!(lens.get_hit(off(0.75, 0.0), 0.001, 100.0).is_none());
+        assert!(lens.get_hit(off(0.0, 0.9), 0.001, 100.0).is_none());
+        let (x, _, z) = lens.get_aabb();
+        assert!((z.min + 0.4).abs() < 1e-4 && (z.max - 0.4).abs() < 1e-4);
+        assert!((x.max - 0.7).abs() < 1e-3);
+
+        // no surfaces inside the union, and rays starting inside find the way out
+        let pair = Csg::union(
+            sphere(Vec3::FORWARD * 4.5, 1.0),
+            sphere(Vec3::FORWARD * 5.5, 1.0),
+        );
+        let r = Ray::new(Vec3::ZERO, Vec3::FORWARD);
+        assert!((pair.get_hit(r, 0.001, 100.0).unwrap().t - 3.5).abs() < 1e-4);
+        let h = pair.get_hit(r, 3.6, 100.0).unwrap();
+        assert!((h.t - 6.5).abs() < 1e-4 && h.ng.z > 0.9999);
+    }
+
+    #[test]
+    fn csg_test() {
+        const WIDTH: usize = 150;
+        const HEIGHT: usize = 100;
+        let center = Vec3::new(1.2, 0.0, 4.0);
+        let mut drilled = sphere(center, 0.8);
+        for axis in [Vec3::FORWARD, Vec3::UP, Vec3::new(1.0, 0.0, 0.0)] {
+            drilled = Arc::new(Csg::difference(drilled, rod(center, axis, 0.35)));
+        }
+        let lens_center = Vec3::new(-1.2, 0.0, 4.0);
+        let lens = Csg::intersection(
+            sphere(lens_center + Vec3::new(0.5, 0.0, 0.3), 0.9),
+            sphere(lens_center - Vec3::new(0.5, 0.0, 0.3), 0.9),
+        );
+        let grey = Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.7, Vec3::ZERO));
+        let objects: Vec<Arc<dyn Object + Send + Sync>> = vec![
+            drilled,
+            Arc::new(lens),
+            Arc::new(Quad::new(
+                Vec3::new(-5.0, -0.8, -2.0),
+                Vec3::new(10.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 10.0),
+                LAMBERTIAN.clone(),
+                Vec3::ZERO,
+                grey.clone(),
+            )),
+            // back wall, seen through the holes
+            Arc::new(Quad::new(
+                Vec3::new(-5.0, -0.8, 6.0),
+                Vec3::new(10.0, 0.0, 0.0),
+                Vec3::new(0.0, 5.0, 0.0),
+                LAMBERTIAN.clone(),
+                Vec3::ZERO,
+                grey,
+            )),
+        ];
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![Arc::new(PointLight::new(
+            Vec3::new(0.0, 3.0, 2.0),
+            Vec3::WHITE * 20.0,
+        ))];
+        let scene =
+            Scene::new(vec![Instance::new(objects.into())], 0.001, 100.0).with_lights(lights);
+        // straight through the drilled sphere onto the wall, beside the hole it is solid
+        let (hit, _) = scene
+            .get_hit(Ray::new(center - Vec3::FORWARD * 4.0, Vec3::FORWARD))
+            .unwrap();
+        assert!((hit.p.z - 6.0).abs() < 1e-4, "{:?}", hit.p);
+        let beside = center + Vec3::new(0.5, 0.5, -4.0);
+        let (hit, _) = scene.get_hit(Ray::new(beside, Vec3::FORWARD)).unwrap();
+        assert!(
+            (hit.p.z - (4.0 - 0.14f32.sqrt())).abs() < 1e-4,
+            "{:?}",
+            hit.p
+        );
+        // the lens starts where the ray enters the far sphere
+        let (hit, _) = scene
+            .get_hit(Ray::new(lens_center - Vec3::FORWARD * 4.0, Vec3::FORWARD))
+            .unwrap();
+        let front = lens_center.z + 0.3 - 0.56f32.sqrt();
+        assert!((hit.p.z - front).abs() < 1e-4, "{:?}", hit.p);
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::new(0.0, 1.2, 0.0),
+            Vec3::UP,
+            Vec3::new(0.0, -0.3, 1.0),
+            50.0,
+            0.0,
+        );
+        Viewport::new(
+            cam,
+            scene,
+            Arc::new(next_event_ray_color),
+            WIDTH,
+            HEIGHT,
+            8,
+            4,
+            Vec3::new(0.4, 0.5, 0.7),
+            2.2,
+        )
+        .render()
+        .save("test_out/csg_test.png")
+        .unwrap();
+    }
+}
@@ -0,0 +1,360 @@
+use std::sync::Arc;
+
+use crate::{
+    onb::ONB,
+    vec3::{ray::Ray, vec3::Vec3},
+};
+
+use super::{
+    aabb::Interval, hit::Hit, material::Material, texture::ColorResult, texture::Texture, Object,
+};
+
+/// Source of a perturbed shading normal for a hit
+pub trait DetailMap {
+    fn shading_normal(&self, h: &Hit) -> Vec3;
+}
+
+/// Tangent-space normal map, the texture color is mapped from `[0, 1]` to `[-1, 1]`
+pub struct NormalMap {
+    pub texture: Arc<dyn Texture + Send + Sync>,
+    pub strength: f32,
+}
+
+impl NormalMap {
+    pub fn new(texture: Arc<dyn Texture + Send + Sync>, strength: f32) -> Self {
+        Self { texture, strength }
+    }
+}
+
+impl DetailMap for NormalMap {
+    fn shading_normal(&self, h: &Hit) -> Vec3 {
+        let c = self.texture.color_at(h).multiplied * 2.0 - Vec3::WHITE;
+        let local = Vec3::new(c.x * self.strength, c.y * self.strength, c.z.max(1e-3));
+        ONB::new_from_tangent(h.n, h.dpdu, h.dpdv)
+            .from_local(local)
+            .unit()
+    }
+}
+
+/// Height map, the height is the average of the texture channels times `scale`
+pub struct BumpMap {
+    pub texture: Arc<dyn Texture + Send + Sync>,
+    pub scale: f32,
+    // finite difference step in uv units
+    pub delta: f32,
+}
+
+impl BumpMap {
+    pub fn new(texture: Arc<dyn Texture + Send + Sync>, scale: f32) -> Self {
+        Self {
+            texture,
+            scale,
+            delta: 1e-3,
+        }
+    }
+
+    fn height(&self, h: &Hit) -> f32 {
+        let c = self.texture.color_at(h).multiplied;
+        (c.x + c.y + c.z) / 3.0 * self.scale
+    }
+}
+
+impl DetailMap for BumpMap {
+    fn shading_normal(&self, h: &Hit) -> Vec3 {
+        let n = h.n.unit();
+        let base = self.height(h);
+
+        let mut shifted = h.clone();
+        shifted.u += self.delta;
+        shifted.p = h.p + h.dpdu * self.delta;
+        let dhdu = (self.height(&shifted) - base) / self.delta;
+
+        let mut shifted = h.clone();
+        shifted.v += self.delta;
+        shifted.p = h.p + h.dpdv * self.delta;
+        let dhdv = (self.height(&shifted) - base) / self.delta;
+
+        let dpdu = h.dpdu + n * dhdu;
+        let dpdv = h.dpdv + n * dhdv;
+        let bumped = dpdu.cross(dpdv);
+        if !bumped.is_normal() || bumped.length2() <= 1e-12 {
+            return n;
+        }
+        let bumped = bumped.unit();
+        if bumped.dot(n) < 0.0 {
+            -bumped
+        } else {
+            bumped
+        }
+    }
+}
+
+/// Wraps any object and replaces the shading normal of its hits with the one from `map`
+pub struct Detailed {
+    pub object: Arc<dyn Object + Send + Sync>,
+    pub map: Arc<dyn DetailMap + Send + Sync>,
+}
+
+impl Detailed {
+    pub fn new(
+        object: Arc<dyn Object + Send + Sync>,
+        map: Arc<dyn DetailMap + Send + Sync>,
+    ) -> Self {
+        Self { object, map }
+    }
+
+    // keeps the viewer on the front side of the shading normal, otherwise grazing hits turn black
+    fn adapt_normal(h: &Hit, ns: Vec3) -> Vec3 {
+        const MIN_COS: f32 = 0.01;
+        let wo = -h.r.direction.unit();
+        let side = if wo.dot(h.ng) >= 0.0 { 1.0 } else { -1.0 };
+        let cos = ns.dot(wo) * side;
+        if cos >= MIN_COS {
+            return ns;
+        }
+        (ns + wo * side * (MIN_COS - cos)).unit()
+    }
+}
+
+impl Object for Detailed {
+    fn get_aabb(&self) -> (Interval, Interval, Interval) {
+        self.object.get_aabb()
+    }
+
+    fn get_aabb_during(&self, t0: f32, t1: f32) -> (Interval, Interval, Interval) {
+        self.object.get_aabb_during(t0, t1)
+    }
+
+    fn get_hit(&self, r: Ray, mint: f32, maxt: f32) -> Option<Hit> {
+        let mut h = self.object.get_hit(r, mint, maxt)?;
+        let ns = self.map.shading_normal(&h);
+        h.n = Self::adapt_normal(&h, ns);
+        Some(h)
+    }
+
+    // composite objects like `Csg` keep shading their hits with the part that was hit
+    fn get_hit_part(
+        &self,
+        r: Ray,
+        mint: f32,
+        maxt: f32,
+    ) -> Option<(Hit, Option<Arc<dyn Object + Send + Sync>>)> {
+        let (mut h, part) = self.object.get_hit_part(r, mint, maxt)?;
+        let ns = self.map.shading_normal(&h);
+        h.n = Self::adapt_normal(&h, ns);
+        Some((h, part))
+    }
+
+    fn reflect(&self, h: &Hit) -> Ray {
+        self.object.reflect(h)
+    }
+
+    fn generator_pdf(&self, h: &Hit, r: &Ray) -> f32 {
+        self.object.generator_pdf(h, r)
+    }
+
+    fn material_pdf(&self, h: &Hit, r: &Ray) -> f32 {
+        self.object.material_pdf(h, r)
+    }
+
+    fn color(&self, h: &Hit) -> ColorResult {
+        self.object.color(h)
+    }
+
+    fn is_specular(&self, h: &Hit) -> bool {
+        self.object.is_specular(h)
+    }
+
+    fn material(&self) -> Option<&Arc<dyn Material + Send + Sync>> {
+        self.object.material()
+    }
+
+    fn parts(&self, f: &mut dyn FnMut(&Arc<dyn Object + Send + Sync>)) {
+        self.object.parts(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use image::ImageResult;
+
+    use crate::{
+        objects::{
+            instance::Instance,
+            material::{Material, MirrorGlass, MixedMaterial, LAMBERTIAN, MIRROR},
+            quad::Quad,
+            sphere::Sphere,
+            texture::{
+                solid::{ColorRamp, NoiseTexture, WorleyTexture},
+                ConstColorTexture,
+            },
+            Object,
+        },
+        vec3::{ray::Ray, vec3::Vec3},
+        viewport::{camera::Camera, ray_color::ray_color, scene::Scene, Viewport},
+    };
+
+    use super::{BumpMap, Detailed, NormalMap};
+
+    #[test]
+    fn shading_normal_faces_viewer() {
+        // normal map that tilts the normal almost into the surface
+        let tilted = Arc::new(ConstColorTexture::new(Vec3::new(1.0, 0.5, 0.5), Vec3::ZERO));
+        let quad = Detailed::new(
+            Arc::new(Quad::new(
+                Vec3::new(-1.0, -1.0, 0.0),
+                Vec3::new(2.0, 0.0, 0.0),
+                Vec3::new(0.0, 2.0, 0.0),
+                LAMBERTIAN.clone(),
+                Vec3::ZERO,
+                tilted.clone(),
+            )),
+            Arc::new(NormalMap::new(tilted, 1.0)),
+        );
+        let r = Ray::new(Vec3::new(-5.0, 0.0, 1.0), Vec3::new(5.0, 0.0, -0.99));
+        let h = quad.get_hit(r, 0.001, 100.0).unwrap();
+        assert!(h.n.dot(-r.direction.unit()) > 0.0, "{:?}", h.n);
+        for _ in 0..100 {
+            let out = quad.reflect(&h);
+            assert!(
+                out.direction.dot(h.ng) > 0.0,
+                "light leak {:?}",
+                out.direction
+            );
+        }
+    }
+
+    #[test]
+    fn specular_rays_stay_on_their_side() {
+        let tilted = Arc::new(ConstColorTexture::new(Vec3::new(1.0, 0.5, 0.5), Vec3::ZERO));
+        let quad = |mat: Arc<dyn Material + Send + Sync>| {
+            Detailed::new(
+                Arc::new(Quad::new(
+                    Vec3::new(-1.0, -1.0, 0.0),
+                    Vec3::new(2.0, 0.0, 0.0),
+                    Vec3::new(0.0, 2.0, 0.0),
+                    mat,
+                    Vec3::ZERO,
+                    tilted.clone(),
+                )),
+                Arc::new(NormalMap::new(tilted.clone(), 1.0)),
+            )
+        };
+        let r = Ray::new(Vec3::new(-5.0, 0.0, 1.0), Vec3::new(5.0, 0.0, -0.99));
+        let mirror = quad(MIRROR.clone());
+        let h = mirror.get_hit(r, 0.001, 100.0).unwrap();
+        let out = mirror.reflect(&h);
+        assert!(
+            out.direction.dot(h.ng) > 0.0,
+            "light leak {:?}",
+            out.direction
+        );
+        assert_eq!(mirror.generator_pdf(&h, &out), 1.0);
+
+        let glass = quad(Arc::new(MirrorGlass { ir: 1.5 }));
+        let h = glass.get_hit(r, 0.001, 100.0).unwrap();
+        // reflections stay above the surface and refractions below it, each with its own pdf
+        let (mut above, mut below) = (None, None);
+        for _ in 0..200 {
+            let out = glass.reflect(&h);
+            let pdf = glass.generator_pdf(&h, &out);
+            assert!(pdf > 0.0);
+            let side = if out.direction.dot(h.ng) > 0.0 {
+                &mut above
+            } else {
+                &mut below
+            };
+            assert_eq!(*side.get_or_insert(pdf), pdf);
+        }
+        if let (Some(a), Some(b)) = (above, below) {
+            assert!((a + b - 1.0).abs() < 1e-5, "{a} + {b}");
+        }
+    }
+
+    #[test]
+    fn detail_map_test() -> ImageResult<()> {
+        const WIDTH: usize = 400;
+        const HEIGHT: usize = 200;
+        let white = Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.8, Vec3::ZERO));
+        let bumpy = Detailed::new(
+            Arc::new(Sphere {
+                origin: Vec3::new(0.8, 0.0, 3.0),
+                radius: 0.7,
+                mat: LAMBERTIAN.clone(),
+                texture: white.clone(),
+            }),
+            Arc::new(BumpMap::new(
+                Arc::new(WorleyTexture::new(
+                    6.0,
+                    false,
+                    ColorRamp::two_color(Vec3::ZERO, Vec3::WHITE),
+                )),
+                0.3,
+            )),
+        );
+        let normal_mapped = Detailed::new(
+            Arc::new(Sphere {
+                origin: Vec3::new(-0.8, 0.0, 3.0),
+                radius: 0.7,
+                mat: Arc::new(MixedMaterial::new(20.0)),
+                texture: white.clone(),
+            }),
+            Arc::new(NormalMap::new(
+                Arc::new(NoiseTexture::new(
+                    8.0,
+                    1,
+                    ColorRamp::two_color(Vec3::new(0.3, 0.3, 1.0), Vec3::new(0.7, 0.7, 1.0)),
+                )),
+                1.0,
+            )),
+        );
+        let floor = Detailed::new(
+            Arc::new(Quad::new(
+                Vec3::new(-5.0, -0.7, 0.0),
+                Vec3::new(10.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 10.0),
+                LAMBERTIAN.clone(),
+                Vec3::ZERO,
+                white,
+            )),
+            Arc::new(BumpMap::new(
+                Arc::new(NoiseTexture::new(
+                    3.0,
+                    5,
+                    ColorRamp::two_color(Vec3::ZERO, Vec3::WHITE),
+                )),
+                0.1,
+            )),
+        );
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::ZERO,
+            Vec3::UP,
+            Vec3::FORWARD,
+            50.0,
+            0.0,
+        );
+        let vp = Viewport::new(
+            cam,
+            Scene::new(
+                vec![
+                    Instance::new(Arc::new([Arc::new(bumpy)])),
+                    Instance::new(Arc::new([Arc::new(normal_mapped)])),
+                    Instance::new(Arc::new([Arc::new(floor)])),
+                ],
+                0.001,
+                1000.0,
+            ),
+            Arc::new(ray_color),
+            WIDTH,
+            HEIGHT,
+            25,
+            5,
+            Vec3::WHITE * 0.8,
+            2.0,
+        );
+        vp.render_rows_async().save("test_out/detail_map_test.png")
+    }
+}
@@ -1,14 +1,95 @@
+use std::sync::Arc;
+
 use crate::vec3::{ray::Ray, vec3::Vec3};
 
+use super::{material::Material, texture::ColorResult, Object};
+
 #[derive(PartialEq, Clone, Debug)]
 pub struct Hit {
     pub r: Ray,
     pub p: Vec3,
+    // shading normal, may be perturbed by detail maps
     pub n: Vec3,
+    // geometric normal of the surface
+    pub ng: Vec3,
     pub t: f32,
+    // surface parameterization of the hit point, both in [0, 1]
+    pub u: f32,
+    pub v: f32,
+    // partial derivatives of the surface point with respect to u and v
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
 }
 impl PartialOrd for Hit {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         self.t.partial_cmp(&other.t)
     }
 }
+impl Hit {
+    /// Shading and geometric normals flipped to the side the ray came from
+    pub fn facing_normals(&self) -> (Vec3, Vec3) {
+        if self.r.direction.dot(self.ng) > 0.0 {
+            (-self.n, -self.ng)
+        } else {
+            (self.n, self.ng)
+        }
+    }
+}
+
+/// Object a ray hit, shaded by the material of the instance it was hit through when that
+/// instance replaces the materials of its prototype. The material is borrowed from the instance
+#[derive(Clone)]
+pub struct HitObject<'a> {
+    pub(crate) object: Arc<dyn Object + Send + Sync>,
+    pub(crate) material: Option<&'a Arc<dyn Material + Send + Sync>>,
+}
+
+impl<'a> HitObject<'a> {
+    pub(crate) fn new(object: Arc<dyn Object + Send + Sync>) -> Self {
+        Self {
+            object,
+            material: None,
+        }
+    }
+
+    pub fn reflect(&self, h: &Hit) -> Ray {
+        match self.material {
+            Some(m) => m.on_hit(h),
+            None => self.object.reflect(h),
+        }
+    }
+
+    pub fn generator_pdf(&self, h: &Hit, r: &Ray) -> f32 {
+        match self.material {
+            Some(m) => m.generator_pdf(h, r),
+            None => self.object.generator_pdf(h, r),
+        }
+    }
+
+    pub fn material_pdf(&self, h: &Hit, r: &Ray) -> f32 {
+        match self.material {
+            Some(m) => m.material_pdf(h, r),
+            None => self.object.material_pdf(h, r),
+        }
+    }
+
+    /// The texture stays the one of the object
+    pub fn color(&self, h: &Hit) -> ColorResult {
+        self.object.color(h)
+    }
+
+    pub fn is_specular(&self, h: &Hit) -> bool {
+        match self.material {
+            Some(m) => m.is_specular(),
+            None => self.object.is_specular(h),
+        }
+    }
+
+    pub fn material(&self) -> Option<&Arc<dyn Material + Send + Sync>> {
+        self.material.or_else(|| self.object.material())
+    }
+
+    pub fn identity(&self) -> *const () {
+        self.object.identity()
+    }
+}
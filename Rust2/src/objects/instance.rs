@@ -1,17 +1,18 @@
-use std::{sync::Arc, vec};
+use std::{collections::HashSet, sync::Arc, vec};
 
 use crate::{
+    matrix::Matrix4,
     // onb::ONB,
     quaternions::{Quaternion, ZERO_ROTATION},
     rotation::Rotation,
     vec3::{ray::Ray, vec3::Vec3},
-    viewport::scene::Scene,
 };
 
 use super::{
-    aabb::{maxf, minf, Interval, AABB},
-    hit::Hit,
+    aabb::{maxf, minf, Bounded, Interval, AABB, TIME_STEPS},
+    hit::{Hit, HitObject},
     material::Material,
+    prototype::{Numbered, Prototype},
     quad::Quad,
     texture::Texture,
     Object,
@@ -21,43 +22,126 @@ use super::{
 pub struct Instance {
     position: Vec3,
     rotation: Quaternion,
+    // scale along the axes of the objects, applied before the rotation
+    scale: Vec3,
+    // applied to the objects before the scale, rotation and position
+    matrix: Matrix4,
+    // position and rotation at time 1, the instance moves there from its transform at time 0
+    motion: Option<(Vec3, Quaternion)>,
+    // objects to world at time 0 and back, `None` when the transform flattens the objects
+    to_world: Matrix4,
+    to_object: Option<Matrix4>,
     // onb: ONB,
-    objects: Arc<[Arc<dyn Object + Send + Sync>]>,
-    x: Interval,
-    y: Interval,
-    z: Interval,
+    prototype: Arc<Prototype>,
+    // replaces the materials of everything in the prototype, including nested instances
+    material: Option<Arc<dyn Material + Send + Sync>>,
 }
 
 impl Instance {
     pub fn rotate(&mut self, rot: impl Rotation) {
         self.rotation *= rot.into();
+        self.update();
     }
     pub fn reset_rotation(&mut self) {
         self.rotation = ZERO_ROTATION;
+        self.update();
     }
     pub fn getr(&self) -> Quaternion {
         return self.rotation.to_owned();
     }
 
+    pub fn set_rotation(&mut self, rot: impl Rotation) {
+        self.rotation = rot.into();
+        self.update();
+    }
+
     pub fn translate(&mut self, vec: Vec3) {
         self.position += vec;
+        self.update();
+    }
+    pub fn set_position(&mut self, position: Vec3) {
+        self.position = position;
+        self.update();
     }
     pub fn gett(&self) -> Vec3 {
         return self.position.to_owned();
     }
 
-    pub fn new(objects: Arc<[Arc<dyn Object + Send + Sync>]>) -> Self {
-        if objects.len() == 0 {
-            return Self::empty();
+    /// Same scale along every axis
+    pub fn set_scale(&mut self, scale: f32) {
+        self.set_scale_axes(Vec3::new(scale, scale, scale));
+    }
+    /// Scale along the x, y and z axes of the objects
+    pub fn set_scale_axes(&mut self, scale: Vec3) {
+        self.scale = scale;
+        self.update();
+    }
+    pub fn gets(&self) -> Vec3 {
+        self.scale
+    }
+
+    /// Affine transform of the objects applied before the scale, rotation and position,
+    /// e.g. to shear them or to bring an imported model into the scene's axes
+    pub fn set_matrix(&mut self, matrix: Matrix4) {
+        self.matrix = matrix;
+        self.update();
+    }
+    pub fn getm(&self) -> Matrix4 {
+        self.matrix
+    }
+
+    fn update(&mut self) {
+        self.to_world = self.object_to_world(self.position, &self.rotation);
+        self.to_object = self.to_world.inverse();
+    }
+
+    fn object_to_world(&self, position: Vec3, rotation: &Quaternion) -> Matrix4 {
+        Matrix4::from_trs(position, rotation, self.scale) * self.matrix
+    }
+
+    /// Transform from the space of the objects to the world at `time`
+    pub fn matrix_at(&self, time: f32) -> Matrix4 {
+        if self.motion.is_none() {
+            return self.to_world;
         }
+        let (position, rotation) = self.transform_at(time);
+        self.object_to_world(position, &rotation)
+    }
+
+    /// Moves the instance from its current transform at time 0 to `position` and `rotation`
+    /// at time 1, rays see it where it is at their time
+    pub fn set_motion(&mut self, position: Vec3, rotation: impl Rotation) {
+        self.motion = Some((position, rotation.into()));
+    }
+    pub fn clear_motion(&mut self) {
+        self.motion = None;
+    }
 
-        let mut intervals = objects[0].get_aabb();
-        for o in &objects[1..] {
-            let ni = o.get_aabb();
-            intervals.0 += ni.0;
-            intervals.1 += ni.1;
-            intervals.2 += ni.2;
+    /// Position and rotation at `time`, times outside of [0, 1] hold the ends of the motion
+    pub fn transform_at(&self, time: f32) -> (Vec3, Quaternion) {
+        match &self.motion {
+            Some((position, rotation)) => {
+                let t = time.clamp(0.0, 1.0);
+                (
+                    self.position + (*position - self.position) * t,
+                    self.rotation.slerp(rotation, t),
+                )
+            }
+            None => (self.position, self.rotation.to_owned()),
         }
+    }
+
+    pub fn new(objects: Arc<[Arc<dyn Object + Send + Sync>]>) -> Self {
+        Self::from_prototype(Arc::new(Prototype::new(objects.to_vec(), vec![])))
+    }
+
+    /// Instance holding other instances, transforming it moves all of them
+    pub fn group(instances: Vec<Instance>) -> Self {
+        Self::from_prototype(Arc::new(Prototype::new(vec![], instances)))
+    }
+
+    /// Instance of geometry shared with other instances
+    pub fn from_prototype(prototype: Arc<Prototype>) -> Self {
         Self {
             position: Vec3 {
                 x: 0.0,
@@ -70,13 +154,42 @@ impl Instance {
                 y: 0.0,
                 z: 0.0,
             },
+            scale: Vec3::new(1.0, 1.0, 1.0),
+            matrix: Matrix4::IDENTITY,
+            motion: None,
+            to_world: Matrix4::IDENTITY,
+            to_object: Some(Matrix4::IDENTITY),
             // onb: ONB::new(),
-            objects,
-            x: intervals.0,
-            y: intervals.1,
-            z: intervals.2,
+            prototype,
+            material: None,
+        }
+    }
+
+    pub fn prototype(&self) -> &Arc<Prototype> {
+        &self.prototype
+    }
+
+    pub fn set_material(&mut self, mat: Arc<dyn Material + Send + Sync>) {
+        self.material = Some(mat);
+    }
+
+    pub fn clear_material(&mut self) {
+        self.material = None;
+    }
+
+    pub(crate) fn material(&self) -> Option<&Arc<dyn Material + Send + Sync>> {
+        self.material.as_ref()
+    }
+
+    /// Calls `f` with the material and contents of the instance in the order they were given,
+    /// for the id AOVs. Prototypes in `seen` were numbered before and are skipped
+    pub(crate) fn number(&self, seen: &mut HashSet<*const Prototype>, f: &mut dyn FnMut(Numbered)) {
+        if let Some(m) = &self.material {
+            f(Numbered::material(m));
         }
+        self.prototype.number(seen, f);
     }
+
     pub fn new_box(
         a: Vec3,
         b: Vec3,
@@ -165,13 +278,14 @@ impl Instance {
             )),
         ]))
     }
-    pub fn get_aabb(&self) -> AABB {
-        let vecs = Interval::intervals_to_bounding_vecs(self.x, self.y, self.z);
-        let mut minv = vecs.1;
-        let mut maxv = vecs.0;
+    // bounds of the corners of the `local` bounds transformed by `matrix`
+    fn bounds(local: (Interval, Interval, Interval), matrix: &Matrix4) -> (Vec3, Vec3) {
+        let vecs = Interval::intervals_to_bounding_vecs(local.0, local.1, local.2);
+        let mut minv = Vec3::WHITE * f32::INFINITY;
+        let mut maxv = Vec3::WHITE * f32::NEG_INFINITY;
 
         for i in 0..8 {
-            let v = self.rotation.rotate(&Vec3 {
+            let v = matrix.transform_point(Vec3 {
                 x: if i & 0b1 == 0 { vecs.0.x } else { vecs.1.x },
                 y: if i & 0b10 == 0 { vecs.0.y } else { vecs.1.y },
                 z: if i & 0b100 == 0 { vecs.0.z } else { vecs.1.z },
@@ -184,73 +298,610 @@ impl Instance {
             maxv.z = maxf(v.z, maxv.z);
         }
 
-        minv += self.gett();
-        maxv += self.gett();
-        let (x, y, z) = Interval::from_vecs(minv, maxv);
+        (minv, maxv)
+    }
+
+    // world bounds of the `local` bounds while the instance moves from time `t0` to `t1`
+    fn swept_bounds(
+        &self,
+        local: (Interval, Interval, Interval),
+        t0: f32,
+        t1: f32,
+    ) -> (Vec3, Vec3) {
+        let (mut minv, mut maxv) = Self::bounds(local, &self.matrix_at(t0));
+        if self.motion.is_none() {
+            return (minv, maxv);
+        }
+        // corners swing on arcs between the samples
+        const SAMPLES: usize = 4;
+        let (_, start) = self.transform_at(t0);
+        let (_, end) = self.transform_at(t1);
+        let cos = start.normalized().dot(&end.normalized()).abs();
+        let angle = 2.0 * cos.min(1.0).acos();
+        // distance of the farthest corner from the point the instance turns around
+        let (lo, hi) = Self::bounds(local, &(Matrix4::scaling(self.scale) * self.matrix));
+        let far = Vec3::new(
+            maxf(lo.x.abs(), hi.x.abs()),
+            maxf(lo.y.abs(), hi.y.abs()),
+            maxf(lo.z.abs(), hi.z.abs()),
+        );
+        let radius = far.length();
+        let sagitta = radius * (1.0 - (angle / SAMPLES as f32 * 0.5).cos());
+        for i in 1..=SAMPLES {
+            let matrix = self.matrix_at(t0 + (t1 - t0) * i as f32 / SAMPLES as f32);
+            let (lo, hi) = Self::bounds(local, &matrix);
+            minv = Vec3::new(minf(lo.x, minv.x), minf(lo.y, minv.y), minf(lo.z, minv.z));
+            maxv = Vec3::new(maxf(hi.x, maxv.x), maxf(hi.y, maxv.y), maxf(hi.z, maxv.z));
+        }
+        (minv - Vec3::WHITE * sagitta, maxv + Vec3::WHITE * sagitta)
+    }
+
+    pub fn get_aabb(&self) -> AABB {
+        let local = self.prototype.bounds();
+        let deforms = self.prototype.deforms();
+        if self.motion.is_none() && !deforms {
+            let (lo, hi) = Self::bounds(local, &self.to_world);
+            let (x, y, z) = Interval::from_vecs(lo, hi);
+            return AABB {
+                x,
+                y,
+                z,
+                steps: vec![],
+                instances: vec![self.to_owned()],
+                aabbs: vec![],
+            };
+        }
+        // separate bounds for every time step keep fast motion from covering the whole path
+        let step = 1.0 / TIME_STEPS as f32;
+        let steps: Vec<_> = (0..TIME_STEPS)
+            .map(|i| {
+                let local = if deforms {
+                    self.prototype.bounds_during(i)
+                } else {
+                    local
+                };
+                let (lo, hi) = self.swept_bounds(local, i as f32 * step, (i + 1) as f32 * step);
+                Interval::from_vecs(lo, hi)
+            })
+            .collect();
+        let (mut x, mut y, mut z) = steps[0];
+        for b in &steps[1..] {
+            x += b.0;
+            y += b.1;
+            z += b.2;
+        }
         return AABB {
             x,
             y,
             z,
+            steps,
             instances: vec![self.to_owned()],
             aabbs: vec![],
         };
     }
 
-    fn empty() -> Instance {
-        Instance {
-            position: Vec3::new(0.0, 0.0, 0.0),
-            // onb: ONB::new(),
-            rotation: Quaternion {
-                w: 1.0,
-                x: 0.0,
-                y: 0.0,
-                z: 0.0,
-            },
-            objects: Arc::new([]),
-            x: Interval::new(0.0, 0.0),
-            y: Interval::new(0.0, 0.0),
-            z: Interval::new(0.0, 0.0),
-        }
-    }
-    pub fn get_hit(&self, mut r: Ray, s: &Scene) -> Option<(Hit, Arc<dyn Object + Send + Sync>)> {
+    pub fn get_hit(&self, mut r: Ray, mint: f32, maxt: f32) -> Option<(Hit, HitObject<'_>)> {
         // eprintln!("instance_hit");
         // debug_assert!(r.direction.is_normal(), "dir is nan");
-        let mut min_h = None;
-        r.origin -= self.position;
-        r = r.rotated(self.getr());
-        // debug_assert!(r.direction.is_normal(), "dir2 is nan");
-        for (i, h) in self
-            .objects
-            .iter()
-            .map(|i| (i, i.get_hit(r, s.mint, s.maxt)))
-        {
-            if h.is_none() {
-                continue;
-            }
-            if min_h.is_none() {
-                min_h = Some((h.unwrap(), i.to_owned()));
-                continue;
+        let world = r;
+        let (to_world, to_object) = match self.motion {
+            None => (self.to_world, self.to_object?),
+            Some(_) => {
+                let m = self.matrix_at(r.time);
+                (m, m.inverse()?)
             }
-            let hit = h.unwrap();
-            if hit < min_h.clone().unwrap().0 {
-                min_h = Some((hit, i.to_owned()));
-            }
-        }
+        };
+        // into the space of the objects, the direction is transformed with the ray
+        // so the distances along it stay the same
+        r.origin = to_object.transform_point(r.origin);
+        r.direction = to_object.transform_vector(r.direction);
+        // debug_assert!(r.direction.is_normal(), "dir2 is nan");
+        let min_h = self.prototype.get_hit(r, mint, maxt);
         // if min_h.is_some() {
         //     let h = min_h.to_owned().unwrap().0;
         //     eprintln!("We Hit: {:?}\n dist to sphere_origin: {}", h, h.p.length())
         // }
         if let Some(mut hit) = min_h {
-            hit.0.p = self.rotation.rotate(&hit.0.p);
-            hit.0.p += self.position;
+            hit.0.r = world;
+            hit.0.p = to_world.transform_point(hit.0.p);
 
             // debug_assert!(hit.0.n.length2() > 1e-8);
             // let sn = hit.0.n;
-            hit.0.n = self.rotation.rotate(&hit.0.n);
+            // normals go with the inverse transpose to stay perpendicular to the surface
+            let normals = to_object.transpose();
+            hit.0.n = normals.transform_vector(hit.0.n).unit();
+            hit.0.ng = normals.transform_vector(hit.0.ng).unit();
+            hit.0.dpdu = to_world.transform_vector(hit.0.dpdu);
+            hit.0.dpdv = to_world.transform_vector(hit.0.dpdv);
             // debug_assert!(hit.0.n.length2() > 1e-8, "{:?}, {:?}", self.rotation, sn);
+            // the outermost instance with a material wins
+            if let Some(mat) = &self.material {
+                hit.1.material = Some(mat);
+            }
             return Some(hit);
         }
 
         return None;
     }
 }
+
+impl Bounded for Instance {
+    fn leaf(&self) -> AABB {
+        self.get_aabb()
+    }
+
+    fn closest_hit(&self, r: Ray, mint: f32, maxt: f32) -> Option<(Hit, HitObject<'_>)> {
+        self.get_hit(r, mint, maxt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{f32::consts::PI, sync::Arc};
+
+    use crate::{
+        matrix::Matrix4,
+        objects::{
+            aabb::{AABB, TIME_STEPS},
+            hit::HitObject,
+            light::{Light, PointLight},
+            material::{LAMBERTIAN, MIRROR},
+            prototype::Prototype,
+            quad::Quad,
+            sphere::Sphere,
+            texture::ConstColorTexture,
+        },
+        quaternions::{Quaternion, ZERO_ROTATION},
+        rotation::Rotation,
+        vec3::{ray::Ray, vec3::Vec3},
+        viewport::{camera::Camera, ray_color::next_event_ray_color, scene::Scene, Viewport},
+    };
+
+    use super::Instance;
+
+    fn unit_box(emission: Vec3) -> Instance {
+        Instance::new_box(
+            Vec3::new(-0.5, -0.5, -0.5),
+            Vec3::new(0.5, 0.5, 0.5),
+            Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.5, emission)),
+            LAMBERTIAN.clone(),
+        )
+    }
+
+    fn unit_sphere(color: Vec3) -> Instance {
+        Instance::new(Arc::new([Arc::new(Sphere {
+            origin: Vec3::ZERO,
+            radius: 1.0,
+            mat: LAMBERTIAN.clone(),
+            texture: Arc::new(ConstColorTexture::new(color, Vec3::ZERO)),
+        })]))
+    }
+
+    // rays go into the instance with the inverse rotation, so the geometry turns the same way
+    // as the rotation turns points. Rotating rays forwards instead would mirror the rotation
+    #[test]
+    fn rotation_turns_geometry_forwards() {
+        let mut instance = Instance::new(Arc::new([Arc::new(Sphere {
+            origin: Vec3::new(2.0, 0.0, 0.0),
+            radius: 0.5,
+            mat: LAMBERTIAN.clone(),
+            texture: Arc::new(ConstColorTexture::new(Vec3::WHITE, Vec3::ZERO)),
+        })]));
+        instance.rotate(Quaternion::new_from_axis(
+            std::f32::consts::FRAC_PI_2,
+            Vec3::UP,
+        ));
+        let center = instance.getr().rotate(&Vec3::new(2.0, 0.0, 0.0));
+        let mirrored = instance
+            .getr()
+            .conjugate()
+            .rotate(&Vec3::new(2.0, 0.0, 0.0));
+        assert!((center - mirrored).length() > 3.0);
+
+        let scene = Scene::new(vec![instance], 0.001, 100.0);
+        let (hit, _) = scene.get_hit(Ray::new(Vec3::ZERO, center)).unwrap();
+        assert!((hit.t * center.length() - 1.5).abs() < 1e-4, "{}", hit.t);
+        assert!((hit.p - center * 0.75).length() < 1e-4, "{:?}", hit.p);
+        assert!(hit.n.dot(-center.unit()) > 0.9999);
+        assert!(scene.get_hit(Ray::new(Vec3::ZERO, mirrored)).is_none());
+    }
+
+    #[test]
+    fn non_uniform_scale() {
+        let mut ellipsoid = unit_sphere(Vec3::WHITE);
+        ellipsoid.set_scale_axes(Vec3::new(2.0, 0.5, 1.0));
+        ellipsoid.set_position(Vec3::FORWARD * 5.0);
+        let aabb = ellipsoid.get_aabb();
+        assert!((aabb.x.min + 2.0).abs() < 1e-5 && (aabb.x.max - 2.0).abs() < 1e-5);
+        assert!((aabb.y.min + 0.5).abs() < 1e-5 && (aabb.y.max - 0.5).abs() < 1e-5);
+
+        let scene = Scene::new(vec![ellipsoid], 0.001, 100.0);
+        let origin = Vec3::new(1.0, 0.2, 0.0);
+        let (hit, _) = scene.get_hit(Ray::new(origin, Vec3::FORWARD)).unwrap();
+        // on the surface x²/4 + y²/0.25 + (z - 5)² = 1
+        let z = 5.0 - (1.0f32 - 0.25 - 0.16).sqrt();
+        assert!(
+            (hit.p - Vec3::new(1.0, 0.2, z)).length() < 1e-4,
+            "{:?}",
+            hit.p
+        );
+        assert!((hit.t - z).abs() < 1e-4);
+        // the normal is the gradient of the surface, not the scaled sphere normal
+        let gradient = Vec3::new(1.0 / 4.0, 0.2 / 0.25, z - 5.0).unit();
+        assert!(hit.n.dot(gradient) > 0.9999, "{:?} {:?}", hit.n, gradient);
+        assert!(hit.n.dot(hit.dpdu).abs() < 1e-4 && hit.n.dot(hit.dpdv).abs() < 1e-4);
+        // tiny instances don't count as flattened
+        let mut speck = unit_sphere(Vec3::WHITE);
+        speck.set_scale(1e-4);
+        speck.set_position(Vec3::FORWARD * 0.01);
+        let scene = Scene::new(vec![speck], 0.001, 100.0);
+        let (hit, _) = scene.get_hit(Ray::new(Vec3::ZERO, Vec3::FORWARD)).unwrap();
+        assert!((hit.t - 0.0099).abs() < 1e-6, "{}", hit.t);
+    }
+
+    #[test]
+    fn affine_matrix() {
+        // leans the box over along x, it stays as high as it was
+        let shear = Matrix4::new(
+            [[1.0, 1.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            Vec3::ZERO,
+        );
+        let mut leaning = unit_box(Vec3::ZERO);
+        leaning.set_matrix(shear);
+        leaning.set_position(Vec3::FORWARD * 3.0);
+        let aabb = leaning.get_aabb();
+        assert!((aabb.x.min + 1.0).abs() < 0.01 && (aabb.x.max - 1.0).abs() < 0.01);
+        assert!((aabb.y.min + 0.5).abs() < 0.01 && (aabb.y.max - 0.5).abs() < 0.01);
+
+        let scene = Scene::new(vec![leaning.clone()], 0.001, 100.0);
+        let side = |origin: Vec3| {
+            let r = Ray::new(origin + Vec3::new(-3.0, 0.0, 3.0), Vec3::new(1.0, 0.0, 0.0));
+            scene.get_hit(r).map(|(h, _)| h)
+        };
+        // the side at x = -0.5 moved with the height
+        let low = side(Vec3::new(0.0, -0.4, 0.0)).unwrap();
+        let high = side(Vec3::new(0.0, 0.4, 0.0)).unwrap();
+        assert!((low.p.x + 0.9).abs() < 1e-4 && (high.p.x + 0.1).abs() < 1e-4);
+        let normal = Vec3::new(-1.0, 1.0, 0.0).unit();
+        assert!(low.n.dot(normal) > 0.9999, "{:?}", low.n);
+
+        // the matrix comes before the scale, rotation and position
+        leaning.set_scale(2.0);
+        let m = leaning.matrix_at(0.0);
+        let p = m.transform_point(Vec3::new(0.5, 0.5, 0.0));
+        assert!((p - Vec3::new(2.0, 1.0, 3.0)).length() < 1e-5);
+        // flattened instances can't be hit
+        leaning.set_scale_axes(Vec3::new(1.0, 0.0, 1.0));
+        let scene = Scene::new(vec![leaning], 0.001, 100.0);
+        assert!(scene.get_hit(Ray::new(Vec3::ZERO, Vec3::FORWARD)).is_none());
+    }
+
+    #[test]
+    fn affine_test() {
+        const WIDTH: usize = 90;
+        const HEIGHT: usize = 60;
+        // one sphere reused at different sizes and proportions
+        let sizes = [
+            Vec3::new(0.5, 0.5, 0.5),
+            Vec3::new(1.0, 0.3, 0.6),
+            Vec3::new(0.3, 1.0, 0.3),
+        ];
+        let mut objects: Vec<_> = sizes
+            .into_iter()
+            .enumerate()
+            .map(|(i, size)| {
+                let mut sphere = unit_sphere(Vec3::new(0.8, 0.3 + 0.25 * i as f32, 0.2));
+                sphere.set_scale_axes(size);
+                sphere.rotate(Quaternion::new_from_axis(0.4, Vec3::FORWARD));
+                sphere.set_position(Vec3::new(1.8 - 1.8 * i as f32, 0.0, 5.0));
+                sphere
+            })
+            .collect();
+        let mut leaning = unit_box(Vec3::ZERO);
+        leaning.set_matrix(Matrix4::new(
+            [[1.0, 0.6, 0.0], [0.0, 1.0, 0.0], [0.0, 0.3, 1.0]],
+            Vec3::ZERO,
+        ));
+        leaning.set_position(Vec3::new(0.0, -1.6, 5.0));
+        objects.push(leaning);
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![Arc::new(PointLight::new(
+            Vec3::new(0.0, 3.0, 1.0),
+            Vec3::WHITE * 30.0,
+        ))];
+        let scene = Scene::new(objects, 0.001, 100.0).with_lights(lights);
+        // the middle sphere is 0.6 deep, its rotation around the view axis keeps it so
+        let (hit, _) = scene.get_hit(Ray::new(Vec3::ZERO, Vec3::FORWARD)).unwrap();
+        assert!((hit.p.z - 4.4).abs() < 1e-4, "{:?}", hit.p);
+        assert!(hit.n.dot(-Vec3::FORWARD) > 0.9999);
+        // the front of the box leans back with its height, its normal stays perpendicular
+        let (hit, _) = scene
+            .get_hit(Ray::new(Vec3::new(0.0, -1.3, 0.0), Vec3::FORWARD))
+            .unwrap();
+        assert!((hit.p.z - 4.59).abs() < 1e-4, "{:?}", hit.p);
+        let up_the_face = Vec3::new(0.6, 1.0, 0.3);
+        assert!(hit.n.dot(up_the_face).abs() < 1e-4, "{:?}", hit.n);
+        assert!(hit.n.x.abs() < 1e-4, "{:?}", hit.n);
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::ZERO,
+            Vec3::UP,
+            Vec3::FORWARD,
+            50.0,
+            0.0,
+        );
+        Viewport::new(
+            cam,
+            scene,
+            Arc::new(next_event_ray_color),
+            WIDTH,
+            HEIGHT,
+            4,
+            2,
+            Vec3::new(0.4, 0.5, 0.7),
+            2.2,
+        )
+        .render()
+        .save("test_out/instance_affine_test.png")
+        .unwrap();
+    }
+
+    // trunk and crown standing on the origin, built from nested instances
+    fn tree() -> Arc<Prototype> {
+        let mut trunk = unit_box(Vec3::ZERO);
+        trunk.set_scale_axes(Vec3::new(0.2, 1.0, 0.2));
+        trunk.set_position(Vec3::UP * 0.5);
+        let mut crown = unit_sphere(Vec3::new(0.2, 0.6, 0.2));
+        crown.set_scale(0.6);
+        crown.set_position(Vec3::UP * 1.4);
+        Arc::new(Prototype::new(vec![], vec![trunk, crown]))
+    }
+
+    fn forest(tree: &Arc<Prototype>, size: usize) -> Vec<Instance> {
+        (0..size * size)
+            .map(|i| {
+                let mut t = Instance::from_prototype(tree.clone());
+                t.set_position(Vec3::new(
+                    3.0 * (i % size) as f32,
+                    0.0,
+                    10.0 + 3.0 * (i / size) as f32,
+                ));
+                t
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nested_instances() {
+        let tree = tree();
+        let mut trees = forest(&tree, 10);
+        trees[0].set_material(MIRROR.clone());
+        let near = Instance::group(trees);
+        let mut far = near.clone();
+        far.set_position(Vec3::UP * 10.0);
+        far.set_scale(2.0);
+        far.set_material(LAMBERTIAN.clone());
+        // every tree and both forests share the same geometry
+        assert_eq!(Arc::strong_count(&tree), 101);
+        assert!(Arc::ptr_eq(near.prototype(), far.prototype()));
+
+        let scene = Scene::new(vec![near, far], 0.001, 1000.0);
+        let (hit, o) = scene
+            .get_hit(Ray::new(Vec3::UP * 1.4, Vec3::FORWARD))
+            .unwrap();
+        assert!(
+            (hit.p - Vec3::new(0.0, 1.4, 9.4)).length() < 1e-4,
+            "{:?}",
+            hit.p
+        );
+        assert!(hit.n.dot(-Vec3::FORWARD) > 0.9999);
+        let is =
+            |o: &HitObject, m: *const ()| std::ptr::addr_eq(Arc::as_ptr(o.material().unwrap()), m);
+        assert!(is(&o, Arc::as_ptr(&MIRROR) as *const ()));
+        // the outer instance scales everything in it, and its material wins
+        let (hit, o) = scene
+            .get_hit(Ray::new(Vec3::UP * 12.8, Vec3::FORWARD))
+            .unwrap();
+        assert!(
+            (hit.p - Vec3::new(0.0, 12.8, 18.8)).length() < 1e-4,
+            "{:?}",
+            hit.p
+        );
+        assert!(is(&o, Arc::as_ptr(&LAMBERTIAN) as *const ()));
+        // trees further along are hit through both hierarchies
+        let (hit, _) = scene
+            .get_hit(Ray::new(Vec3::new(6.0, 0.5, 0.0), Vec3::FORWARD))
+            .unwrap();
+        assert!((hit.p.z - 9.9).abs() < 1e-4, "{:?}", hit.p);
+    }
+
+    #[test]
+    fn forest_test() {
+        const WIDTH: usize = 90;
+        const HEIGHT: usize = 60;
+        let tree = tree();
+        let grove = Arc::new(Prototype::new(vec![], forest(&tree, 4)));
+        // the same grove repeated, one of them with mirror trees
+        let objects: Vec<_> = (0..4)
+            .map(|i| {
+                let mut g = Instance::from_prototype(grove.clone());
+                g.set_position(Vec3::new(
+                    12.0 * (i % 2) as f32 - 10.0,
+                    0.0,
+                    12.0 * (i / 2) as f32,
+                ));
+                g.rotate(Quaternion::new_from_axis(0.3 * i as f32, Vec3::UP));
+                if i == 3 {
+                    g.set_material(MIRROR.clone());
+                }
+                g
+            })
+            .collect();
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![Arc::new(PointLight::new(
+            Vec3::new(0.0, 20.0, 10.0),
+            Vec3::WHITE * 600.0,
+        ))];
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::new(0.0, 8.0, -2.0),
+            Vec3::UP,
+            Vec3::new(0.0, -0.4, 1.0),
+            60.0,
+            0.0,
+        );
+        Viewport::new(
+            cam,
+            Scene::new(objects, 0.001, 100.0).with_lights(lights),
+            Arc::new(next_event_ray_color),
+            WIDTH,
+            HEIGHT,
+            4,
+            2,
+            Vec3::new(0.4, 0.5, 0.7),
+            2.2,
+        )
+        .render()
+        .save("test_out/instance_forest_test.png")
+        .unwrap();
+    }
+
+    #[test]
+    fn motion_follows_ray_time() {
+        let mut moving = unit_box(Vec3::ZERO);
+        moving.translate(Vec3::FORWARD * 3.0);
+        moving.set_motion(
+            Vec3::FORWARD * 3.0 + Vec3::RIGHT * 2.0,
+            Quaternion::new_from_axis(PI / 2.0, Vec3::UP),
+        );
+        let (position, rotation) = moving.transform_at(0.5);
+        assert_eq!(position, Vec3::FORWARD * 3.0 + Vec3::RIGHT);
+        let eighth = Quaternion::new_from_axis(PI / 4.0, Vec3::UP);
+        assert!((rotation.dot(&eighth).abs() - 1.0).abs() < 1e-5);
+
+        let scene = Scene::new(vec![moving], 0.001, 100.0);
+        let at = |origin: Vec3, time: f32| {
+            scene.get_hit(Ray::new_with_time(origin, Vec3::FORWARD, time))
+        };
+        assert!(at(Vec3::ZERO, 0.0).is_some());
+        assert!(at(Vec3::ZERO, 1.0).is_none());
+        assert!(at(Vec3::RIGHT * 2.0, 0.0).is_none());
+        let (hit, _) = at(Vec3::RIGHT * 2.0, 1.0).unwrap();
+        assert!((hit.p.z - 2.5).abs() < 1e-4, "{:?}", hit.p);
+        // half way the box is turned by 45 degrees, its edge points at the camera
+        let (hit, _) = at(Vec3::RIGHT * 1.1, 0.5).unwrap();
+        let z = 3.0 - 0.5 * 2f32.sqrt() + 0.1;
+        assert!((hit.p.z - z).abs() < 1e-3, "{:?}", hit.p);
+    }
+
+    #[test]
+    fn bounds_cover_motion() {
+        let mut moving = unit_box(Vec3::ZERO);
+        moving.set_scale(2.0);
+        moving.rotate(Quaternion::new_from_axis(0.3, Vec3::RIGHT));
+        moving.set_motion(Vec3::UP, Quaternion::new_from_axis(PI * 0.9, Vec3::UP));
+        let aabb = moving.get_aabb();
+        for i in 0..=100 {
+            let (position, rotation) = moving.transform_at(i as f32 / 100.0);
+            for c in 0..8 {
+                let corner = Vec3::new(
+                    if c & 1 == 0 { -0.5 } else { 0.5 },
+                    if c & 2 == 0 { -0.5 } else { 0.5 },
+                    if c & 4 == 0 { -0.5 } else { 0.5 },
+                );
+                let p = rotation.rotate(&corner) * 2.0 + position;
+                assert!(aabb.x.min <= p.x && p.x <= aabb.x.max, "{:?}", p);
+                assert!(aabb.y.min <= p.y && p.y <= aabb.y.max, "{:?}", p);
+                assert!(aabb.z.min <= p.z && p.z <= aabb.z.max, "{:?}", p);
+            }
+        }
+        // without motion the bounds stay tight
+        moving.clear_motion();
+        moving.set_rotation(ZERO_ROTATION);
+        let aabb = moving.get_aabb();
+        // quads are padded to a minimal thickness
+        assert!((aabb.x.min + 1.0).abs() < 0.01 && (aabb.x.max - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn time_step_bounds() {
+        let mut moving = unit_box(Vec3::ZERO);
+        moving.set_motion(Vec3::RIGHT * 8.0, ZERO_ROTATION);
+        let aabb = moving.get_aabb();
+        assert_eq!(aabb.steps.len(), TIME_STEPS);
+        // the whole path is covered, every step only by a part of it
+        assert!(aabb.x.min < -8.4 && aabb.x.max > 0.4);
+        for (i, (x, _, _)) in aabb.steps.iter().enumerate() {
+            assert!(x.max - x.min < 2.1, "{} {:?}", i, x);
+            let center = -8.0 * (i as f32 + 0.5) / TIME_STEPS as f32;
+            assert!(
+                x.min < center - 0.5 && center + 0.5 < x.max,
+                "{} {:?}",
+                i,
+                x
+            );
+        }
+        // nodes above it keep them
+        let scene = AABB::new(vec![moving, unit_box(Vec3::ZERO)]);
+        assert_eq!(scene.steps.len(), TIME_STEPS);
+        let (x, _, _) = scene.bounds_during(TIME_STEPS - 1);
+        assert!(x.max - x.min < 10.0 && x.min < -7.9);
+        // static instances don't need them
+        assert!(unit_box(Vec3::ZERO).get_aabb().steps.is_empty());
+    }
+
+    #[test]
+    fn quad_velocity() {
+        let quad = Instance::new(Arc::new([Arc::new(Quad::new(
+            Vec3::new(0.5, -0.5, 2.0),
+            Vec3::RIGHT,
+            Vec3::UP,
+            LAMBERTIAN.clone(),
+            Vec3::RIGHT * 3.0,
+            Arc::new(ConstColorTexture::new(Vec3::WHITE, Vec3::ZERO)),
+        ))]));
+        let scene = Scene::new(vec![quad], 0.001, 100.0);
+        let hits = |time: f32| {
+            scene
+                .get_hit(Ray::new_with_time(Vec3::RIGHT * 3.0, Vec3::FORWARD, time))
+                .is_some()
+        };
+        assert!(!hits(0.0));
+        assert!(hits(1.0));
+    }
+
+    #[test]
+    fn motion_blur_test() {
+        const WIDTH: usize = 60;
+        const HEIGHT: usize = 20;
+        // a glowing box crossing the middle of the image while the shutter is open
+        let mut moving = unit_box(Vec3::WHITE);
+        moving.translate(Vec3::new(-1.5, 0.0, 4.0));
+        moving.set_motion(Vec3::new(1.5, 0.0, 4.0), ZERO_ROTATION);
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::ZERO,
+            Vec3::UP,
+            Vec3::FORWARD,
+            30.0,
+            0.0,
+        );
+        let vp = Viewport::new(
+            cam,
+            Scene::new(vec![moving], 0.001, 100.0),
+            Arc::new(next_event_ray_color),
+            WIDTH,
+            HEIGHT,
+            64,
+            2,
+            Vec3::ZERO,
+            1.0,
+        )
+        .with_shutter(0.0, 1.0);
+        let img = vp.render_linear();
+        let middle = &img[HEIGHT / 2];
+        // the box covers every pixel on its way for a part of the time
+        let center = middle[WIDTH / 2].x;
+        assert!(0.15 < center && center < 0.6, "{}", center);
+        assert!(middle[WIDTH / 2 - 8].x > 0.1 && middle[WIDTH / 2 + 8].x > 0.1);
+        assert!(middle[0].x < 1e-3 && middle[WIDTH - 1].x < 1e-3);
+    }
+}
@@ -0,0 +1,997 @@
+use std::{f32::consts::PI, sync::Arc};
+
+use crate::{
+    matrix::Matrix4,
+    onb::ONB,
+    sampler::random_f32,
+    vec3::{ray::Ray, vec3::Vec3},
+};
+
+use super::{hit::Hit, instance::Instance, shapes::Surface, Object};
+
+/// Light arriving at a point from a sampled position on a light
+#[derive(Debug, Clone, Copy)]
+pub struct LightSample {
+    // unit direction towards the light
+    pub dir: Vec3,
+    // distance to the sampled point, infinite for directional lights
+    pub dist: f32,
+    pub radiance: Vec3,
+    // solid angle density, 1 for delta lights
+    pub pdf: f32,
+}
+
+/// Ray leaving a light, the first segment of a light path
+#[derive(Debug, Clone, Copy)]
+pub struct EmissionSample {
+    // unit direction
+    pub ray: Ray,
+    // normal of the light surface at the origin, zero for lights without a surface
+    pub normal: Vec3,
+    // radiance for area lights, intensity for point lights and irradiance for directional lights
+    pub radiance: Vec3,
+    // area density of the origin and solid angle density of the direction, 1 for delta distributions
+    pub pdf_pos: f32,
+    pub pdf_dir: f32,
+}
+
+/// Light sources that are not part of the scene geometry, sampled directly by the integrators
+pub trait Light {
+    fn sample(&self, p: Vec3) -> Option<LightSample>;
+    /// Distance along `r` and radiance if the ray hits the light, always `None` for delta lights
+    fn intersect(&self, r: &Ray) -> Option<(f32, Vec3)>;
+    /// Solid angle density of `sample` choosing `dir` from `p`, 0 for delta lights
+    fn pdf(&self, p: Vec3, dir: Vec3) -> f32;
+    /// Ray leaving the light. `bounds` is a sphere around the scene, lights infinitely far away start on a disk covering it
+    fn sample_emission(&self, bounds: (Vec3, f32)) -> Option<EmissionSample>;
+    /// Densities of `sample_emission` starting at `p` and leaving along the unit direction `dir`
+    fn emission_pdf(&self, p: Vec3, dir: Vec3, bounds: (Vec3, f32)) -> (f32, f32);
+    /// Normal of the emitting surface at `p`, zero for lights without a surface
+    fn normal(&self, _p: Vec3) -> Vec3 {
+        Vec3::ZERO
+    }
+    /// Emits from a single point or in a single direction, so rays can't hit it
+    fn is_delta(&self) -> bool {
+        false
+    }
+    /// Infinitely far away, emission densities are over the disk around the scene
+    fn is_infinite(&self) -> bool {
+        false
+    }
+    fn visible_to_camera(&self) -> bool {
+        false
+    }
+}
+
+// Cosine weighted direction around `z`, its density is `z / PI`
+fn random_cosine_direction() -> Vec3 {
+    let r = random_f32();
+    let phi = random_f32() * 2.0 * PI;
+    Vec3::new(phi.cos() * r.sqrt(), phi.sin() * r.sqrt(), (1.0 - r).sqrt())
+}
+
+// Uniform direction in the cone around `z` with 1 - cos of the half angle `one_minus_cos_max`
+fn random_cone_direction(one_minus_cos_max: f32) -> Vec3 {
+    let cos = 1.0 - random_f32() * one_minus_cos_max;
+    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+    let phi = random_f32() * 2.0 * PI;
+    Vec3::new(sin * phi.cos(), sin * phi.sin(), cos)
+}
+
+/// Intensity multiplier tabulated over the angle from an axis, like a simplified IES file
+#[derive(Debug, Clone)]
+pub struct AngularProfile {
+    // equally spaced samples from 0 to `max_angle`
+    values: Vec<f32>,
+    max_angle: f32,
+}
+
+impl AngularProfile {
+    pub fn new(values: Vec<f32>, max_angle: f32) -> Self {
+        assert!(!values.is_empty(), "profile needs at least one value");
+        Self { values, max_angle }
+    }
+
+    pub fn at(&self, angle: f32) -> f32 {
+        if angle >= self.max_angle {
+            return 0.0;
+        }
+        if self.values.len() == 1 {
+            return self.values[0];
+        }
+        let x = angle.max(0.0) / self.max_angle * (self.values.len() - 1) as f32;
+        let i = (x.floor() as usize).min(self.values.len() - 2);
+        let f = x - i as f32;
+        self.values[i] * (1.0 - f) + self.values[i + 1] * f
+    }
+
+    fn eval(profile: &Option<AngularProfile>, axis: Vec3, dir: Vec3) -> f32 {
+        match profile {
+            Some(p) => p.at(axis.dot(dir).clamp(-1.0, 1.0).acos()),
+            None => 1.0,
+        }
+    }
+}
+
+pub struct PointLight {
+    pub position: Vec3,
+    // radiant intensity
+    pub intensity: Vec3,
+    pub axis: Vec3,
+    pub profile: Option<AngularProfile>,
+}
+
+impl PointLight {
+    pub fn new(position: Vec3, intensity: Vec3) -> Self {
+        Self {
+            position,
+            intensity,
+            axis: Vec3::DOWN,
+            profile: None,
+        }
+    }
+    pub fn with_profile(mut self, axis: Vec3, profile: AngularProfile) -> Self {
+        self.axis = axis.unit();
+        self.profile = Some(profile);
+        self
+    }
+}
+
+impl Light for PointLight {
+    fn sample(&self, p: Vec3) -> Option<LightSample> {
+        let to_light = self.position - p;
+        let dist2 = to_light.length2();
+        let dist = dist2.sqrt();
+        let dir = to_light / dist;
+        let emitted = self.intensity * AngularProfile::eval(&self.profile, self.axis, -dir);
+        Some(LightSample {
+            dir,
+            dist,
+            radiance: emitted / dist2,
+            pdf: 1.0,
+        })
+    }
+    fn intersect(&self, _: &Ray) -> Option<(f32, Vec3)> {
+        None
+    }
+    fn pdf(&self, _: Vec3, _: Vec3) -> f32 {
+        0.0
+    }
+    fn sample_emission(&self, _: (Vec3, f32)) -> Option<EmissionSample> {
+        let dir = Vec3::random_unit_vec();
+        Some(EmissionSample {
+            ray: Ray::new(self.position, dir),
+            normal: Vec3::ZERO,
+            radiance: self.intensity * AngularProfile::eval(&self.profile, self.axis, dir),
+            pdf_pos: 1.0,
+            pdf_dir: 0.25 / PI,
+        })
+    }
+    fn emission_pdf(&self, _: Vec3, _: Vec3, _: (Vec3, f32)) -> (f32, f32) {
+        (1.0, 0.25 / PI)
+    }
+    fn is_delta(&self) -> bool {
+        true
+    }
+}
+
+/// Point light limited to a cone, full intensity inside `inner` and a smooth falloff until `outer`
+pub struct SpotLight {
+    pub position: Vec3,
+    pub direction: Vec3,
+    pub intensity: Vec3,
+    cos_inner: f32,
+    cos_outer: f32,
+    pub profile: Option<AngularProfile>,
+}
+
+impl SpotLight {
+    /// angles in radians, measured from `direction`
+    pub fn new(position: Vec3, direction: Vec3, intensity: Vec3, inner: f32, outer: f32) -> Self {
+        Self {
+            position,
+            direction: direction.unit(),
+            intensity,
+            cos_inner: inner.min(outer).cos(),
+            cos_outer: outer.cos(),
+            profile: None,
+        }
+    }
+    pub fn with_profile(mut self, profile: AngularProfile) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    fn falloff(&self, cos: f32) -> f32 {
+        if cos >= self.cos_inner {
+            return 1.0;
+        }
+        if cos <= self.cos_outer {
+            return 0.0;
+        }
+        let t = (cos - self.cos_outer) / (self.cos_inner - self.cos_outer);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl Light for SpotLight {
+    fn sample(&self, p: Vec3) -> Option<LightSample> {
+        let to_light = self.position - p;
+        let dist2 = to_light.length2();
+        let dist = dist2.sqrt();
+        let dir = to_light / dist;
+        let falloff = self.falloff(self.direction.dot(-dir))
+            * AngularProfile::eval(&self.profile, self.direction, -dir);
+        if falloff <= 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            dir,
+            dist,
+            radiance: self.intensity * (falloff / dist2),
+            pdf: 1.0,
+        })
+    }
+    fn intersect(&self, _: &Ray) -> Option<(f32, Vec3)> {
+        None
+    }
+    fn pdf(&self, _: Vec3, _: Vec3) -> f32 {
+        0.0
+    }
+    fn sample_emission(&self, _: (Vec3, f32)) -> Option<EmissionSample> {
+        let one_minus_cos_max = 1.0 - self.cos_outer;
+        let dir =
+            ONB::new_from_w(self.direction).from_local(random_cone_direction(one_minus_cos_max));
+        let falloff = self.falloff(self.direction.dot(dir))
+            * AngularProfile::eval(&self.profile, self.direction, dir);
+        Some(EmissionSample {
+            ray: Ray::new(self.position, dir),
+            normal: Vec3::ZERO,
+            radiance: self.intensity * falloff,
+            pdf_pos: 1.0,
+            pdf_dir: 1.0 / (2.0 * PI * one_minus_cos_max),
+        })
+    }
+    fn emission_pdf(&self, _: Vec3, dir: Vec3, _: (Vec3, f32)) -> (f32, f32) {
+        if self.direction.dot(dir) < self.cos_outer {
+            return (1.0, 0.0);
+        }
+        (1.0, 1.0 / (2.0 * PI * (1.0 - self.cos_outer)))
+    }
+    fn is_delta(&self) -> bool {
+        true
+    }
+}
+
+/// Parallel light, `direction` is where the light travels
+pub struct DirectionalLight {
+    pub direction: Vec3,
+    pub irradiance: Vec3,
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Vec3, irradiance: Vec3) -> Self {
+        Self {
+            direction: direction.unit(),
+            irradiance,
+        }
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample(&self, _: Vec3) -> Option<LightSample> {
+        Some(LightSample {
+            dir: -self.direction,
+            dist: f32::INFINITY,
+            radiance: self.irradiance,
+            pdf: 1.0,
+        })
+    }
+    fn intersect(&self, _: &Ray) -> Option<(f32, Vec3)> {
+        None
+    }
+    fn pdf(&self, _: Vec3, _: Vec3) -> f32 {
+        0.0
+    }
+    fn sample_emission(&self, bounds: (Vec3, f32)) -> Option<EmissionSample> {
+        let (center, radius) = bounds;
+        let offset =
+            ONB::new_from_w(self.direction).from_local(Vec3::random_in_unit_disk() * radius);
+        Some(EmissionSample {
+            ray: Ray::new(center - self.direction * radius + offset, self.direction),
+            normal: Vec3::ZERO,
+            radiance: self.irradiance,
+            pdf_pos: 1.0 / (PI * radius * radius),
+            pdf_dir: 1.0,
+        })
+    }
+    fn emission_pdf(&self, _: Vec3, _: Vec3, bounds: (Vec3, f32)) -> (f32, f32) {
+        (1.0 / (PI * bounds.1 * bounds.1), 1.0)
+    }
+    fn is_delta(&self) -> bool {
+        true
+    }
+    fn is_infinite(&self) -> bool {
+        true
+    }
+}
+
+/// Sphere emitting `radiance` from its whole surface
+pub struct SphereLight {
+    pub center: Vec3,
+    pub radius: f32,
+    pub radiance: Vec3,
+    pub visible: bool,
+}
+
+impl SphereLight {
+    pub fn new(center: Vec3, radius: f32, radiance: Vec3) -> Self {
+        Self {
+            center,
+            radius,
+            radiance,
+            visible: true,
+        }
+    }
+    pub fn with_visibility(mut self, visible: bool) -> Self {
+        self.visible = visible;
+        self
+    }
+
+    // 1 - cos of the half angle of the cone the sphere subtends from `p`
+    fn cone(&self, p: Vec3) -> Option<f32> {
+        let dist2 = (self.center - p).length2();
+        let r2 = self.radius * self.radius;
+        if dist2 <= r2 {
+            return None;
+        }
+        let sin2 = r2 / dist2;
+        Some(sin2 / (1.0 + (1.0 - sin2).sqrt()))
+    }
+}
+
+impl Light for SphereLight {
+    fn sample(&self, p: Vec3) -> Option<LightSample> {
+        let one_minus_cos_max = self.cone(p)?;
+        let one_minus_cos = random_f32() * one_minus_cos_max;
+        let cos = 1.0 - one_minus_cos;
+        let sin = (one_minus_cos * (2.0 - one_minus_cos)).max(0.0).sqrt();
+        let phi = random_f32() * 2.0 * PI;
+        let dir = ONB::new_from_w(self.center - p).from_local(Vec3::new(
+            sin * phi.cos(),
+            sin * phi.sin(),
+            cos,
+        ));
+        let (dist, _) = self.intersect(&Ray::new(p, dir))?;
+        Some(LightSample {
+            dir,
+            dist,
+            radiance: self.radiance,
+            pdf: 1.0 / (2.0 * PI * one_minus_cos_max),
+        })
+    }
+
+    fn intersect(&self, r: &Ray) -> Option<(f32, Vec3)> {
+        let dir = r.direction.unit();
+        let oc = r.origin - self.center;
+        let b = oc.dot(dir);
+        let c = oc.length2() - self.radius * self.radius;
+        let d = b * b - c;
+        if d < 0.0 {
+            return None;
+        }
+        let sq = d.sqrt();
+        let t = if -b - sq > 1e-4 { -b - sq } else { -b + sq };
+        if t <= 1e-4 {
+            return None;
+        }
+        Some((t, self.radiance))
+    }
+
+    fn pdf(&self, p: Vec3, dir: Vec3) -> f32 {
+        match self.cone(p) {
+            Some(one_minus_cos_max) => {
+                let cos = dir.unit().dot((self.center - p).unit());
+                if 1.0 - cos <= one_minus_cos_max {
+                    1.0 / (2.0 * PI * one_minus_cos_max)
+                } else {
+                    0.0
+                }
+            }
+            None => 0.0,
+        }
+    }
+
+    fn sample_emission(&self, _: (Vec3, f32)) -> Option<EmissionSample> {
+        let normal = Vec3::random_unit_vec();
+        let local = random_cosine_direction();
+        Some(EmissionSample {
+            ray: Ray::new(
+                self.center + normal * self.radius,
+                ONB::new_from_w(normal).from_local(local),
+            ),
+            normal,
+            radiance: self.radiance,
+            pdf_pos: 1.0 / (4.0 * PI * self.radius * self.radius),
+            pdf_dir: local.z / PI,
+        })
+    }
+
+    fn emission_pdf(&self, p: Vec3, dir: Vec3, _: (Vec3, f32)) -> (f32, f32) {
+        (
+            1.0 / (4.0 * PI * self.radius * self.radius),
+            self.normal(p).dot(dir).max(0.0) / PI,
+        )
+    }
+
+    fn normal(&self, p: Vec3) -> Vec3 {
+        (p - self.center).unit()
+    }
+
+    fn visible_to_camera(&self) -> bool {
+        self.visible
+    }
+}
+
+/// Parallelogram light spanned by `u` and `v`, one sided lights emit along `u x v`
+pub struct RectLight {
+    pub origin: Vec3,
+    pub u: Vec3,
+    pub v: Vec3,
+    pub radiance: Vec3,
+    pub two_sided: bool,
+    pub visible: bool,
+    pub profile: Option<AngularProfile>,
+
+    normal: Vec3,
+    area: f32,
+}
+
+impl RectLight {
+    pub fn new(origin: Vec3, u: Vec3, v: Vec3, radiance: Vec3) -> Self {
+        let n = u.cross(v);
+        Self {
+            origin,
+            u,
+            v,
+            radiance,
+            two_sided: false,
+            visible: true,
+            profile: None,
+            normal: n.unit(),
+            area: n.length(),
+        }
+    }
+    pub fn with_two_sided(mut self, two_sided: bool) -> Self {
+        self.two_sided = two_sided;
+        self
+    }
+    pub fn with_visibility(mut self, visible: bool) -> Self {
+        self.visible = visible;
+        self
+    }
+    /// Profile angles are measured from the normal
+    pub fn with_profile(mut self, profile: AngularProfile) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    // radiance leaving the light towards `-dir`
+    fn emitted(&self, dir: Vec3) -> Vec3 {
+        let cos = -dir.dot(self.normal);
+        if cos <= 0.0 && !self.two_sided {
+            return Vec3::ZERO;
+        }
+        let axis = if cos >= 0.0 {
+            self.normal
+        } else {
+            -self.normal
+        };
+        self.radiance * AngularProfile::eval(&self.profile, axis, -dir)
+    }
+}
+
+impl Light for RectLight {
+    fn sample(&self, p: Vec3) -> Option<LightSample> {
+        let point = self.origin + self.u * random_f32() + self.v * random_f32();
+        let to_light = point - p;
+        let dist2 = to_light.length2();
+        let dist = dist2.sqrt();
+        let dir = to_light / dist;
+        let cos = dir.dot(self.normal).abs();
+        if cos <= 1e-6 {
+            return None;
+        }
+        let radiance = self.emitted(dir);
+        if radiance.close_to_zero() {
+            return None;
+        }
+        Some(LightSample {
+            dir,
+            dist,
+            radiance,
+            pdf: dist2 / (cos * self.area),
+        })
+    }
+
+    fn intersect(&self, r: &Ray) -> Option<(f32, Vec3)> {
+        let dir = r.direction.unit();
+        let denominator = self.normal.dot(dir);
+        if denominator.abs() <= 1e-8 {
+            return None;
+        }
+        let t = (self.normal.dot(self.origin) - self.normal.dot(r.origin)) / denominator;
+        if t <= 1e-4 {
+            return None;
+        }
+        let planar = r.origin + dir * t - self.origin;
+        let w = self.u.cross(self.v) / (self.area * self.area);
+        let alfa = w.dot(planar.cross(self.v));
+        let beta = w.dot(self.u.cross(planar));
+        if !(0.0..=1.0).contains(&alfa) || !(0.0..=1.0).contains(&beta) {
+            return None;
+        }
+        Some((t, self.emitted(dir)))
+    }
+
+    fn pdf(&self, p: Vec3, dir: Vec3) -> f32 {
+        let dir = dir.unit();
+        match self.intersect(&Ray::new(p, dir)) {
+            Some((t, _)) => {
+                let cos = dir.dot(self.normal).abs();
+                if cos <= 1e-6 {
+                    0.0
+                } else {
+                    t * t / (cos * self.area)
+                }
+            }
+            None => 0.0,
+        }
+    }
+
+    fn sample_emission(&self, _: (Vec3, f32)) -> Option<EmissionSample> {
+        let point = self.origin + self.u * random_f32() + self.v * random_f32();
+        let normal = if self.two_sided && random_f32() < 0.5 {
+            -self.normal
+        } else {
+            self.normal
+        };
+        let local = random_cosine_direction();
+        let dir = ONB::new_from_w(normal).from_local(local);
+        let side = if self.two_sided { 0.5 } else { 1.0 };
+        Some(EmissionSample {
+            ray: Ray::new(point, dir),
+            normal,
+            radiance: self.emitted(-dir),
+            pdf_pos: 1.0 / self.area,
+            pdf_dir: local.z / PI * side,
+        })
+    }
+
+    fn emission_pdf(&self, _: Vec3, dir: Vec3, _: (Vec3, f32)) -> (f32, f32) {
+        let cos = dir.dot(self.normal);
+        let pdf_dir = if self.two_sided {
+            cos.abs() * 0.5
+        } else {
+            cos.max(0.0)
+        } / PI;
+        (1.0 / self.area, pdf_dir)
+    }
+
+    fn normal(&self, _: Vec3) -> Vec3 {
+        self.normal
+    }
+
+    fn visible_to_camera(&self) -> bool {
+        self.visible
+    }
+}
+
+/// Light leaving the outer side of a shape, sampled uniformly over its area
+pub struct ShapeLight {
+    pub shape: Arc<dyn Surface + Send + Sync>,
+    pub radiance: Vec3,
+    pub visible: bool,
+}
+
+impl ShapeLight {
+    pub fn new(shape: Arc<dyn Surface + Send + Sync>, radiance: Vec3) -> Self {
+        Self {
+            shape,
+            radiance,
+            visible: true,
+        }
+    }
+    pub fn with_visibility(mut self, visible: bool) -> Self {
+        self.visible = visible;
+        self
+    }
+
+    // distance and cosine of the first hit towards `dir`, `None` if it's on the inner side.
+    // `sample` and `pdf` both go through here, so they trace the very same ray
+    fn facing_hit(&self, p: Vec3, dir: Vec3) -> Option<(f32, f32)> {
+        let dir = dir.unit();
+        let h = self.shape.get_hit(Ray::new(p, dir), 1e-4, f32::INFINITY)?;
+        let cos = -dir.dot(h.ng);
+        (cos > 1e-6).then_some((h.t, cos))
+    }
+}
+
+impl Light for ShapeLight {
+    fn sample(&self, p: Vec3) -> Option<LightSample> {
+        let (point, _) = self.shape.sample_point();
+        let to_light = point - p;
+        let dist = to_light.length();
+        let dir = to_light / dist;
+        let (t, cos) = self.facing_hit(p, dir)?;
+        // the shape isn't part of the scene, so points it hides from `p` itself
+        // (the far side of a torus, behind its own rim) are rejected here
+        if (t - dist).abs() > 1e-3 * dist.max(1.0) {
+            return None;
+        }
+        Some(LightSample {
+            dir,
+            dist: t,
+            radiance: self.radiance,
+            pdf: t * t / (cos * self.shape.area()),
+        })
+    }
+
+    fn intersect(&self, r: &Ray) -> Option<(f32, Vec3)> {
+        let dir = r.direction.unit();
+        let h = self
+            .shape
+            .get_hit(Ray::new(r.origin, dir), 1e-4, f32::INFINITY)?;
+        let radiance = if dir.dot(h.ng) < 0.0 {
+            self.radiance
+        } else {
+            Vec3::ZERO
+        };
+        Some((h.t, radiance))
+    }
+
+    fn pdf(&self, p: Vec3, dir: Vec3) -> f32 {
+        match self.facing_hit(p, dir) {
+            Some((t, cos)) => t * t / (cos * self.shape.area()),
+            None => 0.0,
+        }
+    }
+
+    fn sample_emission(&self, _: (Vec3, f32)) -> Option<EmissionSample> {
+        let (point, normal) = self.shape.sample_point();
+        let local = random_cosine_direction();
+        let dir = ONB::new_from_w(normal).from_local(local);
+        Some(EmissionSample {
+            ray: Ray::new(point, dir),
+            normal,
+            radiance: self.radiance,
+            pdf_pos: 1.0 / self.shape.area(),
+            pdf_dir: local.z / PI,
+        })
+    }
+
+    fn emission_pdf(&self, p: Vec3, dir: Vec3, _: (Vec3, f32)) -> (f32, f32) {
+        (
+            1.0 / self.shape.area(),
+            self.normal(p).dot(dir).max(0.0) / PI,
+        )
+    }
+
+    fn normal(&self, p: Vec3) -> Vec3 {
+        self.shape.normal_at(p)
+    }
+
+    fn visible_to_camera(&self) -> bool {
+        self.visible
+    }
+}
+
+/// Object of the scene with a glowing texture, lit on both sides. Unlike the other lights it's part of
+/// the scene geometry, integrators that find emission by hitting objects only start paths on it
+pub struct EmitterLight {
+    surface: Arc<dyn Surface + Send + Sync>,
+    // the surface alone, where the scene has it
+    instance: Instance,
+    to_world: Matrix4,
+    to_object: Matrix4,
+    normals: Matrix4,
+}
+
+impl EmitterLight {
+    /// `None` when `to_world` flattens the surface
+    pub fn new(surface: Arc<dyn Surface + Send + Sync>, to_world: Matrix4) -> Option<Self> {
+        let to_object = to_world.inverse()?;
+        let normals = to_world.normal_matrix()?;
+        let object: Arc<dyn Object + Send + Sync> = surface.clone();
+        let mut instance = Instance::new(Arc::new([object]));
+        instance.set_matrix(to_world);
+        Some(Self {
+            surface,
+            instance,
+            to_world,
+            to_object,
+            normals,
+        })
+    }
+
+    // area density of the point on the surface with the normal `n` in the space of the surface
+    fn area_pdf(&self, n: Vec3) -> f32 {
+        // the transform stretches areas by its determinant over the stretch of the normal
+        let stretch = self.to_world.determinant().abs() * self.normals.transform_vector(n).length();
+        1.0 / (self.surface.area() * stretch)
+    }
+
+    // first hit towards `dir` with the radiance leaving it and the solid angle density of sampling it.
+    // `sample` and `pdf` both go through here, so they trace the very same ray
+    fn first_hit(&self, p: Vec3, dir: Vec3) -> Option<(Hit, Vec3, f32)> {
+        let dir = dir.unit();
+        let (h, o) = self
+            .instance
+            .get_hit(Ray::new(p, dir), 1e-4, f32::INFINITY)?;
+        let cos = dir.dot(h.ng).abs();
+        if cos <= 1e-6 {
+            return None;
+        }
+        let n = self.surface.normal_at(self.to_object.transform_point(h.p));
+        let pdf = h.t * h.t / cos * self.area_pdf(n);
+        let radiance = o.color(&h).emmited;
+        Some((h, radiance, pdf))
+    }
+}
+
+impl Light for EmitterLight {
+    fn sample(&self, p: Vec3) -> Option<LightSample> {
+        let (point, _) = self.surface.sample_point();
+        let to_light = self.to_world.transform_point(point) - p;
+        let dist = to_light.length();
+        let dir = to_light / dist;
+        let (h, radiance, pdf) = self.first_hit(p, dir)?;
+        // points the surface hides itself, like the far side of a sphere
+        if (h.t - dist).abs() > 1e-3 * dist.max(1.0) || radiance.close_to_zero() {
+            return None;
+        }
+        Some(LightSample {
+            dir,
+            dist: h.t,
+            radiance,
+            pdf,
+        })
+    }
+
+    fn intersect(&self, r: &Ray) -> Option<(f32, Vec3)> {
+        let (h, o) = self.instance.get_hit(
+            Ray::new_with_time(r.origin, r.direction.unit(), r.time),
+            1e-4,
+            f32::INFINITY,
+        )?;
+        Some((h.t, o.color(&h).emmited))
+    }
+
+    fn pdf(&self, p: Vec3, dir: Vec3) -> f32 {
+        self.first_hit(p, dir).map_or(0.0, |(_, _, pdf)| pdf)
+    }
+
+    fn sample_emission(&self, _: (Vec3, f32)) -> Option<EmissionSample> {
+        let (point, n) = self.surface.sample_point();
+        let point = self.to_world.transform_point(point);
+        let normal = self.normals.transform_vector(n).unit();
+        let normal = if random_f32() < 0.5 { -normal } else { normal };
+        // the texture at the sampled point, seen from the side the light leaves
+        let (_, radiance, _) = self.first_hit(point + normal * 1e-3, -normal)?;
+        let local = random_cosine_direction();
+        let dir = ONB::new_from_w(normal).from_local(local);
+        Some(EmissionSample {
+            ray: Ray::new(point, dir),
+            normal,
+            radiance,
+            pdf_pos: self.area_pdf(n),
+            pdf_dir: local.z / PI * 0.5,
+        })
+    }
+
+    fn emission_pdf(&self, p: Vec3, dir: Vec3, _: (Vec3, f32)) -> (f32, f32) {
+        let n = self.surface.normal_at(self.to_object.transform_point(p));
+        (self.area_pdf(n), self.normal(p).dot(dir).abs() * 0.5 / PI)
+    }
+
+    fn normal(&self, p: Vec3) -> Vec3 {
+        let n = self.surface.normal_at(self.to_object.transform_point(p));
+        self.normals.transform_vector(n).unit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{f32::consts::PI, sync::Arc};
+
+    use image::ImageResult;
+
+    use crate::{
+        objects::{
+            instance::Instance, material::LAMBERTIAN, quad::Quad, sphere::Sphere,
+            texture::ConstColorTexture,
+        },
+        vec3::vec3::Vec3,
+        viewport::{camera::Camera, ray_color::next_event_ray_color, scene::Scene, Viewport},
+    };
+
+    use super::*;
+
+    #[test]
+    fn area_light_samples_match_pdf() {
+        let p = Vec3::new(0.3, -1.0, 0.2);
+        let rect = RectLight::new(
+            Vec3::new(-0.5, 1.0, -0.5),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::WHITE,
+        )
+        .with_two_sided(true);
+        let sphere = SphereLight::new(Vec3::new(0.0, 2.0, 0.0), 0.5, Vec3::WHITE);
+        for light in [&rect as &dyn Light, &sphere] {
+            for _ in 0..100 {
+                let s = light.sample(p).unwrap();
+                let pdf = light.pdf(p, s.dir);
+                assert!((s.pdf - pdf).abs() <= 1e-2 * pdf, "{} != {}", s.pdf, pdf);
+                let (t, _) = light.intersect(&Ray::new(p, s.dir)).unwrap();
+                assert!((t - s.dist).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn emitters_match_pdf() {
+        let p = Vec3::new(0.3, -1.0, 0.2);
+        let glow = Arc::new(ConstColorTexture::new(Vec3::ZERO, Vec3::WHITE));
+        let quad = Quad::new(
+            Vec3::new(-0.5, 1.0, -0.5),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            LAMBERTIAN.clone(),
+            Vec3::ZERO,
+            glow.clone(),
+        );
+        let sphere = Sphere {
+            origin: Vec3::ZERO,
+            radius: 0.5,
+            mat: LAMBERTIAN.clone(),
+            texture: glow,
+        };
+        // stretched, the quad covers 2 x 3 and the sphere isn't round anymore
+        let mut quad = Instance::new(Arc::new([Arc::new(quad)]));
+        quad.set_scale_axes(Vec3::new(2.0, 1.0, 3.0));
+        let mut sphere = Instance::new(Arc::new([Arc::new(sphere)]));
+        sphere.set_scale_axes(Vec3::new(1.0, 2.0, 1.0));
+        sphere.set_position(Vec3::new(0.0, 2.0, 0.0));
+        let scene = Scene::new(vec![quad, sphere], 0.001, 1000.0);
+        assert_eq!(scene.emitters.len(), 2);
+
+        let quad = &scene.emitters[0];
+        let (pdf_pos, _) = quad.emission_pdf(Vec3::UP, Vec3::DOWN, (Vec3::ZERO, 1.0));
+        assert!((pdf_pos - 1.0 / 6.0).abs() < 1e-5, "{}", pdf_pos);
+        // the inverse area densities add up to the area of the spheroid
+        let spheroid = &scene.emitters[1];
+        let n = 4000;
+        let area = (0..n)
+            .map(|_| 1.0 / spheroid.sample_emission((Vec3::ZERO, 1.0)).unwrap().pdf_pos)
+            .sum::<f32>()
+            / n as f32;
+        assert!((area - 5.369).abs() < 0.05 * 5.369, "{}", area);
+        for light in scene.emitters.iter() {
+            for _ in 0..100 {
+                // the far side of the sphere is hidden behind its near side
+                let Some(s) = light.sample(p) else {
+                    continue;
+                };
+                let pdf = light.pdf(p, s.dir);
+                assert!((s.pdf - pdf).abs() <= 1e-2 * pdf, "{} != {}", s.pdf, pdf);
+                let (t, radiance) = light.intersect(&Ray::new(p, s.dir)).unwrap();
+                assert!((t - s.dist).abs() < 1e-3);
+                assert_eq!(radiance, Vec3::WHITE);
+            }
+        }
+    }
+
+    #[test]
+    fn one_sided_rect_light() {
+        let rect = RectLight::new(
+            Vec3::new(-0.5, 1.0, -0.5),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::WHITE,
+        );
+        // normal points down, so only points below are lit
+        assert!(rect.sample(Vec3::ZERO).is_some());
+        assert!(rect.sample(Vec3::UP * 2.0).is_none());
+    }
+
+    #[test]
+    fn spot_and_profile() {
+        let spot = SpotLight::new(Vec3::UP, Vec3::DOWN, Vec3::WHITE, 0.2, 0.4);
+        assert!(spot.sample(Vec3::ZERO).is_some());
+        assert!(spot.sample(Vec3::new(5.0, 0.0, 0.0)).is_none());
+        let profile = AngularProfile::new(vec![1.0, 0.5, 0.0], PI * 0.5);
+        assert!((profile.at(PI * 0.25) - 0.5).abs() < 1e-6);
+        assert_eq!(profile.at(PI), 0.0);
+    }
+
+    #[test]
+    fn lights_test() -> ImageResult<()> {
+        const WIDTH: usize = 300;
+        const HEIGHT: usize = 200;
+        let grey = Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.7, Vec3::ZERO));
+        let objects = vec![
+            Instance::new(Arc::new([Arc::new(Sphere {
+                origin: Vec3::new(0.6, 0.0, 3.0),
+                radius: 0.5,
+                mat: LAMBERTIAN.clone(),
+                texture: grey.clone(),
+            })])),
+            Instance::new(Arc::new([Arc::new(Sphere {
+                origin: Vec3::new(-0.6, 0.0, 3.0),
+                radius: 0.5,
+                mat: LAMBERTIAN.clone(),
+                texture: grey.clone(),
+            })])),
+            Instance::new(Arc::new([Arc::new(Quad::new(
+                Vec3::new(-5.0, -0.5, -2.0),
+                Vec3::new(10.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 10.0),
+                LAMBERTIAN.clone(),
+                Vec3::ZERO,
+                grey,
+            ))])),
+        ];
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![
+            Arc::new(PointLight::new(
+                Vec3::new(1.5, 1.5, 2.0),
+                Vec3::new(1.0, 0.6, 0.3) * 2.0,
+            )),
+            Arc::new(SpotLight::new(
+                Vec3::new(-0.6, 2.0, 3.0),
+                Vec3::DOWN,
+                Vec3::new(0.3, 0.5, 1.0) * 15.0,
+                0.2,
+                0.35,
+            )),
+            Arc::new(DirectionalLight::new(
+                Vec3::new(0.0, -1.0, 1.0),
+                Vec3::WHITE * 0.1,
+            )),
+            Arc::new(
+                RectLight::new(
+                    Vec3::new(-1.0, 1.2, 4.0),
+                    Vec3::new(2.0, 0.0, 0.0),
+                    Vec3::new(0.0, 0.0, 0.5),
+                    Vec3::WHITE * 2.0,
+                )
+                .with_visibility(false),
+            ),
+            Arc::new(SphereLight::new(
+                Vec3::new(0.0, -0.3, 2.5),
+                0.1,
+                Vec3::new(4.0, 1.0, 1.0),
+            )),
+        ];
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::ZERO,
+            Vec3::UP,
+            Vec3::FORWARD,
+            60.0,
+            0.0,
+        );
+        let vp = Viewport::new(
+            cam,
+            Scene::new(objects, 0.001, 1000.0).with_lights(lights),
+            Arc::new(next_event_ray_color),
+            WIDTH,
+            HEIGHT,
+            16,
+            5,
+            Vec3::ZERO,
+            2.0,
+        );
+        vp.render_rows_async().save("test_out/lights_test.png")
+    }
+}
@@ -2,12 +2,12 @@ use std::{f32::consts::PI, sync::Arc};
 
 use crate::{
     onb::ONB,
+    sampler::random_f32,
     vec3::{ray::Ray, vec3::Vec3},
 };
 
 use super::hit::Hit;
 use lazy_static::lazy_static;
-use rand::random;
 
 pub trait Material {
     fn on_hit(&self, h: &Hit) -> Ray;
@@ -15,6 +15,10 @@ pub trait Material {
     fn generator_pdf(&self, h: &Hit, r: &Ray) -> f32;
     // material probability of given reflection
     fn material_pdf(&self, h: &Hit, r: &Ray) -> f32;
+    // reflections are a delta distribution, pdfs are probabilities instead of densities
+    fn is_specular(&self) -> bool {
+        false
+    }
 }
 
 const FRAC_1_2PI: f32 = 1.0 / 2.0 / PI;
@@ -22,15 +26,37 @@ lazy_static! {
     pub static ref LAMBERTIAN: Arc<Lambertian> = Arc::new(Lambertian {});
     pub static ref MIRROR: Arc<Mirror> = Arc::new(Mirror {});
 }
+// Mirrors `dir` to the outer side of the geometric normal, so a perturbed shading normal can't scatter through the surface
+fn above_surface(dir: Vec3, ng: Vec3) -> Vec3 {
+    let cos = dir.dot(ng);
+    if cos < 0.0 {
+        dir - ng * (2.0 * cos / ng.length2())
+    } else {
+        dir
+    }
+}
+// Density of `dir` after sampling `lobe` and folding it with `above_surface`: a direction above the
+// surface is also reached from its mirror image below it
+fn folded_pdf(dir: Vec3, ng: Vec3, lobe: impl Fn(Vec3) -> f32) -> f32 {
+    let cos = dir.dot(ng);
+    if cos < 0.0 {
+        return 0.0;
+    }
+    lobe(dir) + lobe(dir - ng * (2.0 * cos / ng.length2()))
+}
+
 pub struct Lambertian {}
 impl Material for Lambertian {
     fn on_hit(&self, h: &Hit) -> Ray {
-        let dir = (h.n + Vec3::random_unit_vec()).unit();
+        let (n, ng) = h.facing_normals();
+        let dir = above_surface((n + Vec3::random_unit_vec()).unit(), ng);
 
         Ray {
             origin: h.p,
             direction: dir,
             time: h.r.time,
+            spread: 0.0,
+            width: 0.0,
         }
     }
 
@@ -38,27 +64,15 @@ impl Material for Lambertian {
         if r.origin != h.p {
             return 0.0;
         }
-        let cos = r.direction.unit().dot(h.n.unit());
-        return cos.clamp(0.0, 1.0) * core::f32::consts::FRAC_1_PI;
+        let (n, ng) = h.facing_normals();
+        let n = n.unit();
+        folded_pdf(r.direction.unit(), ng, |d| {
+            d.dot(n).max(0.0) * core::f32::consts::FRAC_1_PI
+        })
     }
 
     fn material_pdf(&self, h: &Hit, r: &Ray) -> f32 {
-        if r.origin != h.p {
-            // dbg!("bad origin");
-            return 0.0;
-        }
-        let cos = r.direction.unit().dot(h.n.unit());
-
-        // if cos <= 0.0 {
-        //     dbg!(cos);
-        // }
-        return if h.r.direction.dot(h.n) >= 0.0 {
-            -cos
-        } else {
-            cos
-        }
-        .clamp(0.0, 1.0)
-            * core::f32::consts::FRAC_1_PI;
+        self.generator_pdf(h, r)
     }
 }
 
@@ -69,17 +83,16 @@ pub fn lambertian(h: &Hit) -> Ray {
         origin: h.p,
         direction: dir,
         time: h.r.time,
+        spread: 0.0,
+        width: 0.0,
     }
 }
 
 pub struct Mirror {}
 impl Material for Mirror {
     fn on_hit(&self, h: &Hit) -> Ray {
-        Ray {
-            origin: h.p,
-            direction: h.r.direction.reflect(h.n),
-            time: h.r.time,
-        }
+        let (_, ng) = h.facing_normals();
+        h.r.continued(h.t, h.p, above_surface(h.r.direction.reflect(h.n), ng))
     }
 
     fn generator_pdf(&self, h: &Hit, r: &Ray) -> f32 {
@@ -97,14 +110,14 @@ impl Material for Mirror {
             0.0
         }
     }
+
+    fn is_specular(&self) -> bool {
+        true
+    }
 }
 
 pub fn mirror(h: &Hit) -> Ray {
-    Ray {
-        origin: h.p,
-        direction: h.r.direction.reflect(h.n),
-        time: h.r.time,
-    }
+    h.r.continued(h.t, h.p, h.r.direction.reflect(h.n))
 }
 
 pub struct MirrorGlass {
@@ -126,10 +139,9 @@ impl MirrorGlass {
         r0 = r0 * r0;
         return r0 + (1.0 - r0) * (1.0 - cosine).powi(5);
     }
-}
-
-impl Material for MirrorGlass {
-    fn on_hit(&self, h: &Hit) -> Ray {
+    // reflected and refracted directions with the reflectance, refractions are None under
+    // total internal reflection. Both stay on their side of the geometric surface
+    fn scatter(&self, h: &Hit) -> (Vec3, Option<Vec3>, f32) {
         let n;
         let front_face = if h.r.direction.dot(h.n) > 0.0 {
             n = -h.n;
@@ -147,88 +159,54 @@ impl Material for MirrorGlass {
         }
         let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
 
-        let cannot_refract = refraction_ratio * sin_theta > 1.0;
-        let reflectance = Self::reflectance(cos_theta, refraction_ratio);
-        // eprintln!("ff: {} can refract: {} ref_ratio: {}", front_face, !cannot_refract, refraction_ratio);
-        let direction = if cannot_refract || reflectance > random::<f32>() {
-            // eprintln!("reflect");
-            unit_direction.reflect(n)
-        } else {
-            // eprintln!("ud: {:?} hn: {:?}", unit_direction, n);
-            Self::refract(unit_direction, n, refraction_ratio)
-        };
-
-        return Ray::new_with_time(h.p, direction, h.r.time);
+        let (_, ng) = h.facing_normals();
+        let reflected = above_surface(unit_direction.reflect(n), ng);
+        if refraction_ratio * sin_theta > 1.0 {
+            return (reflected, None, 1.0);
+        }
+        let refracted = -above_surface(-Self::refract(unit_direction, n, refraction_ratio), ng);
+        (
+            reflected,
+            Some(refracted),
+            Self::reflectance(cos_theta, refraction_ratio),
+        )
     }
 
-    fn generator_pdf(&self, h: &Hit, r: &Ray) -> f32 {
+    fn pdf(&self, h: &Hit, r: &Ray) -> f32 {
         if r.origin != h.p {
             return 0.0;
         }
-        let n;
-        let front_face = if h.r.direction.dot(h.n) > 0.0 {
-            n = -h.n;
-            false
-        } else {
-            n = h.n;
-            true
-        };
-        let refraction_ratio = if front_face { 1.0 / self.ir } else { self.ir };
-
-        let unit_direction = h.r.direction.unit();
-        let mut cos_theta = (-unit_direction).dot(n);
-        if cos_theta > 1.0 {
-            cos_theta = 1.0;
-        }
-        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
-
-        let cannot_refract = refraction_ratio * sin_theta > 1.0;
-        if cannot_refract && unit_direction.reflect(n) == r.direction {
-            return 1.0;
-        }
-        let reflectance = Self::reflectance(cos_theta, refraction_ratio);
-        if unit_direction.reflect(n) == r.direction {
+        let (reflected, refracted, reflectance) = self.scatter(h);
+        if reflected == r.direction {
             reflectance
-        } else if Self::refract(unit_direction, n, refraction_ratio) == r.direction {
+        } else if refracted == Some(r.direction) {
             1.0 - reflectance
         } else {
             0.0
         }
     }
+}
 
-    fn material_pdf(&self, h: &Hit, r: &Ray) -> f32 {
-        if r.origin != h.p {
-            return 0.0;
-        }
-        let n;
-        let front_face = if h.r.direction.dot(h.n) > 0.0 {
-            n = -h.n;
-            false
-        } else {
-            n = h.n;
-            true
+impl Material for MirrorGlass {
+    fn on_hit(&self, h: &Hit) -> Ray {
+        let direction = match self.scatter(h) {
+            (_, Some(refracted), reflectance) if reflectance <= random_f32() => refracted,
+            (reflected, _, _) => reflected,
         };
-        let refraction_ratio = if front_face { 1.0 / self.ir } else { self.ir };
 
-        let unit_direction = h.r.direction.unit();
-        let mut cos_theta = (-unit_direction).dot(n);
-        if cos_theta > 1.0 {
-            cos_theta = 1.0;
-        }
-        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        return h.r.continued(h.t, h.p, direction);
+    }
 
-        let cannot_refract = refraction_ratio * sin_theta > 1.0;
-        if cannot_refract && unit_direction.reflect(n) == r.direction {
-            return 1.0;
-        }
-        let reflectance = Self::reflectance(cos_theta, refraction_ratio);
-        if unit_direction.reflect(n) == r.direction {
-            reflectance
-        } else if Self::refract(unit_direction, n, refraction_ratio) == r.direction {
-            1.0 - reflectance
-        } else {
-            0.0
-        }
+    fn generator_pdf(&self, h: &Hit, r: &Ray) -> f32 {
+        self.pdf(h, r)
+    }
+
+    fn material_pdf(&self, h: &Hit, r: &Ray) -> f32 {
+        self.pdf(h, r)
+    }
+
+    fn is_specular(&self) -> bool {
+        true
     }
 }
 
@@ -247,8 +225,8 @@ impl MixedMaterial {
         }
     }
     pub fn gen_random_dir(&self) -> Vec3 {
-        let phi = random::<f32>() * 2.0 * PI;
-        let cos_theta = (1.0 - random::<f32>()).powf(self.gen_exp);
+        let phi = random_f32() * 2.0 * PI;
+        let cos_theta = (1.0 - random_f32()).powf(self.gen_exp);
         let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
 
         Vec3 {
@@ -260,38 +238,24 @@ impl MixedMaterial {
 }
 impl Material for MixedMaterial {
     fn on_hit(&self, h: &Hit) -> Ray {
-        let uvw = ONB::new_from_w(h.n);
-        Ray::new(h.p, uvw.from_local(self.gen_random_dir()))
+        let (n, ng) = h.facing_normals();
+        let uvw = ONB::new_from_w(n);
+        let dir = above_surface(uvw.from_local(self.gen_random_dir()), ng);
+        Ray::new_with_time(h.p, dir, h.r.time)
     }
 
     fn generator_pdf(&self, h: &Hit, r: &Ray) -> f32 {
         if r.origin != h.p {
             return 0.0;
         }
-        let cos = if h.r.direction.dot(h.n) < 0.0 {
-            r.direction.unit().dot(h.n.unit())
-        } else {
-            -r.direction.unit().dot(h.n.unit())
-        };
-
-        return cos.powf(self.exp) * (self.exp + 1.0) * FRAC_1_2PI;
+        let (n, ng) = h.facing_normals();
+        let n = n.unit();
+        folded_pdf(r.direction.unit(), ng, |d| {
+            d.dot(n).max(0.0).powf(self.exp) * (self.exp + 1.0) * FRAC_1_2PI
+        })
     }
 
     fn material_pdf(&self, h: &Hit, r: &Ray) -> f32 {
-        if r.origin != h.p {
-            // eprintln!("Bad origin");
-            return 0.0;
-        }
-        let cos = if h.r.direction.dot(h.n) < 0.0 {
-            r.direction.unit().dot(h.n.unit())
-        } else {
-            -r.direction.unit().dot(h.n.unit())
-        };
-        if cos < 0. {
-            // eprintln!("negative_cos");
-            return 0.;
-        }
-
-        return cos.powf(self.exp) * (self.exp + 1.0) * FRAC_1_2PI;
+        self.generator_pdf(h, r)
     }
 }
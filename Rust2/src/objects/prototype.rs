@@ -0,0 +1,318 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Weak},
+};
+
+use crate::{matrix::Matrix4, vec3::ray::Ray};
+
+use super::{
+    aabb::{Bounded, Interval, AABB, TIME_STEPS},
+    hit::{Hit, HitObject},
+    instance::Instance,
+    material::Material,
+    shapes::Surface,
+    Object,
+};
+
+/// Object or material the id AOVs give a number, by address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Numbered {
+    Object(usize),
+    Material(usize),
+}
+
+impl Numbered {
+    pub(crate) fn material(m: &Arc<dyn Material + Send + Sync>) -> Self {
+        Self::Material(Arc::as_ptr(m) as *const () as usize)
+    }
+
+    // the object, its material and the parts its hits can return
+    fn object(o: &Arc<dyn Object + Send + Sync>, numbered: &mut Vec<Numbered>) {
+        let mut add = |o: &Arc<dyn Object + Send + Sync>| {
+            numbered.push(Self::Object(o.identity() as usize));
+            numbered.extend(o.material().map(Self::material));
+        };
+        add(o);
+        o.parts(&mut add);
+    }
+}
+
+// what the nested instances add to the ids, every material and prototype once
+enum Nested {
+    Material(Numbered),
+    // kept alive by the instances in the hierarchy
+    Prototype(Weak<Prototype>),
+}
+
+/// Geometry shared by any number of instances. The objects and nested instances get their
+/// bounding volume hierarchies once, every instance of it only adds a transform and a material
+pub struct Prototype {
+    objects: AABB<Arc<dyn Object + Send + Sync>>,
+    instances: AABB<Instance>,
+    // glowing surfaces among the objects, nested instances are asked for theirs
+    emitters: Vec<Arc<dyn Surface + Send + Sync>>,
+    emits: bool,
+    // the objects in the order they were given, the hierarchies are built with random splits
+    numbered: Vec<Numbered>,
+    nested: Vec<Nested>,
+}
+
+impl Prototype {
+    pub fn new(objects: Vec<Arc<dyn Object + Send + Sync>>, instances: Vec<Instance>) -> Self {
+        let emitters: Vec<_> = objects.iter().filter_map(|o| o.clone().emitter()).collect();
+        let emits = !emitters.is_empty() || instances.iter().any(|i| i.prototype().emits);
+        let mut numbered = vec![];
+        for o in objects.iter() {
+            Numbered::object(o, &mut numbered);
+        }
+        let mut nested = vec![];
+        let (mut materials, mut prototypes) = (HashSet::new(), HashSet::new());
+        for i in instances.iter() {
+            if let Some(m) = i.material().map(Numbered::material) {
+                if materials.insert(m) {
+                    nested.push(Nested::Material(m));
+                }
+            }
+            if prototypes.insert(Arc::as_ptr(i.prototype())) {
+                nested.push(Nested::Prototype(Arc::downgrade(i.prototype())));
+            }
+        }
+        Self {
+            objects: AABB::new(objects),
+            instances: AABB::new(instances),
+            emitters,
+            emits,
+            numbered,
+            nested,
+        }
+    }
+
+    /// Calls `f` with the objects and materials in the order they were given, nested ones included.
+    /// Prototypes already in `seen` are skipped, everything in them was numbered before
+    pub(crate) fn number(
+        self: &Arc<Self>,
+        seen: &mut HashSet<*const Prototype>,
+        f: &mut dyn FnMut(Numbered),
+    ) {
+        if !seen.insert(Arc::as_ptr(self)) {
+            return;
+        }
+        self.numbered.iter().for_each(|&n| f(n));
+        for n in self.nested.iter() {
+            match n {
+                Nested::Material(n) => f(*n),
+                Nested::Prototype(p) => {
+                    if let Some(p) = p.upgrade() {
+                        p.number(seen, f);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Calls `f` with every surface with a glowing texture, so it can be sampled as a light,
+    /// and its transform into the space `to_world` leads to. Nested instances are taken as
+    /// they are at time 0
+    pub(crate) fn emitters(
+        &self,
+        to_world: Matrix4,
+        f: &mut dyn FnMut(&Arc<dyn Surface + Send + Sync>, Matrix4),
+    ) {
+        if !self.emits {
+            return;
+        }
+        for s in self.emitters.iter() {
+            f(s, to_world);
+        }
+        self.instances.for_each(&mut |i| {
+            i.prototype().emitters(to_world * i.matrix_at(0.0), f);
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty() && self.instances.is_empty()
+    }
+
+    /// Bounds during the time step `i`, in the space of the prototype
+    pub(crate) fn bounds_during(&self, i: usize) -> (Interval, Interval, Interval) {
+        match (self.objects.is_empty(), self.instances.is_empty()) {
+            (false, true) => self.objects.bounds_during(i),
+            (true, false) => self.instances.bounds_during(i),
+            _ => {
+                let (a, b) = (
+                    self.objects.bounds_during(i),
+                    self.instances.bounds_during(i),
+                );
+                (a.0 + b.0, a.1 + b.1, a.2 + b.2)
+            }
+        }
+    }
+
+    pub(crate) fn bounds(&self) -> (Interval, Interval, Interval) {
+        let (a, b) = (&self.objects, &self.instances);
+        match (a.is_empty(), b.is_empty()) {
+            (false, true) => (a.x, a.y, a.z),
+            (true, false) => (b.x, b.y, b.z),
+            _ => (a.x + b.x, a.y + b.y, a.z + b.z),
+        }
+    }
+
+    /// Whether the bounds change over time
+    pub(crate) fn deforms(&self) -> bool {
+        !self.objects.steps.is_empty() || !self.instances.steps.is_empty()
+    }
+
+    pub(crate) fn get_hit(&self, r: Ray, mint: f32, maxt: f32) -> Option<(Hit, HitObject<'_>)> {
+        let objects = self.objects.get_hit(r, mint, maxt);
+        let instances = self.instances.get_hit(r, mint, maxt);
+        match (objects, instances) {
+            (Some(o), Some(i)) => Some(if i.0 < o.0 { i } else { o }),
+            (o, i) => o.or(i),
+        }
+    }
+}
+
+impl Bounded for Arc<dyn Object + Send + Sync> {
+    fn leaf(&self) -> AABB<Self> {
+        let (x, y, z) = self.get_aabb();
+        let step = 1.0 / TIME_STEPS as f32;
+        let mut steps: Vec<_> = (0..TIME_STEPS)
+            .map(|i| self.get_aabb_during(i as f32 * step, (i + 1) as f32 * step))
+            .collect();
+        if steps.iter().all(|b| *b == (x, y, z)) {
+            steps.clear();
+        }
+        AABB {
+            x,
+            y,
+            z,
+            steps,
+            instances: vec![self.clone()],
+            aabbs: vec![],
+        }
+    }
+
+    fn closest_hit(&self, r: Ray, mint: f32, maxt: f32) -> Option<(Hit, HitObject<'_>)> {
+        self.get_hit_part(r, mint, maxt)
+            .map(|(h, part)| (h, HitObject::new(part.unwrap_or_else(|| self.clone()))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        matrix::Matrix4,
+        objects::{
+            instance::Instance,
+            material::{Lambertian, Material, MIRROR},
+            sphere::Sphere,
+            texture::ConstColorTexture,
+            Object,
+        },
+        vec3::{ray::Ray, vec3::Vec3},
+    };
+
+    use super::Prototype;
+
+    fn sphere(emission: Vec3) -> Arc<dyn Object + Send + Sync> {
+        Arc::new(Sphere {
+            origin: Vec3::ZERO,
+            radius: 1.0,
+            mat: MIRROR.clone(),
+            texture: Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.5, emission)),
+        })
+    }
+
+    fn placed(prototype: &Arc<Prototype>, position: Vec3, scale: f32) -> Instance {
+        let mut i = Instance::from_prototype(prototype.clone());
+        i.set_position(position);
+        i.set_scale(scale);
+        i
+    }
+
+    #[test]
+    fn nested_bounds() {
+        let ball = Arc::new(Prototype::new(vec![sphere(Vec3::ZERO)], vec![]));
+        let pair = Arc::new(Prototype::new(
+            vec![],
+            vec![
+                placed(&ball, Vec3::new(3.0, 0.0, 0.0), 1.0),
+                placed(&ball, Vec3::new(-1.0, 0.0, 0.0), 0.5),
+            ],
+        ));
+        let outer = Prototype::new(
+            vec![sphere(Vec3::ZERO)],
+            vec![placed(&pair, Vec3::UP * 5.0, 2.0)],
+        );
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-3;
+        let (x, y, _) = pair.bounds();
+        assert!(close(x.min, -1.5) && close(x.max, 4.0), "{:?}", x);
+        assert!(close(y.min, -1.0) && close(y.max, 1.0), "{:?}", y);
+        // the pair doubled and lifted, together with the sphere at the origin
+        let (x, y, z) = outer.bounds();
+        assert!(close(x.min, -3.0) && close(x.max, 8.0), "{:?}", x);
+        assert!(close(y.min, -1.0) && close(y.max, 7.0), "{:?}", y);
+        assert!(close(z.min, -2.0) && close(z.max, 2.0), "{:?}", z);
+    }
+
+    #[test]
+    fn material_override() {
+        let s = sphere(Vec3::ZERO);
+        let ball = Arc::new(Prototype::new(vec![s.clone()], vec![]));
+        let outer_mat: Arc<dyn Material + Send + Sync> = Arc::new(Lambertian {});
+        let mut inner = placed(&ball, Vec3::ZERO, 1.0);
+        inner.set_material(Arc::new(Lambertian {}));
+        let mut outer = Instance::group(vec![inner]);
+        outer.set_material(outer_mat.clone());
+        outer.set_position(Vec3::FORWARD * 5.0);
+
+        let (hit, o) = outer
+            .get_hit(Ray::new(Vec3::ZERO, Vec3::FORWARD), 0.001, 100.0)
+            .unwrap();
+        assert!((hit.p - Vec3::FORWARD * 4.0).length() < 1e-4, "{:?}", hit.p);
+        // the outermost material wins, borrowed from the instance
+        assert!(std::ptr::eq(
+            o.material().unwrap(),
+            outer.material().unwrap()
+        ));
+        assert_eq!(Arc::strong_count(&outer_mat), 2);
+        // the hit still belongs to the sphere
+        assert_eq!(o.identity(), s.identity());
+
+        // without a material anywhere along the chain the object's own is used
+        let plain = placed(&ball, Vec3::FORWARD * 5.0, 1.0);
+        let (_, o) = plain
+            .get_hit(Ray::new(Vec3::ZERO, Vec3::FORWARD), 0.001, 100.0)
+            .unwrap();
+        assert!(std::ptr::addr_eq(
+            Arc::as_ptr(o.material().unwrap()),
+            Arc::as_ptr(&MIRROR)
+        ));
+    }
+
+    #[test]
+    fn emitter_transforms() {
+        let lamp = Arc::new(Prototype::new(vec![sphere(Vec3::WHITE)], vec![]));
+        let dark = Arc::new(Prototype::new(vec![sphere(Vec3::ZERO)], vec![]));
+        let child = placed(&lamp, Vec3::UP * 2.0, 0.5);
+        let room = Arc::new(Prototype::new(
+            vec![],
+            vec![child.clone(), placed(&dark, Vec3::ZERO, 1.0)],
+        ));
+        let parent = placed(&room, Vec3::new(5.0, 0.0, 0.0), 2.0);
+
+        let mut found = vec![];
+        room.emitters(parent.matrix_at(0.0), &mut |_, m| found.push(m));
+        assert_eq!(found.len(), 1);
+        let expected = parent.matrix_at(0.0) * child.matrix_at(0.0);
+        let p = found[0].transform_point(Vec3::ZERO);
+        assert!((p - expected.transform_point(Vec3::ZERO)).length() < 1e-5);
+        assert!((p - Vec3::new(5.0, 4.0, 0.0)).length() < 1e-5, "{:?}", p);
+
+        let mut none = 0;
+        dark.emitters(Matrix4::IDENTITY, &mut |_, _| none += 1);
+        assert_eq!(none, 0);
+    }
+}
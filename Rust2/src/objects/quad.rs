@@ -1,10 +1,14 @@
 use std::sync::Arc;
 
-use crate::vec3::{ray::Ray, vec3::Vec3};
+use crate::{
+    sampler::random_f32,
+    vec3::{ray::Ray, vec3::Vec3},
+};
 
 use super::{
     aabb::{maxf, minf, Interval},
     material::Material,
+    shapes::Surface,
     texture::Texture,
     Object,
 };
@@ -47,28 +51,15 @@ impl Quad {
             w: n / n.dot(n),
         }
     }
-}
 
-impl Object for Quad {
-    fn get_aabb(
-        &self,
-    ) -> (
-        super::aabb::Interval,
-        super::aabb::Interval,
-        super::aabb::Interval,
-    ) {
-        let op_corner = self.origin + self.u + self.v;
-        let v_corner = self.origin + self.v;
-        let u_corner = self.origin + self.u;
+    // bounds of the quad moved to `origin`
+    fn bounds(&self, origin: Vec3) -> (Interval, Interval, Interval) {
+        let op_corner = origin + self.u + self.v;
+        let v_corner = origin + self.v;
+        let u_corner = origin + self.u;
         let mut x = Interval {
-            min: minf(
-                minf(op_corner.x, v_corner.x),
-                minf(u_corner.x, self.origin.x),
-            ),
-            max: maxf(
-                maxf(op_corner.x, v_corner.x),
-                maxf(u_corner.x, self.origin.x),
-            ),
+            min: minf(minf(op_corner.x, v_corner.x), minf(u_corner.x, origin.x)),
+            max: maxf(maxf(op_corner.x, v_corner.x), maxf(u_corner.x, origin.x)),
         };
         if x.max - x.min < Quad::MIN_AABB_WIDTH {
             let c = 0.5 * (x.max + x.min);
@@ -76,14 +67,8 @@ impl Object for Quad {
             x.min = c - Quad::MIN_AABB_WIDTH * 0.5;
         }
         let mut y = Interval {
-            min: minf(
-                minf(op_corner.y, v_corner.y),
-                minf(u_corner.y, self.origin.y),
-            ),
-            max: maxf(
-                maxf(op_corner.y, v_corner.y),
-                maxf(u_corner.y, self.origin.y),
-            ),
+            min: minf(minf(op_corner.y, v_corner.y), minf(u_corner.y, origin.y)),
+            max: maxf(maxf(op_corner.y, v_corner.y), maxf(u_corner.y, origin.y)),
         };
         if y.max - y.min < Quad::MIN_AABB_WIDTH {
             let c = 0.5 * (y.max + y.min);
@@ -91,14 +76,8 @@ impl Object for Quad {
             y.min = c - Quad::MIN_AABB_WIDTH * 0.5;
         }
         let mut z = Interval {
-            min: minf(
-                minf(op_corner.z, v_corner.z),
-                minf(u_corner.z, self.origin.z),
-            ),
-            max: maxf(
-                maxf(op_corner.z, v_corner.z),
-                maxf(u_corner.z, self.origin.z),
-            ),
+            min: minf(minf(op_corner.z, v_corner.z), minf(u_corner.z, origin.z)),
+            max: maxf(maxf(op_corner.z, v_corner.z), maxf(u_corner.z, origin.z)),
         };
         if z.max - z.min < Quad::MIN_AABB_WIDTH {
             let c = 0.5 * (z.max + z.min);
@@ -107,6 +86,35 @@ impl Object for Quad {
         }
         (x, y, z)
     }
+}
+
+impl Object for Quad {
+    fn get_aabb(
+        &self,
+    ) -> (
+        super::aabb::Interval,
+        super::aabb::Interval,
+        super::aabb::Interval,
+    ) {
+        let mut bounds = self.bounds(self.origin);
+        // covers the whole motion from time 0 to 1
+        if !self.velocity.close_to_zero() {
+            let end = self.bounds(self.origin + self.velocity);
+            bounds.0 += end.0;
+            bounds.1 += end.1;
+            bounds.2 += end.2;
+        }
+        bounds
+    }
+
+    fn get_aabb_during(&self, t0: f32, t1: f32) -> (Interval, Interval, Interval) {
+        let mut bounds = self.bounds(self.origin + self.velocity * t0);
+        let end = self.bounds(self.origin + self.velocity * t1);
+        bounds.0 += end.0;
+        bounds.1 += end.1;
+        bounds.2 += end.2;
+        bounds
+    }
 
     fn get_hit(&self, r: crate::vec3::ray::Ray, mint: f32, maxt: f32) -> Option<super::hit::Hit> {
         // eprintln!("Get hit");
@@ -115,7 +123,9 @@ impl Object for Quad {
             // eprintln!("parallel");
             return None;
         }
-        let t = (self.d - self.normal.dot(r.origin)) / denominator;
+        // the quad moves by its velocity every unit of time
+        let offset = self.velocity * r.time;
+        let t = (self.d + self.normal.dot(offset) - self.normal.dot(r.origin)) / denominator;
         if t < mint || t > maxt {
             // eprintln!("Out of range");
             // dbg!(t);
@@ -124,7 +134,7 @@ impl Object for Quad {
             return None;
         }
         let point = r.at(t);
-        let planar = point - self.origin;
+        let planar = point - self.origin - offset;
 
         let alfa = self.w.dot(planar.cross(self.v));
         let beta = self.w.dot(self.u.cross(planar));
@@ -138,8 +148,13 @@ impl Object for Quad {
         Some(super::Hit {
             t: t,
             n: self.normal,
+            ng: self.normal,
             p: point,
             r,
+            u: alfa,
+            v: beta,
+            dpdu: self.u,
+            dpdv: self.v,
         })
     }
 
@@ -148,11 +163,7 @@ impl Object for Quad {
     }
 
     fn color(&self, h: &super::hit::Hit) -> super::texture::ColorResult {
-        let planar = h.p - self.origin;
-        let alfa = self.w.dot(planar.cross(self.v));
-        let beta = self.w.dot(self.u.cross(planar));
-        // eprintln!("color: {:?}", self.texture.color_at(alfa, beta).multiplied);
-        self.texture.color_at(alfa, beta)
+        self.texture.color_at(h)
     }
 
     fn generator_pdf(&self, h: &super::hit::Hit, r: &Ray) -> f32 {
@@ -162,6 +173,38 @@ impl Object for Quad {
     fn material_pdf(&self, h: &super::hit::Hit, r: &Ray) -> f32 {
         self.mat.material_pdf(h, r)
     }
+
+    fn is_specular(&self, _h: &super::hit::Hit) -> bool {
+        self.mat.is_specular()
+    }
+
+    fn material(&self) -> Option<&Arc<dyn Material + Send + Sync>> {
+        Some(&self.mat)
+    }
+
+    fn emitter(self: Arc<Self>) -> Option<Arc<dyn Surface + Send + Sync>> {
+        if self.texture.emits() {
+            Some(self)
+        } else {
+            None
+        }
+    }
+}
+
+impl Surface for Quad {
+    fn area(&self) -> f32 {
+        self.u.cross(self.v).length()
+    }
+
+    // where a moving quad is at time 0
+    fn sample_point(&self) -> (Vec3, Vec3) {
+        let p = self.origin + self.u * random_f32() + self.v * random_f32();
+        (p, self.normal)
+    }
+
+    fn normal_at(&self, _: Vec3) -> Vec3 {
+        self.normal
+    }
 }
 
 #[allow(unused)]
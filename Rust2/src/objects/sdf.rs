@@ -0,0 +1,550 @@
+use std::sync::Arc;
+
+use crate::{
+    onb::ONB,
+    vec3::{ray::Ray, vec3::Vec3},
+};
+
+use super::{
+    aabb::{maxf, minf, Interval},
+    hit::Hit,
+    material::Material,
+    texture::{ColorResult, Texture},
+    Object,
+};
+
+/// Signed distance expression, negative inside. Built from the constructors and combined with
+/// the methods, like `Sdf::sphere(1.0).smooth_union(Sdf::cuboid(v), 0.2).twist(0.5)`
+#[derive(Debug, Clone)]
+pub enum Sdf {
+    Sphere(f32),
+    // half of the size of the box
+    Cuboid(Vec3),
+    // half size and the radius of the rounded edges, stays inside the same box
+    RoundedCuboid(Vec3, f32),
+    // major and minor radius, lying on the `y` plane
+    Torus(f32, f32),
+    Translate(Vec3, Box<Sdf>),
+    Scale(f32, Box<Sdf>),
+    // hard when the blend radius is 0
+    SmoothUnion(Box<Sdf>, Box<Sdf>, f32),
+    // the second shape cut out of the first
+    SmoothSubtraction(Box<Sdf>, Box<Sdf>, f32),
+    // copies `period` apart, up to the count of copies on each side of the original
+    Repeat(Vec3, Vec3, Box<Sdf>),
+    // turns around `y` by the rate in radians per unit of height
+    Twist(f32, Box<Sdf>),
+}
+
+impl Sdf {
+    pub fn sphere(radius: f32) -> Self {
+        Self::Sphere(radius)
+    }
+
+    /// Box with `half` of its size along each axis
+    pub fn cuboid(half: Vec3) -> Self {
+        Self::Cuboid(half)
+    }
+
+    pub fn rounded_cuboid(half: Vec3, radius: f32) -> Self {
+        Self::RoundedCuboid(half, radius)
+    }
+
+    pub fn torus(major_radius: f32, minor_radius: f32) -> Self {
+        Self::Torus(major_radius, minor_radius)
+    }
+
+    pub fn translate(self, offset: Vec3) -> Self {
+        Self::Translate(offset, Box::new(self))
+    }
+
+    pub fn scale(self, scale: f32) -> Self {
+        Self::Scale(scale, Box::new(self))
+    }
+
+    pub fn union(self, other: Sdf) -> Self {
+        self.smooth_union(other, 0.0)
+    }
+
+    pub fn subtract(self, other: Sdf) -> Self {
+        self.smooth_subtract(other, 0.0)
+    }
+
+    pub fn smooth_union(self, other: Sdf, blend: f32) -> Self {
+        Self::SmoothUnion(Box::new(self), Box::new(other), blend)
+    }
+
+    pub fn smooth_subtract(self, other: Sdf, blend: f32) -> Self {
+        Self::SmoothSubtraction(Box::new(self), Box::new(other), blend)
+    }
+
+    /// `count` copies on both sides along each axis, axes with a period of 0 aren't repeated
+    pub fn repeat(self, period: Vec3, count: Vec3) -> Self {
+        Self::Repeat(period, count, Box::new(self))
+    }
+
+    pub fn twist(self, rate: f32) -> Self {
+        Self::Twist(rate, Box::new(self))
+    }
+
+    pub fn distance(&self, p: Vec3) -> f32 {
+        match self {
+            Sdf::Sphere(r) => p.length() - r,
+            Sdf::Cuboid(half) => outside_box(abs(p) - *half),
+            Sdf::RoundedCuboid(half, r) => outside_box(abs(p) - *half + Vec3::WHITE * *r) - r,
+            Sdf::Torus(major, minor) => {
+                let ring = (p.x * p.x + p.z * p.z).sqrt() - major;
+                (ring * ring + p.y * p.y).sqrt() - minor
+            }
+            Sdf::Translate(offset, inner) => inner.distance(p - *offset),
+            Sdf::Scale(s, inner) => inner.distance(p / *s) * s,
+            Sdf::SmoothUnion(a, b, k) => smooth_min(a.distance(p), b.distance(p), *k),
+            Sdf::SmoothSubtraction(a, b, k) => -smooth_min(-a.distance(p), b.distance(p), *k),
+            Sdf::Repeat(period, count, inner) => {
+                let copy = |x: f32, period: f32, count: f32| {
+                    if period == 0.0 {
+                        x
+                    } else {
+                        x - period * (x / period).round().clamp(-count, count)
+                    }
+                };
+                inner.distance(Vec3::new(
+                    copy(p.x, period.x, count.x),
+                    copy(p.y, period.y, count.y),
+                    copy(p.z, period.z, count.z),
+                ))
+            }
+            Sdf::Twist(rate, inner) => {
+                let (s, c) = (rate * p.y).sin_cos();
+                inner.distance(Vec3::new(c * p.x - s * p.z, p.y, s * p.x + c * p.z))
+            }
+        }
+    }
+
+    /// Box around where the distance is negative
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        match self {
+            Sdf::Sphere(r) => (-Vec3::WHITE * *r, Vec3::WHITE * *r),
+            Sdf::Cuboid(half) | Sdf::RoundedCuboid(half, _) => (-*half, *half),
+            Sdf::Torus(major, minor) => {
+                let e = Vec3::new(major + minor, *minor, major + minor);
+                (-e, e)
+            }
+            Sdf::Translate(offset, inner) => {
+                let (lo, hi) = inner.bounds();
+                (lo + *offset, hi + *offset)
+            }
+            Sdf::Scale(s, inner) => {
+                let (lo, hi) = inner.bounds();
+                (lo * s.abs(), hi * s.abs())
+            }
+            // blending grows the shapes by up to a quarter of the blend radius
+            Sdf::SmoothUnion(a, b, k) => {
+                let ((alo, ahi), (blo, bhi)) = (a.bounds(), b.bounds());
+                let lo = Vec3::new(minf(alo.x, blo.x), minf(alo.y, blo.y), minf(alo.z, blo.z));
+                let hi = Vec3::new(maxf(ahi.x, bhi.x), maxf(ahi.y, bhi.y), maxf(ahi.z, bhi.z));
+                (lo - Vec3::WHITE * (k * 0.25), hi + Vec3::WHITE * (k * 0.25))
+            }
+            Sdf::SmoothSubtraction(a, _, k) => {
+                let (lo, hi) = a.bounds();
+                (lo - Vec3::WHITE * (k * 0.25), hi + Vec3::WHITE * (k * 0.25))
+            }
+            Sdf::Repeat(period, count, inner) => {
+                let (lo, hi) = inner.bounds();
+                let e = abs(*period) * *count;
+                (lo - e, hi + e)
+            }
+            Sdf::Twist(_, inner) => {
+                let (lo, hi) = inner.bounds();
+                let r = Vec3::new(
+                    maxf(lo.x.abs(), hi.x.abs()),
+                    0.0,
+                    maxf(lo.z.abs(), hi.z.abs()),
+                )
+                .length();
+                (Vec3::new(-r, lo.y, -r), Vec3::new(r, hi.y, r))
+            }
+        }
+    }
+
+    /// How much faster than the distance to the surface the value can change, steps along
+    /// rays are divided by it so they don't go through the surface
+    pub fn lipschitz(&self) -> f32 {
+        match self {
+            Sdf::Sphere(_) | Sdf::Cuboid(_) | Sdf::RoundedCuboid(_, _) | Sdf::Torus(_, _) => 1.0,
+            Sdf::Translate(_, inner) | Sdf::Scale(_, inner) | Sdf::Repeat(_, _, inner) => {
+                inner.lipschitz()
+            }
+            Sdf::SmoothUnion(a, b, _) | Sdf::SmoothSubtraction(a, b, _) => {
+                maxf(a.lipschitz(), b.lipschitz())
+            }
+            Sdf::Twist(rate, inner) => {
+                let (lo, hi) = inner.bounds();
+                let r = Vec3::new(
+                    maxf(lo.x.abs(), hi.x.abs()),
+                    0.0,
+                    maxf(lo.z.abs(), hi.z.abs()),
+                )
+                .length();
+                inner.lipschitz() * (1.0 + rate * rate * r * r).sqrt()
+            }
+        }
+    }
+
+    /// Gradient of the distance by central differences
+    pub fn normal(&self, p: Vec3) -> Vec3 {
+        const H: f32 = 1e-4;
+        let d = |v: Vec3| self.distance(p + v) - self.distance(p - v);
+        Vec3::new(
+            d(Vec3::new(H, 0.0, 0.0)),
+            d(Vec3::new(0.0, H, 0.0)),
+            d(Vec3::new(0.0, 0.0, H)),
+        )
+        .unit()
+    }
+}
+
+fn abs(v: Vec3) -> Vec3 {
+    Vec3::new(v.x.abs(), v.y.abs(), v.z.abs())
+}
+
+// distance to a box from its corner `q`, the point relative to the corner mirrored into the first octant
+fn outside_box(q: Vec3) -> f32 {
+    let outside = Vec3::new(maxf(q.x, 0.0), maxf(q.y, 0.0), maxf(q.z, 0.0));
+    outside.length() + minf(maxf(q.x, maxf(q.y, q.z)), 0.0)
+}
+
+// polynomial smooth minimum, blends within `k` of the shapes
+fn smooth_min(a: f32, b: f32, k: f32) -> f32 {
+    if k <= 0.0 {
+        return minf(a, b);
+    }
+    let h = maxf(k - (a - b).abs(), 0.0) / k;
+    minf(a, b) - h * h * k * 0.25
+}
+
+/// Surface where a signed distance expression is zero, found by sphere tracing
+pub struct SdfObject {
+    pub sdf: Sdf,
+    pub mat: Arc<dyn Material + Send + Sync>,
+    pub texture: Arc<dyn Texture + Send + Sync>,
+
+    bounds: (Vec3, Vec3),
+    lipschitz: f32,
+}
+
+impl SdfObject {
+    // distance counted as on the surface
+    const EPSILON: f32 = 1e-4;
+    const MAX_STEPS: usize = 512;
+
+    pub fn new(
+        sdf: Sdf,
+        mat: Arc<dyn Material + Send + Sync>,
+        texture: Arc<dyn Texture + Send + Sync>,
+    ) -> Self {
+        let (lo, hi) = sdf.bounds();
+        // room for the surface point found just outside
+        let pad = Vec3::WHITE * (2.0 * Self::EPSILON);
+        Self {
+            bounds: (lo - pad, hi + pad),
+            lipschitz: sdf.lipschitz(),
+            sdf,
+            mat,
+            texture,
+        }
+    }
+
+    // part of the ray inside the bounds
+    fn span(&self, r: &Ray, mint: f32, maxt: f32) -> Option<(f32, f32)> {
+        let (lo, hi) = self.bounds;
+        let (mut t0, mut t1) = (mint, maxt);
+        for (o, d, lo, hi) in [
+            (r.origin.x, r.direction.x, lo.x, hi.x),
+            (r.origin.y, r.direction.y, lo.y, hi.y),
+            (r.origin.z, r.direction.z, lo.z, hi.z),
+        ] {
+            let (a, b) = ((lo - o) / d, (hi - o) / d);
+            t0 = maxf(t0, minf(a, b));
+            t1 = minf(t1, maxf(a, b));
+        }
+        (t0 <= t1).then_some((t0, t1))
+    }
+}
+
+impl Object for SdfObject {
+    fn get_aabb(&self) -> (Interval, Interval, Interval) {
+        Interval::from_vecs(self.bounds.0, self.bounds.1)
+    }
+
+    fn get_hit(&self, r: Ray, mint: f32, maxt: f32) -> Option<Hit> {
+        let (mut t, t1) = self.span(&r, mint, maxt)?;
+        let speed = r.direction.length() * self.lipschitz;
+        let start = self.sdf.distance(r.at(t));
+        // rays leaving the surface they start on look for where it changes sign again
+        let mut left = start.abs() >= Self::EPSILON;
+        let sign = if left {
+            start.signum()
+        } else {
+            self.sdf.normal(r.at(t)).dot(r.direction).signum()
+        };
+        for _ in 0..Self::MAX_STEPS {
+            if t > t1 {
+                return None;
+            }
+            let d = sign * self.sdf.distance(r.at(t));
+            if d < Self::EPSILON {
+                if left {
+                    let p = r.at(t);
+                    let n = self.sdf.normal(p);
+                    let onb = ONB::new_from_w(n);
+                    return Some(Hit {
+                        r,
+                        p,
+                        n,
+                        ng: n,
+                        t,
+                        u: (f32::atan2(-n.z, n.x) + std::f32::consts::PI)
+                            * std::f32::consts::FRAC_1_PI
+                            * 0.5,
+                        v: 1.0 - std::f32::consts::FRAC_1_PI * (-n.y).clamp(-1.0, 1.0).acos(),
+                        dpdu: onb.u,
+                        dpdv: onb.v,
+                    });
+                }
+            } else {
+                left = true;
+            }
+            t += maxf(d, Self::EPSILON) / speed;
+        }
+        None
+    }
+
+    fn reflect(&self, h: &Hit) -> Ray {
+        self.mat.on_hit(h)
+    }
+
+    fn color(&self, h: &Hit) -> ColorResult {
+        self.texture.color_at(h)
+    }
+
+    fn generator_pdf(&self, h: &Hit, r: &Ray) -> f32 {
+        self.mat.generator_pdf(h, r)
+    }
+
+    fn material_pdf(&self, h: &Hit, r: &Ray) -> f32 {
+        self.mat.material_pdf(h, r)
+    }
+
+    fn is_specular(&self, _h: &Hit) -> bool {
+        self.mat.is_specular()
+    }
+
+    fn material(&self) -> Option<&Arc<dyn Material + Send + Sync>> {
+        Some(&self.mat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        objects::{
+            instance::Instance,
+            light::{Light, PointLight},
+            material::{LAMBERTIAN, MIRROR},
+            quad::Quad,
+            texture::ConstColorTexture,
+            Object,
+        },
+        sampler::random_f32,
+        vec3::{ray::Ray, vec3::Vec3},
+        viewport::{camera::Camera, ray_color::next_event_ray_color, scene::Scene, Viewport},
+    };
+
+    use super::{Sdf, SdfObject};
+
+    fn object(sdf: Sdf) -> SdfObject {
+        SdfObject::new(
+            sdf,
+            LAMBERTIAN.clone(),
+            Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.7, Vec3::ZERO)),
+        )
+    }
+
+    #[test]
+    fn distances() {
+        let p = Vec3::new(2.0, 0.0, 0.0);
+        assert!((Sdf::sphere(1.0).distance(p) - 1.0).abs() < 1e-6);
+        let half = Vec3::new(1.0, 0.5, 0.5);
+        assert!((Sdf::cuboid(half).distance(p) - 1.0).abs() < 1e-6);
+        assert!((Sdf::cuboid(half).distance(Vec3::ZERO) + 0.5).abs() < 1e-6);
+        // the rounded corner is further away than the sharp one
+        let corner = Vec3::new(2.0, 1.5, 0.0);
+        let rounded = Sdf::rounded_cuboid(half, 0.25).distance(corner);
+        assert!((rounded - (1.25f32 * 1.25 * 2.0).sqrt() + 0.25).abs() < 1e-5);
+        assert!(rounded > Sdf::cuboid(half).distance(corner));
+        assert!((Sdf::torus(1.0, 0.25).distance(Vec3::new(0.0, 0.0, 1.0)) + 0.25).abs() < 1e-6);
+        assert!(Sdf::torus(1.0, 0.25).distance(Vec3::ZERO) > 0.7);
+
+        // copies stop after the count
+        let row = Sdf::sphere(0.5).repeat(Vec3::new(2.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0));
+        assert!((row.distance(Vec3::new(4.0, 0.0, 0.0)) + 0.5).abs() < 1e-5);
+        assert!((row.distance(Vec3::new(-4.0, 0.0, 0.0)) + 0.5).abs() < 1e-5);
+        assert!((row.distance(Vec3::new(6.0, 0.0, 0.0)) - 1.5).abs() < 1e-5);
+        // blends fill the gap between shapes
+        let a = Sdf::sphere(1.0).translate(Vec3::new(-1.1, 0.0, 0.0));
+        let b = Sdf::sphere(1.0).translate(Vec3::new(1.1, 0.0, 0.0));
+        assert!(a.clone().union(b.clone()).distance(Vec3::ZERO) > 0.0);
+        assert!(a.clone().smooth_union(b.clone(), 0.5).distance(Vec3::ZERO) < 0.0);
+        let bitten = Sdf::sphere(1.0).subtract(Sdf::sphere(0.5).translate(Vec3::UP));
+        assert!(bitten.distance(Vec3::UP * 0.9) > 0.0 && bitten.distance(-Vec3::UP * 0.9) < 0.0);
+        // twists leave the middle where it was
+        let bar = Sdf::cuboid(Vec3::new(1.0, 2.0, 0.2));
+        let twisted = bar.clone().twist(1.0);
+        assert_eq!(twisted.distance(p), bar.distance(p));
+        assert!(twisted.lipschitz() > 1.0);
+    }
+
+    #[test]
+    fn inside_bounds() {
+        let sdf = Sdf::rounded_cuboid(Vec3::new(0.3, 1.0, 0.6), 0.1)
+            .twist(0.8)
+            .smooth_union(Sdf::torus(1.0, 0.2).translate(Vec3::UP * 0.5), 0.3)
+            .smooth_subtract(Sdf::sphere(0.4), 0.1)
+            .repeat(Vec3::new(3.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0))
+            .scale(0.5);
+        let (lo, hi) = sdf.bounds();
+        for _ in 0..10000 {
+            let p = Vec3::new(random_f32(), random_f32(), random_f32()) * 6.0 - Vec3::WHITE * 3.0;
+            if sdf.distance(p) < 0.0 {
+                assert!(lo.x <= p.x && p.x <= hi.x, "{:?}", p);
+                assert!(lo.y <= p.y && p.y <= hi.y, "{:?}", p);
+                assert!(lo.z <= p.z && p.z <= hi.z, "{:?}", p);
+            }
+        }
+    }
+
+    #[test]
+    fn sphere_tracing() {
+        let ball = object(Sdf::sphere(1.0).translate(Vec3::FORWARD * 5.0));
+        let h = ball
+            .get_hit(Ray::new(Vec3::ZERO, Vec3::FORWARD * 2.0), 0.001, 100.0)
+            .unwrap();
+        // distances along the ray like every other object
+        assert!((h.t - 2.0).abs() < 1e-3, "{}", h.t);
+        assert!(h.n.dot(-Vec3::FORWARD) > 0.999, "{:?}", h.n);
+        assert!((h.n.length() - 1.0).abs() < 1e-4);
+        // leaving the surface it hit, it finds the other side
+        let through = Ray::new(h.p, Vec3::FORWARD);
+        let exit = ball.get_hit(through, 0.001, 100.0).unwrap();
+        assert!((exit.p.z - 6.0).abs() < 1e-3 && exit.n.dot(Vec3::FORWARD) > 0.999);
+        // and bouncing off, nothing else
+        assert!(ball
+            .get_hit(Ray::new(h.p, -Vec3::FORWARD), 0.001, 100.0)
+            .is_none());
+        assert!(ball
+            .get_hit(
+                Ray::new(Vec3::new(1.5, 0.0, 0.0), Vec3::FORWARD),
+                0.001,
+                100.0
+            )
+            .is_none());
+
+        // twisted shapes need smaller steps to not go through thin parts
+        let blade = object(Sdf::cuboid(Vec3::new(1.0, 1.0, 0.02)).twist(3.0));
+        for i in 0..20 {
+            let y = i as f32 * 0.1 - 1.0;
+            let r = Ray::new(Vec3::new(0.0, y, -5.0), Vec3::FORWARD);
+            let h = blade.get_hit(r, 0.001, 100.0).unwrap();
+            assert!(blade.sdf.distance(h.p).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn sdf_test() {
+        const WIDTH: usize = 150;
+        const HEIGHT: usize = 100;
+        let blob = Sdf::sphere(0.4)
+            .translate(Vec3::new(-0.3, 0.0, 0.0))
+            .smooth_union(Sdf::sphere(0.3).translate(Vec3::new(0.3, 0.2, 0.0)), 0.3)
+            .smooth_union(Sdf::torus(0.35, 0.08).translate(Vec3::UP * -0.3), 0.2)
+            .translate(Vec3::new(-1.5, 0.0, 4.0));
+        let column = Sdf::rounded_cuboid(Vec3::new(0.25, 0.8, 0.25), 0.05)
+            .twist(1.5)
+            .translate(Vec3::new(0.0, 0.0, 4.0));
+        // a box with rows of holes, and rows of smaller holes between them
+        let sponge = Sdf::cuboid(Vec3::WHITE * 0.45)
+            .subtract(Sdf::sphere(0.2).repeat(Vec3::WHITE * 0.3, Vec3::WHITE))
+            .subtract(Sdf::sphere(0.06).repeat(Vec3::WHITE * 0.1, Vec3::WHITE * 4.0))
+            .translate(Vec3::new(1.5, 0.0, 4.0));
+        let objects: Vec<Arc<dyn Object + Send + Sync>> = vec![
+            Arc::new(object(blob)),
+            Arc::new(SdfObject::new(
+                column,
+                MIRROR.clone(),
+                Arc::new(ConstColorTexture::new(Vec3::new(0.9, 0.8, 0.5), Vec3::ZERO)),
+            )),
+            Arc::new(object(sponge)),
+            Arc::new(Quad::new(
+                Vec3::new(-5.0, -0.8, -2.0),
+                Vec3::new(10.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 10.0),
+                LAMBERTIAN.clone(),
+                Vec3::ZERO,
+                Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.5, Vec3::ZERO)),
+            )),
+        ];
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![Arc::new(PointLight::new(
+            Vec3::new(1.0, 3.0, 1.0),
+            Vec3::WHITE * 20.0,
+        ))];
+        let scene =
+            Scene::new(vec![Instance::new(objects.into())], 0.001, 100.0).with_lights(lights);
+        // far from the blend the blob is its sphere, with the normal of the sphere
+        let (hit, _) = scene
+            .get_hit(Ray::new(
+                Vec3::new(-4.0, 0.2, 4.0),
+                Vec3::new(1.0, 0.0, 0.0),
+            ))
+            .unwrap();
+        let ball = Vec3::new(-1.8, 0.0, 4.0);
+        assert!(
+            (hit.p.x - (ball.x - 0.12f32.sqrt())).abs() < 1e-3,
+            "{:?}",
+            hit.p
+        );
+        assert!(hit.n.dot((hit.p - ball).unit()) > 0.999, "{:?}", hit.n);
+        // a row of holes goes all the way through the sponge, beside it the front is solid
+        let r = Ray::new(Vec3::new(1.5, 0.0, 0.0), Vec3::FORWARD);
+        assert!(scene.get_hit(r).is_none());
+        let (hit, _) = scene
+            .get_hit(Ray::new(Vec3::new(1.65, 0.15, 0.0), Vec3::FORWARD))
+            .unwrap();
+        assert!((hit.p.z - 3.55).abs() < 1e-3, "{:?}", hit.p);
+        assert!(hit.n.dot(-Vec3::FORWARD) > 0.999, "{:?}", hit.n);
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::new(0.0, 0.8, 0.0),
+            Vec3::UP,
+            Vec3::new(0.0, -0.2, 1.0),
+            50.0,
+            0.0,
+        );
+        Viewport::new(
+            cam,
+            scene,
+            Arc::new(next_event_ray_color),
+            WIDTH,
+            HEIGHT,
+            8,
+            4,
+            Vec3::new(0.4, 0.5, 0.7),
+            2.2,
+        )
+        .render()
+        .save("test_out/sdf_test.png")
+        .unwrap();
+    }
+}
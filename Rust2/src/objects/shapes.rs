@@ -0,0 +1,1048 @@
+use std::{
+    f32::consts::{FRAC_PI_2, PI},
+    sync::Arc,
+};
+
+use crate::{
+    onb::ONB,
+    sampler::random_f32,
+    vec3::{ray::Ray, vec3::Vec3},
+};
+
+use super::{
+    aabb::{maxf, minf, Interval},
+    hit::Hit,
+    material::Material,
+    texture::{ColorResult, Texture},
+    Object,
+};
+
+/// Point on a shape in its own frame, where the axis of the shape is `z`
+#[derive(Debug, Clone, Copy)]
+pub struct SurfacePoint {
+    pub p: Vec3,
+    // outward normal
+    pub n: Vec3,
+    pub u: f32,
+    pub v: f32,
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+}
+
+/// Analytic surface around the `z` axis, placed in the scene by `Shape`
+pub trait Geometry {
+    /// Closest hit with a distance between `mint` and `maxt`
+    fn hit(&self, o: Vec3, d: Vec3, mint: f32, maxt: f32) -> Option<(f32, SurfacePoint)>;
+    /// Bounds when the origin of the frame is at `origin` and `z` along the unit `axis`
+    fn bounds(&self, origin: Vec3, axis: Vec3) -> (Vec3, Vec3);
+    fn area(&self) -> f32;
+    /// Uniformly distributed point on the surface and the outward normal there
+    fn sample(&self) -> (Vec3, Vec3);
+    /// Outward normal at `p` on the surface
+    fn normal(&self, p: Vec3) -> Vec3;
+}
+
+/// Surfaces that can pick uniformly distributed points, so they can emit as a `ShapeLight`
+pub trait Surface: Object {
+    fn area(&self) -> f32;
+    /// Uniformly distributed point on the surface and the outward normal there
+    fn sample_point(&self) -> (Vec3, Vec3);
+    /// Outward normal at `p` on the surface
+    fn normal_at(&self, p: Vec3) -> Vec3;
+}
+
+/// Flat ring around the axis, a full disk when `inner_radius` is 0. It faces along the axis
+#[derive(Debug, Clone)]
+pub struct Disk {
+    pub radius: f32,
+    pub inner_radius: f32,
+}
+
+/// Side of a cylinder standing on the origin, `closed` ones have disks on both ends
+#[derive(Debug, Clone)]
+pub struct Cylinder {
+    pub radius: f32,
+    pub height: f32,
+    pub closed: bool,
+}
+
+/// Cone with its base on the origin and the apex at `height`, `closed` ones have a base disk
+#[derive(Debug, Clone)]
+pub struct Cone {
+    pub radius: f32,
+    pub height: f32,
+    pub closed: bool,
+}
+
+/// Ring with its center line at `major_radius` around the axis and tubes of `minor_radius`
+#[derive(Debug, Clone)]
+pub struct Torus {
+    pub major_radius: f32,
+    pub minor_radius: f32,
+}
+
+/// Cylinder with half spheres on its ends, their centers are on the origin and at `height`
+#[derive(Debug, Clone)]
+pub struct Capsule {
+    pub radius: f32,
+    pub height: f32,
+}
+
+impl Disk {
+    pub fn new(radius: f32, inner_radius: f32) -> Self {
+        assert!(0.0 <= inner_radius && inner_radius < radius);
+        Self {
+            radius,
+            inner_radius,
+        }
+    }
+}
+
+impl Cylinder {
+    pub fn new(radius: f32, height: f32, closed: bool) -> Self {
+        Self {
+            radius,
+            height,
+            closed,
+        }
+    }
+}
+
+impl Cone {
+    pub fn new(radius: f32, height: f32, closed: bool) -> Self {
+        Self {
+            radius,
+            height,
+            closed,
+        }
+    }
+}
+
+impl Torus {
+    pub fn new(major_radius: f32, minor_radius: f32) -> Self {
+        assert!(0.0 < minor_radius && minor_radius < major_radius);
+        Self {
+            major_radius,
+            minor_radius,
+        }
+    }
+}
+
+impl Capsule {
+    pub fn new(radius: f32, height: f32) -> Self {
+        Self { radius, height }
+    }
+}
+
+/// Geometry placed at `origin` with its axis along `axis`
+#[derive(Clone)]
+pub struct Shape<G> {
+    pub geometry: G,
+    pub mat: Arc<dyn Material + Send + Sync>,
+    pub texture: Arc<dyn Texture + Send + Sync>,
+
+    origin: Vec3,
+    // `w` is the axis
+    onb: ONB,
+}
+
+impl<G: Geometry> Shape<G> {
+    pub fn new(
+        geometry: G,
+        origin: Vec3,
+        axis: Vec3,
+        mat: Arc<dyn Material + Send + Sync>,
+        texture: Arc<dyn Texture + Send + Sync>,
+    ) -> Self {
+        Self {
+            geometry,
+            mat,
+            texture,
+            origin,
+            onb: ONB::new_from_w(axis),
+        }
+    }
+}
+
+impl<G: Geometry> Object for Shape<G> {
+    fn get_aabb(&self) -> (Interval, Interval, Interval) {
+        let (lo, hi) = self.geometry.bounds(self.origin, self.onb.w);
+        // flat shapes facing an axis still get some thickness
+        let pad = Vec3::WHITE * 1e-4;
+        Interval::from_vecs(lo - pad, hi + pad)
+    }
+
+    fn get_hit(&self, r: Ray, mint: f32, maxt: f32) -> Option<Hit> {
+        // a rotation and translation, so distances along the ray stay the same
+        let o = self.onb.from_global(r.origin - self.origin);
+        let d = self.onb.from_global(r.direction);
+        let (t, s) = self.geometry.hit(o, d, mint, maxt)?;
+        let n = self.onb.from_local(s.n);
+        Some(Hit {
+            r,
+            p: self.origin + self.onb.from_local(s.p),
+            n,
+            ng: n,
+            t,
+            u: s.u,
+            v: s.v,
+            dpdu: self.onb.from_local(s.dpdu),
+            dpdv: self.onb.from_local(s.dpdv),
+        })
+    }
+
+    fn reflect(&self, h: &Hit) -> Ray {
+        self.mat.on_hit(h)
+    }
+
+    fn color(&self, h: &Hit) -> ColorResult {
+        self.texture.color_at(h)
+    }
+
+    fn generator_pdf(&self, h: &Hit, r: &Ray) -> f32 {
+        self.mat.generator_pdf(h, r)
+    }
+
+    fn material_pdf(&self, h: &Hit, r: &Ray) -> f32 {
+        self.mat.material_pdf(h, r)
+    }
+
+    fn is_specular(&self, _h: &Hit) -> bool {
+        self.mat.is_specular()
+    }
+
+    fn material(&self) -> Option<&Arc<dyn Material + Send + Sync>> {
+        Some(&self.mat)
+    }
+}
+
+impl<G: Geometry> Surface for Shape<G> {
+    fn area(&self) -> f32 {
+        self.geometry.area()
+    }
+
+    fn sample_point(&self) -> (Vec3, Vec3) {
+        let (p, n) = self.geometry.sample();
+        (self.origin + self.onb.from_local(p), self.onb.from_local(n))
+    }
+
+    fn normal_at(&self, p: Vec3) -> Vec3 {
+        let local = self.onb.from_global(p - self.origin);
+        self.onb.from_local(self.geometry.normal(local))
+    }
+}
+
+const Z: Vec3 = Vec3 {
+    x: 0.0,
+    y: 0.0,
+    z: 1.0,
+};
+
+fn union(a: (Vec3, Vec3), b: (Vec3, Vec3)) -> (Vec3, Vec3) {
+    (
+        Vec3::new(minf(a.0.x, b.0.x), minf(a.0.y, b.0.y), minf(a.0.z, b.0.z)),
+        Vec3::new(maxf(a.1.x, b.1.x), maxf(a.1.y, b.1.y), maxf(a.1.z, b.1.z)),
+    )
+}
+
+// bounds of a circle of `radius` around `center` facing along the unit `axis`
+fn disk_bounds(center: Vec3, axis: Vec3, radius: f32) -> (Vec3, Vec3) {
+    let extent = |a: f32| radius * (1.0 - a * a).max(0.0).sqrt();
+    let e = Vec3::new(extent(axis.x), extent(axis.y), extent(axis.z));
+    (center - e, center + e)
+}
+
+fn sphere_bounds(center: Vec3, radius: f32) -> (Vec3, Vec3) {
+    (center - Vec3::WHITE * radius, center + Vec3::WHITE * radius)
+}
+
+// roots of `a t² + 2 b t + c`, smallest first
+fn quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
+    if a.abs() < 1e-12 {
+        if b.abs() < 1e-12 {
+            return None;
+        }
+        let t = -c / (2.0 * b);
+        return Some((t, t));
+    }
+    let d = b * b - a * c;
+    if d < 0.0 {
+        return None;
+    }
+    let (t0, t1) = ((-b - d.sqrt()) / a, (-b + d.sqrt()) / a);
+    Some((minf(t0, t1), maxf(t0, t1)))
+}
+
+// keeps the closer of two hits
+fn closer(
+    a: Option<(f32, SurfacePoint)>,
+    b: Option<(f32, SurfacePoint)>,
+) -> Option<(f32, SurfacePoint)> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if b.0 < a.0 { b } else { a }),
+        (a, b) => a.or(b),
+    }
+}
+
+// angle around the axis turned into u, and the derivative of the point along it
+fn around(p: Vec3) -> (f32, Vec3) {
+    let phi = p.y.atan2(p.x);
+    (
+        (phi + PI) / (2.0 * PI),
+        Vec3::new(-p.y, p.x, 0.0) * (2.0 * PI),
+    )
+}
+
+// unit direction away from the axis, any of them on the axis
+fn radial(p: Vec3) -> Vec3 {
+    let rho = (p.x * p.x + p.y * p.y).sqrt();
+    if rho < 1e-7 {
+        Vec3::new(1.0, 0.0, 0.0)
+    } else {
+        Vec3::new(p.x / rho, p.y / rho, 0.0)
+    }
+}
+
+fn random_angle() -> f32 {
+    random_f32() * 2.0 * PI
+}
+
+// hit with the disk at height `z` facing along `normal`
+fn hit_disk(
+    disk: &Disk,
+    z: f32,
+    normal: Vec3,
+    o: Vec3,
+    d: Vec3,
+    mint: f32,
+    maxt: f32,
+) -> Option<(f32, SurfacePoint)> {
+    if d.z.abs() < 1e-12 {
+        return None;
+    }
+    let t = (z - o.z) / d.z;
+    if t < mint || t > maxt {
+        return None;
+    }
+    let p = o + d * t;
+    let rho2 = p.x * p.x + p.y * p.y;
+    if rho2 > disk.radius * disk.radius || rho2 < disk.inner_radius * disk.inner_radius {
+        return None;
+    }
+    let (u, dpdu) = around(p);
+    let width = disk.radius - disk.inner_radius;
+    Some((
+        t,
+        SurfacePoint {
+            p: Vec3::new(p.x, p.y, z),
+            n: normal,
+            u,
+            v: (disk.radius - rho2.sqrt()) / width,
+            dpdu,
+            dpdv: -radial(p) * width,
+        },
+    ))
+}
+
+fn sample_disk(disk: &Disk, z: f32) -> Vec3 {
+    let r2 = disk.inner_radius * disk.inner_radius;
+    let rho = (r2 + random_f32() * (disk.radius * disk.radius - r2)).sqrt();
+    let phi = random_angle();
+    Vec3::new(rho * phi.cos(), rho * phi.sin(), z)
+}
+
+impl Geometry for Disk {
+    fn hit(&self, o: Vec3, d: Vec3, mint: f32, maxt: f32) -> Option<(f32, SurfacePoint)> {
+        hit_disk(self, 0.0, Z, o, d, mint, maxt)
+    }
+
+    fn bounds(&self, origin: Vec3, axis: Vec3) -> (Vec3, Vec3) {
+        disk_bounds(origin, axis, self.radius)
+    }
+
+    fn area(&self) -> f32 {
+        PI * (self.radius * self.radius - self.inner_radius * self.inner_radius)
+    }
+
+    fn sample(&self) -> (Vec3, Vec3) {
+        (sample_disk(self, 0.0), Z)
+    }
+
+    fn normal(&self, _p: Vec3) -> Vec3 {
+        Z
+    }
+}
+
+impl Cylinder {
+    fn caps(&self) -> Disk {
+        Disk::new(self.radius, 0.0)
+    }
+}
+
+impl Geometry for Cylinder {
+    fn hit(&self, o: Vec3, d: Vec3, mint: f32, maxt: f32) -> Option<(f32, SurfacePoint)> {
+        let mut best = None;
+        let a = d.x * d.x + d.y * d.y;
+        let b = o.x * d.x + o.y * d.y;
+        let c = o.x * o.x + o.y * o.y - self.radius * self.radius;
+        if let Some((t0, t1)) = quadratic(a, b, c).filter(|_| a > 1e-12) {
+            for t in [t0, t1] {
+                let p = o + d * t;
+                if t < mint || t > maxt || p.z < 0.0 || p.z > self.height {
+                    continue;
+                }
+                let (u, dpdu) = around(p);
+                best = Some((
+                    t,
+                    SurfacePoint {
+                        p,
+                        n: radial(p),
+                        u,
+                        v: p.z / self.height,
+                        dpdu,
+                        dpdv: Z * self.height,
+                    },
+                ));
+                break;
+            }
+        }
+        if self.closed {
+            let caps = self.caps();
+            best = closer(best, hit_disk(&caps, 0.0, -Z, o, d, mint, maxt));
+            best = closer(best, hit_disk(&caps, self.height, Z, o, d, mint, maxt));
+        }
+        best
+    }
+
+    fn bounds(&self, origin: Vec3, axis: Vec3) -> (Vec3, Vec3) {
+        union(
+            disk_bounds(origin, axis, self.radius),
+            disk_bounds(origin + axis * self.height, axis, self.radius),
+        )
+    }
+
+    fn area(&self) -> f32 {
+        let side = 2.0 * PI * self.radius * self.height;
+        if self.closed {
+            side + 2.0 * PI * self.radius * self.radius
+        } else {
+            side
+        }
+    }
+
+    fn sample(&self) -> (Vec3, Vec3) {
+        let side = 2.0 * PI * self.radius * self.height;
+        let pick = random_f32() * self.area();
+        if pick < side {
+            let phi = random_angle();
+            let n = Vec3::new(phi.cos(), phi.sin(), 0.0);
+            return (n * self.radius + Z * (random_f32() * self.height), n);
+        }
+        if pick < side + PI * self.radius * self.radius {
+            (sample_disk(&self.caps(), 0.0), -Z)
+        } else {
+            (sample_disk(&self.caps(), self.height), Z)
+        }
+    }
+
+    fn normal(&self, p: Vec3) -> Vec3 {
+        let side = ((p.x * p.x + p.y * p.y).sqrt() - self.radius).abs();
+        if self.closed && p.z.abs() < side {
+            -Z
+        } else if self.closed && (p.z - self.height).abs() < side {
+            Z
+        } else {
+            radial(p)
+        }
+    }
+}
+
+impl Cone {
+    fn base(&self) -> Disk {
+        Disk::new(self.radius, 0.0)
+    }
+
+    fn side_area(&self) -> f32 {
+        PI * self.radius * (self.radius * self.radius + self.height * self.height).sqrt()
+    }
+
+    // outward normal of the side in the direction `radial` from the axis
+    fn side_normal(&self, radial: Vec3) -> Vec3 {
+        (radial * self.height + Z * self.radius).unit()
+    }
+}
+
+impl Geometry for Cone {
+    fn hit(&self, o: Vec3, d: Vec3, mint: f32, maxt: f32) -> Option<(f32, SurfacePoint)> {
+        let mut best = None;
+        // x² + y² = (k (h - z))²
+        let k = self.radius / self.height;
+        let k2 = k * k;
+        let h = self.height - o.z;
+        let a = d.x * d.x + d.y * d.y - k2 * d.z * d.z;
+        let b = o.x * d.x + o.y * d.y + k2 * h * d.z;
+        let c = o.x * o.x + o.y * o.y - k2 * h * h;
+        if let Some((t0, t1)) = quadratic(a, b, c) {
+            for t in [t0, t1] {
+                let p = o + d * t;
+                if t < mint || t > maxt || p.z < 0.0 || p.z > self.height {
+                    continue;
+                }
+                let (u, dpdu) = around(p);
+                let radial = radial(p);
+                best = Some((
+                    t,
+                    SurfacePoint {
+                        p,
+                        n: self.side_normal(radial),
+                        u,
+                        v: p.z / self.height,
+                        dpdu,
+                        dpdv: Z * self.height - radial * self.radius,
+                    },
+                ));
+                break;
+            }
+        }
+        if self.closed {
+            best = closer(best, hit_disk(&self.base(), 0.0, -Z, o, d, mint, maxt));
+        }
+        best
+    }
+
+    fn bounds(&self, origin: Vec3, axis: Vec3) -> (Vec3, Vec3) {
+        let apex = origin + axis * self.height;
+        union(disk_bounds(origin, axis, self.radius), (apex, apex))
+    }
+
+    fn area(&self) -> f32 {
+        if self.closed {
+            self.side_area() + PI * self.radius * self.radius
+        } else {
+            self.side_area()
+        }
+    }
+
+    fn sample(&self) -> (Vec3, Vec3) {
+        if random_f32() * self.area() >= self.side_area() {
+            return (sample_disk(&self.base(), 0.0), -Z);
+        }
+        // the circumference grows linearly away from the apex
+        let s = random_f32().sqrt();
+        let phi = random_angle();
+        let radial = Vec3::new(phi.cos(), phi.sin(), 0.0);
+        let p = radial * (self.radius * s) + Z * (self.height * (1.0 - s));
+        (p, self.side_normal(radial))
+    }
+
+    fn normal(&self, p: Vec3) -> Vec3 {
+        let n = self.side_normal(radial(p));
+        // distance to the side along its normal
+        let side = (p - Z * self.height).dot(n).abs();
+        if self.closed && p.z.abs() < side {
+            -Z
+        } else {
+            n
+        }
+    }
+}
+
+// real roots of `x³ + a x² + b x + c`
+fn solve_cubic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    const EPS: f64 = 1e-12;
+    // x = y - a / 3 leaves y³ + 3 p y + 2 q
+    let p = (b - a * a / 3.0) / 3.0;
+    let q = (2.0 * a * a * a / 27.0 - a * b / 3.0 + c) / 2.0;
+    let d = q * q + p * p * p;
+    let roots = if d.abs() < EPS {
+        if q.abs() < EPS {
+            vec![0.0]
+        } else {
+            let u = (-q).cbrt();
+            vec![2.0 * u, -u]
+        }
+    } else if d < 0.0 {
+        let phi = (-q / (-p * p * p).sqrt()).clamp(-1.0, 1.0).acos() / 3.0;
+        let t = 2.0 * (-p).sqrt();
+        let third = std::f64::consts::PI / 3.0;
+        vec![
+            t * phi.cos(),
+            -t * (phi + third).cos(),
+            -t * (phi - third).cos(),
+        ]
+    } else {
+        let sd = d.sqrt();
+        vec![(sd - q).cbrt() - (sd + q).cbrt()]
+    };
+    roots.into_iter().map(|y| y - a / 3.0).collect()
+}
+
+// real roots of `x² + b x + c`
+fn solve_monic_quadratic(b: f64, c: f64) -> Vec<f64> {
+    let d = b * b / 4.0 - c;
+    if d < 0.0 {
+        return vec![];
+    }
+    let sd = d.sqrt();
+    vec![-b / 2.0 - sd, -b / 2.0 + sd]
+}
+
+// real roots of `x⁴ + b x³ + c x² + d x + e` with Ferrari's method
+fn solve_quartic(b: f64, c: f64, d: f64, e: f64) -> Vec<f64> {
+    const EPS: f64 = 1e-12;
+    // x = y - b / 4 leaves y⁴ + p y² + q y + r
+    let b2 = b * b;
+    let p = c - 3.0 * b2 / 8.0;
+    let q = d - b * c / 2.0 + b2 * b / 8.0;
+    let r = e - b * d / 4.0 + b2 * c / 16.0 - 3.0 * b2 * b2 / 256.0;
+    let roots = if r.abs() < EPS {
+        let mut roots = solve_cubic(0.0, p, q);
+        roots.push(0.0);
+        roots
+    } else {
+        // splits into two quadratics around a root of the resolvent cubic
+        let z = solve_cubic(-p / 2.0, -r, r * p / 2.0 - q * q / 8.0)[0];
+        let sqrt_or_zero = |x: f64| {
+            if x.abs() < EPS {
+                Some(0.0)
+            } else if x > 0.0 {
+                Some(x.sqrt())
+            } else {
+                None
+            }
+        };
+        let (Some(u), Some(v)) = (sqrt_or_zero(z * z - r), sqrt_or_zero(2.0 * z - p)) else {
+            return vec![];
+        };
+        let v = if q < 0.0 { -v } else { v };
+        let mut roots = solve_monic_quadratic(v, z - u);
+        roots.extend(solve_monic_quadratic(-v, z + u));
+        roots
+    };
+    roots
+        .into_iter()
+        .map(|y| {
+            // polished on the original polynomial
+            let mut x = y - b / 4.0;
+            for _ in 0..2 {
+                let f = (((x + b) * x + c) * x + d) * x + e;
+                let df = ((4.0 * x + 3.0 * b) * x + 2.0 * c) * x + d;
+                if df.abs() > EPS {
+                    x -= f / df;
+                }
+            }
+            x
+        })
+        .collect()
+}
+
+impl Torus {
+    fn point(&self, phi: f32, theta: f32) -> SurfacePoint {
+        let (r, rr) = (self.minor_radius, self.major_radius);
+        let radial = Vec3::new(phi.cos(), phi.sin(), 0.0);
+        let n = radial * theta.cos() + Z * theta.sin();
+        let p = radial * rr + n * r;
+        let (u, dpdu) = around(p);
+        SurfacePoint {
+            p,
+            n,
+            u,
+            v: (theta + PI) / (2.0 * PI),
+            dpdu,
+            dpdv: (Z * theta.cos() - radial * theta.sin()) * (2.0 * PI * r),
+        }
+    }
+}
+
+impl Geometry for Torus {
+    fn hit(&self, o: Vec3, d: Vec3, mint: f32, maxt: f32) -> Option<(f32, SurfacePoint)> {
+        let (r, rr) = (self.minor_radius as f64, self.major_radius as f64);
+        let len = d.length() as f64;
+        let dir = [d.x as f64 / len, d.y as f64 / len, d.z as f64 / len];
+        // starts from the point closest to the center to keep the coefficients small
+        let o = [o.x as f64, o.y as f64, o.z as f64];
+        let s0 = -(o[0] * dir[0] + o[1] * dir[1] + o[2] * dir[2]);
+        let o = [o[0] + dir[0] * s0, o[1] + dir[1] * s0, o[2] + dir[2] * s0];
+        // (|p|² + R² - r²)² = 4 R² (x² + y²)
+        let n = o[0] * dir[0] + o[1] * dir[1] + o[2] * dir[2];
+        let k = o[0] * o[0] + o[1] * o[1] + o[2] * o[2] + rr * rr - r * r;
+        let r4 = 4.0 * rr * rr;
+        let roots = solve_quartic(
+            4.0 * n,
+            4.0 * n * n + 2.0 * k - r4 * (dir[0] * dir[0] + dir[1] * dir[1]),
+            4.0 * n * k - 2.0 * r4 * (o[0] * dir[0] + o[1] * dir[1]),
+            k * k - r4 * (o[0] * o[0] + o[1] * o[1]),
+        );
+        let t = roots
+            .into_iter()
+            .map(|s| ((s + s0) / len) as f32)
+            .filter(|t| *t >= mint && *t <= maxt)
+            .min_by(|a, b| a.total_cmp(b))?;
+        let p = Vec3::new(
+            (o[0] + dir[0] * (t as f64 * len - s0)) as f32,
+            (o[1] + dir[1] * (t as f64 * len - s0)) as f32,
+            (o[2] + dir[2] * (t as f64 * len - s0)) as f32,
+        );
+        let rho = (p.x * p.x + p.y * p.y).sqrt();
+        Some((
+            t,
+            self.point(p.y.atan2(p.x), p.z.atan2(rho - self.major_radius)),
+        ))
+    }
+
+    fn bounds(&self, origin: Vec3, axis: Vec3) -> (Vec3, Vec3) {
+        let (lo, hi) = disk_bounds(origin, axis, self.major_radius);
+        let tube = Vec3::WHITE * self.minor_radius;
+        (lo - tube, hi + tube)
+    }
+
+    fn area(&self) -> f32 {
+        4.0 * PI * PI * self.major_radius * self.minor_radius
+    }
+
+    fn sample(&self) -> (Vec3, Vec3) {
+        // the outer side of the tube is longer than the inner one
+        let outer = self.major_radius + self.minor_radius;
+        let theta = loop {
+            let theta = random_angle();
+            if random_f32() * outer <= self.major_radius + self.minor_radius * theta.cos() {
+                break theta;
+            }
+        };
+        let s = self.point(random_angle(), theta);
+        (s.p, s.n)
+    }
+
+    fn normal(&self, p: Vec3) -> Vec3 {
+        (p - radial(p) * self.major_radius).unit()
+    }
+}
+
+impl Capsule {
+    fn side_area(&self) -> f32 {
+        2.0 * PI * self.radius * self.height
+    }
+
+    // length of the profile from one pole to the other
+    fn profile(&self) -> f32 {
+        self.height + PI * self.radius
+    }
+
+    // hit on the half sphere around `z` on the side of `sign`
+    fn hit_end(
+        &self,
+        z: f32,
+        sign: f32,
+        o: Vec3,
+        d: Vec3,
+        mint: f32,
+        maxt: f32,
+    ) -> Option<(f32, SurfacePoint)> {
+        let oc = o - Z * z;
+        let (t0, t1) = quadratic(d.dot(d), oc.dot(d), oc.dot(oc) - self.radius * self.radius)?;
+        [t0, t1]
+            .into_iter()
+            .find(|t| *t >= mint && *t <= maxt && ((o + d * *t).z - z) * sign >= 0.0)
+            .map(|t| (t, self.point(o + d * t)))
+    }
+
+    fn point(&self, p: Vec3) -> SurfacePoint {
+        let n = self.normal(p);
+        let radial = radial(p);
+        let nr = n.dot(radial);
+        let (u, dpdu) = around(p);
+        // distance along the profile from the bottom pole
+        let s = if p.z < 0.0 {
+            self.radius * (-n.z).clamp(-1.0, 1.0).acos()
+        } else if p.z > self.height {
+            FRAC_PI_2 * self.radius + self.height + self.radius * n.z.clamp(-1.0, 1.0).asin()
+        } else {
+            FRAC_PI_2 * self.radius + p.z
+        };
+        SurfacePoint {
+            p,
+            n,
+            u,
+            v: s / self.profile(),
+            dpdu,
+            dpdv: (Z * nr - radial * n.z) * self.profile(),
+        }
+    }
+}
+
+impl Geometry for Capsule {
+    fn hit(&self, o: Vec3, d: Vec3, mint: f32, maxt: f32) -> Option<(f32, SurfacePoint)> {
+        let side = Cylinder::new(self.radius, self.height, false)
+            .hit(o, d, mint, maxt)
+            .map(|(t, s)| (t, self.point(s.p)));
+        let bottom = self.hit_end(0.0, -1.0, o, d, mint, maxt);
+        let top = self.hit_end(self.height, 1.0, o, d, mint, maxt);
+        closer(closer(side, bottom), top)
+    }
+
+    fn bounds(&self, origin: Vec3, axis: Vec3) -> (Vec3, Vec3) {
+        union(
+            sphere_bounds(origin, self.radius),
+            sphere_bounds(origin + axis * self.height, self.radius),
+        )
+    }
+
+    fn area(&self) -> f32 {
+        self.side_area() + 4.0 * PI * self.radius * self.radius
+    }
+
+    fn sample(&self) -> (Vec3, Vec3) {
+        let phi = random_angle();
+        if random_f32() * self.area() < self.side_area() {
+            let n = Vec3::new(phi.cos(), phi.sin(), 0.0);
+            return (n * self.radius + Z * (random_f32() * self.height), n);
+        }
+        // the two ends make up a whole sphere
+        let z = 1.0 - 2.0 * random_f32();
+        let rho = (1.0 - z * z).max(0.0).sqrt();
+        let n = Vec3::new(rho * phi.cos(), rho * phi.sin(), z);
+        let center = if z > 0.0 { Z * self.height } else { Vec3::ZERO };
+        (center + n * self.radius, n)
+    }
+
+    fn normal(&self, p: Vec3) -> Vec3 {
+        if p.z < 0.0 {
+            p.unit()
+        } else if p.z > self.height {
+            (p - Z * self.height).unit()
+        } else {
+            radial(p)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        objects::{
+            instance::Instance,
+            light::{Light, ShapeLight},
+            material::{LAMBERTIAN, MIRROR},
+            quad::Quad,
+            texture::ConstColorTexture,
+            Object,
+        },
+        vec3::{ray::Ray, vec3::Vec3},
+        viewport::{camera::Camera, ray_color::next_event_ray_color, scene::Scene, Viewport},
+    };
+
+    use super::*;
+
+    fn shape<G: Geometry + Send + Sync + 'static>(
+        geometry: G,
+        origin: Vec3,
+        axis: Vec3,
+    ) -> Arc<dyn Surface + Send + Sync> {
+        Arc::new(Shape::new(
+            geometry,
+            origin,
+            axis,
+            LAMBERTIAN.clone(),
+            Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.7, Vec3::ZERO)),
+        ))
+    }
+
+    fn all_shapes(origin: Vec3, axis: Vec3) -> Vec<Arc<dyn Surface + Send + Sync>> {
+        vec![
+            shape(Disk::new(1.0, 0.3), origin, axis),
+            shape(Cylinder::new(0.5, 1.5, false), origin, axis),
+            shape(Cylinder::new(0.5, 1.5, true), origin, axis),
+            shape(Cone::new(0.6, 1.2, false), origin, axis),
+            shape(Cone::new(0.6, 1.2, true), origin, axis),
+            shape(Torus::new(1.0, 0.25), origin, axis),
+            shape(Capsule::new(0.4, 1.0), origin, axis),
+        ]
+    }
+
+    #[test]
+    fn samples_are_on_the_surface() {
+        let axis = Vec3::new(0.3, 1.0, -0.6).unit();
+        for s in all_shapes(Vec3::new(1.0, -2.0, 3.0), axis) {
+            let (x, y, z) = s.get_aabb();
+            for _ in 0..200 {
+                let (p, n) = s.sample_point();
+                assert!((n.length() - 1.0).abs() < 1e-4);
+                assert!(s.normal_at(p).dot(n) > 0.999, "{:?} {:?}", p, n);
+                assert!(x.min <= p.x && p.x <= x.max);
+                assert!(y.min <= p.y && p.y <= y.max);
+                assert!(z.min <= p.z && p.z <= z.max);
+                // coming back along the normal finds the same point
+                let h = s.get_hit(Ray::new(p + n * 0.01, -n), 0.0, 1.0).unwrap();
+                assert!((h.p - p).length() < 1e-3, "{:?} {:?}", h.p, p);
+                assert!((h.t - 0.01).abs() < 1e-3);
+                assert!(h.ng.dot(n) > 0.999, "{:?} {:?}", h.ng, n);
+                assert!((0.0..=1.0).contains(&h.u) && (0.0..=1.0).contains(&h.v));
+                let tangents = h.dpdu.cross(h.dpdv);
+                assert!(tangents.length2() < 1e-10 || tangents.unit().dot(n).abs() > 0.99);
+            }
+        }
+    }
+
+    #[test]
+    fn shape_hits() {
+        fn up<G: Geometry + Send + Sync + 'static>(g: G) -> Arc<dyn Surface + Send + Sync> {
+            shape(g, Vec3::ZERO, Vec3::UP)
+        }
+        let along = |y: f32| Ray::new(Vec3::new(0.0, y, 0.0), Vec3::UP);
+        let across = |y: f32| Ray::new(Vec3::new(0.0, y, -5.0), Vec3::FORWARD);
+        let t = |s: &Arc<dyn Surface + Send + Sync>, r: Ray| s.get_hit(r, 0.0, 100.0).map(|h| h.t);
+
+        // rays along the axis pass open cylinders and the hole of rings
+        assert!(t(&up(Cylinder::new(0.5, 1.0, false)), along(-1.0)).is_none());
+        let closed = up(Cylinder::new(0.5, 1.0, true));
+        let h = closed.get_hit(along(-1.0), 0.0, 100.0).unwrap();
+        assert!((h.t - 1.0).abs() < 1e-5 && h.ng.dot(Vec3::DOWN) > 0.9999);
+        assert!(t(&up(Disk::new(1.0, 0.3)), along(-1.0)).is_none());
+        assert!(t(&up(Torus::new(1.0, 0.25)), along(-1.0)).is_none());
+
+        let torus = up(Torus::new(1.0, 0.25));
+        assert!((t(&torus, across(0.0)).unwrap() - 3.75).abs() < 1e-4);
+        assert!((t(&torus, across(0.2)).unwrap() - (5.0 - 1.0 - 0.15)).abs() < 1e-4);
+        // half way up the cone is half as wide
+        let cone = up(Cone::new(1.0, 2.0, false));
+        assert!((t(&cone, across(1.0)).unwrap() - 4.5).abs() < 1e-4);
+        assert!(t(&cone, across(2.5)).is_none());
+        let capsule = up(Capsule::new(0.5, 1.0));
+        assert!((t(&capsule, along(-2.0)).unwrap() - 1.5).abs() < 1e-5);
+        assert!((t(&capsule, across(0.5)).unwrap() - 4.5).abs() < 1e-4);
+        assert!((t(&capsule, across(1.3)).unwrap() - 4.6).abs() < 1e-4);
+
+        // flat on the floor the disk is only as thick as the padding
+        let (x, y, _) = up(Disk::new(2.0, 0.0)).get_aabb();
+        assert!((x.max - 2.0).abs() < 1e-3 && y.max - y.min < 1e-3);
+        let (x, y, z) = shape(Cylinder::new(0.5, 3.0, false), Vec3::ZERO, Vec3::FORWARD).get_aabb();
+        assert!((z.max - 3.0).abs() < 1e-3 && (x.max - 0.5).abs() < 1e-3);
+        assert!((y.min + 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn shape_lights_match_pdf() {
+        let p = Vec3::new(0.3, -2.0, 0.2);
+        let shapes = [
+            shape(Disk::new(0.5, 0.0), Vec3::UP, Vec3::DOWN),
+            shape(Cylinder::new(0.3, 0.5, true), Vec3::UP, Vec3::UP),
+            shape(
+                Capsule::new(0.3, 0.5),
+                Vec3::UP,
+                Vec3::new(1.0, 1.0, 0.0).unit(),
+            ),
+            // seen at an angle, the torus hides parts of its own outer side
+            shape(
+                Torus::new(0.5, 0.2),
+                Vec3::UP,
+                Vec3::new(0.0, 1.0, 1.0).unit(),
+            ),
+        ];
+        for s in shapes {
+            let light = ShapeLight::new(s, Vec3::WHITE);
+            for _ in 0..100 {
+                // points on the far side don't light `p`
+                let Some(sample) = light.sample(p) else {
+                    continue;
+                };
+                let pdf = light.pdf(p, sample.dir);
+                assert!(
+                    (sample.pdf - pdf).abs() <= 1e-2 * pdf,
+                    "{} != {}",
+                    sample.pdf,
+                    pdf
+                );
+                let (t, radiance) = light.intersect(&Ray::new(p, sample.dir)).unwrap();
+                assert!((t - sample.dist).abs() < 1e-3);
+                assert_eq!(radiance, Vec3::WHITE);
+            }
+        }
+
+        // from above, the disk only shows its dark side
+        let disk = ShapeLight::new(
+            shape(Disk::new(0.5, 0.0), Vec3::UP, Vec3::DOWN),
+            Vec3::WHITE,
+        );
+        assert_eq!(disk.pdf(Vec3::UP * 2.0, Vec3::DOWN), 0.0);
+        assert!(disk.sample(Vec3::UP * 2.0).is_none());
+    }
+
+    #[test]
+    fn shapes_test() {
+        const WIDTH: usize = 150;
+        const HEIGHT: usize = 100;
+        let tilt = Vec3::new(0.3, 1.0, -0.4).unit();
+        let row = |x: f32, z: f32| Vec3::new(x, -0.5, z);
+        let objects: Vec<Arc<dyn Object + Send + Sync>> = vec![
+            shape(
+                Disk::new(0.5, 0.2),
+                row(2.4, 4.0),
+                Vec3::new(0.0, 1.0, -1.0),
+            ),
+            shape(Cylinder::new(0.35, 0.8, false), row(1.2, 4.0), tilt),
+            shape(Cone::new(0.4, 0.9, true), row(0.0, 4.0), Vec3::UP),
+            shape(Torus::new(0.4, 0.12), row(-1.2, 4.0) + Vec3::UP * 0.4, tilt),
+            Arc::new(Shape::new(
+                Capsule::new(0.25, 0.6),
+                row(-2.4, 4.0) + Vec3::UP * 0.3,
+                Vec3::new(1.0, 1.0, 0.0),
+                MIRROR.clone(),
+                Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.9, Vec3::ZERO)),
+            )),
+            Arc::new(Quad::new(
+                Vec3::new(-5.0, -0.5, -2.0),
+                Vec3::new(10.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 10.0),
+                LAMBERTIAN.clone(),
+                Vec3::ZERO,
+                Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.7, Vec3::ZERO)),
+            )),
+        ];
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![
+            Arc::new(ShapeLight::new(
+                shape(Torus::new(0.8, 0.05), Vec3::new(0.0, 1.6, 4.0), Vec3::DOWN),
+                Vec3::new(1.0, 0.8, 0.6) * 30.0,
+            )),
+            Arc::new(ShapeLight::new(
+                shape(Disk::new(0.3, 0.0), Vec3::new(-2.0, 2.0, 2.5), Vec3::DOWN),
+                Vec3::new(0.4, 0.6, 1.0) * 40.0,
+            )),
+        ];
+        let scene =
+            Scene::new(vec![Instance::new(objects.into())], 0.001, 100.0).with_lights(lights);
+        // the cone is a quarter as wide three quarters up, the floor shows through the hole
+        // of the tilted torus
+        let (hit, _) = scene
+            .get_hit(Ray::new(row(0.1, 4.0) + Vec3::UP * 3.0, Vec3::DOWN))
+            .unwrap();
+        assert!((hit.p.y - 0.175).abs() < 1e-4, "{:?}", hit.p);
+        let center = row(-1.2, 4.0) + Vec3::UP * 0.4;
+        let (hit, _) = scene.get_hit(Ray::new(center + tilt, -tilt)).unwrap();
+        let floor = center - tilt * (0.4 / tilt.y);
+        assert!((hit.p - floor).length() < 1e-4, "{:?}", hit.p);
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::new(0.0, 0.8, 0.0),
+            Vec3::UP,
+            Vec3::new(0.0, -0.3, 1.0),
+            60.0,
+            0.0,
+        );
+        Viewport::new(
+            cam,
+            scene,
+            Arc::new(next_event_ray_color),
+            WIDTH,
+            HEIGHT,
+            16,
+            4,
+            Vec3::ZERO,
+            2.2,
+        )
+        .render()
+        .save("test_out/shapes_test.png")
+        .unwrap();
+    }
+}
@@ -1,11 +1,15 @@
-use std::sync::Arc;
+use std::{f32::consts::PI, sync::Arc};
 
-use crate::vec3::{ray, vec3::Vec3};
+use crate::{
+    animation::{times_between, Steps},
+    vec3::{ray, vec3::Vec3},
+};
 
 use super::{
     aabb::Interval,
     hit::Hit,
     material::Material,
+    shapes::Surface,
     texture::{ColorResult, Texture},
     Object,
 };
@@ -18,6 +22,82 @@ pub struct Sphere {
     pub texture: Arc<dyn Texture + Send + Sync>,
 }
 
+fn hit_sphere(origin: Vec3, radius: f32, r: ray::Ray, mint: f32, maxt: f32) -> Option<Hit> {
+    let oc = r.origin - origin;
+    let a = r.direction.dot(r.direction);
+    let b = oc.dot(r.direction);
+    let c = oc.dot(oc) - radius * radius;
+    let d = b * b - a * c;
+
+    if d < 0.0 {
+        return None;
+    }
+
+    let mut x = (-b - d.sqrt()) / a;
+    if x < mint {
+        x = (-b + d.sqrt()) / a
+    }
+
+    if x < mint || x > maxt {
+        return None;
+    }
+    let normal = (r.at(x) - origin).unit();
+    // println!("{:?}", normal);
+    debug_assert!(
+        !normal.x.is_nan(),
+        "nx is nan: {}, {:?}, {:?}, {:?}, {}",
+        normal.x,
+        r.at(x) - origin,
+        r,
+        origin,
+        x
+    );
+    debug_assert!(
+        !normal.y.is_nan(),
+        "ny is nan: {}, {:?}",
+        normal.y,
+        r.at(x) - origin
+    );
+    debug_assert!(
+        !normal.z.is_nan(),
+        "nz is nan: {}, {:?}",
+        normal.z,
+        r.at(x) - origin
+    );
+    debug_assert!(normal.length2() > 1e-10);
+    let u: f32 = (f32::atan2(-normal.z, normal.x) + std::f32::consts::PI)
+        * std::f32::consts::FRAC_1_PI
+        * 0.5;
+    let v: f32 = 1.0 - (std::f32::consts::FRAC_1_PI * f32::acos((-normal.y).clamp(-1.0, 1.0)));
+
+    debug_assert!(
+        u <= 1.0 && u >= 0.0,
+        "U too big: {}, atan: {}, z: {}, x: {}",
+        u,
+        f32::atan2(-normal.z, normal.x),
+        -normal.z,
+        normal.x
+    );
+    debug_assert!(v <= 1.0 && v >= 0.0, "V too big {}", v);
+
+    let local = r.at(x) - origin;
+    let rho = (local.x * local.x + local.z * local.z).sqrt().max(1e-6);
+    let dpdu = Vec3::new(local.z, 0.0, -local.x) * (2.0 * std::f32::consts::PI);
+    let dpdv =
+        Vec3::new(local.y * local.x / rho, -rho, local.y * local.z / rho) * std::f32::consts::PI;
+    return Some(Hit {
+        r,
+        p: r.at(x),
+        n: normal,
+        ng: normal,
+        t: x,
+        u,
+        v,
+        dpdu,
+        dpdv,
+    });
+}
+
 impl Object for Sphere {
     fn get_aabb(
         &self,
@@ -34,55 +114,112 @@ impl Object for Sphere {
     }
 
     fn get_hit(&self, r: crate::vec3::ray::Ray, mint: f32, maxt: f32) -> Option<super::hit::Hit> {
-        let origin = self.origin;
-        let oc = r.origin - origin;
-        let a = r.direction.dot(r.direction);
-        let b = oc.dot(r.direction);
-        let c = oc.dot(oc) - self.radius * self.radius;
-        let d = b * b - a * c;
-
-        if d < 0.0 {
-            return None;
+        hit_sphere(self.origin, self.radius, r, mint, maxt)
+    }
+
+    fn reflect(&self, h: &Hit) -> ray::Ray {
+        self.mat.on_hit(h)
+    }
+
+    fn color(&self, h: &Hit) -> ColorResult {
+        self.texture.color_at(h)
+    }
+
+    fn generator_pdf(&self, h: &Hit, r: &ray::Ray) -> f32 {
+        self.mat.generator_pdf(h, r)
+    }
+
+    fn material_pdf(&self, h: &Hit, r: &ray::Ray) -> f32 {
+        self.mat.material_pdf(h, r)
+    }
+
+    fn is_specular(&self, _h: &Hit) -> bool {
+        self.mat.is_specular()
+    }
+
+    fn material(&self) -> Option<&Arc<dyn Material + Send + Sync>> {
+        Some(&self.mat)
+    }
+
+    fn emitter(self: Arc<Self>) -> Option<Arc<dyn Surface + Send + Sync>> {
+        if self.texture.emits() {
+            Some(self)
+        } else {
+            None
         }
+    }
+}
+
+impl Surface for Sphere {
+    fn area(&self) -> f32 {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    fn sample_point(&self) -> (Vec3, Vec3) {
+        let n = Vec3::random_unit_vec();
+        (self.origin + n * self.radius, n)
+    }
+
+    fn normal_at(&self, p: Vec3) -> Vec3 {
+        (p - self.origin).unit()
+    }
+}
+
+/// Sphere with its center and radius changing during the shutter, rays see it at their time
+#[derive(Clone)]
+pub struct DeformingSphere {
+    pub origin: Steps<Vec3>,
+    pub radius: Steps<f32>,
+    pub mat: Arc<dyn Material + Send + Sync>,
+    pub texture: Arc<dyn Texture + Send + Sync>,
+}
 
-        let mut x = (-b - d.sqrt()) / a;
-        if x < mint {
-            x = (-b + d.sqrt()) / a
+impl DeformingSphere {
+    pub fn new(
+        origin: Steps<Vec3>,
+        radius: Steps<f32>,
+        mat: Arc<dyn Material + Send + Sync>,
+        texture: Arc<dyn Texture + Send + Sync>,
+    ) -> Self {
+        Self {
+            origin,
+            radius,
+            mat,
+            texture,
         }
+    }
+}
 
-        if x < mint || x > maxt {
-            return None;
+impl Object for DeformingSphere {
+    fn get_aabb(&self) -> (Interval, Interval, Interval) {
+        self.get_aabb_during(0.0, 1.0)
+    }
+
+    fn get_aabb_during(&self, t0: f32, t1: f32) -> (Interval, Interval, Interval) {
+        // both change linearly between their steps, so do the sides of the bounds
+        let mut bounds: Option<(Interval, Interval, Interval)> = None;
+        let times = times_between(self.origin.values().len(), t0, t1)
+            .into_iter()
+            .chain(times_between(self.radius.values().len(), t0, t1));
+        for t in times {
+            let (o, r) = (self.origin.at(t), self.radius.at(t));
+            let b = Interval::from_vecs(o - Vec3::WHITE * r, o + Vec3::WHITE * r);
+            bounds = Some(match bounds {
+                Some(a) => (a.0 + b.0, a.1 + b.1, a.2 + b.2),
+                None => b,
+            });
         }
-        let normal = (r.at(x) - origin).unit();
-        // println!("{:?}", normal);
-        debug_assert!(
-            !normal.x.is_nan(),
-            "nx is nan: {}, {:?}, {:?}, {:?}, {}",
-            normal.x,
-            r.at(x) - origin,
-            r,
-            origin,
-            x
-        );
-        debug_assert!(
-            !normal.y.is_nan(),
-            "ny is nan: {}, {:?}",
-            normal.y,
-            r.at(x) - origin
-        );
-        debug_assert!(
-            !normal.z.is_nan(),
-            "nz is nan: {}, {:?}",
-            normal.z,
-            r.at(x) - origin
-        );
-        debug_assert!(normal.length2() > 1e-10);
-        return Some(Hit {
+        bounds.unwrap()
+    }
+
+    fn get_hit(&self, r: ray::Ray, mint: f32, maxt: f32) -> Option<Hit> {
+        hit_sphere(
+            self.origin.at(r.time),
+            self.radius.at(r.time),
             r,
-            p: r.at(x),
-            n: normal,
-            t: x,
-        });
+            mint,
+            maxt,
+        )
     }
 
     fn reflect(&self, h: &Hit) -> ray::Ray {
@@ -90,20 +227,7 @@ impl Object for Sphere {
     }
 
     fn color(&self, h: &Hit) -> ColorResult {
-        let u: f32 =
-            (f32::atan2(-h.n.z, h.n.x) + std::f32::consts::PI) * std::f32::consts::FRAC_1_PI * 0.5;
-        let v: f32 = 1.0 - (std::f32::consts::FRAC_1_PI * f32::acos(-h.n.y));
-
-        debug_assert!(
-            u <= 1.0 && u >= 0.0,
-            "U too big: {}, atan: {}, z: {}, x: {}",
-            u,
-            f32::atan2(-h.n.z, h.n.x),
-            -h.n.z,
-            h.n.x
-        );
-        debug_assert!(v <= 1.0 && v >= 0.0, "V too big {}", v);
-        self.texture.color_at(u, v)
+        self.texture.color_at(h)
     }
 
     fn generator_pdf(&self, h: &Hit, r: &ray::Ray) -> f32 {
@@ -113,4 +237,12 @@ impl Object for Sphere {
     fn material_pdf(&self, h: &Hit, r: &ray::Ray) -> f32 {
         self.mat.material_pdf(h, r)
     }
+
+    fn is_specular(&self, _h: &Hit) -> bool {
+        self.mat.is_specular()
+    }
+
+    fn material(&self) -> Option<&Arc<dyn Material + Send + Sync>> {
+        Some(&self.mat)
+    }
 }
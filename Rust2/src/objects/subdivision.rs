@@ -0,0 +1,744 @@
+use std::{collections::HashMap, f32::consts::PI, sync::Arc};
+
+use crate::vec3::vec3::Vec3;
+
+use super::{material::Material, texture::Texture, triangle::Triangle, Object};
+
+/// Refinement rules applied to a control mesh
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scheme {
+    /// Catmull-Clark, any polygons, every face becomes quads
+    CatmullClark,
+    /// Loop, triangles only
+    Loop,
+}
+
+/// Polygon mesh refined into a smooth surface. Creased edges have a sharpness, the number of
+/// levels they stay sharp for before relaxing, `f32::INFINITY` keeps them sharp at the limit.
+/// Boundary edges are always sharp
+#[derive(Debug, Clone)]
+pub struct ControlMesh {
+    pub vertices: Vec<Vec3>,
+    pub faces: Vec<Vec<usize>>,
+    pub creases: HashMap<(usize, usize), f32>,
+}
+
+// edges by their sorted vertex pair
+fn key(a: usize, b: usize) -> (usize, usize) {
+    if a < b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+fn lerp(a: Vec3, b: Vec3, t: f32) -> Vec3 {
+    a * (1.0 - t) + b * t
+}
+
+fn average(points: impl Iterator<Item = Vec3>) -> Vec3 {
+    let (sum, n) = points.fold((Vec3::ZERO, 0), |(s, n), p| (s + p, n + 1));
+    sum / n.max(1) as f32
+}
+
+// adjacency of a mesh, edges are listed in the order the faces first reach them
+struct Topology {
+    edges: Vec<(usize, usize)>,
+    edge_index: HashMap<(usize, usize), usize>,
+    edge_faces: Vec<Vec<usize>>,
+    vertex_faces: Vec<Vec<usize>>,
+    neighbours: Vec<Vec<usize>>,
+}
+
+impl Topology {
+    fn new(mesh: &ControlMesh) -> Self {
+        let mut t = Topology {
+            edges: vec![],
+            edge_index: HashMap::new(),
+            edge_faces: vec![],
+            vertex_faces: vec![vec![]; mesh.vertices.len()],
+            neighbours: vec![vec![]; mesh.vertices.len()],
+        };
+        for (f, face) in mesh.faces.iter().enumerate() {
+            for (i, &a) in face.iter().enumerate() {
+                let b = face[(i + 1) % face.len()];
+                t.vertex_faces[a].push(f);
+                let e = match t.edge_index.get(&key(a, b)) {
+                    Some(&e) => e,
+                    None => {
+                        t.edge_index.insert(key(a, b), t.edges.len());
+                        t.edges.push(key(a, b));
+                        t.edge_faces.push(vec![]);
+                        t.neighbours[a].push(b);
+                        t.neighbours[b].push(a);
+                        t.edges.len() - 1
+                    }
+                };
+                t.edge_faces[e].push(f);
+            }
+        }
+        t
+    }
+
+    fn edge(&self, a: usize, b: usize) -> usize {
+        self.edge_index[&key(a, b)]
+    }
+}
+
+impl ControlMesh {
+    pub fn new(vertices: Vec<Vec3>, faces: Vec<Vec<usize>>) -> Self {
+        assert!(
+            faces.iter().all(|f| f.len() >= 3),
+            "Faces need at least three vertices"
+        );
+        assert!(
+            faces.iter().flatten().all(|&i| i < vertices.len()),
+            "Face indices must point at vertices"
+        );
+        Self {
+            vertices,
+            faces,
+            creases: HashMap::new(),
+        }
+    }
+
+    /// Creases the edge between vertices `a` and `b`
+    pub fn with_crease(mut self, a: usize, b: usize, sharpness: f32) -> Self {
+        self.creases.insert(key(a, b), sharpness);
+        self
+    }
+
+    /// The mesh refined `levels` times, original vertices keep their indices
+    pub fn subdivide(&self, scheme: Scheme, levels: usize) -> ControlMesh {
+        let mut mesh = self.clone();
+        for _ in 0..levels {
+            mesh = match scheme {
+                Scheme::CatmullClark => mesh.catmull_clark(),
+                Scheme::Loop => mesh.loop_subdivision(),
+            };
+        }
+        mesh
+    }
+
+    /// Refines the mesh and turns it into smooth shaded triangles
+    pub fn smooth(
+        &self,
+        scheme: Scheme,
+        levels: usize,
+        mat: Arc<dyn Material + Send + Sync>,
+        texture: Arc<dyn Texture + Send + Sync>,
+    ) -> Arc<[Arc<dyn Object + Send + Sync>]> {
+        self.subdivide(scheme, levels)
+            .triangles(scheme, mat, texture)
+    }
+
+    /// Faces fanned into triangles shaded with the limit normals of the scheme
+    pub fn triangles(
+        &self,
+        scheme: Scheme,
+        mat: Arc<dyn Material + Send + Sync>,
+        texture: Arc<dyn Texture + Send + Sync>,
+    ) -> Arc<[Arc<dyn Object + Send + Sync>]> {
+        let normals = self.limit_normals(scheme);
+        let mut triangles: Vec<Arc<dyn Object + Send + Sync>> = vec![];
+        for (face, n) in self.faces.iter().zip(normals) {
+            let p = |i: usize| self.vertices[face[i]];
+            for i in 1..face.len() - 1 {
+                triangles.push(Arc::new(
+                    Triangle::new(
+                        p(0),
+                        p(i) - p(0),
+                        p(i + 1) - p(0),
+                        mat.clone(),
+                        texture.clone(),
+                    )
+                    .with_normals([n[0], n[i], n[i + 1]]),
+                ));
+            }
+        }
+        triangles.into()
+    }
+
+    // boundary and non-manifold edges are always sharp
+    fn sharpness(&self, topology: &Topology, e: usize) -> f32 {
+        if topology.edge_faces[e].len() != 2 {
+            return f32::INFINITY;
+        }
+        let (a, b) = topology.edges[e];
+        self.creases.get(&(a, b)).copied().unwrap_or(0.0)
+    }
+
+    fn edge_point(&self, topology: &Topology, e: usize, smooth: Vec3) -> Vec3 {
+        let (a, b) = topology.edges[e];
+        let mid = (self.vertices[a] + self.vertices[b]) * 0.5;
+        lerp(smooth, mid, self.sharpness(topology, e).min(1.0))
+    }
+
+    // with two sharp edges the vertex follows the crease, with more it is a fixed corner,
+    // semi-sharp edges blend in the smooth position by their average sharpness
+    fn vertex_point(&self, topology: &Topology, v: usize, smooth: Vec3) -> Vec3 {
+        let p = self.vertices[v];
+        let sharp: Vec<(usize, f32)> = topology.neighbours[v]
+            .iter()
+            .map(|&w| (w, self.sharpness(topology, topology.edge(v, w))))
+            .filter(|(_, s)| *s > 0.0)
+            .collect();
+        let crease = match sharp.len() {
+            0 | 1 => return smooth,
+            // corners of an open mesh stay put
+            2 if topology.vertex_faces[v].len() > 1 => {
+                (self.vertices[sharp[0].0] + p * 6.0 + self.vertices[sharp[1].0]) / 8.0
+            }
+            _ => p,
+        };
+        let s = sharp.iter().map(|(_, s)| s).sum::<f32>() / sharp.len() as f32;
+        lerp(smooth, crease, s.min(1.0))
+    }
+
+    // both halves of a split edge are one level less sharp
+    fn child_creases(
+        &self,
+        topology: &Topology,
+        edge_vertex: impl Fn(usize) -> usize,
+    ) -> HashMap<(usize, usize), f32> {
+        let mut creases = HashMap::new();
+        for (&(a, b), &s) in &self.creases {
+            let Some(&e) = topology.edge_index.get(&(a, b)) else {
+                continue;
+            };
+            if s > 1.0 {
+                creases.insert(key(a, edge_vertex(e)), s - 1.0);
+                creases.insert(key(b, edge_vertex(e)), s - 1.0);
+            }
+        }
+        creases
+    }
+
+    fn catmull_clark(&self) -> ControlMesh {
+        let t = Topology::new(self);
+        let (n, e) = (self.vertices.len(), t.edges.len());
+        let face_points: Vec<Vec3> = self
+            .faces
+            .iter()
+            .map(|f| average(f.iter().map(|&i| self.vertices[i])))
+            .collect();
+
+        let mut vertices = Vec::with_capacity(n + e + face_points.len());
+        for v in 0..n {
+            let p = self.vertices[v];
+            let valence = t.neighbours[v].len() as f32;
+            let smooth = if valence == 0.0 {
+                p
+            } else {
+                let q = average(t.vertex_faces[v].iter().map(|&f| face_points[f]));
+                let r = average(
+                    t.neighbours[v]
+                        .iter()
+                        .map(|&w| (p + self.vertices[w]) * 0.5),
+                );
+                (q + r * 2.0 + p * (valence - 3.0)) / valence
+            };
+            vertices.push(self.vertex_point(&t, v, smooth));
+        }
+        for (i, &(a, b)) in t.edges.iter().enumerate() {
+            let smooth = match t.edge_faces[i][..] {
+                [f, g] => {
+                    (self.vertices[a] + self.vertices[b] + face_points[f] + face_points[g]) / 4.0
+                }
+                _ => (self.vertices[a] + self.vertices[b]) * 0.5,
+            };
+            vertices.push(self.edge_point(&t, i, smooth));
+        }
+        vertices.extend(face_points);
+
+        let mut faces = vec![];
+        for (f, face) in self.faces.iter().enumerate() {
+            let k = face.len();
+            for i in 0..k {
+                let (prev, v, next) = (face[(i + k - 1) % k], face[i], face[(i + 1) % k]);
+                faces.push(vec![v, n + t.edge(v, next), n + e + f, n + t.edge(prev, v)]);
+            }
+        }
+        ControlMesh {
+            vertices,
+            faces,
+            creases: self.child_creases(&t, |i| n + i),
+        }
+    }
+
+    fn loop_subdivision(&self) -> ControlMesh {
+        assert!(
+            self.faces.iter().all(|f| f.len() == 3),
+            "Loop subdivision needs a triangle mesh"
+        );
+        let t = Topology::new(self);
+        let n = self.vertices.len();
+        let opposite = |f: usize, a: usize, b: usize| {
+            let face = &self.faces[f];
+            self.vertices[face.iter().copied().find(|&i| i != a && i != b).unwrap()]
+        };
+
+        let mut vertices = Vec::with_capacity(n + t.edges.len());
+        for v in 0..n {
+            let p = self.vertices[v];
+            let valence = t.neighbours[v].len() as f32;
+            let smooth = if valence == 0.0 {
+                p
+            } else {
+                let beta =
+                    (5.0 / 8.0 - (3.0 / 8.0 + (2.0 * PI / valence).cos() / 4.0).powi(2)) / valence;
+                t.neighbours[v]
+                    .iter()
+                    .fold(p * (1.0 - valence * beta), |s, &w| {
+                        s + self.vertices[w] * beta
+                    })
+            };
+            vertices.push(self.vertex_point(&t, v, smooth));
+        }
+        for (i, &(a, b)) in t.edges.iter().enumerate() {
+            let smooth = match t.edge_faces[i][..] {
+                [f, g] => {
+                    (self.vertices[a] + self.vertices[b]) * (3.0 / 8.0)
+                        + (opposite(f, a, b) + opposite(g, a, b)) / 8.0
+                }
+                _ => (self.vertices[a] + self.vertices[b]) * 0.5,
+            };
+            vertices.push(self.edge_point(&t, i, smooth));
+        }
+
+        let mut faces = vec![];
+        for face in &self.faces {
+            let [a, b, c] = [face[0], face[1], face[2]];
+            let (ab, bc, ca) = (n + t.edge(a, b), n + t.edge(b, c), n + t.edge(c, a));
+            faces.push(vec![a, ab, ca]);
+            faces.push(vec![b, bc, ab]);
+            faces.push(vec![c, ca, bc]);
+            faces.push(vec![ab, bc, ca]);
+        }
+        ControlMesh {
+            vertices,
+            faces,
+            creases: self.child_creases(&t, |i| n + i),
+        }
+    }
+
+    // area weighted normal of a polygon
+    fn face_normal(&self, f: usize) -> Vec3 {
+        let face = &self.faces[f];
+        face.iter().enumerate().fold(Vec3::ZERO, |n, (i, &a)| {
+            n + self.vertices[a].cross(self.vertices[face[(i + 1) % face.len()]])
+        })
+    }
+
+    /// Normals of the limit surface at every face corner. Smooth vertices use the limit
+    /// tangents of the scheme, vertices on creases get one normal per side of the crease
+    pub fn limit_normals(&self, scheme: Scheme) -> Vec<Vec<Vec3>> {
+        let t = Topology::new(self);
+        let mut normals: Vec<Vec<Vec3>> = self
+            .faces
+            .iter()
+            .map(|f| vec![Vec3::ZERO; f.len()])
+            .collect();
+        for v in 0..self.vertices.len() {
+            let faces = &t.vertex_faces[v];
+            let creased = t.neighbours[v]
+                .iter()
+                .filter(|&&w| self.sharpness(&t, t.edge(v, w)) > 0.0)
+                .count();
+            let average = faces
+                .iter()
+                .fold(Vec3::ZERO, |n, &f| n + self.face_normal(f));
+            let limit = if creased < 2 {
+                self.limit_tangents(&t, v, scheme)
+                    .map(|(t1, t2)| t1.cross(t2))
+                    .filter(|n| n.length2() > 0.0)
+                    .map(|n| if n.dot(average) < 0.0 { -n } else { n })
+            } else {
+                None
+            };
+            for (i, &f) in faces.iter().enumerate() {
+                let n = limit.unwrap_or_else(|| self.sector_normal(&t, v, i));
+                let corner = self.faces[f].iter().position(|&a| a == v).unwrap();
+                normals[f][corner] = n.unit();
+            }
+        }
+        normals
+    }
+
+    // faces around `v` ordered by turning around it, starting from its first face.
+    // None at boundaries and non-manifold vertices
+    fn ring(&self, t: &Topology, v: usize) -> Option<Vec<(usize, usize)>> {
+        let corners: Vec<(usize, usize)> = t.vertex_faces[v]
+            .iter()
+            .map(|&f| {
+                let face = &self.faces[f];
+                (f, face.iter().position(|&a| a == v).unwrap())
+            })
+            .collect();
+        let next = |&(f, i): &(usize, usize)| self.faces[f][(i + 1) % self.faces[f].len()];
+        let prev = |&(f, i): &(usize, usize)| {
+            let k = self.faces[f].len();
+            self.faces[f][(i + k - 1) % k]
+        };
+        let mut ring = vec![*corners.first()?];
+        while ring.len() < corners.len() {
+            let after = prev(ring.last().unwrap());
+            ring.push(*corners.iter().find(|c| next(c) == after)?);
+        }
+        let closed = next(&ring[0]) == prev(ring.last().unwrap());
+        let distinct = ring
+            .iter()
+            .all(|c| ring.iter().filter(|d| d.0 == c.0).count() == 1);
+        (closed && distinct).then_some(ring)
+    }
+
+    fn limit_tangents(&self, t: &Topology, v: usize, scheme: Scheme) -> Option<(Vec3, Vec3)> {
+        let ring = self.ring(t, v)?;
+        let n = ring.len() as f32;
+        let angle = |i: usize| 2.0 * PI * i as f32 / n;
+        let at = |(f, i): (usize, usize), offset: usize| {
+            let face = &self.faces[f];
+            self.vertices[face[(i + offset) % face.len()]]
+        };
+        let (mut t1, mut t2) = (Vec3::ZERO, Vec3::ZERO);
+        match scheme {
+            Scheme::Loop => {
+                for (i, &c) in ring.iter().enumerate() {
+                    t1 += at(c, 1) * angle(i).cos();
+                    t2 += at(c, 1) * angle(i).sin();
+                }
+            }
+            Scheme::CatmullClark => {
+                if ring.iter().any(|&(f, _)| self.faces[f].len() != 4) {
+                    return None;
+                }
+                let a =
+                    1.0 + angle(1).cos() + (PI / n).cos() * (2.0 * (9.0 + angle(1).cos())).sqrt();
+                // edge neighbours weighted by a, the diagonal of each quad by its two edges
+                for (i, &c) in ring.iter().enumerate() {
+                    t1 += at(c, 1) * (a * angle(i).cos())
+                        + at(c, 2) * (angle(i).cos() + angle(i + 1).cos());
+                    t2 += at(c, 1) * (a * angle(i).sin())
+                        + at(c, 2) * (angle(i).sin() + angle(i + 1).sin());
+                }
+            }
+        }
+        Some((t1, t2))
+    }
+
+    // average normal of the faces around `v` reachable from its `i`th face without crossing
+    // a sharp edge
+    fn sector_normal(&self, t: &Topology, v: usize, i: usize) -> Vec3 {
+        let faces = &t.vertex_faces[v];
+        let mut sector = vec![faces[i]];
+        let mut k = 0;
+        while k < sector.len() {
+            let face = &self.faces[sector[k]];
+            let c = face.iter().position(|&a| a == v).unwrap();
+            for w in [
+                face[(c + 1) % face.len()],
+                face[(c + face.len() - 1) % face.len()],
+            ] {
+                let e = t.edge(v, w);
+                if self.sharpness(t, e) > 0.0 {
+                    continue;
+                }
+                for &f in &t.edge_faces[e] {
+                    if !sector.contains(&f) {
+                        sector.push(f);
+                    }
+                }
+            }
+            k += 1;
+        }
+        sector
+            .iter()
+            .fold(Vec3::ZERO, |n, &f| n + self.face_normal(f))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        objects::{
+            instance::Instance,
+            light::{Light, PointLight},
+            material::LAMBERTIAN,
+            quad::Quad,
+            texture::ConstColorTexture,
+            Object,
+        },
+        vec3::{ray::Ray, vec3::Vec3},
+        viewport::{camera::Camera, ray_color::next_event_ray_color, scene::Scene, Viewport},
+    };
+
+    use super::{ControlMesh, Scheme};
+
+    // corners on the bits of their index, faces wound outwards
+    fn cube(offset: Vec3) -> ControlMesh {
+        let bit = |i: usize, b: usize| if i & b == 0 { -1.0 } else { 1.0 };
+        ControlMesh::new(
+            (0..8)
+                .map(|i| Vec3::new(bit(i, 1), bit(i, 2), bit(i, 4)) + offset)
+                .collect(),
+            vec![
+                vec![0, 4, 6, 2],
+                vec![1, 3, 7, 5],
+                vec![0, 1, 5, 4],
+                vec![2, 6, 7, 3],
+                vec![0, 2, 3, 1],
+                vec![4, 5, 7, 6],
+            ],
+        )
+    }
+
+    fn octahedron(offset: Vec3) -> ControlMesh {
+        let axes = [
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(-1.0, 0.0, 0.0),
+            Vec3::UP,
+            Vec3::DOWN,
+            Vec3::FORWARD,
+            Vec3::BACKWARD,
+        ];
+        let mut faces = vec![];
+        for x in 0..2 {
+            for y in 2..4 {
+                for z in 4..6 {
+                    // an odd number of negative axes turns the winding inwards
+                    faces.push(if (x + y + z) % 2 == 0 {
+                        vec![x, y, z]
+                    } else {
+                        vec![x, z, y]
+                    });
+                }
+            }
+        }
+        ControlMesh::new(axes.iter().map(|&a| a + offset).collect(), faces)
+    }
+
+    #[test]
+    fn cube_rounds_off() {
+        let mesh = cube(Vec3::ZERO).subdivide(Scheme::CatmullClark, 3);
+        assert_eq!(mesh.faces.len(), 6 * 4 * 4 * 4);
+        // a closed quad mesh has two faces more than vertices
+        assert_eq!(mesh.vertices.len(), mesh.faces.len() + 2);
+        assert!(mesh
+            .vertices
+            .iter()
+            .all(|p| p.x.abs() < 1.0 && p.y.abs() < 1.0 && p.z.abs() < 1.0));
+        assert_eq!(mesh.vertices[0], -mesh.vertices[7]);
+
+        let normals = mesh.limit_normals(Scheme::CatmullClark);
+        let corner = -Vec3::new(1.0, 1.0, 1.0).unit();
+        for (face, n) in mesh.faces.iter().zip(&normals) {
+            for (&v, &n) in face.iter().zip(n) {
+                if v == 0 {
+                    assert!(n.dot(corner) > 0.9999);
+                }
+                assert!(n.dot(mesh.vertices[v].unit()) > 0.8);
+            }
+        }
+    }
+
+    #[test]
+    fn creases_keep_edges() {
+        let edges = [
+            (0, 1),
+            (2, 3),
+            (4, 5),
+            (6, 7),
+            (0, 2),
+            (1, 3),
+            (4, 6),
+            (5, 7),
+            (0, 4),
+            (1, 5),
+            (2, 6),
+            (3, 7),
+        ];
+        let mesh = edges
+            .iter()
+            .fold(cube(Vec3::ZERO), |m, &(a, b)| {
+                m.with_crease(a, b, f32::INFINITY)
+            })
+            .subdivide(Scheme::CatmullClark, 2);
+        assert_eq!(mesh.vertices[0], Vec3::new(-1.0, -1.0, -1.0));
+        assert!(mesh
+            .vertices
+            .iter()
+            .all(|p| (p.x.abs().max(p.y.abs()).max(p.z.abs()) - 1.0).abs() < 1e-6));
+        // every face stays flat, so its corners share its normal
+        for (f, n) in mesh.limit_normals(Scheme::CatmullClark).iter().enumerate() {
+            let flat = mesh.face_normal(f).unit();
+            assert!(n.iter().all(|&n| n == flat));
+        }
+    }
+
+    #[test]
+    fn semi_sharp_creases() {
+        let corner = |s: f32| {
+            let mesh = [(0, 1), (0, 2), (0, 4)]
+                .iter()
+                .fold(cube(Vec3::ZERO), |m, &(a, b)| m.with_crease(a, b, s))
+                .subdivide(Scheme::CatmullClark, 4);
+            mesh.vertices[0].length()
+        };
+        let distances: Vec<f32> = [0.0, 0.5, 1.0, 2.0, f32::INFINITY]
+            .iter()
+            .map(|&s| corner(s))
+            .collect();
+        assert!(distances.windows(2).all(|d| d[0] < d[1]));
+        assert!((distances[4] - 3f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn open_patch_stays_flat() {
+        let mesh = ControlMesh::new(
+            (0..9)
+                .map(|i| Vec3::new((i % 3) as f32, 0.0, (i / 3) as f32))
+                .collect(),
+            vec![
+                vec![0, 3, 4, 1],
+                vec![1, 4, 5, 2],
+                vec![3, 6, 7, 4],
+                vec![4, 7, 8, 5],
+            ],
+        )
+        .subdivide(Scheme::CatmullClark, 2);
+        assert_eq!(mesh.vertices[8], Vec3::new(2.0, 0.0, 2.0));
+        assert!(mesh.vertices.iter().all(|p| p.y == 0.0));
+        let normals = mesh.limit_normals(Scheme::CatmullClark);
+        assert!(normals.iter().flatten().all(|&n| n == Vec3::UP));
+    }
+
+    #[test]
+    fn loop_octahedron() {
+        let mesh = octahedron(Vec3::ZERO).subdivide(Scheme::Loop, 3);
+        assert_eq!(mesh.faces.len(), 8 * 4 * 4 * 4);
+        assert_eq!(mesh.vertices.len(), mesh.faces.len() / 2 + 2);
+        for (face, n) in mesh.faces.iter().zip(mesh.limit_normals(Scheme::Loop)) {
+            for (&v, n) in face.iter().zip(n) {
+                if v == 2 {
+                    assert!(n.dot(Vec3::UP) > 0.9999);
+                }
+                assert!(n.dot(mesh.vertices[v].unit()) > 0.95);
+            }
+        }
+        assert_eq!(
+            mesh.triangles(
+                Scheme::Loop,
+                LAMBERTIAN.clone(),
+                Arc::new(ConstColorTexture::new(Vec3::WHITE, Vec3::ZERO)),
+            )
+            .len(),
+            mesh.faces.len()
+        );
+    }
+
+    #[test]
+    fn subdivision_test() {
+        const WIDTH: usize = 150;
+        const HEIGHT: usize = 100;
+        let texture = |c: Vec3| Arc::new(ConstColorTexture::new(c, Vec3::ZERO));
+        // a cube with a sharp top rim and softly creased sides
+        let rimmed = [(2, 3), (3, 7), (7, 6), (6, 2)]
+            .iter()
+            .fold(cube(Vec3::ZERO), |m, &(a, b)| {
+                m.with_crease(a, b, f32::INFINITY)
+            })
+            .with_crease(0, 2, 2.0)
+            .with_crease(1, 3, 2.0)
+            .with_crease(4, 6, 2.0)
+            .with_crease(5, 7, 2.0);
+        // from the limit masks of the valence 3 and 4 vertices of the cages, the corners of
+        // the cube end up half way in and the tip of the octahedron at 24 / 55
+        let corner = Vec3::new(-1.0, -1.0, -1.0) * 0.5;
+        let tip = Vec3::UP * (24.0 / 55.0);
+        let deep = |m: ControlMesh, scheme, v: usize| m.subdivide(scheme, 6).vertices[v];
+        let c = deep(cube(Vec3::ZERO), Scheme::CatmullClark, 0);
+        assert!((c - corner).length() < 1e-4, "{:?}", c);
+        let t = deep(octahedron(Vec3::ZERO), Scheme::Loop, 2);
+        assert!((t - tip).length() < 1e-4, "{:?}", t);
+        let mut objects: Vec<Arc<dyn Object + Send + Sync>> = vec![];
+        for mesh in [
+            cube(Vec3::new(2.6, 0.0, 0.0)).smooth(
+                Scheme::CatmullClark,
+                3,
+                LAMBERTIAN.clone(),
+                texture(Vec3::new(0.8, 0.3, 0.3)),
+            ),
+            rimmed.smooth(
+                Scheme::CatmullClark,
+                3,
+                LAMBERTIAN.clone(),
+                texture(Vec3::new(0.3, 0.8, 0.3)),
+            ),
+            octahedron(Vec3::new(-2.6, 0.0, 0.0)).smooth(
+                Scheme::Loop,
+                3,
+                LAMBERTIAN.clone(),
+                texture(Vec3::new(0.3, 0.3, 0.8)),
+            ),
+        ] {
+            objects.extend(mesh.iter().cloned());
+        }
+        objects.push(Arc::new(Quad::new(
+            Vec3::new(-10.0, -1.0, -10.0),
+            Vec3::new(20.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 20.0),
+            LAMBERTIAN.clone(),
+            Vec3::ZERO,
+            texture(Vec3::WHITE * 0.5),
+        )));
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![Arc::new(PointLight::new(
+            Vec3::new(1.0, 5.0, -3.0),
+            Vec3::WHITE * 40.0,
+        ))];
+        let scene =
+            Scene::new(vec![Instance::new(objects.into())], 0.001, 100.0).with_lights(lights);
+        // three levels in, the rendered surfaces are close to their limits. The rays pass
+        // just beside the vertices, not through the corners of the triangles
+        let aside = Vec3::new(0.01, 0.0, 0.013);
+        let octahedron_tip = tip + Vec3::new(-2.6, 0.0, 0.0);
+        let (hit, _) = scene
+            .get_hit(Ray::new(
+                octahedron_tip + aside + Vec3::UP * 5.0,
+                Vec3::DOWN,
+            ))
+            .unwrap();
+        assert!((hit.p.y - tip.y).abs() < 0.005, "{:?}", hit.p);
+        // the top corners of the cube, seen from above where the floor is out of the way
+        let cube_corner = Vec3::new(-0.5, 0.5, -0.5) + Vec3::new(2.6, 0.0, 0.0);
+        let diagonal = Vec3::new(1.0, -1.0, 1.0).unit();
+        let (hit, _) = scene
+            .get_hit(Ray::new(cube_corner + aside - diagonal * 5.0, diagonal))
+            .unwrap();
+        let depth = (hit.p - cube_corner).dot(diagonal);
+        assert!(depth.abs() < 0.005, "{:?}", hit.p);
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::new(0.0, 2.5, -7.0),
+            Vec3::UP,
+            Vec3::new(0.0, -0.35, 1.0),
+            50.0,
+            0.0,
+        );
+        Viewport::new(
+            cam,
+            scene,
+            Arc::new(next_event_ray_color),
+            WIDTH,
+            HEIGHT,
+            8,
+            4,
+            Vec3::new(0.4, 0.5, 0.7),
+            2.2,
+        )
+        .render()
+        .save("test_out/subdivision_test.png")
+        .unwrap();
+    }
+}
@@ -1,5 +1,13 @@
 use crate::vec3::vec3::Vec3;
-use image::io::Reader as ImageReader;
+use image::ImageReader;
+
+use super::hit::Hit;
+
+pub mod mipmap;
+pub mod noise;
+pub mod solid;
+
+use self::mipmap::{Filter, MipMap, WrapMode};
 
 pub struct ColorResult {
     pub emmited: Vec3,
@@ -7,7 +15,12 @@ pub struct ColorResult {
 }
 
 pub trait Texture {
-    fn color_at(&self, x: f32, y: f32) -> ColorResult;
+    // `h.p` and `h.n` are in world space, `h.u` and `h.v` are the surface coordinates
+    fn color_at(&self, h: &Hit) -> ColorResult;
+    /// Whether some of the texture glows, so objects can be sampled as lights
+    fn emits(&self) -> bool {
+        false
+    }
 }
 
 pub struct ConstColorTexture {
@@ -22,22 +35,31 @@ impl ConstColorTexture {
 }
 
 impl Texture for ConstColorTexture {
-    fn color_at(&self, _: f32, _: f32) -> ColorResult {
+    fn color_at(&self, _: &Hit) -> ColorResult {
         ColorResult {
             emmited: self.emmit,
             multiplied: self.mult,
         }
     }
+
+    fn emits(&self) -> bool {
+        !self.emmit.close_to_zero()
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct ImageTexture {
-    img: Vec<Vec3>,
+    img: MipMap,
     pub width: usize,
     pub height: usize,
-    emmit_img: Vec<Vec3>,
+    emmit_img: MipMap,
     pub emmit_height: usize,
     pub emmit_width: usize,
+    pub filter: Filter,
+    pub wrap: WrapMode,
+    // applied to the surface coordinates before the lookup: uv * scale + offset
+    pub uv_scale: (f32, f32),
+    pub uv_offset: (f32, f32),
 }
 
 impl ImageTexture {
@@ -52,10 +74,14 @@ impl ImageTexture {
         Self {
             width,
             height,
-            img,
-            emmit_img,
+            img: MipMap::new(img, width, height),
+            emmit_img: MipMap::new(emmit_img, emmit_width, emmit_height),
             emmit_height,
             emmit_width,
+            filter: Filter::Trilinear,
+            wrap: WrapMode::Repeat,
+            uv_scale: (1.0, 1.0),
+            uv_offset: (0.0, 0.0),
         }
     }
 
@@ -63,14 +89,14 @@ impl ImageTexture {
         let img = ImageReader::open(path)?.decode()?.into_rgb32f();
 
         let (w, h) = img.dimensions();
-        Ok(Self {
-            width: w as usize,
-            height: h as usize,
-            img: img.pixels().map(|col| Vec3::from_rgb_ref(col)).collect(),
-            emmit_img: vec![Vec3::ZERO],
-            emmit_height: 1,
-            emmit_width: 1,
-        })
+        Ok(Self::new(
+            img.pixels().map(|col| Vec3::from_rgb_ref(col)).collect(),
+            vec![Vec3::ZERO],
+            w as usize,
+            h as usize,
+            1,
+            1,
+        ))
     }
     pub fn from_path_with_emmisive_mask(
         path: &str,
@@ -81,26 +107,199 @@ impl ImageTexture {
 
         let (w, h) = img.dimensions();
         let (ew, eh) = e_img.dimensions();
-        Ok(Self {
-            width: w as usize,
-            height: h as usize,
-            img: img.pixels().map(|col| Vec3::from_rgb_ref(col)).collect(),
-            emmit_img: e_img.pixels().map(|col| Vec3::from_rgb_ref(col)).collect(),
-            emmit_height: eh as usize,
-            emmit_width: ew as usize,
-        })
+        Ok(Self::new(
+            img.pixels().map(|col| Vec3::from_rgb_ref(col)).collect(),
+            e_img.pixels().map(|col| Vec3::from_rgb_ref(col)).collect(),
+            w as usize,
+            h as usize,
+            ew as usize,
+            eh as usize,
+        ))
+    }
+
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+    pub fn with_wrap(mut self, wrap: WrapMode) -> Self {
+        self.wrap = wrap;
+        self
+    }
+    pub fn with_uv_transform(mut self, scale: (f32, f32), offset: (f32, f32)) -> Self {
+        self.uv_scale = scale;
+        self.uv_offset = offset;
+        self
+    }
+
+    /// Axes of the ray cone footprint on the surface in uv units, `None` for rays without a cone
+    fn footprint(&self, h: &Hit) -> Option<((f32, f32), (f32, f32))> {
+        let radius = 0.5 * h.r.width_at(h.t);
+        if radius <= 0.0 {
+            return None;
+        }
+        let dir = h.r.direction.unit();
+        let n = h.n.unit();
+        let cos = dir.dot(n).abs().max(0.05);
+
+        // the cone is stretched along the projection of the ray onto the surface
+        let projected = dir - n * dir.dot(n);
+        let (major, minor) = if projected.length2() > 1e-8 {
+            let major = projected.unit();
+            (major * (radius / cos), n.cross(major) * radius)
+        } else {
+            let major = h.dpdu.unit();
+            (major * radius, n.cross(major) * radius)
+        };
+
+        let e = h.dpdu.dot(h.dpdu);
+        let f = h.dpdu.dot(h.dpdv);
+        let g = h.dpdv.dot(h.dpdv);
+        let det = e * g - f * f;
+        if det.is_nan() || det.abs() <= 1e-12 {
+            return None;
+        }
+        let to_uv = |a: Vec3| {
+            let (r1, r2) = (a.dot(h.dpdu), a.dot(h.dpdv));
+            (
+                (g * r1 - f * r2) / det * self.uv_scale.0,
+                (e * r2 - f * r1) / det * self.uv_scale.1,
+            )
+        };
+        Some((to_uv(major), to_uv(minor)))
+    }
+
+    fn sample(
+        &self,
+        map: &MipMap,
+        u: f32,
+        v: f32,
+        footprint: Option<((f32, f32), (f32, f32))>,
+    ) -> Vec3 {
+        match (self.filter, footprint) {
+            (Filter::Nearest, _) => map.nearest(u, v, self.wrap),
+            (Filter::Bilinear, _) | (_, None) => map.bilinear(0, u, v, self.wrap),
+            (Filter::Trilinear, Some((a, b))) => {
+                let width = 2.0 * (a.0 * a.0 + a.1 * a.1).max(b.0 * b.0 + b.1 * b.1).sqrt();
+                map.trilinear(u, v, width, self.wrap)
+            }
+            (Filter::Ewa, Some((a, b))) => map.ewa(u, v, a, b, self.wrap),
+        }
     }
 }
 impl Texture for ImageTexture {
-    fn color_at(&self, x: f32, y: f32) -> ColorResult {
-        let emmit_x = (x * self.emmit_width as f32).floor() as usize;
-        let emmit_y = (y * self.emmit_height as f32).floor() as usize;
-
-        let x = (x * self.width as f32) as usize;
-        let y = (y * self.height as f32) as usize;
-        return ColorResult {
-            emmited: self.emmit_img[emmit_x * self.emmit_width + emmit_y],
-            multiplied: self.img[x * self.width + y],
+    fn color_at(&self, h: &Hit) -> ColorResult {
+        let u = h.u * self.uv_scale.0 + self.uv_offset.0;
+        let v = h.v * self.uv_scale.1 + self.uv_offset.1;
+        let footprint = self.footprint(h);
+
+        ColorResult {
+            emmited: self.sample(&self.emmit_img, u, v, footprint),
+            multiplied: self.sample(&self.img, u, v, footprint),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use image::ImageResult;
+
+    use crate::{
+        objects::{hit::Hit, instance::Instance, material::LAMBERTIAN, quad::Quad},
+        vec3::{ray::Ray, vec3::Vec3},
+        viewport::{camera::Camera, ray_color::ray_color, scene::Scene, Viewport},
+    };
+
+    use super::{
+        mipmap::{Filter, WrapMode},
+        ImageTexture,
+    };
+
+    fn checker_texture(size: usize) -> ImageTexture {
+        let mut img = vec![];
+        for y in 0..size {
+            for x in 0..size {
+                img.push(if (x / 2 + y / 2) % 2 == 0 {
+                    Vec3::WHITE * 0.9
+                } else {
+                    Vec3::WHITE * 0.1
+                });
+            }
+        }
+        ImageTexture::new(img, vec![Vec3::ZERO], size, size, 1, 1)
+    }
+
+    #[test]
+    fn footprint_grows_across_bounces() {
+        let texture = checker_texture(8);
+        let hit = |r: Ray, t: f32| Hit {
+            r,
+            p: r.at(t),
+            n: Vec3::BACKWARD,
+            ng: Vec3::BACKWARD,
+            t,
+            u: 0.5,
+            v: 0.5,
+            dpdu: Vec3::new(1.0, 0.0, 0.0),
+            dpdv: Vec3::UP,
         };
+        let camera = Ray::new(Vec3::new(0.0, 0.0, -4.0), Vec3::FORWARD).with_spread(0.01);
+        // the same cone after a bounce 3 units out
+        let bounced = camera.continued(3.0, Vec3::new(0.0, 0.0, -1.0), Vec3::FORWARD);
+        let direct = texture.footprint(&hit(camera, 4.0)).unwrap();
+        let after = texture.footprint(&hit(bounced, 1.0)).unwrap();
+        assert!((direct.0 .0 - 0.02).abs() < 1e-6, "{:?}", direct);
+        assert!((after.0 .0 - direct.0 .0).abs() < 1e-6, "{:?}", after);
+        assert!(texture
+            .footprint(&hit(Ray::new(Vec3::ZERO, Vec3::FORWARD), 1.0))
+            .is_none());
+    }
+
+    #[test]
+    fn texture_filtering_test() -> ImageResult<()> {
+        const WIDTH: usize = 400;
+        const HEIGHT: usize = 200;
+        for (name, filter) in [
+            ("nearest", Filter::Nearest),
+            ("bilinear", Filter::Bilinear),
+            ("trilinear", Filter::Trilinear),
+            ("ewa", Filter::Ewa),
+        ] {
+            let texture = checker_texture(256)
+                .with_filter(filter)
+                .with_wrap(WrapMode::Mirror)
+                .with_uv_transform((4.0, 4.0), (0.25, 0.0));
+            let floor = Instance::new(Arc::new([Arc::new(Quad::new(
+                Vec3::new(-50.0, -1.0, 0.0),
+                Vec3::new(100.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 200.0),
+                LAMBERTIAN.clone(),
+                Vec3::ZERO,
+                Arc::new(texture),
+            ))]));
+            let cam = Camera::new(
+                WIDTH as f32 / HEIGHT as f32,
+                Vec3::ZERO,
+                Vec3::UP,
+                Vec3::FORWARD,
+                60.0,
+                0.0,
+            );
+            let vp = Viewport::new(
+                cam,
+                Scene::new(vec![floor], 0.001, 1000.0),
+                Arc::new(ray_color),
+                WIDTH,
+                HEIGHT,
+                1,
+                1,
+                Vec3::WHITE,
+                1.0,
+            );
+            vp.render_rows_async()
+                .save(format!("test_out/texture_filter_{}.png", name))?;
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,325 @@
+use crate::vec3::vec3::Vec3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+impl WrapMode {
+    fn wrap(&self, i: isize, n: usize) -> usize {
+        let n = n as isize;
+        match self {
+            WrapMode::Repeat => i.rem_euclid(n) as usize,
+            WrapMode::Clamp => i.clamp(0, n - 1) as usize,
+            WrapMode::Mirror => {
+                let m = i.rem_euclid(2 * n);
+                (if m >= n { 2 * n - 1 - m } else { m }) as usize
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    Trilinear,
+    Ewa,
+}
+
+#[derive(Debug, Clone)]
+struct MipLevel {
+    width: usize,
+    height: usize,
+    texels: Vec<Vec3>,
+}
+
+impl MipLevel {
+    fn texel(&self, x: isize, y: isize, wrap: WrapMode) -> Vec3 {
+        let x = wrap.wrap(x, self.width);
+        let y = wrap.wrap(y, self.height);
+        self.texels[y * self.width + x]
+    }
+
+    // box filters the level down to half the resolution rounded up, the last texel of an odd
+    // row or column averages the edge with itself
+    fn downsample(&self) -> Self {
+        let width = self.width.div_ceil(2);
+        let height = self.height.div_ceil(2);
+        let mut texels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let (x, y) = (2 * x as isize, 2 * y as isize);
+                texels.push(
+                    (self.texel(x, y, WrapMode::Clamp)
+                        + self.texel(x + 1, y, WrapMode::Clamp)
+                        + self.texel(x, y + 1, WrapMode::Clamp)
+                        + self.texel(x + 1, y + 1, WrapMode::Clamp))
+                        * 0.25,
+                );
+            }
+        }
+        Self {
+            width,
+            height,
+            texels,
+        }
+    }
+}
+
+/// Image pyramid, level 0 is the full resolution image and the last level is 1x1
+#[derive(Debug, Clone)]
+pub struct MipMap {
+    levels: Vec<MipLevel>,
+}
+
+impl MipMap {
+    /// `texels` are stored row by row, starting from the top left corner
+    pub fn new(texels: Vec<Vec3>, width: usize, height: usize) -> Self {
+        assert_eq!(texels.len(), width * height, "image size does not match");
+        let mut levels = vec![MipLevel {
+            width,
+            height,
+            texels,
+        }];
+        while {
+            let last = &levels[levels.len() - 1];
+            last.width > 1 || last.height > 1
+        } {
+            let next = levels[levels.len() - 1].downsample();
+            levels.push(next);
+        }
+        Self { levels }
+    }
+
+    pub fn levels(&self) -> usize {
+        self.levels.len()
+    }
+    pub fn width(&self) -> usize {
+        self.levels[0].width
+    }
+    pub fn height(&self) -> usize {
+        self.levels[0].height
+    }
+
+    pub fn nearest(&self, u: f32, v: f32, wrap: WrapMode) -> Vec3 {
+        let l = &self.levels[0];
+        l.texel(
+            (u * l.width as f32).floor() as isize,
+            (v * l.height as f32).floor() as isize,
+            wrap,
+        )
+    }
+
+    pub fn bilinear(&self, level: usize, u: f32, v: f32, wrap: WrapMode) -> Vec3 {
+        let l = &self.levels[level.min(self.levels.len() - 1)];
+        let x = u * l.width as f32 - 0.5;
+        let y = v * l.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (dx, dy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+
+        l.texel(x0, y0, wrap) * ((1.0 - dx) * (1.0 - dy))
+            + l.texel(x0 + 1, y0, wrap) * (dx * (1.0 - dy))
+            + l.texel(x0, y0 + 1, wrap) * ((1.0 - dx) * dy)
+            + l.texel(x0 + 1, y0 + 1, wrap) * (dx * dy)
+    }
+
+    /// `width` is the footprint size in uv units
+    pub fn trilinear(&self, u: f32, v: f32, width: f32, wrap: WrapMode) -> Vec3 {
+        let lod = self.lod(width);
+        let level = lod.floor();
+        let f = lod - level;
+        let level = level as usize;
+        if f <= 0.0 || level + 1 >= self.levels.len() {
+            return self.bilinear(level, u, v, wrap);
+        }
+        self.bilinear(level, u, v, wrap) * (1.0 - f) + self.bilinear(level + 1, u, v, wrap) * f
+    }
+
+    fn lod(&self, width: f32) -> f32 {
+        let texels = width * self.width().max(self.height()) as f32;
+        if texels.is_nan() || texels <= 1.0 {
+            return 0.0;
+        }
+        texels.log2().min((self.levels.len() - 1) as f32)
+    }
+
+    /// Elliptically weighted average over the ellipse with axes `axis0` and `axis1` given in uv units
+    pub fn ewa(
+        &self,
+        u: f32,
+        v: f32,
+        axis0: (f32, f32),
+        axis1: (f32, f32),
+        wrap: WrapMode,
+    ) -> Vec3 {
+        const MAX_ANISOTROPY: f32 = 8.0;
+        let len0 = (axis0.0 * axis0.0 + axis0.1 * axis0.1).sqrt();
+        let len1 = (axis1.0 * axis1.0 + axis1.1 * axis1.1).sqrt();
+        let (major, mut minor, major_len, mut minor_len) = if len0 >= len1 {
+            (axis0, axis1, len0, len1)
+        } else {
+            (axis1, axis0, len1, len0)
+        };
+        if major_len == 0.0 {
+            return self.bilinear(0, u, v, wrap);
+        }
+        // clamp the eccentricity so the filter loop stays bounded
+        if minor_len * MAX_ANISOTROPY < major_len {
+            let scale = major_len / (minor_len.max(1e-8) * MAX_ANISOTROPY);
+            if minor_len == 0.0 {
+                minor = (-major.1 / MAX_ANISOTROPY, major.0 / MAX_ANISOTROPY);
+            } else {
+                minor = (minor.0 * scale, minor.1 * scale);
+            }
+            minor_len = major_len / MAX_ANISOTROPY;
+        }
+        let lod = self.lod(minor_len);
+        let level = lod.floor();
+        let f = lod - level;
+        let level = level as usize;
+        if f <= 0.0 || level + 1 >= self.levels.len() {
+            return self.ewa_level(level, u, v, major, minor, wrap);
+        }
+        self.ewa_level(level, u, v, major, minor, wrap) * (1.0 - f)
+            + self.ewa_level(level + 1, u, v, major, minor, wrap) * f
+    }
+
+    fn ewa_level(
+        &self,
+        level: usize,
+        u: f32,
+        v: f32,
+        axis0: (f32, f32),
+        axis1: (f32, f32),
+        wrap: WrapMode,
+    ) -> Vec3 {
+        const ALPHA: f32 = 2.0;
+        let l = &self.levels[level];
+        let (w, h) = (l.width as f32, l.height as f32);
+        let s = u * w - 0.5;
+        let t = v * h - 0.5;
+        let d0 = (axis0.0 * w, axis0.1 * h);
+        let d1 = (axis1.0 * w, axis1.1 * h);
+
+        // implicit ellipse A*s^2 + B*s*t + C*t^2 = F, the +1 keeps at least a texel in the footprint
+        let mut a = d0.1 * d0.1 + d1.1 * d1.1 + 1.0;
+        let mut b = -2.0 * (d0.0 * d0.1 + d1.0 * d1.1);
+        let mut c = d0.0 * d0.0 + d1.0 * d1.0 + 1.0;
+        let inv_f = 1.0 / (a * c - b * b * 0.25);
+        a *= inv_f;
+        b *= inv_f;
+        c *= inv_f;
+
+        let det = -b * b + 4.0 * a * c;
+        let inv_det = 1.0 / det;
+        let u_sqrt = (det * c).sqrt();
+        let v_sqrt = (a * det).sqrt();
+        let s0 = (s - 2.0 * inv_det * u_sqrt).ceil() as isize;
+        let s1 = (s + 2.0 * inv_det * u_sqrt).floor() as isize;
+        let t0 = (t - 2.0 * inv_det * v_sqrt).ceil() as isize;
+        let t1 = (t + 2.0 * inv_det * v_sqrt).floor() as isize;
+
+        let falloff = (-ALPHA).exp();
+        let mut sum = Vec3::ZERO;
+        let mut weight_sum = 0.0;
+        for it in t0..=t1 {
+            let tt = it as f32 - t;
+            for is in s0..=s1 {
+                let ss = is as f32 - s;
+                let r2 = a * ss * ss + b * ss * tt + c * tt * tt;
+                if r2 < 1.0 {
+                    let weight = (-ALPHA * r2).exp() - falloff;
+                    sum += l.texel(is, it, wrap) * weight;
+                    weight_sum += weight;
+                }
+            }
+        }
+        if weight_sum <= 0.0 {
+            return self.bilinear(level, u, v, wrap);
+        }
+        sum / weight_sum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vec3::vec3::Vec3;
+
+    use super::{MipMap, WrapMode};
+
+    fn checker(size: usize) -> MipMap {
+        let mut texels = vec![];
+        for y in 0..size {
+            for x in 0..size {
+                texels.push(if (x + y) % 2 == 0 {
+                    Vec3::WHITE
+                } else {
+                    Vec3::BLACK
+                });
+            }
+        }
+        MipMap::new(texels, size, size)
+    }
+
+    #[test]
+    fn pyramid_levels() {
+        let m = MipMap::new(vec![Vec3::WHITE; 12 * 5], 12, 5);
+        // 12x5, 6x3, 3x2, 2x1 and 1x1
+        assert_eq!(m.levels(), 5);
+        let top = m.bilinear(m.levels() - 1, 0.3, 0.7, WrapMode::Clamp);
+        assert!((top - Vec3::WHITE).length() < 1e-5, "{:?}", top);
+    }
+
+    #[test]
+    fn odd_levels_keep_the_last_row_and_column() {
+        // black 5x5 image with a white last row and column
+        let texels = (0..25)
+            .map(|i| {
+                if i % 5 == 4 || i / 5 == 4 {
+                    Vec3::WHITE
+                } else {
+                    Vec3::BLACK
+                }
+            })
+            .collect();
+        let m = MipMap::new(texels, 5, 5);
+        assert_eq!(m.levels(), 4);
+        let top = m.bilinear(m.levels() - 1, 0.5, 0.5, WrapMode::Clamp);
+        assert!(top.x > 0.1, "{:?}", top);
+    }
+
+    #[test]
+    fn wrap_modes() {
+        assert_eq!(WrapMode::Repeat.wrap(-1, 4), 3);
+        assert_eq!(WrapMode::Repeat.wrap(4, 4), 0);
+        assert_eq!(WrapMode::Clamp.wrap(-3, 4), 0);
+        assert_eq!(WrapMode::Clamp.wrap(7, 4), 3);
+        assert_eq!(WrapMode::Mirror.wrap(4, 4), 3);
+        assert_eq!(WrapMode::Mirror.wrap(-1, 4), 0);
+        assert_eq!(WrapMode::Mirror.wrap(9, 4), 1);
+    }
+
+    #[test]
+    fn edge_lookup_does_not_panic() {
+        let m = checker(4);
+        for wrap in [WrapMode::Repeat, WrapMode::Clamp, WrapMode::Mirror] {
+            m.nearest(1.0, 1.0, wrap);
+            m.bilinear(0, 1.0, 1.0, wrap);
+            m.trilinear(1.0, 1.0, 0.3, wrap);
+            m.ewa(1.0, 1.0, (0.2, 0.0), (0.0, 0.01), wrap);
+        }
+    }
+
+    #[test]
+    fn wide_footprint_averages() {
+        let m = checker(64);
+        let avg = m.trilinear(0.37, 0.61, 1.0, WrapMode::Repeat);
+        assert!((avg.x - 0.5).abs() < 0.05, "{:?}", avg);
+        let avg = m.ewa(0.37, 0.61, (0.5, 0.0), (0.0, 0.25), WrapMode::Repeat);
+        assert!((avg.x - 0.5).abs() < 0.05, "{:?}", avg);
+    }
+}
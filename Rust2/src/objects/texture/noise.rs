@@ -0,0 +1,227 @@
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::vec3::vec3::Vec3;
+
+const PERLIN_POINT_COUNT: usize = 256;
+
+fn create_permute(rng: &mut impl Rng) -> [usize; PERLIN_POINT_COUNT] {
+    let mut p = [0; PERLIN_POINT_COUNT];
+    for (i, v) in p.iter_mut().enumerate() {
+        *v = i;
+    }
+    p.shuffle(rng);
+    p
+}
+
+#[derive(Clone, Debug)]
+pub struct PerlinNoise {
+    ranvec: [Vec3; PERLIN_POINT_COUNT],
+    perm_x: [usize; PERLIN_POINT_COUNT],
+    perm_y: [usize; PERLIN_POINT_COUNT],
+    perm_z: [usize; PERLIN_POINT_COUNT],
+}
+
+impl PerlinNoise {
+    fn perlin_interp(c: [[[Vec3; 2]; 2]; 2], u: f32, v: f32, w: f32) -> f32 {
+        let uu = u * u * (3.0 - 2.0 * u);
+        let vv = v * v * (3.0 - 2.0 * v);
+        let ww = w * w * (3.0 - 2.0 * w);
+        let mut accum = 0.0;
+
+        for (i, ci) in c.iter().enumerate() {
+            for (j, cij) in ci.iter().enumerate() {
+                for (k, cijk) in cij.iter().enumerate() {
+                    let fi = i as f32;
+                    let fj = j as f32;
+                    let fk = k as f32;
+                    let weight_v = Vec3::new(u - fi, v - fj, w - fk);
+                    accum += (fi * uu + (1.0 - fi) * (1.0 - uu))
+                        * (fj * vv + (1.0 - fj) * (1.0 - vv))
+                        * (fk * ww + (1.0 - fk) * (1.0 - ww))
+                        * cijk.dot(weight_v);
+                }
+            }
+        }
+
+        accum
+    }
+
+    pub fn new() -> Self {
+        Self::from_rng(&mut rand::thread_rng())
+    }
+    pub fn with_seed(seed: u64) -> Self {
+        Self::from_rng(&mut StdRng::seed_from_u64(seed))
+    }
+    fn from_rng(rng: &mut impl Rng) -> Self {
+        let mut ranvec = [Vec3::ZERO; PERLIN_POINT_COUNT];
+        for v in ranvec.iter_mut() {
+            *v = loop {
+                let p = Vec3::new(
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                );
+                if p.length2() > 1e-4 && p.length2() <= 1.0 {
+                    break p.unit();
+                }
+            };
+        }
+
+        Self {
+            ranvec,
+            perm_x: create_permute(rng),
+            perm_y: create_permute(rng),
+            perm_z: create_permute(rng),
+        }
+    }
+
+    /// Noise remapped from `[-1, 1]` to `[0, 1]`
+    pub fn value(&self, p: Vec3) -> f32 {
+        (1.0 + self.noise(p)) * 0.5
+    }
+
+    pub fn noise(&self, p: Vec3) -> f32 {
+        let u = p.x - p.x.floor();
+        let v = p.y - p.y.floor();
+        let w = p.z - p.z.floor();
+
+        let i = p.x.floor() as isize;
+        let j = p.y.floor() as isize;
+        let k = p.z.floor() as isize;
+        let mut c = [[[Vec3::ZERO; 2]; 2]; 2];
+
+        for di in 0..2isize {
+            for dj in 0..2isize {
+                for dk in 0..2isize {
+                    c[di as usize][dj as usize][dk as usize] = self.ranvec[self.perm_x
+                        [((i + di) & 255) as usize]
+                        ^ self.perm_y[((j + dj) & 255) as usize]
+                        ^ self.perm_z[((k + dk) & 255) as usize]];
+                }
+            }
+        }
+        Self::perlin_interp(c, u, v, w)
+    }
+
+    pub fn turb(&self, p: Vec3, depth: usize) -> f32 {
+        let mut accum: f32 = 0.0;
+        let mut temp_p = p;
+        let mut weight = 1.0;
+
+        for _ in 0..depth {
+            accum += weight * self.noise(temp_p);
+            weight *= 0.5;
+            temp_p *= 2.0;
+        }
+
+        accum.abs()
+    }
+}
+
+impl Default for PerlinNoise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Cellular noise with one feature point per unit cell.
+#[derive(Clone, Debug)]
+pub struct WorleyNoise {
+    points: [Vec3; PERLIN_POINT_COUNT],
+    perm_x: [usize; PERLIN_POINT_COUNT],
+    perm_y: [usize; PERLIN_POINT_COUNT],
+    perm_z: [usize; PERLIN_POINT_COUNT],
+}
+
+impl WorleyNoise {
+    pub fn new() -> Self {
+        Self::from_rng(&mut rand::thread_rng())
+    }
+    pub fn with_seed(seed: u64) -> Self {
+        Self::from_rng(&mut StdRng::seed_from_u64(seed))
+    }
+    fn from_rng(rng: &mut impl Rng) -> Self {
+        let mut points = [Vec3::ZERO; PERLIN_POINT_COUNT];
+        for p in points.iter_mut() {
+            *p = Vec3::new(rng.gen(), rng.gen(), rng.gen());
+        }
+        Self {
+            points,
+            perm_x: create_permute(rng),
+            perm_y: create_permute(rng),
+            perm_z: create_permute(rng),
+        }
+    }
+
+    fn feature_point(&self, i: isize, j: isize, k: isize) -> Vec3 {
+        let offset = self.points[self.perm_x[(i & 255) as usize]
+            ^ self.perm_y[(j & 255) as usize]
+            ^ self.perm_z[(k & 255) as usize]];
+        Vec3::new(i as f32, j as f32, k as f32) + offset
+    }
+
+    /// Returns distances to the closest and second closest feature points (F1, F2)
+    pub fn distances(&self, p: Vec3) -> (f32, f32) {
+        let i = p.x.floor() as isize;
+        let j = p.y.floor() as isize;
+        let k = p.z.floor() as isize;
+
+        let mut f1 = f32::INFINITY;
+        let mut f2 = f32::INFINITY;
+        for di in -1..=1 {
+            for dj in -1..=1 {
+                for dk in -1..=1 {
+                    let d = (self.feature_point(i + di, j + dj, k + dk) - p).length2();
+                    if d < f1 {
+                        f2 = f1;
+                        f1 = d;
+                    } else if d < f2 {
+                        f2 = d;
+                    }
+                }
+            }
+        }
+        (f1.sqrt(), f2.sqrt())
+    }
+}
+
+impl Default for WorleyNoise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vec3::vec3::Vec3;
+
+    use super::{PerlinNoise, WorleyNoise};
+
+    #[test]
+    fn perlin_range() {
+        let noise = PerlinNoise::with_seed(7);
+        for _ in 0..1000 {
+            let p = Vec3::random(-50.0, 50.0);
+            let n = noise.noise(p);
+            assert!(n.abs() <= 1.0, "noise out of range: {}", n);
+            assert!(noise.turb(p, 7) >= 0.0);
+        }
+    }
+
+    #[test]
+    fn perlin_seeded_is_deterministic() {
+        let a = PerlinNoise::with_seed(42);
+        let b = PerlinNoise::with_seed(42);
+        let p = Vec3::new(1.3, -2.7, 0.4);
+        assert_eq!(a.noise(p), b.noise(p));
+    }
+
+    #[test]
+    fn worley_feature_point_is_zero() {
+        let noise = WorleyNoise::with_seed(3);
+        let p = noise.feature_point(2, -1, 5);
+        let (f1, f2) = noise.distances(p);
+        assert!(f1 < 1e-5);
+        assert!(f2 >= f1);
+    }
+}
@@ -0,0 +1,304 @@
+use crate::{objects::hit::Hit, vec3::vec3::Vec3};
+
+use super::{
+    noise::{PerlinNoise, WorleyNoise},
+    ColorResult, Texture,
+};
+
+/// Piecewise linear gradient over `[0, 1]`
+#[derive(Clone, Debug)]
+pub struct ColorRamp {
+    // sorted by position
+    stops: Vec<(f32, Vec3)>,
+}
+
+impl ColorRamp {
+    pub fn new(mut stops: Vec<(f32, Vec3)>) -> Self {
+        assert!(!stops.is_empty(), "color ramp needs at least one stop");
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self { stops }
+    }
+    pub fn two_color(a: Vec3, b: Vec3) -> Self {
+        Self::new(vec![(0.0, a), (1.0, b)])
+    }
+
+    pub fn at(&self, t: f32) -> Vec3 {
+        let first = self.stops[0];
+        if t <= first.0 {
+            return first.1;
+        }
+        for w in self.stops.windows(2) {
+            let (t0, c0) = w[0];
+            let (t1, c1) = w[1];
+            if t <= t1 {
+                let f = if t1 - t0 > 0.0 {
+                    (t - t0) / (t1 - t0)
+                } else {
+                    1.0
+                };
+                return c0 * (1.0 - f) + c1 * f;
+            }
+        }
+        self.stops[self.stops.len() - 1].1
+    }
+}
+
+fn multiplied(c: Vec3) -> ColorResult {
+    ColorResult {
+        emmited: Vec3::ZERO,
+        multiplied: c,
+    }
+}
+
+/// Perlin noise, or turbulence when `depth > 1`
+pub struct NoiseTexture {
+    noise: PerlinNoise,
+    pub scale: f32,
+    pub depth: usize,
+    pub ramp: ColorRamp,
+}
+
+impl NoiseTexture {
+    pub fn new(scale: f32, depth: usize, ramp: ColorRamp) -> Self {
+        Self {
+            noise: PerlinNoise::new(),
+            scale,
+            depth,
+            ramp,
+        }
+    }
+    pub fn with_noise(mut self, noise: PerlinNoise) -> Self {
+        self.noise = noise;
+        self
+    }
+}
+
+impl Texture for NoiseTexture {
+    fn color_at(&self, h: &Hit) -> ColorResult {
+        let p = h.p * self.scale;
+        let t = if self.depth <= 1 {
+            self.noise.value(p)
+        } else {
+            self.noise.turb(p, self.depth)
+        };
+        multiplied(self.ramp.at(t))
+    }
+}
+
+/// Turbulence-distorted sine bands along `axis`
+pub struct MarbleTexture {
+    noise: PerlinNoise,
+    pub scale: f32,
+    pub axis: Vec3,
+    pub turbulence: f32,
+    pub depth: usize,
+    pub ramp: ColorRamp,
+}
+
+impl MarbleTexture {
+    pub fn new(scale: f32, ramp: ColorRamp) -> Self {
+        Self {
+            noise: PerlinNoise::new(),
+            scale,
+            axis: Vec3::FORWARD,
+            turbulence: 10.0,
+            depth: 7,
+            ramp,
+        }
+    }
+    pub fn with_noise(mut self, noise: PerlinNoise) -> Self {
+        self.noise = noise;
+        self
+    }
+}
+
+impl Texture for MarbleTexture {
+    fn color_at(&self, h: &Hit) -> ColorResult {
+        let p = h.p * self.scale;
+        let phase = p.dot(self.axis) + self.turbulence * self.noise.turb(p, self.depth);
+        multiplied(self.ramp.at(0.5 * (1.0 + phase.sin())))
+    }
+}
+
+/// 3D checkerboard with cells of size `1 / scale`
+pub struct CheckerTexture {
+    pub scale: f32,
+    pub ramp: ColorRamp,
+}
+
+impl CheckerTexture {
+    pub fn new(scale: f32, ramp: ColorRamp) -> Self {
+        Self { scale, ramp }
+    }
+}
+
+impl Texture for CheckerTexture {
+    fn color_at(&self, h: &Hit) -> ColorResult {
+        let p = h.p * self.scale;
+        // small offset keeps axis-aligned faces at integer coordinates from flickering
+        let sum = (p.x + 1e-4).floor() + (p.y + 1e-4).floor() + (p.z + 1e-4).floor();
+        let t = if sum.rem_euclid(2.0) < 1.0 { 0.0 } else { 1.0 };
+        multiplied(self.ramp.at(t))
+    }
+}
+
+/// Concentric rings around the line through `center` along `axis`
+pub struct WoodTexture {
+    noise: PerlinNoise,
+    pub scale: f32,
+    pub center: Vec3,
+    pub axis: Vec3,
+    pub rings: f32,
+    pub turbulence: f32,
+    pub ramp: ColorRamp,
+}
+
+impl WoodTexture {
+    pub fn new(scale: f32, rings: f32, ramp: ColorRamp) -> Self {
+        Self {
+            noise: PerlinNoise::new(),
+            scale,
+            center: Vec3::ZERO,
+            axis: Vec3::UP,
+            rings,
+            turbulence: 0.5,
+            ramp,
+        }
+    }
+    pub fn with_noise(mut self, noise: PerlinNoise) -> Self {
+        self.noise = noise;
+        self
+    }
+}
+
+impl Texture for WoodTexture {
+    fn color_at(&self, h: &Hit) -> ColorResult {
+        let p = (h.p - self.center) * self.scale;
+        let axis = self.axis.unit();
+        let radial = p - axis * p.dot(axis);
+        let r = radial.length() * self.rings + self.turbulence * self.noise.turb(p, 4);
+        multiplied(self.ramp.at(r - r.floor()))
+    }
+}
+
+/// Cellular texture, `F1` or `F2 - F1` of the distance to feature points
+pub struct WorleyTexture {
+    noise: WorleyNoise,
+    pub scale: f32,
+    pub edges: bool,
+    pub ramp: ColorRamp,
+}
+
+impl WorleyTexture {
+    pub fn new(scale: f32, edges: bool, ramp: ColorRamp) -> Self {
+        Self {
+            noise: WorleyNoise::new(),
+            scale,
+            edges,
+            ramp,
+        }
+    }
+    pub fn with_noise(mut self, noise: WorleyNoise) -> Self {
+        self.noise = noise;
+        self
+    }
+}
+
+impl Texture for WorleyTexture {
+    fn color_at(&self, h: &Hit) -> ColorResult {
+        let (f1, f2) = self.noise.distances(h.p * self.scale);
+        let t = if self.edges { f2 - f1 } else { f1 };
+        multiplied(self.ramp.at(t.clamp(0.0, 1.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use image::ImageResult;
+
+    use crate::{
+        objects::{instance::Instance, material::LAMBERTIAN, quad::Quad, sphere::Sphere},
+        vec3::vec3::Vec3,
+        viewport::{camera::Camera, ray_color::ray_color, scene::Scene, Viewport},
+    };
+
+    use super::*;
+
+    #[test]
+    fn ramp_interpolation() {
+        let ramp = ColorRamp::new(vec![
+            (1.0, Vec3::WHITE),
+            (0.0, Vec3::BLACK),
+            (0.5, Vec3::new(1.0, 0.0, 0.0)),
+        ]);
+        assert_eq!(ramp.at(-1.0), Vec3::BLACK);
+        assert_eq!(ramp.at(0.25), Vec3::new(0.5, 0.0, 0.0));
+        assert_eq!(ramp.at(0.75), Vec3::new(1.0, 0.5, 0.5));
+        assert_eq!(ramp.at(2.0), Vec3::WHITE);
+    }
+
+    #[test]
+    fn solid_textures_test() -> ImageResult<()> {
+        const WIDTH: usize = 500;
+        const HEIGHT: usize = 200;
+        let ramp = ColorRamp::two_color(Vec3::WHITE * 0.1, Vec3::WHITE * 0.9);
+        let textures: [Arc<dyn Texture + Send + Sync>; 5] = [
+            Arc::new(NoiseTexture::new(4.0, 7, ramp.clone())),
+            Arc::new(MarbleTexture::new(4.0, ramp.clone())),
+            Arc::new(CheckerTexture::new(8.0, ramp.clone())),
+            Arc::new(WoodTexture::new(
+                2.0,
+                8.0,
+                ColorRamp::two_color(Vec3::new(0.6, 0.4, 0.2), Vec3::new(0.3, 0.15, 0.05)),
+            )),
+            Arc::new(WorleyTexture::new(6.0, true, ramp)),
+        ];
+        let mut instances: Vec<Instance> = textures
+            .into_iter()
+            .enumerate()
+            .map(|(i, texture)| {
+                Instance::new(Arc::new([Arc::new(Sphere {
+                    origin: Vec3::new(2.0 - i as f32, 0.0, 3.0),
+                    radius: 0.45,
+                    mat: LAMBERTIAN.clone(),
+                    texture,
+                })]))
+            })
+            .collect();
+        instances.push(Instance::new(Arc::new([Arc::new(Quad::new(
+            Vec3::new(-5.0, -0.45, 0.0),
+            Vec3::new(10.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 10.0),
+            LAMBERTIAN.clone(),
+            Vec3::ZERO,
+            Arc::new(CheckerTexture::new(
+                2.0,
+                ColorRamp::two_color(Vec3::new(0.2, 0.3, 0.1), Vec3::WHITE * 0.9),
+            )),
+        ))])));
+
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::ZERO,
+            Vec3::UP,
+            Vec3::FORWARD,
+            50.0,
+            0.0,
+        );
+        let vp = Viewport::new(
+            cam,
+            Scene::new(instances, 0.001, 1000.0),
+            Arc::new(ray_color),
+            WIDTH,
+            HEIGHT,
+            16,
+            5,
+            Vec3::WHITE * 0.8,
+            2.0,
+        );
+        vp.render_rows_async()
+            .save("test_out/solid_textures_test.png")
+    }
+}
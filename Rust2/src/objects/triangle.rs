@@ -1,9 +1,13 @@
 use std::sync::Arc;
 
-use crate::vec3::{ray::Ray, vec3::Vec3};
+use crate::{
+    animation::{segment, times_between, Animatable},
+    vec3::{ray::Ray, vec3::Vec3},
+};
 
 use super::{
     aabb::{maxf, minf, Interval},
+    hit::Hit,
     material::Material,
     texture::Texture,
     Object,
@@ -21,6 +25,8 @@ pub struct Triangle {
     normal: Vec3,
     d: f32,
     w: Vec3,
+    // shading normals at the origin, u and v corners
+    normals: Option<[Vec3; 3]>,
 }
 
 impl Triangle {
@@ -32,8 +38,7 @@ impl Triangle {
         mat: Arc<dyn Material + Send + Sync>,
         texture: Arc<dyn Texture + Send + Sync>,
     ) -> Self {
-        let n = u.cross(v);
-        let normal = n.unit();
+        let (normal, d, w) = plane(origin, u, v);
         Self {
             origin,
             u,
@@ -42,10 +47,100 @@ impl Triangle {
             // velocity,
             texture,
             normal,
-            d: normal.dot(origin),
-            w: n / n.dot(n),
+            d,
+            w,
+            normals: None,
         }
     }
+
+    /// Smooth shading from normals at the origin, `origin + u` and `origin + v` corners,
+    /// interpolated over the face while hits keep the flat geometric normal
+    pub fn with_normals(mut self, normals: [Vec3; 3]) -> Self {
+        self.normals = Some(normals);
+        self
+    }
+}
+
+// normal, distance from the origin and scaled normal for the barycentric coordinates
+fn plane(origin: Vec3, u: Vec3, v: Vec3) -> (Vec3, f32, Vec3) {
+    let n = u.cross(v);
+    let normal = n.unit();
+    (normal, normal.dot(origin), n / n.dot(n))
+}
+
+fn bounds(origin: Vec3, u: Vec3, v: Vec3) -> (Interval, Interval, Interval) {
+    let v_corner = origin + v;
+    let u_corner = origin + u;
+    let mut x = Interval {
+        min: minf(v_corner.x, minf(u_corner.x, origin.x)),
+        max: maxf(v_corner.x, maxf(u_corner.x, origin.x)),
+    };
+    if x.max - x.min < Triangle::MIN_AABB_WIDTH {
+        let c = 0.5 * (x.max + x.min);
+        x.max = c + Triangle::MIN_AABB_WIDTH * 0.5;
+        x.min = c - Triangle::MIN_AABB_WIDTH * 0.5;
+    }
+    let mut y = Interval {
+        min: minf(v_corner.y, minf(u_corner.y, origin.y)),
+        max: maxf(v_corner.y, maxf(u_corner.y, origin.y)),
+    };
+    if y.max - y.min < Triangle::MIN_AABB_WIDTH {
+        let c = 0.5 * (y.max + y.min);
+        y.max = c + Triangle::MIN_AABB_WIDTH * 0.5;
+        y.min = c - Triangle::MIN_AABB_WIDTH * 0.5;
+    }
+    let mut z = Interval {
+        min: minf(v_corner.z, minf(u_corner.z, origin.z)),
+        max: maxf(v_corner.z, maxf(u_corner.z, origin.z)),
+    };
+    if z.max - z.min < Triangle::MIN_AABB_WIDTH {
+        let c = 0.5 * (z.max + z.min);
+        z.max = c + Triangle::MIN_AABB_WIDTH * 0.5;
+        z.min = c - Triangle::MIN_AABB_WIDTH * 0.5;
+    }
+    (x, y, z)
+}
+
+fn hit_triangle(
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    (normal, d, w): (Vec3, f32, Vec3),
+    r: Ray,
+    mint: f32,
+    maxt: f32,
+) -> Option<Hit> {
+    // eprintln!("Get hit");
+    let denominator = normal.dot(r.direction);
+    if denominator.abs() <= 1e-8 {
+        // eprintln!("parallel");
+        return None;
+    }
+    let t = (d - normal.dot(r.origin)) / denominator;
+    if t < mint || t > maxt {
+        // eprintln!("Out of range");
+        return None;
+    }
+    let point = r.at(t);
+    let planar = point - origin;
+
+    let alfa = w.dot(planar.cross(v));
+    let beta = w.dot(u.cross(planar));
+    if alfa < 0.0 || beta < 0.0 || alfa + beta > 1.0 {
+        // eprintln!("Out of quad");
+        return None;
+    }
+    Some(Hit {
+        t,
+        n: normal,
+        ng: normal,
+        p: point,
+        r,
+        u: alfa,
+        v: beta,
+        dpdu: u,
+        dpdv: v,
+    })
 }
 
 impl Object for Triangle {
@@ -56,71 +151,26 @@ impl Object for Triangle {
         super::aabb::Interval,
         super::aabb::Interval,
     ) {
-        let v_corner = self.origin + self.v;
-        let u_corner = self.origin + self.u;
-        let mut x = Interval {
-            min: minf(v_corner.x, minf(u_corner.x, self.origin.x)),
-            max: maxf(v_corner.x, maxf(u_corner.x, self.origin.x)),
-        };
-        if x.max - x.min < Triangle::MIN_AABB_WIDTH {
-            let c = 0.5 * (x.max + x.min);
-            x.max = c + Triangle::MIN_AABB_WIDTH * 0.5;
-            x.min = c - Triangle::MIN_AABB_WIDTH * 0.5;
-        }
-        let mut y = Interval {
-            min: minf(v_corner.y, minf(u_corner.y, self.origin.y)),
-            max: maxf(v_corner.y, maxf(u_corner.y, self.origin.y)),
-        };
-        if y.max - y.min < Triangle::MIN_AABB_WIDTH {
-            let c = 0.5 * (y.max + y.min);
-            y.max = c + Triangle::MIN_AABB_WIDTH * 0.5;
-            y.min = c - Triangle::MIN_AABB_WIDTH * 0.5;
-        }
-        let mut z = Interval {
-            min: minf(v_corner.z, minf(u_corner.z, self.origin.z)),
-            max: maxf(v_corner.z, maxf(u_corner.z, self.origin.z)),
-        };
-        if z.max - z.min < Triangle::MIN_AABB_WIDTH {
-            let c = 0.5 * (z.max + z.min);
-            z.max = c + Triangle::MIN_AABB_WIDTH * 0.5;
-            z.min = c - Triangle::MIN_AABB_WIDTH * 0.5;
-        }
-        (x, y, z)
+        bounds(self.origin, self.u, self.v)
     }
 
     fn get_hit(&self, r: crate::vec3::ray::Ray, mint: f32, maxt: f32) -> Option<super::hit::Hit> {
-        // eprintln!("Get hit");
-        let denominator = self.normal.dot(r.direction);
-        if denominator.abs() <= 1e-8 {
-            // eprintln!("parallel");
-            return None;
-        }
-        let t = (self.d - self.normal.dot(r.origin)) / denominator;
-        if t < mint || t > maxt {
-            // eprintln!("Out of range");
-            // dbg!(t);
-            // dbg!(r);
-            // dbg!(self);
-            return None;
-        }
-        let point = r.at(t);
-        let planar = point - self.origin;
-
-        let alfa = self.w.dot(planar.cross(self.v));
-        let beta = self.w.dot(self.u.cross(planar));
-        if alfa < 0.0 || beta < 0.0 || alfa + beta > 1.0 {
-            // eprintln!("Out of quad");
-            // eprintln!("alfa: {alfa}, beta: {beta}");
-            return None;
-        }
-        // eprintln!("Hit");
-        // debug_assert!(self.normal.length2() > 1e-10);
-        Some(super::Hit {
-            t: t,
-            n: self.normal,
-            p: point,
+        let mut h = hit_triangle(
+            self.origin,
+            self.u,
+            self.v,
+            (self.normal, self.d, self.w),
             r,
-        })
+            mint,
+            maxt,
+        )?;
+        if let Some([n0, n1, n2]) = self.normals {
+            let n = n0 * (1.0 - h.u - h.v) + n1 * h.u + n2 * h.v;
+            if n.length2() > 0.0 {
+                h.n = n.unit();
+            }
+        }
+        Some(h)
     }
 
     fn reflect(&self, h: &super::hit::Hit) -> Ray {
@@ -128,11 +178,7 @@ impl Object for Triangle {
     }
 
     fn color(&self, h: &super::hit::Hit) -> super::texture::ColorResult {
-        let planar = h.p - self.origin;
-        let alfa = self.w.dot(planar.cross(self.v));
-        let beta = self.w.dot(self.u.cross(planar));
-        // eprintln!("color: {:?}", self.texture.color_at(alfa, beta).multiplied);
-        self.texture.color_at(alfa, beta)
+        self.texture.color_at(h)
     }
 
     fn generator_pdf(&self, h: &super::hit::Hit, r: &Ray) -> f32 {
@@ -142,6 +188,138 @@ impl Object for Triangle {
     fn material_pdf(&self, h: &super::hit::Hit, r: &Ray) -> f32 {
         self.mat.material_pdf(h, r)
     }
+
+    fn is_specular(&self, _h: &super::hit::Hit) -> bool {
+        self.mat.is_specular()
+    }
+
+    fn material(&self) -> Option<&Arc<dyn Material + Send + Sync>> {
+        Some(&self.mat)
+    }
+}
+
+/// Triangle mesh with vertices moving during the shutter. Every frame holds the positions
+/// of all vertices, the frames are evenly spaced over [0, 1] and rays see the mesh at their time
+#[derive(Clone)]
+pub struct DeformingMesh {
+    frames: Arc<[Vec<Vec3>]>,
+    indices: Vec<[usize; 3]>,
+    pub mat: Arc<dyn Material + Send + Sync>,
+    pub texture: Arc<dyn Texture + Send + Sync>,
+}
+
+impl DeformingMesh {
+    pub fn new(
+        frames: Vec<Vec<Vec3>>,
+        indices: Vec<[usize; 3]>,
+        mat: Arc<dyn Material + Send + Sync>,
+        texture: Arc<dyn Texture + Send + Sync>,
+    ) -> Self {
+        assert!(!frames.is_empty(), "a mesh needs at least one frame");
+        let vertices = frames[0].len();
+        assert!(
+            frames.iter().all(|f| f.len() == vertices),
+            "every frame needs a position for every vertex"
+        );
+        assert!(
+            indices.iter().flatten().all(|&i| i < vertices),
+            "triangle indices out of range"
+        );
+        Self {
+            frames: frames.into(),
+            indices,
+            mat,
+            texture,
+        }
+    }
+
+    /// The triangles of the mesh as objects of an instance
+    pub fn triangles(&self) -> Arc<[Arc<dyn Object + Send + Sync>]> {
+        self.indices
+            .iter()
+            .map(|&indices| {
+                Arc::new(DeformingTriangle {
+                    frames: self.frames.clone(),
+                    indices,
+                    mat: self.mat.clone(),
+                    texture: self.texture.clone(),
+                }) as Arc<dyn Object + Send + Sync>
+            })
+            .collect()
+    }
+}
+
+/// One triangle of a `DeformingMesh`
+pub struct DeformingTriangle {
+    frames: Arc<[Vec<Vec3>]>,
+    indices: [usize; 3],
+    mat: Arc<dyn Material + Send + Sync>,
+    texture: Arc<dyn Texture + Send + Sync>,
+}
+
+impl DeformingTriangle {
+    // origin and edges at `time`
+    fn at(&self, time: f32) -> (Vec3, Vec3, Vec3) {
+        let (i, t) = segment(self.frames.len(), time);
+        let vertex = |k: usize| {
+            let a = self.frames[i][self.indices[k]];
+            if t == 0.0 {
+                a
+            } else {
+                a.lerp(&self.frames[i + 1][self.indices[k]], t)
+            }
+        };
+        let origin = vertex(0);
+        (origin, vertex(1) - origin, vertex(2) - origin)
+    }
+}
+
+impl Object for DeformingTriangle {
+    fn get_aabb(&self) -> (Interval, Interval, Interval) {
+        self.get_aabb_during(0.0, 1.0)
+    }
+
+    fn get_aabb_during(&self, t0: f32, t1: f32) -> (Interval, Interval, Interval) {
+        // the vertices move linearly between the frames
+        let mut times = times_between(self.frames.len(), t0, t1).into_iter();
+        let (origin, u, v) = self.at(times.next().unwrap());
+        let mut b = bounds(origin, u, v);
+        for t in times {
+            let (origin, u, v) = self.at(t);
+            let next = bounds(origin, u, v);
+            b = (b.0 + next.0, b.1 + next.1, b.2 + next.2);
+        }
+        b
+    }
+
+    fn get_hit(&self, r: Ray, mint: f32, maxt: f32) -> Option<Hit> {
+        let (origin, u, v) = self.at(r.time);
+        hit_triangle(origin, u, v, plane(origin, u, v), r, mint, maxt)
+    }
+
+    fn reflect(&self, h: &Hit) -> Ray {
+        self.mat.on_hit(h)
+    }
+
+    fn color(&self, h: &Hit) -> super::texture::ColorResult {
+        self.texture.color_at(h)
+    }
+
+    fn generator_pdf(&self, h: &Hit, r: &Ray) -> f32 {
+        self.mat.generator_pdf(h, r)
+    }
+
+    fn material_pdf(&self, h: &Hit, r: &Ray) -> f32 {
+        self.mat.material_pdf(h, r)
+    }
+
+    fn is_specular(&self, _h: &Hit) -> bool {
+        self.mat.is_specular()
+    }
+
+    fn material(&self) -> Option<&Arc<dyn Material + Send + Sync>> {
+        Some(&self.mat)
+    }
 }
 
 #[cfg(test)]
@@ -151,14 +329,169 @@ mod tests {
     use image::ImageResult;
 
     use crate::{
+        animation::Steps,
         objects::{
-            instance::Instance, material::LAMBERTIAN, texture::ConstColorTexture,
+            instance::Instance,
+            light::{Light, PointLight},
+            material::LAMBERTIAN,
+            sphere::DeformingSphere,
+            texture::ConstColorTexture,
             triangle::Triangle,
+            Object,
+        },
+        vec3::{ray::Ray, vec3::Vec3},
+        viewport::{
+            camera::Camera,
+            ray_color::{next_event_ray_color, ray_color},
+            scene::Scene,
+            Viewport,
         },
-        vec3::vec3::Vec3,
-        viewport::{camera::Camera, ray_color::ray_color, scene::Scene, Viewport},
     };
 
+    use super::DeformingMesh;
+
+    // a square flapping up at its right side and back down
+    fn flag() -> DeformingMesh {
+        let square = |lift: f32| {
+            vec![
+                Vec3::new(1.0, -1.0, 3.0),
+                Vec3::new(-1.0, -1.0, 3.0 - lift),
+                Vec3::new(-1.0, 1.0, 3.0 - lift),
+                Vec3::new(1.0, 1.0, 3.0),
+            ]
+        };
+        DeformingMesh::new(
+            vec![square(0.0), square(2.0), square(0.0)],
+            vec![[0, 1, 2], [0, 2, 3]],
+            LAMBERTIAN.clone(),
+            Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.5, Vec3::BLACK)),
+        )
+    }
+
+    #[test]
+    fn deforming_mesh_follows_ray_time() {
+        let scene = Scene::new(vec![Instance::new(flag().triangles())], 0.001, 100.0);
+        let depth = |time: f32| {
+            let r = Ray::new_with_time(Vec3::UP * 0.3, Vec3::FORWARD, time);
+            scene.get_hit(r).unwrap().0.p.z
+        };
+        assert!((depth(0.0) - 3.0).abs() < 1e-4);
+        // the middle of the square moves half as far as its left side
+        assert!((depth(0.5) - 2.0).abs() < 1e-4);
+        assert!((depth(0.25) - 2.5).abs() < 1e-4);
+        assert!((depth(1.0) - 3.0).abs() < 1e-4);
+
+        let triangle = &flag().triangles()[0];
+        let (_, _, z) = triangle.get_aabb();
+        assert!(z.min < 1.01 && z.max > 2.99);
+        let (_, _, z) = triangle.get_aabb_during(0.9, 1.0);
+        assert!(z.min > 1.3 && z.max > 2.99, "{:?}", z);
+    }
+
+    #[test]
+    fn deforming_sphere_follows_ray_time() {
+        let sphere = DeformingSphere::new(
+            Steps::new(vec![Vec3::FORWARD * 3.0, Vec3::FORWARD * 3.0 + Vec3::UP]),
+            Steps::new(vec![0.5, 1.0, 0.5]),
+            LAMBERTIAN.clone(),
+            Arc::new(ConstColorTexture::new(Vec3::WHITE, Vec3::BLACK)),
+        );
+        let hit = |time: f32| {
+            sphere
+                .get_hit(
+                    Ray::new_with_time(Vec3::ZERO, Vec3::FORWARD, time),
+                    0.001,
+                    10.0,
+                )
+                .map(|h| h.p.z)
+        };
+        assert!((hit(0.0).unwrap() - 2.5).abs() < 1e-4);
+        // half way the center moved up by half and the radius grew to 1
+        let z = 3.0 - 0.75f32.sqrt();
+        assert!((hit(0.5).unwrap() - z).abs() < 1e-4);
+        assert!(hit(1.0).is_none());
+        let (_, y, _) = sphere.get_aabb();
+        assert!((y.min + 0.5).abs() < 1e-5 && (y.max - 1.5).abs() < 1e-5);
+        let (_, y, _) = sphere.get_aabb_during(0.0, 0.25);
+        assert!((y.min + 0.5).abs() < 1e-5 && (y.max - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn smooth_normals_are_interpolated() {
+        let tilted = Vec3::new(1.0, 0.0, 1.0).unit();
+        let triangle = Triangle::new(
+            Vec3::ZERO,
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            LAMBERTIAN.clone(),
+            Arc::new(ConstColorTexture::new(Vec3::WHITE, Vec3::ZERO)),
+        )
+        .with_normals([Vec3::FORWARD, tilted, Vec3::FORWARD]);
+        let at = |x: f32, y: f32| {
+            triangle
+                .get_hit(Ray::new(Vec3::new(x, y, -1.0), Vec3::FORWARD), 0.0, 10.0)
+                .unwrap()
+        };
+        let corner = at(0.0001, 0.0001);
+        assert!(corner.n.dot(Vec3::FORWARD) > 0.9999);
+        assert_eq!(corner.ng, Vec3::FORWARD);
+        let edge = at(0.9998, 0.0001);
+        assert!(edge.n.dot(tilted) > 0.9999);
+        let middle = at(0.5, 0.0);
+        assert!((middle.n.length() - 1.0).abs() < 1e-5);
+        assert_eq!(middle.n, (Vec3::FORWARD + tilted).unit());
+        assert_eq!(middle.ng, Vec3::FORWARD);
+    }
+
+    #[test]
+    fn deformation_blur_test() -> ImageResult<()> {
+        const WIDTH: usize = 120;
+        const HEIGHT: usize = 90;
+        let sphere = DeformingSphere::new(
+            Steps::new(vec![
+                Vec3::new(1.2, -0.5, 3.0),
+                Vec3::new(1.2, 0.5, 3.0),
+                Vec3::new(1.2, 0.0, 3.0),
+            ]),
+            Steps::new(vec![0.3, 0.6]),
+            LAMBERTIAN.clone(),
+            Arc::new(ConstColorTexture::new(
+                Vec3::new(0.8, 0.3, 0.2),
+                Vec3::BLACK,
+            )),
+        );
+        let objects = vec![
+            Instance::new(flag().triangles()),
+            Instance::new(Arc::new([Arc::new(sphere)])),
+        ];
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![Arc::new(PointLight::new(
+            Vec3::new(0.0, 2.0, 0.0),
+            Vec3::WHITE * 20.0,
+        ))];
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::ZERO,
+            Vec3::UP,
+            Vec3::FORWARD,
+            60.0,
+            0.0,
+        );
+        Viewport::new(
+            cam,
+            Scene::new(objects, 0.001, 100.0).with_lights(lights),
+            Arc::new(next_event_ray_color),
+            WIDTH,
+            HEIGHT,
+            16,
+            2,
+            Vec3::new(0.4, 0.5, 0.7),
+            2.2,
+        )
+        .with_shutter(0.0, 1.0)
+        .render()
+        .save("test_out/deformation_blur_test.png")
+    }
+
     #[test]
     fn triangle_test() -> ImageResult<()> {
         const WIDTH: usize = 400;
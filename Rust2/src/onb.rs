@@ -39,6 +39,20 @@ impl ONB {
         Self { u, v, w: unit_w }
     }
 
+    /// Tangent frame with `w` along `n`, `u` along `t` projected onto the surface
+    /// and `v` on the same side as `bitangent`. Falls back to `new_from_w` for degenerate tangents.
+    pub fn new_from_tangent(n: Vec3, t: Vec3, bitangent: Vec3) -> Self {
+        let w = n.unit();
+        let projected = t - w * t.dot(w);
+        if projected.length2() < 1e-12 {
+            return Self::new_from_w(n);
+        }
+        let u = projected.unit();
+        let v = w.cross(u);
+        let v = if v.dot(bitangent) < 0.0 { -v } else { v };
+        Self { u, v, w }
+    }
+
     pub fn from_local(&self, v: Vec3) -> Vec3 {
         self.u * v.x + self.v * v.y + self.w * v.z
     }
@@ -1,155 +1,800 @@
-use std::cmp::min;
+use std::{collections::HashMap, fmt::Display, str::FromStr, sync::Arc};
 
-use image::{ImageBuffer, Rgb};
+use image::{DynamicImage, ImageBuffer, ImageFormat, ImageResult, Rgb};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
 
 use crate::{
-    vec3::ray::Ray,
-    viewport::{scene::Scene, Viewport},
+    vec3::vec3::Vec3,
+    viewport::{
+        aov::{Aov, AovImage},
+        environment::luminance,
+    },
 };
 
-type Img = ImageBuffer<Rgb<u8>, Vec<u8>>;
+// B3 spline used by every level of the a-trous filter
+const ATROUS_KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
 
-pub(crate) enum ProximityType {
-    Square,
-    Edges,
+/// Edge avoiding a-trous wavelet filter (Dammertz et al.) for float images.
+/// Every iteration blurs with a 5x5 kernel spread twice as wide as the last one,
+/// neighbours are weighted down when their color, albedo, normal or depth differ from the pixel.
+/// Colors are divided by the albedo while filtering so textures stay sharp
+#[derive(Debug, Clone)]
+pub struct Denoiser {
+    pub iterations: usize,
+    pub sigma_color: f32,
+    pub sigma_albedo: f32,
+    pub sigma_normal: f32,
+    // relative to the depth of the pixel
+    pub sigma_depth: f32,
 }
 
-pub(crate) struct Proximity {
-    size: u32,
-    _type: ProximityType,
+impl Default for Denoiser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// guides of one pixel
+#[derive(Debug, Clone, Copy)]
+struct Features {
+    albedo: Vec3,
+    normal: Vec3,
+    depth: f32,
 }
 
-impl Proximity {
-    pub fn new(size: u32, proximity_type: ProximityType) -> Self {
+impl Denoiser {
+    pub fn new() -> Self {
         Self {
-            size,
-            _type: proximity_type,
+            iterations: 5,
+            sigma_color: 1.0,
+            sigma_albedo: 0.1,
+            sigma_normal: 0.3,
+            sigma_depth: 0.1,
         }
     }
+    pub fn with_iterations(mut self, iterations: usize) -> Self {
+        self.iterations = iterations;
+        self
+    }
+    pub fn with_sigma_color(mut self, sigma: f32) -> Self {
+        self.sigma_color = sigma;
+        self
+    }
+    pub fn with_sigma_albedo(mut self, sigma: f32) -> Self {
+        self.sigma_albedo = sigma;
+        self
+    }
+    pub fn with_sigma_normal(mut self, sigma: f32) -> Self {
+        self.sigma_normal = sigma;
+        self
+    }
+    pub fn with_sigma_depth(mut self, sigma: f32) -> Self {
+        self.sigma_depth = sigma;
+        self
+    }
 
-    pub fn get_pixels(&self, x: u32, y: u32, width: u32, height: u32) -> Vec<(u32, u32)> {
-        let left = x - min(x, self.size);
-        let up = y - min(y, self.size);
-        let right = x + min(width - x - 1, self.size);
-        let down = y + min(height - y - 1, self.size);
+    fn feature_weight(&self, p: &Features, q: &Features) -> f32 {
+        let depth = match (p.depth.is_finite(), q.depth.is_finite()) {
+            // both are background
+            (false, false) => 0.0,
+            (true, true) => (p.depth - q.depth) / (self.sigma_depth * p.depth.max(1e-4)),
+            _ => return 0.0,
+        };
+        (-(p.albedo - q.albedo).length2() / (self.sigma_albedo * self.sigma_albedo)
+            - (p.normal - q.normal).length2() / (self.sigma_normal * self.sigma_normal)
+            - depth * depth)
+            .exp()
+    }
 
-        // println!(
-        //     "get_pixels: l: {}, u: {}, r: {}, d: {}",
-        //     left, up, right, down
-        // );
+    // one level of the filter with taps `step` pixels apart
+    fn atrous_pass(
+        &self,
+        color: &[Vec<Vec3>],
+        features: &[Vec<Features>],
+        step: usize,
+        sigma_color: f32,
+    ) -> Vec<Vec<Vec3>> {
+        let (height, width) = (color.len() as i64, color[0].len() as i64);
+        (0..height)
+            .into_par_iter()
+            .map(|y| {
+                (0..width)
+                    .map(|x| {
+                        let p = color[y as usize][x as usize];
+                        let fp = &features[y as usize][x as usize];
+                        let mut sum = Vec3::ZERO;
+                        let mut weights = 0.0;
+                        for (j, ky) in ATROUS_KERNEL.iter().enumerate() {
+                            let yi = y + (j as i64 - 2) * step as i64;
+                            if !(0..height).contains(&yi) {
+                                continue;
+                            }
+                            for (i, kx) in ATROUS_KERNEL.iter().enumerate() {
+                                let xi = x + (i as i64 - 2) * step as i64;
+                                if !(0..width).contains(&xi) {
+                                    continue;
+                                }
+                                let q = color[yi as usize][xi as usize];
+                                let fq = &features[yi as usize][xi as usize];
+                                let w = kx
+                                    * ky
+                                    * self.feature_weight(fp, fq)
+                                    * (-(p - q).length2() / (sigma_color * sigma_color)).exp();
+                                sum += q * w;
+                                weights += w;
+                            }
+                        }
+                        // the pixel itself always has a weight of at least 9/64
+                        sum / weights
+                    })
+                    .collect()
+            })
+            .collect()
+    }
 
-        let mut pixels = vec![];
+    /// Denoises the linear `beauty` image, `albedo`, `normal` and `depth` (in the x component)
+    /// are the first hit buffers of the same render. Rays that escape have infinite depth
+    pub fn denoise(
+        &self,
+        beauty: &[Vec<Vec3>],
+        albedo: &[Vec<Vec3>],
+        normal: &[Vec<Vec3>],
+        depth: &[Vec<Vec3>],
+    ) -> Vec<Vec<Vec3>> {
+        // albedo the color is divided by, channels without albedo are filtered as they are
+        let demodulation = |a: Vec3| {
+            let channel = |c: f32| if c > 1e-3 { c } else { 1.0 };
+            Vec3::new(channel(a.x), channel(a.y), channel(a.z))
+        };
+        let features: Vec<Vec<Features>> = (0..beauty.len())
+            .map(|y| {
+                (0..beauty[y].len())
+                    .map(|x| Features {
+                        albedo: albedo[y][x],
+                        normal: normal[y][x],
+                        depth: depth[y][x].x,
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut color: Vec<Vec<Vec3>> = beauty
+            .iter()
+            .zip(albedo)
+            .map(|(row, albedo)| {
+                row.iter()
+                    .zip(albedo)
+                    .map(|(&c, &a)| {
+                        let a = demodulation(a);
+                        Vec3::new(c.x / a.x, c.y / a.y, c.z / a.z)
+                    })
+                    .collect()
+            })
+            .collect();
 
-        for i in left..right {
-            for j in up..down {
-                match self._type {
-                    ProximityType::Square => {
-                        pixels.push((i, j));
-                    }
-                    ProximityType::Edges => {
-                        if (if x < i { i - x } else { x - i } + if y < j { j - y } else { y - j }
-                            < self.size)
-                        {
-                            pixels.push((i, j));
-                        }
-                    }
-                }
+        let mut sigma_color = self.sigma_color;
+        for i in 0..self.iterations {
+            color = self.atrous_pass(&color, &features, 1 << i, sigma_color);
+            // later levels average more noise away, so their colors can be trusted more
+            sigma_color *= 0.5;
+        }
+
+        color
+            .into_iter()
+            .zip(albedo)
+            .map(|(row, albedo)| {
+                row.into_iter()
+                    .zip(albedo)
+                    .map(|(c, &a)| c.field_wise_mult(demodulation(a)))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Denoised beauty layer of `img`, guided by its albedo, normal and depth layers.
+    /// Emission is added back after filtering, lights seen directly don't have features to stop the blur
+    pub fn denoise_aovs(&self, img: &AovImage) -> Vec<Vec<Vec3>> {
+        let emission = img.layer(Aov::Emission);
+        let scattered: Vec<Vec<Vec3>> = img
+            .layer(Aov::Beauty)
+            .iter()
+            .zip(emission)
+            .map(|(row, emission)| row.iter().zip(emission).map(|(&c, &e)| c - e).collect())
+            .collect();
+        let mut denoised = self.denoise(
+            &scattered,
+            img.layer(Aov::Albedo),
+            img.layer(Aov::Normal),
+            img.layer(Aov::Depth),
+        );
+        for (row, emission) in denoised.iter_mut().zip(emission) {
+            for (c, &e) in row.iter_mut().zip(emission) {
+                *c += e;
             }
         }
+        denoised
+    }
+}
+
+/// Linear float image the post effects work on
+pub type ImageF32 = ImageBuffer<Rgb<f32>, Vec<f32>>;
 
-        pixels
+pub fn to_image_f32(iv: &[Vec<Vec3>]) -> ImageF32 {
+    ImageBuffer::from_fn(iv[0].len() as u32, iv.len() as u32, |x, y| {
+        let c = iv[y as usize][x as usize];
+        Rgb([c.x, c.y, c.z])
+    })
+}
+
+pub fn from_image_f32(img: &ImageF32) -> Vec<Vec<Vec3>> {
+    img.rows()
+        .map(|row| row.map(|p| Vec3::new(p.0[0], p.0[1], p.0[2])).collect())
+        .collect()
+}
+
+fn pixels(img: &ImageF32) -> Vec<Vec3> {
+    img.pixels()
+        .map(|p| Vec3::new(p.0[0], p.0[1], p.0[2]))
+        .collect()
+}
+
+fn from_pixels(width: u32, height: u32, pixels: &[Vec3]) -> ImageF32 {
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let c = pixels[(y * width + x) as usize];
+        Rgb([c.x, c.y, c.z])
+    })
+}
+
+/// One stage of a post processing chain, works on linear colors
+pub trait PostEffect {
+    fn apply(&self, img: &ImageF32) -> ImageF32;
+}
+
+// 1D convolution along rows or columns, the edges are extended
+fn convolve(
+    pixels: &[Vec3],
+    width: usize,
+    height: usize,
+    kernel: &[f32],
+    horizontal: bool,
+) -> Vec<Vec3> {
+    let r = (kernel.len() / 2) as i64;
+    (0..width * height)
+        .into_par_iter()
+        .map(|i| {
+            let (x, y) = ((i % width) as i64, (i / width) as i64);
+            let mut sum = Vec3::ZERO;
+            for (k, w) in kernel.iter().enumerate() {
+                let offset = k as i64 - r;
+                let (xi, yi) = if horizontal {
+                    ((x + offset).clamp(0, width as i64 - 1), y)
+                } else {
+                    (x, (y + offset).clamp(0, height as i64 - 1))
+                };
+                sum += pixels[yi as usize * width + xi as usize] * *w;
+            }
+            sum
+        })
+        .collect()
+}
+
+fn gaussian_blur(pixels: &[Vec3], width: usize, height: usize, sigma: f32) -> Vec<Vec3> {
+    if sigma <= 0.0 {
+        return pixels.to_vec();
     }
+    let r = (3.0 * sigma).ceil() as i64;
+    let mut kernel: Vec<f32> = (-r..=r)
+        .map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    kernel.iter_mut().for_each(|w| *w /= sum);
+    let blurred = convolve(pixels, width, height, &kernel, true);
+    convolve(&blurred, width, height, &kernel, false)
 }
 
-fn intensity(p: &Rgb<u8>) -> f32 {
-    0.2989 * (p.0[0] as f32) / 255.0
-        + 0.5870 * (p.0[1] as f32) / 255.0
-        + 0.1140 * (p.0[2] as f32) / 255.0
+/// Glow around the parts of the image brighter than `threshold`, blurred over `radius` pixels
+#[derive(Debug, Clone)]
+pub struct Bloom {
+    pub threshold: f32,
+    pub intensity: f32,
+    pub radius: f32,
+}
+
+impl Bloom {
+    pub fn new(threshold: f32, intensity: f32, radius: f32) -> Self {
+        Self {
+            threshold,
+            intensity,
+            radius,
+        }
+    }
 }
 
-pub(crate) fn bilateral_filter(img: &Img, proximity: Proximity) -> Img {
-    let (w, h) = img.dimensions();
+impl PostEffect for Bloom {
+    fn apply(&self, img: &ImageF32) -> ImageF32 {
+        let (width, height) = img.dimensions();
+        let source = pixels(img);
+        // only the light above the threshold glows, the hue of the pixel is kept
+        let bright: Vec<Vec3> = source
+            .iter()
+            .map(|&c| {
+                let l = luminance(c);
+                if l > self.threshold {
+                    c * ((l - self.threshold) / l)
+                } else {
+                    Vec3::ZERO
+                }
+            })
+            .collect();
+        let glow = gaussian_blur(&bright, width as usize, height as usize, self.radius);
+        let out: Vec<Vec3> = source
+            .iter()
+            .zip(glow)
+            .map(|(&c, g)| c + g * self.intensity)
+            .collect();
+        from_pixels(width, height, &out)
+    }
+}
 
-    let spatial = (0.02 * ((w * w + h * h) as f32).sqrt()).ceil();
+/// Darkening towards the corners following the cos^4 law of a lens,
+/// `strength` is the tangent of the angle between the optical axis and the corners
+#[derive(Debug, Clone)]
+pub struct Vignette {
+    pub strength: f32,
+}
 
-    let mut gradient_sum = 0.0;
-    for y in 1..(h - 1) {
-        for x in 1..(w - 1) {
-            let Iu = intensity(img.get_pixel(x, y - 1));
-            let Id = intensity(img.get_pixel(x, y + 1));
-            let Ir = intensity(img.get_pixel(x + 1, y));
-            let Il = intensity(img.get_pixel(x - 1, y));
+impl Vignette {
+    pub fn new(strength: f32) -> Self {
+        Self { strength }
+    }
+}
 
-            gradient_sum += ((Iu - Id) * (Iu - Id) + (Il - Ir) * (Il - Ir)).sqrt();
+impl PostEffect for Vignette {
+    fn apply(&self, img: &ImageF32) -> ImageF32 {
+        let (width, height) = img.dimensions();
+        let (cx, cy) = (width as f32 * 0.5, height as f32 * 0.5);
+        let half_diagonal = (cx * cx + cy * cy).sqrt();
+        let mut out = img.clone();
+        for (x, y, p) in out.enumerate_pixels_mut() {
+            let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+            let tan = (dx * dx + dy * dy).sqrt() / half_diagonal * self.strength;
+            let cos2 = 1.0 / (1.0 + tan * tan);
+            for c in p.0.iter_mut() {
+                *c *= cos2 * cos2;
+            }
         }
+        out
     }
+}
 
-    let avg_gradient = gradient_sum / (((w - 2) * (h - 2)) as f32);
+// bilinear sample of `channel` at pixel coordinates, the edges are extended
+fn sample_channel(img: &ImageF32, x: f32, y: f32, channel: usize) -> f32 {
+    let (width, height) = img.dimensions();
+    let x = (x - 0.5).clamp(0.0, width as f32 - 1.0);
+    let y = (y - 0.5).clamp(0.0, height as f32 - 1.0);
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+    let get = |x, y| img.get_pixel(x, y).0[channel];
+    let top = get(x0, y0) * (1.0 - fx) + get(x1, y0) * fx;
+    let bottom = get(x0, y1) * (1.0 - fx) + get(x1, y1) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
 
-    let inv_range = 0.5 / (avg_gradient * avg_gradient);
-    let inv_spatial = 0.5 / (spatial * spatial);
+/// Lateral chromatic aberration, red is magnified and blue shrunk by `strength` around the center
+#[derive(Debug, Clone)]
+pub struct ChromaticAberration {
+    pub strength: f32,
+}
 
-    // println!("range: {}, spatial: {}", avg_gradient, spatial);
+impl ChromaticAberration {
+    pub fn new(strength: f32) -> Self {
+        Self { strength }
+    }
+}
 
-    let n_img = ImageBuffer::from_fn(w, h, |x, y| {
-        let mut w_sum = [0.0, 0.0, 0.0];
-        let mut col_sum = [0.0; 3];
-        let p = img.get_pixel(x, y);
+impl PostEffect for ChromaticAberration {
+    fn apply(&self, img: &ImageF32) -> ImageF32 {
+        let (width, height) = img.dimensions();
+        let (cx, cy) = (width as f32 * 0.5, height as f32 * 0.5);
+        let scales = [1.0 + self.strength, 1.0, 1.0 - self.strength];
+        ImageBuffer::from_fn(width, height, |x, y| {
+            let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+            let mut p = *img.get_pixel(x, y);
+            for (channel, scale) in scales.iter().enumerate() {
+                if *scale != 1.0 {
+                    p.0[channel] = sample_channel(img, cx + dx / scale, cy + dy / scale, channel);
+                }
+            }
+            p
+        })
+    }
+}
 
-        let pixels: Vec<_> = proximity.get_pixels(x, y, w, h);
-        // println!("pixels: {:?}", pixels);
+/// Multiplicative noise with a mean of 1, the same `seed` gives the same grain
+#[derive(Debug, Clone)]
+pub struct FilmGrain {
+    pub amount: f32,
+    pub seed: u64,
+}
 
-        for (xi, yi) in proximity.get_pixels(x, y, w, h) {
-            let pi = img.get_pixel(xi, yi);
-            // println!("pos: {} {}", xi, yi);
-            for i in 0..3 {
-                // print!("{} ", i);
-                let w = (-inv_spatial
-                    * ((xi as i64 - x as i64) * (xi as i64 - x as i64)
-                        + (yi as i64 - y as i64) * (yi as i64 - y as i64))
-                        as f32
-                    - inv_range * ((pi.0[i] as i32 - p.0[i] as i32) as f32 / 255.0).powi(2))
-                .exp();
-                col_sum[i] += pi.0[i] as f32 * w / 255.0;
-                w_sum[i] += w;
+impl FilmGrain {
+    pub fn new(amount: f32, seed: u64) -> Self {
+        Self { amount, seed }
+    }
+}
+
+impl PostEffect for FilmGrain {
+    fn apply(&self, img: &ImageF32) -> ImageF32 {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut out = img.clone();
+        for p in out.pixels_mut() {
+            // grain is monochrome, all channels of a pixel get the same noise
+            let n = 1.0 + self.amount * (rng.gen::<f32>() * 2.0 - 1.0);
+            for c in p.0.iter_mut() {
+                *c *= n;
+            }
+        }
+        out
+    }
+}
+
+// approximate linear color of a black body at `kelvin`, from Tanner Helland's fit
+fn black_body(kelvin: f32) -> Vec3 {
+    let t = kelvin.clamp(1000.0, 40000.0) / 100.0;
+    let r = if t <= 66.0 {
+        255.0
+    } else {
+        329.69873 * (t - 60.0).powf(-0.13320476)
+    };
+    let g = if t <= 66.0 {
+        99.4708 * t.ln() - 161.11957
+    } else {
+        288.12217 * (t - 60.0).powf(-0.07551485)
+    };
+    let b = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.51773 * (t - 10.0).ln() - 305.0448
+    };
+    let channel = |c: f32| (c.clamp(1.0, 255.0) / 255.0).powf(2.2);
+    Vec3::new(channel(r), channel(g), channel(b))
+}
+
+/// Neutralizes light of color `temperature` (in kelvin) to the 6500K white of the display.
+/// Positive `tint` moves the image towards magenta, negative towards green
+#[derive(Debug, Clone)]
+pub struct WhiteBalance {
+    pub temperature: f32,
+    pub tint: f32,
+}
+
+impl WhiteBalance {
+    pub fn new(temperature: f32, tint: f32) -> Self {
+        Self { temperature, tint }
+    }
+
+    /// Factors the channels are multiplied by
+    pub fn gains(&self) -> Vec3 {
+        let (white, light) = (black_body(6500.0), black_body(self.temperature));
+        let gains = Vec3::new(white.x / light.x, white.y / light.y, white.z / light.z);
+        // keeps the brightness of grey
+        let gains = gains / luminance(gains);
+        Vec3::new(gains.x, gains.y * (1.0 - 0.5 * self.tint), gains.z)
+    }
+}
+
+impl PostEffect for WhiteBalance {
+    fn apply(&self, img: &ImageF32) -> ImageF32 {
+        let gains = self.gains();
+        let mut out = img.clone();
+        for p in out.pixels_mut() {
+            p.0[0] *= gains.x;
+            p.0[1] *= gains.y;
+            p.0[2] *= gains.z;
+        }
+        out
+    }
+}
+
+/// 3D color lookup table with trilinear interpolation, the grade of a `.cube` file.
+/// Colors are clamped to the domain and raised to `1 / gamma` before the lookup,
+/// so tables made for gamma encoded images can be used on the linear image
+#[derive(Debug, Clone)]
+pub struct Lut {
+    size: usize,
+    // red changes fastest, then green, then blue
+    table: Vec<Vec3>,
+    domain_min: Vec3,
+    domain_max: Vec3,
+    pub gamma: f32,
+}
+
+impl Lut {
+    pub fn identity(size: usize) -> Self {
+        let size = size.max(2);
+        let step = 1.0 / (size - 1) as f32;
+        let table = (0..size * size * size)
+            .map(|i| {
+                Vec3::new(
+                    (i % size) as f32 * step,
+                    (i / size % size) as f32 * step,
+                    (i / size / size) as f32 * step,
+                )
+            })
+            .collect();
+        Self {
+            size,
+            table,
+            domain_min: Vec3::ZERO,
+            domain_max: Vec3::WHITE,
+            gamma: 1.0,
+        }
+    }
+
+    /// Parses the text of an Adobe `.cube` 3D LUT
+    pub fn from_cube(text: &str) -> Result<Self, String> {
+        let mut size = 0;
+        let mut table = vec![];
+        let mut domain_min = Vec3::ZERO;
+        let mut domain_max = Vec3::WHITE;
+        let parse_vec = |parts: &[&str]| -> Result<Vec3, String> {
+            let v: Vec<f32> = parts
+                .iter()
+                .map(|p| p.parse::<f32>().map_err(|e| format!("{}: {}", p, e)))
+                .collect::<Result<_, _>>()?;
+            match v[..] {
+                [r, g, b] => Ok(Vec3::new(r, g, b)),
+                _ => Err(format!("expected 3 values, got {}", v.len())),
+            }
+        };
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
             }
-            // println!("");
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts[0] {
+                "TITLE" | "LUT_1D_INPUT_RANGE" => {}
+                "LUT_3D_SIZE" => {
+                    size = parts
+                        .get(1)
+                        .and_then(|s| s.parse().ok())
+                        .ok_or(format!("bad size: {}", line))?;
+                }
+                "LUT_1D_SIZE" => return Err("1D LUTs aren't supported".to_string()),
+                "DOMAIN_MIN" => domain_min = parse_vec(&parts[1..])?,
+                "DOMAIN_MAX" => domain_max = parse_vec(&parts[1..])?,
+                // the same range for every channel, written by DaVinci Resolve
+                "LUT_3D_INPUT_RANGE" => {
+                    let range: Vec<f32> = parts[1..]
+                        .iter()
+                        .map(|p| p.parse::<f32>().map_err(|e| format!("{}: {}", p, e)))
+                        .collect::<Result<_, _>>()?;
+                    match range[..] {
+                        [min, max] => {
+                            domain_min = Vec3::WHITE * min;
+                            domain_max = Vec3::WHITE * max;
+                        }
+                        _ => return Err(format!("bad input range: {}", line)),
+                    }
+                }
+                _ => table.push(parse_vec(&parts)?),
+            }
+        }
+        if size < 2 || table.len() != size * size * size {
+            return Err(format!(
+                "LUT_3D_SIZE {} needs {} entries, found {}",
+                size,
+                size * size * size,
+                table.len()
+            ));
+        }
+        Ok(Self {
+            size,
+            table,
+            domain_min,
+            domain_max,
+            gamma: 1.0,
+        })
+    }
+
+    pub fn from_path(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::from_cube(&text)
+    }
+
+    pub fn with_gamma(mut self, gamma: f32) -> Self {
+        self.gamma = gamma;
+        self
+    }
+
+    fn at(&self, r: usize, g: usize, b: usize) -> Vec3 {
+        self.table[r + self.size * (g + self.size * b)]
+    }
+
+    /// Graded color of `c`
+    pub fn lookup(&self, c: Vec3) -> Vec3 {
+        let encode = |v: f32, min: f32, max: f32| {
+            let v = v.max(0.0).powf(1.0 / self.gamma);
+            ((v - min) / (max - min)).clamp(0.0, 1.0) * (self.size - 1) as f32
+        };
+        let p = [
+            encode(c.x, self.domain_min.x, self.domain_max.x),
+            encode(c.y, self.domain_min.y, self.domain_max.y),
+            encode(c.z, self.domain_min.z, self.domain_max.z),
+        ];
+        let i0 = p.map(|v| (v.floor() as usize).min(self.size - 2));
+        let f = [0, 1, 2].map(|i| p[i] - i0[i] as f32);
+        let mut out = Vec3::ZERO;
+        for corner in 0..8 {
+            let d = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+            let w: f32 = (0..3)
+                .map(|i| if d[i] == 1 { f[i] } else { 1.0 - f[i] })
+                .product();
+            out += self.at(i0[0] + d[0], i0[1] + d[1], i0[2] + d[2]) * w;
         }
-        // println!(
-        //     "x: {}, y: {}, pcol: {:?} \nw: {:?}, col: {:?}",
-        //     x, y, p.0, w_sum, col_sum
-        // );
+        let decode = |v: f32| v.max(0.0).powf(self.gamma);
+        Vec3::new(decode(out.x), decode(out.y), decode(out.z))
+    }
+}
+
+impl PostEffect for Lut {
+    fn apply(&self, img: &ImageF32) -> ImageF32 {
+        let (width, height) = img.dimensions();
+        let out: Vec<Vec3> = pixels(img)
+            .into_par_iter()
+            .map(|c| self.lookup(c))
+            .collect();
+        from_pixels(width, height, &out)
+    }
+}
 
-        Rgb::<u8>([
-            (col_sum[0] * 255.0 / w_sum[0]) as u8,
-            (col_sum[1] * 255.0 / w_sum[1]) as u8,
-            (col_sum[2] * 255.0 / w_sum[2]) as u8,
-        ])
-    });
+/// Post effects applied one after another to the linear image
+#[derive(Clone, Default)]
+pub struct PostChain {
+    effects: Vec<Arc<dyn PostEffect + Send + Sync>>,
+}
 
-    return n_img;
+// parameter `key` of the effect `name` taken out of `params`, `default` if it is missing
+fn param<T: FromStr>(
+    params: &mut HashMap<&str, &str>,
+    name: &str,
+    key: &str,
+    default: T,
+) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    match params.remove(key) {
+        Some(v) => v
+            .parse()
+            .map_err(|e| format!("{} {}={}: {}", name, key, v, e)),
+        None => Ok(default),
+    }
+}
+
+impl PostChain {
+    pub fn new() -> Self {
+        Self { effects: vec![] }
+    }
+    pub fn with(mut self, effect: impl PostEffect + Send + Sync + 'static) -> Self {
+        self.effects.push(Arc::new(effect));
+        self
+    }
+    pub fn len(&self) -> usize {
+        self.effects.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+
+    pub fn apply(&self, img: ImageF32) -> ImageF32 {
+        self.effects.iter().fold(img, |img, e| e.apply(&img))
+    }
+
+    /// Chain described by `spec`, stages are separated by `|` and written as the effect name
+    /// followed by `key=value` parameters, missing ones keep their defaults:
+    /// `bloom threshold=1 intensity=0.2 radius=8 | vignette strength=0.5 | chromatic_aberration strength=0.004
+    /// | grain amount=0.05 seed=0 | white_balance temperature=6500 tint=0 | lut path=grade.cube gamma=1`
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut chain = Self::new();
+        for stage in spec.split('|').map(str::trim).filter(|s| !s.is_empty()) {
+            let mut words = stage.split_whitespace();
+            let name = words.next().unwrap_or_default();
+            let mut params = HashMap::new();
+            for word in words {
+                let (key, value) = word
+                    .split_once('=')
+                    .ok_or(format!("expected key=value in `{}`: {}", stage, word))?;
+                params.insert(key, value);
+            }
+            let mut number = |key: &str, default: f32| param(&mut params, name, key, default);
+            chain = match name {
+                "bloom" => chain.with(Bloom::new(
+                    number("threshold", 1.0)?,
+                    number("intensity", 0.2)?,
+                    number("radius", 8.0)?,
+                )),
+                "vignette" => chain.with(Vignette::new(number("strength", 0.5)?)),
+                "chromatic_aberration" => {
+                    chain.with(ChromaticAberration::new(number("strength", 0.004)?))
+                }
+                "grain" => chain.with(FilmGrain::new(
+                    number("amount", 0.05)?,
+                    param(&mut params, name, "seed", 0u64)?,
+                )),
+                "white_balance" => chain.with(WhiteBalance::new(
+                    number("temperature", 6500.0)?,
+                    number("tint", 0.0)?,
+                )),
+                "lut" => {
+                    let gamma = number("gamma", 1.0)?;
+                    let path = params
+                        .remove("path")
+                        .ok_or(format!("lut needs a path: {}", stage))?;
+                    chain.with(Lut::from_path(path)?.with_gamma(gamma))
+                }
+                _ => return Err(format!("unknown effect: {}", name)),
+            };
+            if let Some(key) = params.keys().next() {
+                return Err(format!("unknown parameter of {}: {}", name, key));
+            }
+        }
+        Ok(chain)
+    }
+
+    /// Applies the chain to the image at `input` and saves the result at `output`.
+    /// Images with 8 or 16 bits per channel are gamma corrected with `gamma`, float images like
+    /// OpenEXR are linear and are written as floats if `output` is a float format as well
+    pub fn apply_to_file(&self, input: &str, output: &str, gamma: f32) -> ImageResult<()> {
+        let img = image::open(input)?;
+        let linear = matches!(
+            img,
+            DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+        );
+        let mut img = img.into_rgb32f();
+        if !linear {
+            img.pixels_mut()
+                .for_each(|p| p.0 = p.0.map(|c| c.max(0.0).powf(gamma)));
+        }
+        let mut img = self.apply(img);
+        if ImageFormat::from_path(output)? == ImageFormat::OpenExr {
+            return DynamicImage::ImageRgb32F(img).save(output);
+        }
+        img.pixels_mut()
+            .for_each(|p| p.0 = p.0.map(|c| c.max(0.0).powf(1.0 / gamma)));
+        DynamicImage::ImageRgb32F(img).into_rgb8().save(output)
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use std::{sync::Arc, time::Instant};
+    use std::sync::Arc;
 
-    use image::ImageResult;
+    use image::{ImageBuffer, ImageResult, Rgb};
 
     use crate::{
         objects::{
             instance::Instance,
+            light::{Light, RectLight},
             material::{LAMBERTIAN, MIRROR},
             quad::Quad,
             sphere::Sphere,
             texture::ConstColorTexture,
             Object,
         },
-        postprocessing::{bilateral_filter, Proximity, ProximityType},
+        postprocessing::{
+            Bloom, ChromaticAberration, Denoiser, FilmGrain, ImageF32, Lut, PostChain, PostEffect,
+            Vignette, WhiteBalance,
+        },
         vec3::{ray::Ray, vec3::Vec3},
         viewport::{
+            aov::Aov,
             camera::Camera,
-            ray_color::{light_biased_ray_cast, light_biased_ray_color, ray_color},
+            ray_color::{next_event_ray_color, ray_color},
             scene::Scene,
             Viewport,
         },
@@ -394,14 +1039,10 @@ mod test {
     }
 
     #[test]
-    fn test_bilateral_filter() -> ImageResult<()> {
-        let (scene, lights) = make_scene();
-        const WIDTH: usize = 800;
-        const HEIGHT: usize = 600;
-        const SAMPLES: usize = 400;
-        const DEPTH: usize = 9;
-        const BIASED_WEIGHT: f32 = 100.;
-
+    fn denoise_path_traced_scene() -> ImageResult<()> {
+        let (scene, _) = make_scene();
+        const WIDTH: usize = 160;
+        const HEIGHT: usize = 120;
         let cam = Camera::new(
             WIDTH as f32 / HEIGHT as f32,
             Vec3::ZERO,
@@ -410,49 +1051,290 @@ mod test {
             90.0,
             0.0,
         );
-        let lclone = lights.clone();
-        // let ray_cast = Box::new(move |r: Ray, vp: Arc<Viewport>, d: usize| {
-        //     light_biased_ray_cast(r, vp, d, lclone.clone())
-        // });
-        let biased_ray_color = Box::new(move |r: Ray, vp: Arc<Viewport>, d: usize| {
-            light_biased_ray_color(r, vp, d, lights.clone(), BIASED_WEIGHT)
-        });
-
         let vp = Viewport::new(
-            cam.clone(),
-            scene.clone(),
+            cam,
+            scene,
             Arc::new(ray_color),
             WIDTH,
             HEIGHT,
-            SAMPLES,
+            16,
             10,
             Vec3::ZERO,
             2.0,
         );
-        let vp2 = Viewport::new(
+        let noisy = vp.clone().render_aovs();
+        let denoised = Denoiser::new().denoise_aovs(&noisy);
+        // the noise is smoothed away without losing light
+        let total = |img: &[Vec<Vec3>]| img.iter().flatten().fold(Vec3::ZERO, |a, &b| a + b);
+        let (before, after) = (total(noisy.layer(Aov::Beauty)), total(&denoised));
+        assert!(
+            (before - after).length() < 0.1 * before.length(),
+            "{:?} != {:?}",
+            before,
+            after
+        );
+        noisy
+            .to_rgb8(Aov::Beauty)
+            .save("test_out/denoise_path_traced_noisy.png")?;
+        vp.render_denoised(&Denoiser::new())
+            .save("test_out/denoise_path_traced.png")
+    }
+
+    fn denoise_scene(samples: usize) -> Viewport {
+        const WIDTH: usize = 48;
+        const HEIGHT: usize = 36;
+        let objects = vec![
+            Instance::new(Arc::new([Arc::new(Sphere {
+                origin: Vec3::new(0.0, 0.0, 3.0),
+                radius: 0.5,
+                mat: LAMBERTIAN.clone(),
+                texture: Arc::new(ConstColorTexture::new(Vec3::new(0.8, 0.2, 0.2), Vec3::ZERO)),
+            })])),
+            Instance::new_box(
+                Vec3::new(-2.0, -0.5, -1.0),
+                Vec3::new(2.0, 2.0, 5.0),
+                Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.6, Vec3::ZERO)),
+                LAMBERTIAN.clone(),
+            ),
+        ];
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![Arc::new(
+            RectLight::new(
+                Vec3::new(-0.5, 1.9, 2.5),
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 1.0),
+                Vec3::WHITE * 3.0,
+            )
+            .with_visibility(false),
+        )];
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::ZERO,
+            Vec3::UP,
+            Vec3::FORWARD,
+            70.0,
+            0.0,
+        );
+        Viewport::new(
             cam,
-            scene,
-            Arc::new(biased_ray_color),
+            Scene::new(objects, 0.001, 1000.0).with_lights(lights),
+            Arc::new(next_event_ray_color),
             WIDTH,
             HEIGHT,
-            SAMPLES,
-            DEPTH,
+            samples,
+            4,
             Vec3::ZERO,
-            2.0,
+            2.2,
+        )
+    }
+
+    fn mean_squared_error(a: &[Vec<Vec3>], b: &[Vec<Vec3>]) -> f32 {
+        let diffs: Vec<f32> = a
+            .iter()
+            .flatten()
+            .zip(b.iter().flatten())
+            .map(|(&a, &b)| (a - b).length2())
+            .collect();
+        diffs.iter().sum::<f32>() / diffs.len() as f32
+    }
+
+    #[test]
+    fn denoiser_keeps_edges() {
+        // two flat regions facing different ways
+        let half = |left: Vec3, right: Vec3| -> Vec<Vec<Vec3>> {
+            vec![(0..20).map(|x| if x < 10 { left } else { right }).collect(); 20]
+        };
+        let beauty = half(Vec3::new(0.9, 0.5, 0.1), Vec3::new(0.1, 0.2, 0.7));
+        let albedo = half(Vec3::WHITE * 0.5, Vec3::WHITE * 0.5);
+        let normal = half(Vec3::UP, Vec3::LEFT);
+        let depth = half(Vec3::WHITE, Vec3::WHITE);
+        let denoised = Denoiser::new().denoise(&beauty, &albedo, &normal, &depth);
+        assert!(mean_squared_error(&beauty, &denoised) < 1e-6);
+    }
+
+    #[test]
+    fn test_denoiser() -> ImageResult<()> {
+        let reference = denoise_scene(256).render_aovs();
+        let noisy = denoise_scene(1).render_aovs();
+        let denoised = Denoiser::new().denoise_aovs(&noisy);
+        noisy
+            .to_rgb8(Aov::Beauty)
+            .save("test_out/denoise_noisy.png")?;
+        reference
+            .to_rgb8(Aov::Beauty)
+            .save("test_out/denoise_reference.png")?;
+
+        let reference = reference.layer(Aov::Beauty);
+        let noisy_error = mean_squared_error(reference, noisy.layer(Aov::Beauty));
+        let denoised_error = mean_squared_error(reference, &denoised);
+        assert!(
+            denoised_error < noisy_error * 0.5,
+            "noisy: {}, denoised: {}",
+            noisy_error,
+            denoised_error
+        );
+
+        denoise_scene(1)
+            .render_denoised(&Denoiser::new())
+            .save("test_out/denoise_test.png")
+    }
+
+    fn flat_image(c: f32) -> ImageF32 {
+        ImageBuffer::from_pixel(32, 24, Rgb([c, c, c]))
+    }
+
+    #[test]
+    fn bloom_spreads_bright_pixels() {
+        let bloom = Bloom::new(1.0, 1.0, 2.0);
+        let dim = flat_image(0.5);
+        assert_eq!(bloom.apply(&dim), dim);
+
+        let mut img = flat_image(0.0);
+        img.put_pixel(16, 12, Rgb([11.0, 11.0, 11.0]));
+        let out = bloom.apply(&img);
+        assert!(out.get_pixel(18, 12).0[0] > 0.0);
+        assert_eq!(out.get_pixel(0, 0).0[0], 0.0);
+        // all of the light above the threshold is spread around
+        let added: f32 = out.pixels().map(|p| p.0[1]).sum::<f32>() - 11.0;
+        assert!((added - 10.0).abs() < 0.01, "{}", added);
+    }
+
+    #[test]
+    fn vignette_darkens_corners() {
+        let out = Vignette::new(1.0).apply(&flat_image(1.0));
+        let center = out.get_pixel(16, 12).0[0];
+        let edge = out.get_pixel(0, 12).0[0];
+        let corner = out.get_pixel(0, 0).0[0];
+        assert!(center > 0.99);
+        assert!(corner < edge && edge < center);
+        // cos^4 of 45 degrees
+        assert!((corner - 0.25).abs() < 0.05);
+    }
+
+    #[test]
+    fn chromatic_aberration_shifts_channels() {
+        let flat = flat_image(0.3);
+        let out = ChromaticAberration::new(0.05).apply(&flat);
+        for (a, b) in flat.pixels().zip(out.pixels()) {
+            for i in 0..3 {
+                assert!((a.0[i] - b.0[i]).abs() < 1e-5);
+            }
+        }
+
+        let mut img = flat_image(0.0);
+        img.put_pixel(28, 12, Rgb([1.0, 1.0, 1.0]));
+        let out = ChromaticAberration::new(0.1).apply(&img);
+        assert_eq!(out.get_pixel(28, 12).0[1], 1.0);
+        // red is pushed outwards and blue inwards
+        assert!(out.get_pixel(29, 12).0[0] > 0.0);
+        assert!(out.get_pixel(27, 12).0[2] > 0.0);
+    }
+
+    #[test]
+    fn film_grain_keeps_mean() {
+        let flat = flat_image(0.5);
+        let out = FilmGrain::new(0.2, 7).apply(&flat);
+        assert_eq!(out, FilmGrain::new(0.2, 7).apply(&flat));
+        assert_ne!(out, flat);
+        let mean = out.pixels().map(|p| p.0[0]).sum::<f32>() / (32.0 * 24.0);
+        assert!((mean - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn white_balance_neutralizes_light() {
+        let neutral = WhiteBalance::new(6500.0, 0.0).gains();
+        assert!((neutral - Vec3::WHITE).length() < 1e-4);
+        // warm tungsten light is made bluer
+        let tungsten = WhiteBalance::new(3200.0, 0.0).gains();
+        assert!(tungsten.z > tungsten.y && tungsten.y > tungsten.x);
+        let magenta = WhiteBalance::new(6500.0, 0.5).gains();
+        assert!(magenta.y < magenta.x);
+    }
+
+    #[test]
+    fn lut_lookup() {
+        let identity = Lut::identity(5).with_gamma(2.2);
+        for c in [Vec3::new(0.1, 0.5, 0.9), Vec3::new(0.33, 0.0, 1.0)] {
+            assert!((identity.lookup(c) - c).length() < 1e-4);
+        }
+
+        // 2x2x2 table swapping red and blue
+        let mut cube = "TITLE \"swap\"\n# comment\nLUT_3D_SIZE 2\n".to_string();
+        for b in 0..2 {
+            for g in 0..2 {
+                for r in 0..2 {
+                    cube += &format!("{} {} {}\n", b, g, r);
+                }
+            }
+        }
+        let swap = Lut::from_cube(&cube).unwrap();
+        assert!((swap.lookup(Vec3::new(0.2, 0.4, 0.8)) - Vec3::new(0.8, 0.4, 0.2)).length() < 1e-5);
+        assert!(Lut::from_cube("LUT_3D_SIZE 3\n0 0 0\n").is_err());
+
+        // Resolve exports give the input range of all channels on one line
+        let ranged = Lut::from_cube(&format!("LUT_3D_INPUT_RANGE 0 2\n{}", cube)).unwrap();
+        assert_eq!(ranged.domain_max, Vec3::WHITE * 2.0);
+        assert!(Lut::from_cube(&format!("LUT_3D_INPUT_RANGE 0\n{}", cube)).is_err());
+    }
+
+    #[test]
+    fn parse_post_chain() {
+        let chain = PostChain::parse(
+            "bloom threshold=2 radius=4 | vignette | chromatic_aberration strength=0.01 \
+             | grain amount=0.1 seed=3 | white_balance temperature=4000",
+        )
+        .unwrap();
+        assert_eq!(chain.len(), 5);
+        assert!(PostChain::parse("").unwrap().is_empty());
+        assert!(PostChain::parse("sharpen").is_err());
+        assert!(PostChain::parse("bloom size=3").is_err());
+        assert!(PostChain::parse("vignette strength=strong").is_err());
+        assert!(PostChain::parse("lut gamma=2").is_err());
+
+        // seeds past 2^24 aren't rounded to a neighbouring float
+        let grey = ImageF32::from_pixel(8, 8, Rgb([0.5, 0.5, 0.5]));
+        let grain = |seed: u64| {
+            PostChain::parse(&format!("grain seed={}", seed))
+                .unwrap()
+                .apply(grey.clone())
+        };
+        assert_eq!(
+            grain(16_777_217),
+            FilmGrain::new(0.05, 16_777_217).apply(&grey)
         );
+        assert_ne!(grain(16_777_217), grain(16_777_216));
+        assert!(PostChain::parse("grain seed=1.5").is_err());
+    }
+
+    #[test]
+    fn apply_chain_to_file() -> ImageResult<()> {
+        let (input, output) = ("test_out/post_file_in.png", "test_out/post_file_out.png");
+        ImageBuffer::from_pixel(16, 12, Rgb([128u8, 64, 200])).save(input)?;
+        // without effects the colors come back as they were
+        PostChain::new().apply_to_file(input, output, 2.2)?;
+        let img = image::open(output)?.into_rgb8();
+        assert!(img.pixels().all(|p| p.0 == [128, 64, 200]));
 
-        let start = Instant::now();
-        let normal = vp.render_rows_async();
-        bilateral_filter(&normal, Proximity::new(10, ProximityType::Square))
-            .save("test_out/bilateral/bilateral_filter.png")?;
-        let end = Instant::now();
-        println!("Bilateral time: {:?}", end - start);
-        normal.save("test_out/bilateral/ray_color_control.png")?;
-        let start = Instant::now();
-        vp2.render_rows_async()
-            .save("test_out/bilateral/ray_color_test.png")?;
-        let end = Instant::now();
-        println!("ray_cast time: {:?}", end - start);
+        PostChain::parse("vignette strength=1")
+            .unwrap()
+            .apply_to_file(input, output, 2.2)?;
+        let img = image::open(output)?.into_rgb8();
+        let (corner, center) = (img.get_pixel(0, 0).0, img.get_pixel(8, 6).0);
+        assert!(corner[0] < center[0] && corner[2] < center[2]);
+        assert!(center[0].abs_diff(128) <= 2);
         Ok(())
     }
+
+    #[test]
+    fn post_chain_test() -> ImageResult<()> {
+        let chain = PostChain::new()
+            .with(WhiteBalance::new(5000.0, 0.0))
+            .with(Bloom::new(1.0, 0.5, 3.0))
+            .with(ChromaticAberration::new(0.01))
+            .with(Vignette::new(0.8))
+            .with(FilmGrain::new(0.05, 0));
+        denoise_scene(16)
+            .with_post(chain)
+            .render_rows_async()
+            .save("test_out/post_chain_test.png")
+    }
 }
@@ -4,6 +4,7 @@ use std::{
 };
 
 use crate::{
+    matrix::Matrix4,
     rotation::{EulerAngles, Rotation},
     vec3::vec3::Vec3,
 };
@@ -156,6 +157,29 @@ impl Quaternion {
         }
     }
 
+    pub fn normalized(&self) -> Quaternion {
+        self * (1.0 / self.len())
+    }
+
+    /// Rotation `t` of the way from `self` to `oth` along the shorter arc
+    pub fn slerp(&self, oth: &Quaternion, t: f32) -> Quaternion {
+        let a = self.normalized();
+        let mut b = oth.normalized();
+        let mut cos = a.dot(&b);
+        // q and -q are the same rotation
+        if cos < 0.0 {
+            b = &b * -1.0;
+            cos = -cos;
+        }
+        if cos > 0.9995 {
+            // nearly parallel, lerping avoids dividing by a tiny sine
+            return (&(&a * (1.0 - t)) + &(&b * t)).normalized();
+        }
+        let angle = cos.acos();
+        let sin = angle.sin();
+        &(&a * (((1.0 - t) * angle).sin() / sin)) + &(&b * ((t * angle).sin() / sin))
+    }
+
     pub fn get_vec(&self) -> Vec3 {
         Vec3 {
             x: self.x,
@@ -190,9 +214,33 @@ impl Rotation for Quaternion {
     }
 }
 
+impl Matrix4 {
+    pub fn rotation(q: &Quaternion) -> Self {
+        let q = q.normalized();
+        // the rotated axes are the columns
+        let [x, y, z] = [
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        ]
+        .map(|a| q.rotate(&a));
+        Self::new(
+            [[x.x, y.x, z.x], [x.y, y.y, z.y], [x.z, y.z, z.z]],
+            Vec3::ZERO,
+        )
+    }
+
+    /// Translation, rotation and scale applied in the order scale, rotation, translation
+    pub fn from_trs(translation: Vec3, rotation: &Quaternion, scale: Vec3) -> Self {
+        Self::translation(translation) * Self::rotation(rotation) * Self::scaling(scale)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::vec3::vec3::Vec3;
+    use std::f32::consts::PI;
+
+    use crate::{matrix::Matrix4, rotation::Rotation, vec3::vec3::Vec3};
 
     use super::{Quaternion, ZERO_ROTATION};
 
@@ -208,4 +256,19 @@ mod test {
         let full = half.hamilton(&cq);
         assert!(full.get_vec() == Vec3::FORWARD, "{:?}", full);
     }
+
+    #[test]
+    fn trs_matches_quaternion() {
+        let q = Quaternion::new_from_axis(0.7, Vec3::new(1.0, 2.0, -0.5));
+        let m = Matrix4::from_trs(Vec3::new(1.0, 2.0, 3.0), &q, Vec3::new(2.0, 0.5, 1.0));
+        let p = Vec3::new(0.3, -1.2, 0.8);
+        let expected = q.rotate(&Vec3::new(0.6, -0.6, 0.8)) + Vec3::new(1.0, 2.0, 3.0);
+        assert!((m.transform_point(p) - expected).length() < 1e-5);
+        // rotations turn vectors around, translations only move points
+        let quarter = Matrix4::rotation(&Quaternion::new_from_axis(PI / 2.0, Vec3::UP));
+        let moved = Matrix4::translation(Vec3::UP) * quarter;
+        let turned = moved.transform_vector(Vec3::FORWARD);
+        assert!((turned - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-6);
+        assert!((moved.transform_point(Vec3::ZERO) - Vec3::UP).length() < 1e-6);
+    }
 }
@@ -0,0 +1,30 @@
+use std::cell::RefCell;
+
+thread_local! {
+    static SOURCE: RefCell<Option<Box<dyn FnMut() -> f32>>> = RefCell::new(None);
+}
+
+/// Uniform number in [0, 1) for sampling paths.
+/// Comes from the source installed on this thread by `with_source`, or from `rand::random` when there is none
+pub fn random_f32() -> f32 {
+    SOURCE.with(|s| match s.borrow_mut().as_mut() {
+        Some(next) => next(),
+        None => rand::random(),
+    })
+}
+
+// puts the previous source back even if the closure panics
+struct Restore(Option<Box<dyn FnMut() -> f32>>);
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let prev = self.0.take();
+        SOURCE.with(|s| *s.borrow_mut() = prev);
+    }
+}
+
+/// Runs `f` with `source` providing the numbers returned by `random_f32` on this thread.
+/// The source itself has to use `rand::random`
+pub fn with_source<R>(source: impl FnMut() -> f32 + 'static, f: impl FnOnce() -> R) -> R {
+    let _restore = Restore(SOURCE.with(|s| s.replace(Some(Box::new(source)))));
+    f()
+}
@@ -1,11 +1,12 @@
-use std::sync::Arc;
+use std::{f32::consts::PI, sync::Arc};
 
 use image::ImageResult;
 
 use crate::{
     objects::{
+        hit::Hit,
         instance::Instance,
-        material::{MirrorGlass, MixedMaterial, LAMBERTIAN, MIRROR},
+        material::{Lambertian, Material, MirrorGlass, MixedMaterial, LAMBERTIAN, MIRROR},
         quad::Quad,
         sphere::Sphere,
         texture::ConstColorTexture,
@@ -15,12 +16,59 @@ use crate::{
     vec3::{ray::Ray, vec3::Vec3},
     viewport::{
         camera::Camera,
+        photon::ProgressivePhotonMapping,
         ray_color::{light_biased_ray_cast, light_biased_ray_color, ray_color},
         scene::Scene,
         Viewport,
     },
 };
 
+#[test]
+fn folded_pdfs_integrate_to_one() {
+    // shading normal tilted away from the geometric one, so part of each lobe is folded back up
+    let p = Vec3::new(0.0, 0.0, 0.0);
+    let h = Hit {
+        r: Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0)),
+        p,
+        n: Vec3::new(0.6, 0.0, 1.0).unit(),
+        ng: Vec3::new(0.0, 0.0, 1.0),
+        t: 1.0,
+        u: 0.0,
+        v: 0.0,
+        dpdu: Vec3::new(1.0, 0.0, 0.0),
+        dpdv: Vec3::new(0.0, 1.0, 0.0),
+    };
+    // midpoint rule over the sphere, uniform in cos(theta) and phi
+    const STEPS: usize = 1000;
+    let integrate = |pdf: &dyn Fn(&Ray) -> f32| {
+        let mut sum = 0.0;
+        for i in 0..STEPS {
+            let z = 1.0 - 2.0 * (i as f32 + 0.5) / STEPS as f32;
+            let s = (1.0 - z * z).sqrt();
+            for j in 0..STEPS {
+                let phi = 2.0 * PI * (j as f32 + 0.5) / STEPS as f32;
+                sum += pdf(&Ray::new(p, Vec3::new(s * phi.cos(), s * phi.sin(), z)));
+            }
+        }
+        sum * 4.0 * PI / (STEPS * STEPS) as f32
+    };
+
+    let materials: [Box<dyn Material>; 2] =
+        [Box::new(Lambertian {}), Box::new(MixedMaterial::new(20.0))];
+    for m in materials {
+        let generator = integrate(&|r| m.generator_pdf(&h, r));
+        let material = integrate(&|r| m.material_pdf(&h, r));
+        assert!(
+            (generator - 1.0).abs() < 0.01,
+            "generator pdf integrates to {generator}"
+        );
+        assert!(
+            (material - 1.0).abs() < 0.01,
+            "material pdf integrates to {material}"
+        );
+    }
+}
+
 #[test]
 fn mixed_material_test() -> ImageResult<()> {
     const WIDTH: usize = 400;
@@ -332,13 +380,9 @@ fn mixed_material_test() -> ImageResult<()> {
 
 const BIASED_WEIGHT: f32 = 100.;
 
-#[test]
-fn glass_material_test() -> ImageResult<()> {
-    const WIDTH: usize = 400;
-    const HEIGHT: usize = 300;
-    const SAMPLES: usize = 100;
-    const DEPTH: usize = 9;
-
+// box of colored walls lit by a glowing quad, with a pane of glass made of two faces.
+// Also returns the glowing objects for the light biased integrators
+fn glass_box() -> (Scene, Arc<[Arc<dyn Object + Send + Sync>]>) {
     let lights: Arc<[Arc<dyn Object + Send + Sync>]> = Arc::new([
         Arc::new(Quad::new(
             Vec3 {
@@ -634,7 +678,7 @@ fn glass_material_test() -> ImageResult<()> {
     //     z: 0.,
     // });
 
-    let (scene, lights) = (
+    (
         Scene::new(
             vec![
                 quad_box, // Instance::new(Arc::new([lights[1].clone()])),
@@ -644,7 +688,17 @@ fn glass_material_test() -> ImageResult<()> {
             1000.0,
         ),
         lights,
-    );
+    )
+}
+
+#[test]
+fn glass_material_test() -> ImageResult<()> {
+    const WIDTH: usize = 400;
+    const HEIGHT: usize = 300;
+    const SAMPLES: usize = 100;
+    const DEPTH: usize = 9;
+
+    let (scene, lights) = glass_box();
 
     let cam = Camera::new(
         WIDTH as f32 / HEIGHT as f32,
@@ -711,3 +765,53 @@ fn glass_material_test() -> ImageResult<()> {
     vp3.render()
         .save("test_out/materials/glass_ray_color_old.png")
 }
+
+#[test]
+fn glass_photon_map_test() -> ImageResult<()> {
+    const WIDTH: usize = 300;
+    const HEIGHT: usize = 200;
+    const SAMPLES: usize = 4;
+    const DEPTH: usize = 9;
+
+    // only the glowing quad lights the box, photons have to start on it
+    let (scene, _) = glass_box();
+    let viewport = |width: usize, height: usize, samples: usize| {
+        let cam = Camera::new(
+            width as f32 / height as f32,
+            Vec3::ZERO,
+            Vec3::UP,
+            Vec3::FORWARD,
+            90.0,
+            0.0,
+        );
+        Viewport::new(
+            cam,
+            scene.clone(),
+            Arc::new(ray_color),
+            width,
+            height,
+            samples,
+            DEPTH,
+            Vec3::ZERO,
+            2.0,
+        )
+    };
+
+    let small = viewport(32, 24, 64);
+    let mean = |img: Vec<Vec<Vec3>>| {
+        img.iter().flatten().fold(Vec3::ZERO, |a, &b| a + b) / img.iter().flatten().count() as f32
+    };
+    let photons = mean(ProgressivePhotonMapping::new(4, 100000, 0.05).render_linear(&small));
+    let reference = mean(small.render_linear());
+    for (a, b) in [
+        (reference.x, photons.x),
+        (reference.y, photons.y),
+        (reference.z, photons.z),
+    ] {
+        assert!((a - b).abs() <= 0.1 * a, "{:?} != {:?}", reference, photons);
+    }
+
+    ProgressivePhotonMapping::new(4, 200000, 0.05)
+        .render(viewport(WIDTH, HEIGHT, SAMPLES))
+        .save("test_out/materials/glass_photon_map.png")
+}
@@ -1,11 +1,10 @@
 #[allow(dead_code)]
 pub mod vec3 {
-    use rand::random;
     use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign};
 
     use image::Rgb;
 
-    use crate::rotation::Rotation;
+    use crate::{rotation::Rotation, sampler::random_f32};
 
     #[derive(Debug, Clone, Copy)]
     pub struct Vec3 {
@@ -19,6 +18,16 @@ pub mod vec3 {
             (*self - *other).close_to_zero()
         }
     }
+    impl From<[f32; 3]> for Vec3 {
+        fn from([x, y, z]: [f32; 3]) -> Self {
+            Self { x, y, z }
+        }
+    }
+    impl From<Vec3> for [f32; 3] {
+        fn from(v: Vec3) -> Self {
+            [v.x, v.y, v.z]
+        }
+    }
     impl Neg for Vec3 {
         type Output = Self;
         fn neg(self) -> Self {
@@ -247,9 +256,9 @@ pub mod vec3 {
 
         pub fn random(min: f32, max: f32) -> Vec3 {
             Vec3 {
-                x: random::<f32>() * (max - min) + min,
-                y: random::<f32>() * (max - min) + min,
-                z: random::<f32>() * (max - min) + min,
+                x: random_f32() * (max - min) + min,
+                y: random_f32() * (max - min) + min,
+                z: random_f32() * (max - min) + min,
             }
         }
         pub fn random_unit_vec() -> Vec3 {
@@ -268,8 +277,8 @@ pub mod vec3 {
             // println!("rand_vec");
             return loop {
                 let p = Vec3 {
-                    x: random::<f32>() * 2.0 - 1.0,
-                    y: random::<f32>() * 2.0 - 1.0,
+                    x: random_f32() * 2.0 - 1.0,
+                    y: random_f32() * 2.0 - 1.0,
                     z: 0.0,
                 };
                 // println!("vec: {:?}, len: {}", p, p.x * p.x + p.y * p.y + p.z * p.z);
@@ -316,6 +325,9 @@ pub mod ray {
         pub origin: Vec3,
         pub direction: Vec3,
         pub time: f32,
+        // angle of the cone around the ray and its width at the origin, used for texture filtering
+        pub spread: f32,
+        pub width: f32,
     }
 
     impl Ray {
@@ -324,6 +336,8 @@ pub mod ray {
                 origin,
                 direction,
                 time: 0.0,
+                spread: 0.0,
+                width: 0.0,
             }
         }
         pub fn new_with_time(origin: Vec3, direction: Vec3, time: f32) -> Self {
@@ -331,8 +345,29 @@ pub mod ray {
                 origin,
                 direction,
                 time,
+                spread: 0.0,
+                width: 0.0,
             }
         }
+        pub fn with_spread(mut self, spread: f32) -> Self {
+            self.spread = spread;
+            self
+        }
+        /// Ray from `origin` towards `direction` that continues the cone of this one after it
+        /// travelled to `t`
+        pub fn continued(&self, t: f32, origin: Vec3, direction: Vec3) -> Self {
+            Self {
+                origin,
+                direction,
+                time: self.time,
+                spread: self.spread,
+                width: self.width_at(t),
+            }
+        }
+        /// Width of the cone at `t`
+        pub fn width_at(&self, t: f32) -> f32 {
+            self.width + self.spread * t * self.direction.length()
+        }
 
         pub fn at(&self, t: f32) -> Vec3 {
             self.origin + self.direction * t
@@ -342,6 +377,8 @@ pub mod ray {
                 origin: rot.rotate(&self.origin),
                 direction: rot.rotate(&self.direction),
                 time: self.time,
+                spread: self.spread,
+                width: self.width,
             }
         }
     }
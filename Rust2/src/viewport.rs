@@ -3,17 +3,36 @@ use std::sync::Arc;
 use image::{ImageBuffer, Rgb};
 use rayon::prelude::*;
 
-use crate::vec3::{ray::Ray, vec3::Vec3};
+use crate::{
+    postprocessing::{from_image_f32, to_image_f32, Denoiser, PostChain},
+    sampler::random_f32,
+    vec3::{ray::Ray, vec3::Vec3},
+};
 
-use self::{camera::Camera, ray_color::RayColor, scene::Scene};
+use self::{
+    camera::Projection, environment::Environment, film::SplatBuffer, ray_color::RayColor,
+    scene::Scene,
+};
 
+pub mod aov;
+pub mod bdpt;
 pub mod camera;
+pub mod environment;
+pub mod film;
+pub mod lens;
+pub mod mlt;
+pub mod photon;
+pub mod projection;
 pub mod ray_color;
 pub mod scene;
+pub mod sky;
+pub mod stereo;
+#[cfg(test)]
+pub(crate) mod test_scenes;
 
 #[derive(Clone)]
 pub(crate) struct Viewport {
-    cam: Camera,
+    cam: Arc<dyn Projection + Send + Sync>,
 
     rc: RayColor,
     width: usize,
@@ -22,12 +41,20 @@ pub(crate) struct Viewport {
     recursion_depth: usize,
     gamma: f32,
     bg_color: Vec3,
+    environment: Option<Arc<dyn Environment + Send + Sync>>,
     s: Scene,
+    // light traced onto the image by integrators, added to the pixels after all samples are taken.
+    // Every render starts a new one
+    splats: Option<Arc<SplatBuffer>>,
+    // effects applied to the linear image before gamma correction
+    post: PostChain,
+    // camera rays get times between the shutter opening and closing, moving instances blur
+    shutter: (f32, f32),
 }
 
 impl Viewport {
     pub fn new(
-        cam: Camera,
+        cam: impl Projection + Send + Sync + 'static,
         s: Scene,
         rc: RayColor,
         width: usize,
@@ -38,7 +65,7 @@ impl Viewport {
         gamma: f32,
     ) -> Self {
         Self {
-            cam,
+            cam: Arc::new(cam),
 
             rc: rc,
             width,
@@ -48,6 +75,38 @@ impl Viewport {
             recursion_depth,
             gamma,
             bg_color,
+            environment: None,
+            splats: None,
+            post: PostChain::new(),
+            shutter: (0.0, 0.0),
+        }
+    }
+    pub fn with_camera(mut self, cam: impl Projection + Send + Sync + 'static) -> Self {
+        self.cam = Arc::new(cam);
+        self
+    }
+    pub fn with_scene(mut self, s: Scene) -> Self {
+        self.s = s;
+        self
+    }
+    /// Opens the shutter from time `open` to `close`, instances move from time 0 to 1
+    pub fn with_shutter(mut self, open: f32, close: f32) -> Self {
+        self.shutter = (open, close);
+        self
+    }
+    pub fn with_post(mut self, post: PostChain) -> Self {
+        self.post = post;
+        self
+    }
+    pub fn with_environment(mut self, env: Arc<dyn Environment + Send + Sync>) -> Self {
+        self.environment = Some(env);
+        self
+    }
+    // color of rays that escape the scene
+    pub(crate) fn background(&self, r: &Ray) -> Vec3 {
+        match &self.environment {
+            Some(env) => env.radiance(r.direction),
+            None => self.bg_color,
         }
     }
     fn make_image(iv: Vec<Vec<Vec3>>) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
@@ -59,6 +118,55 @@ impl Viewport {
         }
         img
     }
+    // empty splat buffer for the next render
+    pub(crate) fn reset_splats(&mut self) {
+        self.splats = Some(Arc::new(SplatBuffer::new(self.width, self.height)));
+    }
+    /// Adds light to pixel (`x`, `y`) of the image being rendered, outside of a render it is lost
+    pub(crate) fn splat(&self, x: usize, y: usize, c: Vec3) {
+        if let Some(splats) = &self.splats {
+            splats.add(x, y, c);
+        }
+    }
+    pub(crate) fn splat_at(&self, x: usize, y: usize) -> Vec3 {
+        self.splats
+            .as_ref()
+            .map_or(Vec3::ZERO, |splats| splats.get(x, y))
+    }
+    // adds the splatted light to the averaged samples
+    fn add_splats(&self, mut iv: Vec<Vec<Vec3>>) -> Vec<Vec<Vec3>> {
+        if !self
+            .splats
+            .as_ref()
+            .is_some_and(|splats| splats.is_allocated())
+        {
+            return iv;
+        }
+        let s_sqrt = (self.samples as f32).sqrt().floor();
+        let samples = s_sqrt * s_sqrt;
+        for (y, row) in iv.iter_mut().enumerate() {
+            for (x, pix) in row.iter_mut().enumerate() {
+                *pix += self.splat_at(x, y) / samples;
+            }
+        }
+        iv
+    }
+    // final image of the linear pixels, after the post effects and gamma correction
+    fn develop(iv: Vec<Vec<Vec3>>, post: &PostChain, gamma: f32) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let iv = if post.is_empty() {
+            iv
+        } else {
+            from_image_f32(&post.apply(to_image_f32(&iv)))
+        };
+        Self::make_image(Self::gamma_correct(iv, gamma))
+    }
+    fn gamma_correct(mut iv: Vec<Vec<Vec3>>, gamma: f32) -> Vec<Vec<Vec3>> {
+        let inv_gamma = 1.0 / gamma;
+        for pix in iv.iter_mut().flatten() {
+            *pix = pix.gamma_correct(inv_gamma);
+        }
+        iv
+    }
     fn ray_depth(r: &Ray, scene: &Scene) -> f32 {
         let ray = Ray::new(r.origin, r.direction.unit());
 
@@ -74,46 +182,75 @@ impl Viewport {
         for j in 0..self.height {
             let mut tmp = vec![];
             for i in 0..self.width {
-                let dir = self.cam.left_top
-                    + self.cam.delta_x * (i as f32 / self.width as f32)
-                    + self.cam.delta_y * (j as f32 / self.height as f32);
-                tmp.push(Self::ray_depth(&Ray::new(self.cam.origin, dir), scene));
+                let ray = self
+                    .cam
+                    .ray(i as f32 / self.width as f32, j as f32 / self.height as f32);
+                tmp.push(match ray {
+                    Some((r, _)) => Self::ray_depth(&r, scene),
+                    None => scene.maxt * 1.6,
+                });
             }
             img.push(tmp);
         }
         return img;
     }
 
+    // angle covered by a single pixel, camera rays are cones of this width
+    fn pixel_spread(&self) -> f32 {
+        self.cam.spread() / self.height as f32
+    }
+
+    // camera ray through the middle of the `stratum` cell of pixel (x, y) split into s_sqrt x s_sqrt cells
+    // and its weight, `None` when the lens blocks it
+    fn pixel_ray(
+        &self,
+        x: usize,
+        y: usize,
+        stratum: (usize, usize),
+        s_sqrt: usize,
+    ) -> Option<(Ray, f32)> {
+        self.camera_ray(
+            (x as f32 + (stratum.0 as f32 + 0.5) / s_sqrt as f32) / self.width as f32,
+            (y as f32 + (stratum.1 as f32 + 0.5) / s_sqrt as f32) / self.height as f32,
+        )
+    }
+
+    // camera ray through (u, v) at a random time while the shutter is open
+    fn camera_ray(&self, u: f32, v: f32) -> Option<(Ray, f32)> {
+        let (mut r, weight) = self.cam.ray(u, v)?;
+        let (open, close) = self.shutter;
+        if close > open {
+            r.time = open + (close - open) * random_f32();
+        } else {
+            r.time = open;
+        }
+        Some((r, weight))
+    }
+
     fn render_row(self: Arc<Self>, y: usize) -> Vec<Vec3> {
         let mut row = Vec::with_capacity(self.width);
-        let inv_gamma = 1.0 / self.gamma;
         let s_sqrt = (self.samples as f32).sqrt().floor() as usize;
+        let spread = self.pixel_spread();
         for j in 0..self.width {
             let mut pix = Vec3::ZERO;
             for k in 0..s_sqrt {
                 for l in 0..s_sqrt {
-                    let dir = self.cam.left_top
-                        + self.cam.delta_x
-                            * ((j as f32 + (k as f32 + 0.5) / s_sqrt as f32) / self.width as f32)
-                        + self.cam.delta_y
-                            * ((y as f32 + (l as f32 + 0.5) / s_sqrt as f32) / self.height as f32);
-                    let r = Ray::new(
-                        self.cam.origin + Vec3::random_in_unit_disk() * self.cam.lens_radius,
-                        dir,
-                    );
-                    pix += (self.rc)(r, self.to_owned(), self.recursion_depth);
+                    if let Some((r, weight)) = self.pixel_ray(j, y, (k, l), s_sqrt) {
+                        let r = r.with_spread(spread);
+                        pix += (self.rc)(r, self.to_owned(), self.recursion_depth) * weight;
+                    }
                 }
             }
             // average all samples
             pix /= (s_sqrt * s_sqrt) as f32;
-            // gamma correct
-            let pix = pix.gamma_correct(inv_gamma);
             row.push(pix);
         }
         row
     }
 
-    pub fn render_rows_async(self) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    /// Pixel values before gamma correction, rows are rendered in parallel
+    pub(crate) fn render_linear(mut self) -> Vec<Vec<Vec3>> {
+        self.reset_splats();
         let arc = Arc::new(self);
 
         let image_vec: Vec<_> = (0..arc.height)
@@ -121,15 +258,28 @@ impl Viewport {
             .map(|y| Self::render_row(arc.clone(), y))
             .collect();
 
-        // self.make_image(image_vec)
-        Self::make_image(image_vec)
+        arc.add_splats(image_vec)
+    }
+
+    pub fn render_rows_async(self) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let (gamma, post) = (self.gamma, self.post.clone());
+        Self::develop(self.render_linear(), &post, gamma)
+    }
+
+    /// Image rendered with its AOVs by next event estimation and filtered by `denoiser`
+    /// guided by them
+    pub fn render_denoised(self, denoiser: &Denoiser) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let (gamma, post) = (self.gamma, self.post.clone());
+        let aovs = self.render_aovs();
+        Self::develop(denoiser.denoise_aovs(&aovs), &post, gamma)
     }
 
-    pub fn render(self) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    pub fn render(mut self) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        self.reset_splats();
         let mut image_vec = Vec::with_capacity(self.height);
         let s_sqrt = (self.samples as f32).sqrt().floor() as usize;
         let arc = Arc::new(self.to_owned());
-        let inv_gamma = 1.0 / self.gamma;
+        let spread = self.pixel_spread();
 
         for i in 0..self.height {
             let mut row = Vec::with_capacity(self.width);
@@ -137,29 +287,19 @@ impl Viewport {
                 let mut pix = Vec3::ZERO;
                 for k in 0..s_sqrt {
                     for l in 0..s_sqrt {
-                        let dir = self.cam.left_top
-                            + self.cam.delta_x
-                                * ((j as f32 + (k as f32 + 0.5) / s_sqrt as f32)
-                                    / self.width as f32)
-                            + self.cam.delta_y
-                                * ((i as f32 + (l as f32 + 0.5) / s_sqrt as f32)
-                                    / self.height as f32);
-                        let r = Ray::new(
-                            self.cam.origin + Vec3::random_in_unit_disk() * self.cam.lens_radius,
-                            dir,
-                        );
-                        pix += (self.rc)(r, arc.to_owned(), self.recursion_depth);
+                        if let Some((r, weight)) = self.pixel_ray(j, i, (k, l), s_sqrt) {
+                            let r = r.with_spread(spread);
+                            pix += (self.rc)(r, arc.to_owned(), self.recursion_depth) * weight;
+                        }
                     }
                 }
                 // average all samples
                 pix /= (s_sqrt * s_sqrt) as f32;
-                // gamma correct
-                let pix = pix.gamma_correct(inv_gamma);
                 row.push(pix);
             }
             image_vec.push(row);
         }
 
-        Self::make_image(image_vec)
+        Self::develop(self.add_splats(image_vec), &self.post, self.gamma)
     }
 }
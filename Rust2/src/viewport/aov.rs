@@ -0,0 +1,466 @@
+use std::sync::Arc;
+
+use image::{ImageBuffer, ImageResult, Rgb};
+use rayon::prelude::*;
+
+use crate::{
+    postprocessing::{to_image_f32, ImageF32},
+    vec3::{ray::Ray, vec3::Vec3},
+};
+
+use super::{
+    ray_color::{next_event_hit, Bounce, PathLight},
+    Viewport,
+};
+
+/// Buffers rendered alongside the image for compositing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Aov {
+    Beauty,
+    // surface color at the first hit
+    Albedo,
+    // shading normal at the first hit, facing the camera
+    Normal,
+    Position,
+    // distance from the camera to the first hit, infinite for rays that escape
+    Depth,
+    ObjectId,
+    MaterialId,
+    // light reaching the camera after a single bounce, the light layers split the beauty layer
+    Direct,
+    // light reaching the camera after two or more bounces
+    Indirect,
+    // light emitted towards the camera by the first hit or the background
+    Emission,
+}
+
+const AOV_COUNT: usize = 10;
+
+impl Aov {
+    pub const ALL: [Aov; AOV_COUNT] = [
+        Aov::Beauty,
+        Aov::Albedo,
+        Aov::Normal,
+        Aov::Position,
+        Aov::Depth,
+        Aov::ObjectId,
+        Aov::MaterialId,
+        Aov::Direct,
+        Aov::Indirect,
+        Aov::Emission,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Beauty => "beauty",
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Position => "position",
+            Aov::Depth => "depth",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::Emission => "emission",
+        }
+    }
+}
+
+// stable pseudo random color of an id so neighbouring ids are easy to tell apart, black for 0
+fn id_color(id: usize) -> Vec3 {
+    if id == 0 {
+        return Vec3::ZERO;
+    }
+    // splitmix64 finalizer
+    let mut h = id as u64;
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h ^= h >> 31;
+    let channel = |shift: u64| ((h >> shift) & 0xff) as f32 / 255.0;
+    Vec3::new(channel(0), channel(8), channel(16))
+}
+
+// every AOV of a single camera ray, indexed by `Aov as usize`
+fn aov_sample(r: Ray, vp: &Arc<Viewport>) -> [Vec3; AOV_COUNT] {
+    let mut values = [Vec3::ZERO; AOV_COUNT];
+    // the first hit is traced once, the light layers continue from it
+    let hit = vp.s.get_hit(r);
+    let light = match vp.recursion_depth {
+        0 => PathLight::ZERO,
+        depth => next_event_hit(r, hit.clone(), vp.clone(), depth, Bounce::Camera),
+    };
+    values[Aov::Beauty as usize] = light.total();
+    values[Aov::Direct as usize] = light.direct;
+    values[Aov::Indirect as usize] = light.indirect;
+    values[Aov::Emission as usize] = light.emitted;
+
+    match hit {
+        Some((h, o)) => {
+            let depth = h.t * r.direction.length();
+            values[Aov::Albedo as usize] = o.color(&h).multiplied;
+            values[Aov::Normal as usize] = h.facing_normals().0;
+            values[Aov::Position as usize] = h.p;
+            values[Aov::Depth as usize] = Vec3::new(depth, depth, depth);
+            values[Aov::ObjectId as usize] = id_color(vp.s.object_id(&o));
+            values[Aov::MaterialId as usize] = id_color(vp.s.material_id(&o));
+        }
+        None => values[Aov::Depth as usize] = Vec3::WHITE * f32::INFINITY,
+    }
+    values
+}
+
+/// All AOVs of a render as linear pixel values
+#[derive(Debug, Clone)]
+pub struct AovImage {
+    pub width: usize,
+    pub height: usize,
+    gamma: f32,
+    // rows of pixels of every AOV, indexed by `Aov as usize`
+    layers: Vec<Vec<Vec<Vec3>>>,
+}
+
+impl AovImage {
+    pub fn layer(&self, aov: Aov) -> &Vec<Vec<Vec3>> {
+        &self.layers[aov as usize]
+    }
+
+    /// Raw values of `aov`, suitable for formats that store floats like OpenEXR
+    pub fn to_rgb32f(&self, aov: Aov) -> ImageF32 {
+        to_image_f32(self.layer(aov))
+    }
+
+    /// `aov` remapped for viewing: colors are gamma corrected, normals are moved to [0, 1],
+    /// positions and depth are scaled by the range of the visible surfaces
+    pub fn to_rgb8(&self, aov: Aov) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let layer = self.layer(aov);
+        let finite = || {
+            layer
+                .iter()
+                .flatten()
+                .filter(|c| c.x.is_finite() && c.y.is_finite() && c.z.is_finite())
+        };
+        let (min, max) = match aov {
+            Aov::Position => finite().fold(
+                (Vec3::WHITE * f32::INFINITY, Vec3::WHITE * -f32::INFINITY),
+                |(min, max), &c| {
+                    (
+                        Vec3::new(min.x.min(c.x), min.y.min(c.y), min.z.min(c.z)),
+                        Vec3::new(max.x.max(c.x), max.y.max(c.y), max.z.max(c.z)),
+                    )
+                },
+            ),
+            Aov::Depth => (
+                Vec3::ZERO,
+                Vec3::WHITE * finite().fold(0.0, |max: f32, c| max.max(c.x)),
+            ),
+            _ => (Vec3::ZERO, Vec3::WHITE),
+        };
+        let inv_gamma = 1.0 / self.gamma;
+        let mut img = ImageBuffer::new(self.width as u32, self.height as u32);
+        for (x, y, pix) in img.enumerate_pixels_mut() {
+            let c = layer[y as usize][x as usize];
+            let c = match aov {
+                Aov::Beauty | Aov::Albedo | Aov::Direct | Aov::Indirect | Aov::Emission => {
+                    c.gamma_correct(inv_gamma)
+                }
+                Aov::Normal => (c + Vec3::WHITE) * 0.5,
+                Aov::Position | Aov::Depth => {
+                    let range = max - min;
+                    let scale = |v: f32, min: f32, range: f32| {
+                        if range > 0.0 {
+                            ((v - min) / range).min(1.0)
+                        } else {
+                            0.0
+                        }
+                    };
+                    Vec3::new(
+                        scale(c.x, min.x, range.x),
+                        scale(c.y, min.y, range.y),
+                        scale(c.z, min.z, range.z),
+                    )
+                }
+                Aov::ObjectId | Aov::MaterialId => c,
+            };
+            *pix = c.to_rgb_u8();
+        }
+        img
+    }
+
+    /// Writes every AOV to `{prefix}_{name}.png`
+    pub fn save_png(&self, prefix: &str) -> ImageResult<()> {
+        for aov in Aov::ALL {
+            self.to_rgb8(aov)
+                .save(format!("{}_{}.png", prefix, aov.name()))?;
+        }
+        Ok(())
+    }
+
+    /// Writes every AOV to `{prefix}_{name}.exr` as 32 bit floats
+    pub fn save_exr(&self, prefix: &str) -> ImageResult<()> {
+        for aov in Aov::ALL {
+            self.to_rgb32f(aov)
+                .save(format!("{}_{}.exr", prefix, aov.name()))?;
+        }
+        Ok(())
+    }
+}
+
+// layers taken from the nearest sample of a pixel instead of averaged
+const NEAREST: [usize; 3] = [
+    Aov::Depth as usize,
+    Aov::ObjectId as usize,
+    Aov::MaterialId as usize,
+];
+
+impl Viewport {
+    fn render_aov_row(self: Arc<Self>, y: usize) -> Vec<[Vec3; AOV_COUNT]> {
+        let s_sqrt = (self.samples as f32).sqrt().floor() as usize;
+        let spread = self.pixel_spread();
+        (0..self.width)
+            .map(|x| {
+                let mut pix = [Vec3::ZERO; AOV_COUNT];
+                pix[Aov::Depth as usize] = Vec3::WHITE * f32::INFINITY;
+                let mut nearest = f32::INFINITY;
+                for k in 0..s_sqrt {
+                    for l in 0..s_sqrt {
+                        let samples = match self.pixel_ray(x, y, (k, l), s_sqrt) {
+                            Some((r, weight)) => {
+                                let mut samples = aov_sample(r.with_spread(spread), &self);
+                                for i in [Aov::Beauty, Aov::Direct, Aov::Indirect, Aov::Emission] {
+                                    samples[i as usize] *= weight;
+                                }
+                                samples
+                            }
+                            // blocked by the lens, nothing was seen
+                            None => {
+                                let mut samples = [Vec3::ZERO; AOV_COUNT];
+                                samples[Aov::Depth as usize] = Vec3::WHITE * f32::INFINITY;
+                                samples
+                            }
+                        };
+                        // depth and ids of the nearest surface of the pixel, averages would float
+                        // between objects and blend ids into colors of no object
+                        let depth = samples[Aov::Depth as usize].x;
+                        if depth < nearest {
+                            nearest = depth;
+                            for aov in [Aov::Depth, Aov::ObjectId, Aov::MaterialId] {
+                                pix[aov as usize] = samples[aov as usize];
+                            }
+                        }
+                        for (i, v) in samples.into_iter().enumerate() {
+                            if !NEAREST.contains(&i) {
+                                pix[i] += v;
+                            }
+                        }
+                    }
+                }
+                for (i, v) in pix.iter_mut().enumerate() {
+                    if !NEAREST.contains(&i) {
+                        *v /= (s_sqrt * s_sqrt) as f32;
+                    }
+                }
+                pix
+            })
+            .collect()
+    }
+
+    /// Renders every AOV in a single pass, rows are rendered in parallel.
+    /// Every sample is traced once with next event estimation, the beauty layer is the sum of the
+    /// emission, direct and indirect layers
+    pub(crate) fn render_aovs(self) -> AovImage {
+        let (width, height, gamma) = (self.width, self.height, self.gamma);
+        let arc = Arc::new(self);
+        let rows: Vec<_> = (0..height)
+            .into_par_iter()
+            .map(|y| Self::render_aov_row(arc.clone(), y))
+            .collect();
+
+        let layers: Vec<Vec<Vec<Vec3>>> = (0..AOV_COUNT)
+            .map(|i| {
+                rows.iter()
+                    .map(|row| row.iter().map(|pix| pix[i]).collect())
+                    .collect()
+            })
+            .collect();
+        AovImage {
+            width,
+            height,
+            gamma,
+            layers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use image::ImageResult;
+
+    use crate::{
+        objects::{
+            instance::Instance,
+            light::{Light, RectLight},
+            material::{Lambertian, LAMBERTIAN},
+            quad::Quad,
+            sphere::Sphere,
+            texture::ConstColorTexture,
+        },
+        vec3::vec3::Vec3,
+        viewport::{camera::Camera, ray_color::next_event_ray_color, scene::Scene, Viewport},
+    };
+
+    use super::{id_color, Aov, AovImage};
+
+    const WIDTH: usize = 40;
+    const HEIGHT: usize = 30;
+
+    fn scene() -> Viewport {
+        let objects = vec![
+            Instance::new(Arc::new([Arc::new(Sphere {
+                origin: Vec3::new(0.0, 0.0, 3.0),
+                radius: 0.5,
+                mat: LAMBERTIAN.clone(),
+                texture: Arc::new(ConstColorTexture::new(Vec3::new(0.8, 0.2, 0.2), Vec3::ZERO)),
+            })])),
+            Instance::new(Arc::new([Arc::new(Quad::new(
+                Vec3::new(-5.0, -0.5, -2.0),
+                Vec3::new(10.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 10.0),
+                Arc::new(Lambertian {}),
+                Vec3::ZERO,
+                Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.5, Vec3::ZERO)),
+            ))])),
+        ];
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![Arc::new(
+            RectLight::new(
+                Vec3::new(-0.5, 1.5, 2.5),
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 1.0),
+                Vec3::WHITE * 2.0,
+            )
+            .with_visibility(false),
+        )];
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::ZERO,
+            Vec3::UP,
+            Vec3::FORWARD,
+            60.0,
+            0.0,
+        );
+        Viewport::new(
+            cam,
+            Scene::new(objects, 0.001, 1000.0).with_lights(lights),
+            Arc::new(next_event_ray_color),
+            WIDTH,
+            HEIGHT,
+            4,
+            4,
+            Vec3::new(0.1, 0.1, 0.2),
+            2.2,
+        )
+    }
+
+    fn pixel(img: &AovImage, aov: Aov, x: usize, y: usize) -> Vec3 {
+        img.layer(aov)[y][x]
+    }
+
+    #[test]
+    fn beauty_is_sum_of_light_layers() {
+        let img = scene().render_aovs();
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let beauty = pixel(&img, Aov::Beauty, x, y);
+                let sum = pixel(&img, Aov::Emission, x, y)
+                    + pixel(&img, Aov::Direct, x, y)
+                    + pixel(&img, Aov::Indirect, x, y);
+                assert!(
+                    (beauty - sum).length() <= 1e-5 * beauty.length().max(1.0),
+                    "({}, {}): {:?} != {:?}",
+                    x,
+                    y,
+                    beauty,
+                    sum
+                );
+            }
+        }
+        // the background is only emission
+        assert_eq!(pixel(&img, Aov::Beauty, 0, 0), Vec3::new(0.1, 0.1, 0.2));
+        assert_eq!(pixel(&img, Aov::Direct, 0, 0), Vec3::ZERO);
+    }
+
+    #[test]
+    fn first_hit_layers() {
+        let img = scene().render_aovs();
+        let (cx, cy) = (WIDTH / 2, HEIGHT / 2);
+        // the middle of the image looks at the front of the sphere
+        assert!((pixel(&img, Aov::Depth, cx, cy).x - 2.5).abs() < 0.05);
+        assert!(pixel(&img, Aov::Normal, cx, cy).dot(Vec3::new(0.0, 0.0, -1.0)) > 0.95);
+        assert_eq!(pixel(&img, Aov::Albedo, cx, cy), Vec3::new(0.8, 0.2, 0.2));
+        assert!(pixel(&img, Aov::Depth, 0, 0).x.is_infinite());
+
+        // the sphere and the floor are told apart by both ids
+        let floor = (cx, HEIGHT - 1);
+        for aov in [Aov::ObjectId, Aov::MaterialId] {
+            let sphere_id = pixel(&img, aov, cx, cy);
+            let floor_id = pixel(&img, aov, floor.0, floor.1);
+            assert_ne!(sphere_id, Vec3::ZERO);
+            assert_ne!(floor_id, Vec3::ZERO);
+            assert_ne!(sphere_id, floor_id);
+            assert_eq!(pixel(&img, aov, 0, 0), Vec3::ZERO);
+        }
+    }
+
+    #[test]
+    fn beauty_ignores_viewport_integrator() {
+        // the beauty layer comes from the same paths as the light layers
+        let mut vp = scene();
+        vp.rc = Arc::new(|_, _, _| Vec3::new(0.25, 0.5, 0.75));
+        let (img, reference) = (vp.render_aovs(), scene().render_aovs());
+        assert_ne!(pixel(&img, Aov::Beauty, 0, 0), Vec3::new(0.25, 0.5, 0.75));
+        assert_eq!(
+            pixel(&img, Aov::Beauty, 0, 0),
+            pixel(&reference, Aov::Beauty, 0, 0)
+        );
+        // the floor below the camera faces the light, the front of the sphere only grazes it
+        assert_ne!(pixel(&img, Aov::Direct, WIDTH / 2, HEIGHT - 1), Vec3::ZERO);
+    }
+
+    #[test]
+    fn ids_are_not_blended() {
+        let img = scene().render_aovs();
+        let ids = [id_color(0), id_color(1), id_color(2)];
+        for aov in [Aov::ObjectId, Aov::MaterialId] {
+            // the silhouette of the sphere mixes sphere, floor and background samples
+            for row in img.layer(aov) {
+                for pix in row {
+                    assert!(ids.contains(pix), "{:?} is no id", pix);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ids_are_stable() {
+        // both scenes are alive at once, so none of their objects share an address
+        let (a, b) = (scene(), scene());
+        let (a, b) = (a.render_aovs(), b.render_aovs());
+        for aov in [Aov::ObjectId, Aov::MaterialId] {
+            assert_eq!(a.layer(aov), b.layer(aov));
+        }
+        // numbered in the order the scene was built
+        let (cx, cy) = (WIDTH / 2, HEIGHT / 2);
+        for aov in [Aov::ObjectId, Aov::MaterialId] {
+            assert_eq!(pixel(&a, aov, cx, cy), id_color(1));
+            assert_eq!(pixel(&a, aov, cx, HEIGHT - 1), id_color(2));
+        }
+    }
+
+    #[test]
+    fn aov_test() -> ImageResult<()> {
+        let img = scene().render_aovs();
+        img.save_png("test_out/aov_test")?;
+        img.save_exr("test_out/aov_test")
+    }
+}
@@ -0,0 +1,760 @@
+use std::sync::Arc;
+
+use crate::{
+    objects::{
+        hit::{Hit, HitObject},
+        light::Light,
+    },
+    sampler::random_f32,
+    vec3::{ray::Ray, vec3::Vec3},
+};
+
+use super::Viewport;
+
+#[derive(Clone)]
+enum Kind<'a> {
+    Camera,
+    Light(Arc<dyn Light + Send + Sync>),
+    Surface {
+        h: Hit,
+        o: HitObject<'a>,
+        albedo: Vec3,
+    },
+}
+
+#[derive(Clone)]
+struct Vertex<'a> {
+    kind: Kind<'a>,
+    p: Vec3,
+    // geometric normal, zero for points without a surface
+    n: Vec3,
+    // throughput of the subpath up to this vertex
+    beta: Vec3,
+    // scattering is a delta distribution, the vertex can't be connected to
+    delta: bool,
+    // area densities of the vertex being sampled by its own subpath and by the opposite one
+    pdf_fwd: f32,
+    pdf_rev: f32,
+}
+
+struct Context<'a> {
+    vp: &'a Viewport,
+    bounds: (Vec3, f32),
+    // the scene lights followed by the glowing objects, light subpaths start on any of them
+    lights: Vec<Arc<dyn Light + Send + Sync>>,
+    // probability of choosing one of the lights
+    light_pick: f32,
+}
+
+impl Context<'_> {
+    fn pick_light(&self) -> Option<&Arc<dyn Light + Send + Sync>> {
+        let n = self.lights.len();
+        if n == 0 {
+            return None;
+        }
+        Some(&self.lights[((random_f32() * n as f32) as usize).min(n - 1)])
+    }
+}
+
+// Turns the solid angle density `pdf` of going from `from` towards `to` into an area density at `to`
+fn to_area(pdf: f32, from: &Vertex, to: &Vertex) -> f32 {
+    let d = to.p - from.p;
+    let dist2 = d.length2();
+    if dist2 <= 0.0 {
+        return 0.0;
+    }
+    if to.n.close_to_zero() {
+        pdf / dist2
+    } else {
+        pdf * to.n.dot(d).abs() / (dist2 * dist2.sqrt())
+    }
+}
+
+impl Vertex<'_> {
+    fn connectible(&self) -> bool {
+        match &self.kind {
+            Kind::Surface { .. } => !self.delta,
+            _ => true,
+        }
+    }
+
+    /// Bsdf times the cosine towards `next`, `h.r` is the direction the path arrived from
+    fn f(&self, next: &Vertex) -> Vec3 {
+        match &self.kind {
+            Kind::Surface { h, o, albedo } => {
+                let r = Ray::new_with_time(h.p, next.p - h.p, h.r.time);
+                *albedo * o.material_pdf(h, &r)
+            }
+            _ => Vec3::ZERO,
+        }
+    }
+
+    /// Area density at `next` of the path coming from `prev` continuing through this vertex to `next`
+    fn pdf(&self, ctx: &Context, prev: Option<&Vertex>, next: &Vertex) -> f32 {
+        match &self.kind {
+            Kind::Camera => to_area(ctx.vp.cam.pdf(next.p - self.p), self, next),
+            Kind::Light(l) => {
+                let dir = (next.p - self.p).unit();
+                let (pdf_pos, pdf_dir) = l.emission_pdf(self.p, dir, ctx.bounds);
+                if l.is_infinite() {
+                    if next.n.close_to_zero() {
+                        pdf_pos
+                    } else {
+                        pdf_pos * next.n.unit().dot(dir).abs()
+                    }
+                } else {
+                    to_area(pdf_dir, self, next)
+                }
+            }
+            Kind::Surface { h, o, .. } => {
+                let Some(prev) = prev else {
+                    return 0.0;
+                };
+                if self.delta {
+                    return 0.0;
+                }
+                let mut h = h.clone();
+                h.r = Ray::new_with_time(prev.p, self.p - prev.p, h.r.time);
+                let r = Ray::new_with_time(h.p, next.p - h.p, h.r.time);
+                to_area(o.generator_pdf(&h, &r), self, next)
+            }
+        }
+    }
+
+    /// Area density of a light path starting at this vertex, which has to be on a light
+    fn pdf_light_origin(&self, ctx: &Context, next: &Vertex) -> f32 {
+        match &self.kind {
+            Kind::Light(l) => {
+                ctx.light_pick
+                    * l.emission_pdf(self.p, (next.p - self.p).unit(), ctx.bounds)
+                        .0
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+// Density of the first vertex after `prev` for a ray sampled with density `pdf`
+fn fwd_density(pdf: f32, prev: &Vertex, next: &Vertex) -> f32 {
+    match &prev.kind {
+        Kind::Light(l) if l.is_infinite() => {
+            if next.n.close_to_zero() {
+                pdf
+            } else {
+                pdf * next.n.unit().dot((next.p - prev.p).unit()).abs()
+            }
+        }
+        _ => to_area(pdf, prev, next),
+    }
+}
+
+// Multiple importance sampling weight of the camera subpath `path` reaching `l` at `p`
+// on a ray with the throughput `beta` that was sampled with the solid angle density `pdf`
+fn light_hit_weight(
+    ctx: &Context,
+    path: &[Vertex],
+    l: &Arc<dyn Light + Send + Sync>,
+    p: Vec3,
+    beta: Vec3,
+    pdf: f32,
+) -> f32 {
+    let prev = &path[path.len() - 1];
+    let mut v = Vertex {
+        kind: Kind::Light(l.clone()),
+        p,
+        n: l.normal(p),
+        beta,
+        delta: false,
+        pdf_fwd: 0.0,
+        pdf_rev: 0.0,
+    };
+    v.pdf_fwd = if prev.delta {
+        0.0
+    } else {
+        fwd_density(pdf, prev, &v)
+    };
+    let mut camera = path.to_vec();
+    camera.push(v);
+    mis_weight(ctx, &[], &camera)
+}
+
+// Light from the scene lights and the environment seen by the camera subpath on the segment `r` leaving `path.last()`
+fn camera_emission(
+    ctx: &Context,
+    path: &[Vertex],
+    r: &Ray,
+    maxt: f32,
+    beta: Vec3,
+    pdf: f32,
+) -> Vec3 {
+    let first = path.len() == 1;
+    let mut light = Vec3::ZERO;
+    if maxt == f32::INFINITY {
+        light += beta * ctx.vp.background(r);
+    }
+    let length = r.direction.length();
+    for l in ctx.vp.s.lights.iter() {
+        let Some((t, radiance)) = l.intersect(r) else {
+            continue;
+        };
+        if t >= maxt * length || (first && !l.visible_to_camera()) {
+            continue;
+        }
+        let p = r.origin + r.direction * (t / length);
+        light += beta * radiance * light_hit_weight(ctx, path, l, p, beta, pdf);
+    }
+    light
+}
+
+// Light emitted by the object the camera subpath hit at `h`, weighted against connecting to it
+// when it's one of the glowing objects light subpaths start on
+fn object_emission(
+    ctx: &Context,
+    path: &[Vertex],
+    h: &Hit,
+    emitted: Vec3,
+    beta: Vec3,
+    pdf: f32,
+) -> Vec3 {
+    let length = h.r.direction.length();
+    let emitter = ctx.vp.s.emitters.iter().find(|l| {
+        l.intersect(&h.r)
+            .is_some_and(|(t, _)| (t - h.t * length).abs() <= 1e-3 * t.max(1.0))
+    });
+    match emitter {
+        Some(l) => beta * emitted * light_hit_weight(ctx, path, l, h.p, beta, pdf),
+        None => beta * emitted,
+    }
+}
+
+// Extends `path` by following `r`, `beta` and `pdf` are the throughput and solid angle density of `r`.
+// Returns the light the path sees directly when it's a camera subpath
+fn random_walk<'a>(
+    ctx: &Context<'a>,
+    mut r: Ray,
+    mut beta: Vec3,
+    mut pdf: f32,
+    max_vertices: usize,
+    path: &mut Vec<Vertex<'a>>,
+) -> Vec3 {
+    let camera = matches!(path[0].kind, Kind::Camera);
+    let depth = ctx.vp.recursion_depth;
+    let mut light = Vec3::ZERO;
+    loop {
+        let hit = ctx.vp.s.get_hit(r);
+        let bounces = path.len() - 1;
+        if camera && bounces <= depth {
+            let maxt = hit.as_ref().map_or(f32::INFINITY, |(h, _)| h.t);
+            light += camera_emission(ctx, path, &r, maxt, beta, pdf);
+        }
+        let Some((h, o)) = hit else {
+            break;
+        };
+        let color = o.color(&h);
+        if camera && bounces <= depth && !color.emmited.close_to_zero() {
+            light += object_emission(ctx, path, &h, color.emmited, beta, pdf);
+        }
+        let prev = path.len() - 1;
+        let mut v = Vertex {
+            p: h.p,
+            n: h.ng.unit(),
+            beta,
+            delta: o.is_specular(&h),
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            kind: Kind::Surface {
+                h: h.clone(),
+                o: o.clone(),
+                albedo: color.multiplied,
+            },
+        };
+        v.pdf_fwd = if path[prev].delta {
+            0.0
+        } else {
+            fwd_density(pdf, &path[prev], &v)
+        };
+        if path.len() + 1 >= max_vertices {
+            path.push(v);
+            break;
+        }
+
+        let reflect = o.reflect(&h);
+        let (throughput, pdf_rev) = if v.delta {
+            pdf = 0.0;
+            (color.multiplied, 0.0)
+        } else {
+            pdf = o.generator_pdf(&h, &reflect);
+            if pdf <= 0.0 {
+                path.push(v);
+                break;
+            }
+            // density of scattering back along the incoming ray when arriving from `reflect`
+            let mut back = h.clone();
+            back.r = Ray::new_with_time(h.p + reflect.direction, -reflect.direction, h.r.time);
+            let rev = o.generator_pdf(&back, &Ray::new_with_time(h.p, -h.r.direction, h.r.time));
+            (
+                color.multiplied * (o.material_pdf(&h, &reflect) / pdf),
+                to_area(rev, &v, &path[prev]),
+            )
+        };
+        path[prev].pdf_rev = pdf_rev;
+        path.push(v);
+        beta = beta * throughput;
+        if beta.close_to_zero() {
+            break;
+        }
+        r = reflect;
+    }
+    light
+}
+
+fn light_subpath<'a>(ctx: &Context<'a>, max_vertices: usize) -> Vec<Vertex<'a>> {
+    let Some(l) = ctx.pick_light() else {
+        return vec![];
+    };
+    let Some(e) = l.sample_emission(ctx.bounds) else {
+        return vec![];
+    };
+    if e.pdf_pos <= 0.0 || e.pdf_dir <= 0.0 || e.radiance.close_to_zero() {
+        return vec![];
+    }
+    let cos = if e.normal.close_to_zero() {
+        1.0
+    } else {
+        e.normal.dot(e.ray.direction).abs()
+    };
+    let mut path = vec![Vertex {
+        kind: Kind::Light(l.clone()),
+        p: e.ray.origin,
+        n: e.normal,
+        beta: e.radiance,
+        delta: false,
+        pdf_fwd: ctx.light_pick * e.pdf_pos,
+        pdf_rev: 0.0,
+    }];
+    let beta = e.radiance * (cos / (ctx.light_pick * e.pdf_pos * e.pdf_dir));
+    let pdf = if l.is_infinite() {
+        e.pdf_pos
+    } else {
+        e.pdf_dir
+    };
+    random_walk(ctx, e.ray, beta, pdf, max_vertices, &mut path);
+    path
+}
+
+// Multiple importance sampling weight of connecting the light subpath `light` to the camera subpath `camera`.
+// Uses the power heuristic over all strategies that could have created the same path
+fn mis_weight(ctx: &Context, light: &[Vertex], camera: &[Vertex]) -> f32 {
+    let (s, t) = (light.len(), camera.len());
+    if s + t == 2 {
+        return 1.0;
+    }
+    let qs = light.last();
+    let qs_minus = if s >= 2 { light.get(s - 2) } else { None };
+    let pt = &camera[t - 1];
+    let pt_minus = if t >= 2 { camera.get(t - 2) } else { None };
+
+    // densities of the vertices around the connection sampled by the opposite subpath
+    let pt_rev = match qs {
+        Some(qs) => qs.pdf(ctx, qs_minus, pt),
+        None => pt_minus.map_or(0.0, |pm| pt.pdf_light_origin(ctx, pm)),
+    };
+    let pt_minus_rev = pt_minus.map(|pm| match qs {
+        Some(qs) => pt.pdf(ctx, Some(qs), pm),
+        None => pt.pdf(ctx, None, pm),
+    });
+    let qs_rev = qs.map(|qs| pt.pdf(ctx, pt_minus, qs));
+    let qs_minus_rev = qs_minus.map(|qm| qs.unwrap().pdf(ctx, Some(pt), qm));
+
+    let remap = |f: f32| if f != 0.0 { f } else { 1.0 };
+    let pinhole = ctx.vp.cam.is_pinhole();
+    let mut sum = 0.0;
+
+    let mut ri = 1.0;
+    for i in (1..t).rev() {
+        let rev = if i == t - 1 {
+            pt_rev
+        } else if i == t - 2 {
+            pt_minus_rev.unwrap_or(0.0)
+        } else {
+            camera[i].pdf_rev
+        };
+        let ratio = remap(rev) / remap(camera[i].pdf_fwd);
+        ri *= ratio * ratio;
+        let delta = i != t - 1 && camera[i].delta;
+        // light tracing needs a pinhole camera to connect to
+        if !delta && !camera[i - 1].delta && (i != 1 || pinhole) {
+            sum += ri;
+        }
+    }
+
+    let mut ri = 1.0;
+    for i in (0..s).rev() {
+        let rev = if i == s - 1 {
+            qs_rev.unwrap_or(0.0)
+        } else if i == s - 2 {
+            qs_minus_rev.unwrap_or(0.0)
+        } else {
+            light[i].pdf_rev
+        };
+        let ratio = remap(rev) / remap(light[i].pdf_fwd);
+        ri *= ratio * ratio;
+        let delta = i != s - 1 && light[i].delta;
+        let delta_light = if i > 0 {
+            light[i - 1].delta
+        } else {
+            matches!(&light[0].kind, Kind::Light(l) if l.is_delta())
+        };
+        if !delta && !delta_light {
+            sum += ri;
+        }
+    }
+    1.0 / (1.0 + sum)
+}
+
+// Contribution of the camera subpath ending at `pt` connected to a freshly sampled point on a light
+fn connect_to_light(ctx: &Context, camera: &[Vertex]) -> Vec3 {
+    let pt = &camera[camera.len() - 1];
+    if !pt.connectible() {
+        return Vec3::ZERO;
+    }
+    let Some(l) = ctx.pick_light() else {
+        return Vec3::ZERO;
+    };
+    let Some(ls) = l.sample(pt.p) else {
+        return Vec3::ZERO;
+    };
+    if ls.pdf <= 0.0 || ls.radiance.close_to_zero() {
+        return Vec3::ZERO;
+    }
+    let dist = if l.is_infinite() {
+        2.0 * ctx.bounds.1 + (pt.p - ctx.bounds.0).length()
+    } else {
+        ls.dist
+    };
+    let p = pt.p + ls.dir * dist;
+    let mut v = Vertex {
+        kind: Kind::Light(l.clone()),
+        p,
+        n: l.normal(p),
+        beta: ls.radiance / (ls.pdf * ctx.light_pick),
+        delta: false,
+        pdf_fwd: 0.0,
+        pdf_rev: 0.0,
+    };
+    v.pdf_fwd = v.pdf_light_origin(ctx, pt);
+    let f = pt.f(&v);
+    if f.close_to_zero() {
+        return Vec3::ZERO;
+    }
+    let time = match &pt.kind {
+        Kind::Surface { h, .. } => h.r.time,
+        _ => 0.0,
+    };
+    if ctx
+        .vp
+        .s
+        .occluded(Ray::new_with_time(pt.p, ls.dir, time), ls.dist)
+    {
+        return Vec3::ZERO;
+    }
+    pt.beta * f * v.beta * mis_weight(ctx, &[v], camera)
+}
+
+// Contribution of the light subpath ending at `qs` seen by the camera, splatted onto the image
+fn connect_to_camera(ctx: &Context, light: &[Vertex], camera: &Vertex) {
+    let qs = &light[light.len() - 1];
+    if !qs.connectible() || !ctx.vp.cam.is_pinhole() {
+        return;
+    }
+    let to_camera = camera.p - qs.p;
+    let Some((x, y)) = ctx.vp.cam.raster(-to_camera, ctx.vp.width, ctx.vp.height) else {
+        return;
+    };
+    let f = qs.f(camera);
+    if f.close_to_zero() {
+        return;
+    }
+    let dist2 = to_camera.length2();
+    let time = match &qs.kind {
+        Kind::Surface { h, .. } => h.r.time,
+        _ => 0.0,
+    };
+    if ctx
+        .vp
+        .s
+        .occluded(Ray::new_with_time(qs.p, to_camera, time), dist2.sqrt())
+    {
+        return;
+    }
+    // importance of the camera times the cosine at the camera
+    let importance = ctx.vp.cam.pdf(-to_camera) / dist2;
+    let l = qs.beta * f * importance * mis_weight(ctx, light, std::slice::from_ref(camera));
+    ctx.vp.splat(x, y, l);
+}
+
+// Contribution of connecting the whole subpaths `light` and `camera`, both end on surfaces
+fn connect(ctx: &Context, light: &[Vertex], camera: &[Vertex]) -> Vec3 {
+    let qs = &light[light.len() - 1];
+    let pt = &camera[camera.len() - 1];
+    if !qs.connectible() || !pt.connectible() {
+        return Vec3::ZERO;
+    }
+    let f = qs.f(pt) * pt.f(qs);
+    if f.close_to_zero() {
+        return Vec3::ZERO;
+    }
+    let d = pt.p - qs.p;
+    let time = match &qs.kind {
+        Kind::Surface { h, .. } => h.r.time,
+        _ => 0.0,
+    };
+    if ctx
+        .vp
+        .s
+        .occluded(Ray::new_with_time(qs.p, d, time), d.length())
+    {
+        return Vec3::ZERO;
+    }
+    qs.beta * pt.beta * f * (mis_weight(ctx, light, camera) / d.length2())
+}
+
+/// Bidirectional path tracer. Light subpaths start on the scene lights and the glowing quads and spheres,
+/// and are connected to every vertex of the camera subpath.
+/// Connections to the camera are splatted onto the image, so light tracing only works with a pinhole camera.
+/// The environment and other emissive objects are only found by the camera subpath
+#[allow(unused)]
+pub(crate) fn bdpt_ray_color(r: Ray, vp: Arc<Viewport>, depth: usize) -> Vec3 {
+    let lights: Vec<_> =
+        vp.s.lights
+            .iter()
+            .chain(vp.s.emitters.iter())
+            .cloned()
+            .collect();
+    let ctx = Context {
+        vp: &vp,
+        bounds: vp.s.bounding_sphere(),
+        light_pick: 1.0 / lights.len().max(1) as f32,
+        lights,
+    };
+    let mut camera = vec![Vertex {
+        kind: Kind::Camera,
+        p: r.origin,
+        n: Vec3::ZERO,
+        beta: Vec3::WHITE,
+        delta: false,
+        pdf_fwd: 1.0,
+        pdf_rev: 0.0,
+    }];
+    let pdf = vp.cam.pdf(r.direction);
+    let mut light = random_walk(&ctx, r, Vec3::WHITE, pdf, depth + 2, &mut camera);
+    let light_path = light_subpath(&ctx, depth + 1);
+
+    for t in 1..=camera.len() {
+        for s in 0..=light_path.len() {
+            if s + t < 3 || s + t - 2 > depth {
+                continue;
+            }
+            light += match (s, t) {
+                // found by the camera subpath during the random walk
+                (0, _) => Vec3::ZERO,
+                (1, _) => connect_to_light(&ctx, &camera[..t]),
+                (_, 1) => {
+                    connect_to_camera(&ctx, &light_path[..s], &camera[0]);
+                    Vec3::ZERO
+                }
+                _ => connect(&ctx, &light_path[..s], &camera[..t]),
+            };
+        }
+    }
+    light
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use image::ImageResult;
+    use rand::random;
+
+    use crate::{
+        objects::{
+            instance::Instance,
+            light::{Light, PointLight, SphereLight},
+            material::{MirrorGlass, LAMBERTIAN},
+            quad::Quad,
+            sphere::Sphere,
+            texture::ConstColorTexture,
+        },
+        vec3::vec3::Vec3,
+        viewport::{
+            camera::Camera,
+            ray_color::next_event_ray_color,
+            ray_color::RayColor,
+            scene::Scene,
+            test_scenes::{
+                ceiling_light, mean_radiance, small_viewport, sphere_on_floor, HEIGHT, WIDTH,
+            },
+            Viewport,
+        },
+    };
+
+    use super::{bdpt_ray_color, light_subpath, Context, Kind};
+
+    fn diffuse_scene(rc: RayColor) -> Viewport {
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![
+            ceiling_light(),
+            Arc::new(PointLight::new(Vec3::new(1.0, 1.0, 2.0), Vec3::WHITE * 0.5)),
+        ];
+        small_viewport(sphere_on_floor(), lights, rc)
+    }
+
+    // lit only by a glowing quad above the sphere and a small glowing sphere next to it
+    fn emissive_scene(rc: RayColor) -> Viewport {
+        let glow = |emission: Vec3| Arc::new(ConstColorTexture::new(Vec3::ZERO, emission));
+        let mut objects = sphere_on_floor();
+        objects.extend([
+            Instance::new(Arc::new([Arc::new(Quad::new(
+                Vec3::new(-0.5, 1.5, 2.5),
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 1.0),
+                LAMBERTIAN.clone(),
+                Vec3::ZERO,
+                glow(Vec3::WHITE * 2.0),
+            ))])),
+            Instance::new(Arc::new([Arc::new(Sphere {
+                origin: Vec3::new(1.2, 0.0, 2.5),
+                radius: 0.3,
+                mat: LAMBERTIAN.clone(),
+                texture: glow(Vec3::new(2.0, 1.0, 0.5)),
+            })])),
+        ]);
+        small_viewport(objects, vec![], rc)
+    }
+
+    #[test]
+    fn camera_raster_matches_rays() {
+        let vp = diffuse_scene(Arc::new(bdpt_ray_color));
+        for _ in 0..100 {
+            let (x, y) = (random::<f32>(), random::<f32>());
+            let dir = vp.cam.ray(x, y).unwrap().0.direction;
+            let (px, py) = vp.cam.raster(dir * 3.0, WIDTH, HEIGHT).unwrap();
+            assert_eq!(px, (x * WIDTH as f32) as usize);
+            assert_eq!(py, (y * HEIGHT as f32) as usize);
+        }
+        let behind = -vp.cam.ray(0.0, 0.0).unwrap().0.direction;
+        assert!(vp.cam.raster(behind, WIDTH, HEIGHT).is_none());
+    }
+
+    #[test]
+    fn only_light_tracing_allocates_splats() {
+        let splats_after = |rc: RayColor| {
+            let mut vp = diffuse_scene(rc);
+            assert!(vp.splats.is_none());
+            vp.reset_splats();
+            let vp = Arc::new(vp);
+            for _ in 0..64 {
+                let (r, _) = vp.cam.ray(random::<f32>(), random::<f32>()).unwrap();
+                (vp.rc)(r, vp.clone(), vp.recursion_depth);
+            }
+            vp.splats.as_ref().unwrap().is_allocated()
+        };
+        assert!(!splats_after(Arc::new(next_event_ray_color)));
+        assert!(splats_after(Arc::new(bdpt_ray_color)));
+    }
+
+    #[test]
+    fn bdpt_matches_next_event() {
+        let reference = mean_radiance(diffuse_scene(Arc::new(next_event_ray_color)), 16);
+        let bdpt = mean_radiance(diffuse_scene(Arc::new(bdpt_ray_color)), 16);
+        for (a, b) in [
+            (reference.x, bdpt.x),
+            (reference.y, bdpt.y),
+            (reference.z, bdpt.z),
+        ] {
+            assert!((a - b).abs() <= 0.1 * a, "{:?} != {:?}", reference, bdpt);
+        }
+    }
+
+    #[test]
+    fn light_subpaths_start_on_emitters() {
+        let vp = emissive_scene(Arc::new(bdpt_ray_color));
+        assert_eq!(vp.s.emitters.len(), 2);
+        let ctx = Context {
+            vp: &vp,
+            bounds: vp.s.bounding_sphere(),
+            lights: vp.s.emitters.clone(),
+            light_pick: 0.5,
+        };
+        for _ in 0..100 {
+            let path = light_subpath(&ctx, 3);
+            let Kind::Light(l) = &path[0].kind else {
+                panic!("light subpath starting on a surface");
+            };
+            let (pdf_pos, _) = l.emission_pdf(path[0].p, Vec3::UP, ctx.bounds);
+            assert!((path[0].pdf_fwd - 0.5 * pdf_pos).abs() <= 1e-5 * pdf_pos);
+        }
+    }
+
+    #[test]
+    fn bdpt_matches_path_tracing_with_emitters() {
+        let reference = mean_radiance(emissive_scene(Arc::new(next_event_ray_color)), 32);
+        let bdpt = mean_radiance(emissive_scene(Arc::new(bdpt_ray_color)), 16);
+        for (a, b) in [
+            (reference.x, bdpt.x),
+            (reference.y, bdpt.y),
+            (reference.z, bdpt.z),
+        ] {
+            assert!((a - b).abs() <= 0.1 * a, "{:?} != {:?}", reference, bdpt);
+        }
+    }
+
+    #[test]
+    fn bdpt_caustic_test() -> ImageResult<()> {
+        const WIDTH: usize = 300;
+        const HEIGHT: usize = 200;
+        let grey = Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.7, Vec3::ZERO));
+        let objects = vec![
+            Instance::new(Arc::new([Arc::new(Sphere {
+                origin: Vec3::new(0.0, 0.0, 3.0),
+                radius: 0.5,
+                mat: Arc::new(MirrorGlass { ir: 1.5 }),
+                texture: Arc::new(ConstColorTexture::new(Vec3::WHITE, Vec3::ZERO)),
+            })])),
+            Instance::new(Arc::new([Arc::new(Quad::new(
+                Vec3::new(-5.0, -0.5, -2.0),
+                Vec3::new(10.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 10.0),
+                LAMBERTIAN.clone(),
+                Vec3::ZERO,
+                grey,
+            ))])),
+        ];
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![Arc::new(SphereLight::new(
+            Vec3::new(-0.5, 1.5, 3.5),
+            0.1,
+            Vec3::WHITE * 100.0,
+        ))];
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::ZERO,
+            Vec3::UP,
+            Vec3::FORWARD,
+            60.0,
+            0.0,
+        );
+        let vp = Viewport::new(
+            cam,
+            Scene::new(objects, 0.001, 1000.0).with_lights(lights),
+            Arc::new(bdpt_ray_color),
+            WIDTH,
+            HEIGHT,
+            16,
+            5,
+            Vec3::ZERO,
+            2.0,
+        );
+        vp.render_rows_async()
+            .save("test_out/bdpt_caustic_test.png")
+    }
+}
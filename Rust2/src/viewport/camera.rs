@@ -1,5 +1,73 @@
+use std::{f32::consts::PI, sync::Arc};
+
 use crate::onb::ONB;
-use crate::vec3::vec3::Vec3;
+use crate::sampler::random_f32;
+use crate::vec3::{ray::Ray, vec3::Vec3};
+
+use super::lens::LensSystem;
+
+// height of 35mm film in meters, focal lengths and f-stops are relative to it
+const SENSOR_HEIGHT: f32 = 0.024;
+
+/// Maps points of the image to camera rays, `Viewport` renders through any projection
+pub trait Projection {
+    /// Ray through the point (`u`, `v`) of the image, both in [0, 1] from the top left corner,
+    /// and the weight of the light it brings back. `None` when nothing is seen through that point
+    fn ray(&self, u: f32, v: f32) -> Option<(Ray, f32)>;
+    /// Angle covered by the height of the image, 0 for parallel rays
+    fn spread(&self) -> f32;
+    /// Whether all rays start at a single point that light paths can be connected to
+    fn is_pinhole(&self) -> bool {
+        false
+    }
+    /// Solid angle density of camera rays going along `dir`, 0 outside of the image
+    fn pdf(&self, _dir: Vec3) -> f32 {
+        0.0
+    }
+    /// Pixel the direction `dir` from the camera origin passes through
+    fn raster(&self, _dir: Vec3, _width: usize, _height: usize) -> Option<(usize, usize)> {
+        None
+    }
+}
+
+/// Frame of a camera looking along `dir`, `u` points right and `v` up in the image
+pub(super) fn look_at(vup: Vec3, dir: Vec3) -> ONB {
+    let w = -dir;
+    let u = vup.cross(w).unit();
+    let v = w.cross(u);
+    ONB { u, v, w }
+}
+
+/// Shape of the lens opening, out of focus highlights take its shape
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aperture {
+    Circle,
+    // regular polygon with `blades` corners, the first one `rotation` radians from the right
+    Polygon { blades: usize, rotation: f32 },
+}
+
+impl Aperture {
+    // uniform point of the aperture inscribed in the unit circle
+    fn sample(&self) -> (f32, f32) {
+        match *self {
+            Aperture::Circle => {
+                let p = Vec3::random_in_unit_disk();
+                (p.x, p.y)
+            }
+            Aperture::Polygon { blades, rotation } => {
+                // the polygon is a fan of equal triangles around the center
+                let wedge = ((random_f32() * blades as f32) as usize).min(blades - 1);
+                let a0 = rotation + 2.0 * PI * wedge as f32 / blades as f32;
+                let a1 = a0 + 2.0 * PI / blades as f32;
+                let (mut s, mut t) = (random_f32(), random_f32());
+                if s + t > 1.0 {
+                    (s, t) = (1.0 - s, 1.0 - t);
+                }
+                (a0.cos() * s + a1.cos() * t, a0.sin() * s + a1.sin() * t)
+            }
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Camera {
@@ -13,6 +81,11 @@ pub struct Camera {
     pub delta_x: Vec3,
     pub delta_y: Vec3,
     pub(super) lens_radius: f32,
+    // distance from the lens to the plane in focus
+    pub(super) focus_dist: f32,
+    aperture: Aperture,
+    // traced lens replacing the thin lens, the camera origin is the center of its film
+    lens: Option<Arc<LensSystem>>,
 }
 
 impl Camera {
@@ -24,31 +97,337 @@ impl Camera {
         vfov: f32,
         lens_radius: f32,
     ) -> Self {
-        let w = -dir;
-        let u = vup.cross(w).unit();
-        let v = w.cross(u);
-        let onb = ONB { u, v, w };
+        let onb = look_at(vup, dir);
         let h = (vfov * std::f32::consts::PI / 360.0).tan();
-        let viewport_height = 2.0 * h;
-        let viewport_width = aspect * viewport_height;
-
-        let viewport_u = u * viewport_width;
-        let viewport_v = -v * viewport_height;
-
-        let pixel_delta_x = viewport_u;
-        let pixel_delta_y = viewport_v;
-
-        Self {
+        let mut cam = Self {
             v_up: vup,
-            left_top: -w - viewport_u / 2.0 - viewport_v / 2.0,
+            left_top: Vec3::ZERO,
             origin: origin,
             onb: onb,
-            width: viewport_width,
-            height: viewport_height,
+            width: 0.0,
+            height: 0.0,
             aspect_ratio: aspect,
-            delta_x: pixel_delta_x,
-            delta_y: pixel_delta_y,
+            delta_x: Vec3::ZERO,
+            delta_y: Vec3::ZERO,
             lens_radius,
+            focus_dist: 1.0,
+            aperture: Aperture::Circle,
+            lens: None,
+        };
+        cam.set_viewport_height(2.0 * h);
+        cam
+    }
+
+    // image plane at distance 1 in front of the camera
+    fn set_viewport_height(&mut self, viewport_height: f32) {
+        let viewport_width = self.aspect_ratio * viewport_height;
+
+        let viewport_u = self.onb.u * viewport_width;
+        let viewport_v = -self.onb.v * viewport_height;
+
+        self.left_top = -self.onb.w - viewport_u / 2.0 - viewport_v / 2.0;
+        self.width = viewport_width;
+        self.height = viewport_height;
+        self.delta_x = viewport_u;
+        self.delta_y = viewport_v;
+    }
+
+    /// Distance from the lens to the plane that is in focus
+    pub fn with_focus_distance(mut self, distance: f32) -> Self {
+        self.focus_dist = distance;
+        if let Some(lens) = &self.lens {
+            let mut lens = lens.as_ref().clone();
+            lens.focus(distance);
+            self.lens = Some(Arc::new(lens));
+        }
+        self
+    }
+    pub fn focus_distance(&self) -> f32 {
+        self.focus_dist
+    }
+    pub fn with_aperture_radius(mut self, radius: f32) -> Self {
+        self.lens_radius = radius;
+        self
+    }
+    /// Field of view of a lens with focal length `mm` on 35mm film
+    pub fn with_focal_length(mut self, mm: f32) -> Self {
+        self.set_viewport_height(SENSOR_HEIGHT / (mm * 0.001));
+        self
+    }
+    /// Focal length in millimeters of a lens with the field of view of the camera on 35mm film
+    pub fn focal_length(&self) -> f32 {
+        SENSOR_HEIGHT / self.height * 1000.0
+    }
+    /// Opens the aperture to the focal length over `f_number`, scenes are in meters
+    pub fn with_f_stop(mut self, f_number: f32) -> Self {
+        self.lens_radius = self.focal_length() * 0.001 / (2.0 * f_number);
+        self
+    }
+    /// Polygonal aperture with `blades` sides rotated by `rotation` radians, fewer than 3 is round
+    pub fn with_aperture_blades(mut self, blades: usize, rotation: f32) -> Self {
+        self.aperture = if blades < 3 {
+            Aperture::Circle
+        } else {
+            Aperture::Polygon { blades, rotation }
+        };
+        self
+    }
+    /// Traces rays through `lens` instead of the thin lens, focused at the focus distance.
+    /// The field of view follows from the lens and its sensor
+    pub fn with_lens_system(mut self, mut lens: LensSystem) -> Self {
+        lens.focus(self.focus_dist);
+        let focal_length = lens.focal_length();
+        if focal_length.is_finite() {
+            self.set_viewport_height(lens.sensor_height / focal_length);
+        }
+        self.lens = Some(Arc::new(lens));
+        self
+    }
+
+    /// The camera moved `offset` to the right, for one eye of a stereo pair. The image plane
+    /// shifts so objects `convergence` away stay in the same place on both images,
+    /// which keeps the views parallel instead of toeing them in
+    pub fn eye(&self, offset: f32, convergence: f32) -> Self {
+        let mut eye = self.clone();
+        eye.origin += self.onb.u * offset;
+        eye.left_top -= self.onb.u * (offset / convergence);
+        eye
+    }
+
+    // direction through the middle of the image, its length is the distance to the image plane
+    fn forward(&self) -> Vec3 {
+        self.left_top + (self.delta_x + self.delta_y) * 0.5
+    }
+}
+
+impl Projection for Camera {
+    fn is_pinhole(&self) -> bool {
+        self.lens_radius == 0.0 && self.lens.is_none()
+    }
+
+    // `None` when the lens blocks the ray
+    fn ray(&self, u: f32, v: f32) -> Option<(Ray, f32)> {
+        if let Some(lens) = &self.lens {
+            // the lens flips the image on the film
+            let film = Vec3::new(
+                (0.5 - u) * lens.sensor_height * self.aspect_ratio,
+                (v - 0.5) * lens.sensor_height,
+                0.0,
+            );
+            let (p, d, weight) = lens.sample_ray(film)?;
+            let to_world = |c: Vec3| self.onb.u * c.x + self.onb.v * c.y - self.onb.w * c.z;
+            return Some((Ray::new(self.origin + to_world(p), to_world(d)), weight));
+        }
+
+        let dir = self.left_top + self.delta_x * u + self.delta_y * v;
+        if self.lens_radius == 0.0 {
+            return Some((Ray::new(self.origin, dir), 1.0));
+        }
+        let (x, y) = self.aperture.sample();
+        let offset = (self.onb.u * x + self.onb.v * y) * self.lens_radius;
+        // every ray through the lens meets the pinhole ray on the plane in focus
+        Some((
+            Ray::new(self.origin + offset, dir * self.focus_dist - offset),
+            1.0,
+        ))
+    }
+
+    fn spread(&self) -> f32 {
+        self.delta_y.length()
+    }
+
+    fn pdf(&self, dir: Vec3) -> f32 {
+        if self.raster(dir, 1, 1).is_none() {
+            return 0.0;
+        }
+        let forward = self.forward();
+        let cos = dir.unit().dot(forward.unit());
+        let area = self.delta_x.length() * self.delta_y.length();
+        forward.length2() / (area * cos * cos * cos)
+    }
+
+    fn raster(&self, dir: Vec3, width: usize, height: usize) -> Option<(usize, usize)> {
+        let forward = self.forward();
+        let cos = dir.dot(forward);
+        if cos <= 0.0 {
+            return None;
+        }
+        let on_plane = dir * (forward.length2() / cos) - self.left_top;
+        let x = on_plane.dot(self.delta_x) / self.delta_x.length2();
+        let y = on_plane.dot(self.delta_y) / self.delta_y.length2();
+        if !(0.0..1.0).contains(&x) || !(0.0..1.0).contains(&y) {
+            return None;
+        }
+        Some(((x * width as f32) as usize, (y * height as f32) as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{f32::consts::PI, sync::Arc};
+
+    use image::ImageResult;
+
+    use crate::{
+        objects::{
+            instance::Instance,
+            light::{Light, RectLight},
+            material::LAMBERTIAN,
+            sphere::Sphere,
+            texture::ConstColorTexture,
+        },
+        vec3::vec3::Vec3,
+        viewport::{lens::LensSystem, ray_color::next_event_ray_color, scene::Scene, Viewport},
+    };
+
+    use super::{Aperture, Camera, Projection};
+
+    const WIDTH: usize = 120;
+    const HEIGHT: usize = 80;
+
+    fn camera() -> Camera {
+        Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::UP,
+            Vec3::FORWARD,
+            40.0,
+            0.0,
+        )
+    }
+
+    #[test]
+    fn thin_lens_focus_plane() {
+        let cam = camera()
+            .with_aperture_radius(0.1)
+            .with_focus_distance(2.5)
+            .with_aperture_blades(5, 0.2);
+        let (pinhole, _) = camera().ray(0.3, 0.7).unwrap();
+        let on_focus_plane = pinhole.at(2.5);
+        for _ in 0..100 {
+            let (r, weight) = cam.ray(0.3, 0.7).unwrap();
+            assert_eq!(weight, 1.0);
+            assert!((r.origin - cam.origin).length() <= 0.1 + 1e-5);
+            assert_eq!(r.at(1.0), on_focus_plane);
+        }
+    }
+
+    #[test]
+    fn polygon_aperture_samples() {
+        let blades = 6;
+        let rotation = 0.3;
+        let aperture = Aperture::Polygon { blades, rotation };
+        let corner = |k: usize| {
+            let a = rotation + 2.0 * PI * k as f32 / blades as f32;
+            (a.cos(), a.sin())
+        };
+        for _ in 0..1000 {
+            let (x, y) = aperture.sample();
+            for k in 0..blades {
+                let (ax, ay) = corner(k);
+                let (bx, by) = corner(k + 1);
+                // inside of every edge going around counter clockwise
+                let cross = (bx - ax) * (y - ay) - (by - ay) * (x - ax);
+                assert!(cross >= -1e-5, "({}, {}) outside of edge {}", x, y, k);
+            }
+        }
+    }
+
+    #[test]
+    fn focal_length_and_f_stop() {
+        let cam = camera().with_focal_length(50.0).with_f_stop(2.0);
+        assert!((cam.focal_length() - 50.0).abs() < 1e-3);
+        assert!((cam.lens_radius - 0.0125).abs() < 1e-6);
+        // 50mm on 35mm film is about 27 degrees of vertical field of view
+        let vfov = 2.0 * (cam.height / 2.0).atan() * 180.0 / PI;
+        assert!((vfov - 27.0).abs() < 0.1, "{}", vfov);
+        assert!(!cam.is_pinhole());
+        assert!(camera().is_pinhole());
+    }
+
+    #[test]
+    fn lens_system_rays() {
+        let cam = camera()
+            .with_focus_distance(2.0)
+            .with_lens_system(LensSystem::double_gauss_50mm());
+        assert!((cam.focal_length() - 50.0).abs() < 3.0);
+        // rays from the middle of the film meet again in front of the camera at the focus distance
+        let mut hits = 0;
+        for _ in 0..200 {
+            if let Some((r, _)) = cam.ray(0.5, 0.5) {
+                let t =
+                    (2.0 + cam.lens.as_ref().unwrap().film_distance() - r.origin.z) / r.direction.z;
+                let p = r.at(t);
+                assert!(p.x.abs() < 0.02 && p.y.abs() < 0.02, "{:?}", p);
+                hits += 1;
+            }
+        }
+        assert!(hits > 50);
+        assert!(!cam.is_pinhole());
+    }
+
+    // small lights far behind the focus plane turn into the shape of the aperture
+    fn bokeh_scene(cam: Camera) -> Viewport {
+        let mut objects = vec![Instance::new(Arc::new([Arc::new(Sphere {
+            origin: Vec3::new(0.0, -0.1, 1.5),
+            radius: 0.1,
+            mat: LAMBERTIAN.clone(),
+            texture: Arc::new(ConstColorTexture::new(Vec3::new(0.8, 0.3, 0.2), Vec3::ZERO)),
+        })]))];
+        for i in 0..5 {
+            for j in 0..3 {
+                objects.push(Instance::new(Arc::new([Arc::new(Sphere {
+                    origin: Vec3::new(-1.6 + 0.8 * i as f32, -0.8 + 0.8 * j as f32, 10.0),
+                    radius: 0.03,
+                    mat: LAMBERTIAN.clone(),
+                    texture: Arc::new(ConstColorTexture::new(
+                        Vec3::ZERO,
+                        Vec3::new(60.0, 50.0, 30.0),
+                    )),
+                })])));
+            }
         }
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![Arc::new(
+            RectLight::new(
+                Vec3::new(-0.5, 1.5, 1.0),
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 1.0),
+                Vec3::WHITE * 3.0,
+            )
+            .with_visibility(false),
+        )];
+        Viewport::new(
+            cam,
+            Scene::new(objects, 0.001, 1000.0).with_lights(lights),
+            Arc::new(next_event_ray_color),
+            WIDTH,
+            HEIGHT,
+            64,
+            4,
+            Vec3::ZERO,
+            2.2,
+        )
+    }
+
+    #[test]
+    fn camera_bokeh_test() -> ImageResult<()> {
+        let cam = camera()
+            .with_focal_length(85.0)
+            .with_f_stop(1.2)
+            .with_focus_distance(1.5)
+            .with_aperture_blades(6, 0.3);
+        bokeh_scene(cam)
+            .render()
+            .save("test_out/camera_bokeh_test.png")
+    }
+
+    #[test]
+    fn camera_lens_system_test() -> ImageResult<()> {
+        let cam = camera()
+            .with_focus_distance(1.5)
+            .with_lens_system(LensSystem::double_gauss_50mm());
+        bokeh_scene(cam)
+            .render()
+            .save("test_out/camera_lens_system_test.png")
     }
 }
@@ -0,0 +1,333 @@
+use std::f32::consts::PI;
+
+use image::ImageReader;
+
+use crate::{
+    quaternions::{Quaternion, ZERO_ROTATION},
+    rotation::Rotation,
+    sampler::random_f32,
+    vec3::vec3::Vec3,
+};
+
+/// Light arriving from infinitely far away, seen by rays that escape the scene
+pub trait Environment {
+    /// Radiance arriving from direction `dir`
+    fn radiance(&self, dir: Vec3) -> Vec3;
+    /// Solid angle density of `sample` generating `dir`
+    fn pdf(&self, dir: Vec3) -> f32;
+    /// Returns a unit direction and its solid angle density
+    fn sample(&self) -> (Vec3, f32);
+}
+
+pub(crate) fn luminance(c: Vec3) -> f32 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+// index of the first element of `cdf` greater than `x`, minus one
+fn find_interval(cdf: &[f32], x: f32) -> usize {
+    let i = cdf.partition_point(|&c| c <= x);
+    i.saturating_sub(1).min(cdf.len() - 2)
+}
+
+/// Lat-long (equirectangular) environment map, `UP` is the top row of the image.
+/// Importance sampled from the luminance of the pixels weighted by their solid angle.
+#[derive(Clone, Debug)]
+pub struct EnvironmentMap {
+    img: Vec<Vec3>,
+    width: usize,
+    height: usize,
+    rotation: Quaternion,
+    pub intensity: f32,
+
+    // sampling distribution over the image, both cdfs start with 0 and end with 1
+    marginal_cdf: Vec<f32>,
+    conditional_cdf: Vec<Vec<f32>>,
+    // density of each pixel in image space, integrates to 1 over [0, 1]^2
+    pixel_pdf: Vec<f32>,
+}
+
+impl EnvironmentMap {
+    pub fn new(img: Vec<Vec3>, width: usize, height: usize) -> Self {
+        assert_eq!(img.len(), width * height, "image size does not match");
+        let lum = |x: isize, y: isize| {
+            let x = x.rem_euclid(width as isize) as usize;
+            let y = y.clamp(0, height as isize - 1) as usize;
+            luminance(img[y * width + x]).max(0.0)
+        };
+        let mut weights = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+            for x in 0..width {
+                // bilinear lookups blend in the neighbours, so the brightest of them is used,
+                // otherwise texels next to a small bright sun get almost no samples
+                let mut l = 0.0f32;
+                for dy in -1..=1 {
+                    for dx in -1..=1 {
+                        l = l.max(lum(x as isize + dx, y as isize + dy));
+                    }
+                }
+                // keep a small floor so every direction can be sampled
+                weights.push(l * sin_theta + 1e-6);
+            }
+        }
+        let total: f32 = weights.iter().sum();
+
+        let mut conditional_cdf = Vec::with_capacity(height);
+        let mut marginal_cdf = Vec::with_capacity(height + 1);
+        marginal_cdf.push(0.0);
+        for y in 0..height {
+            let row = &weights[y * width..(y + 1) * width];
+            let row_sum: f32 = row.iter().sum();
+            let mut cdf = Vec::with_capacity(width + 1);
+            let mut acc = 0.0;
+            cdf.push(0.0);
+            for w in row {
+                acc += w;
+                cdf.push(acc / row_sum);
+            }
+            conditional_cdf.push(cdf);
+            marginal_cdf.push(marginal_cdf[y] + row_sum / total);
+        }
+        let pixel_pdf = weights
+            .iter()
+            .map(|w| w / total * (width * height) as f32)
+            .collect();
+
+        Self {
+            img,
+            width,
+            height,
+            rotation: ZERO_ROTATION,
+            intensity: 1.0,
+            marginal_cdf,
+            conditional_cdf,
+            pixel_pdf,
+        }
+    }
+
+    /// Loads any format supported by `image`, including Radiance `.hdr` and OpenEXR
+    pub fn from_path(path: &str) -> image::ImageResult<Self> {
+        let img = ImageReader::open(path)?.decode()?.into_rgb32f();
+        let (w, h) = img.dimensions();
+        Ok(Self::new(
+            img.pixels().map(Vec3::from_rgb_ref).collect(),
+            w as usize,
+            h as usize,
+        ))
+    }
+
+    pub fn with_rotation(mut self, rot: impl Rotation) -> Self {
+        self.rotation = rot.into();
+        self
+    }
+    pub fn with_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    fn to_uv(&self, dir: Vec3) -> (f32, f32) {
+        let d = self.rotation.conjugate().rotate(&dir).unit();
+        let u = (f32::atan2(d.z, d.x) + PI) * 0.5 / PI;
+        let v = d.y.clamp(-1.0, 1.0).acos() / PI;
+        (u, v)
+    }
+    fn dir_at_uv(&self, u: f32, v: f32) -> Vec3 {
+        let phi = u * 2.0 * PI - PI;
+        let theta = v * PI;
+        let d = Vec3::new(
+            theta.sin() * phi.cos(),
+            theta.cos(),
+            theta.sin() * phi.sin(),
+        );
+        self.rotation.rotate(&d)
+    }
+
+    fn texel(&self, x: isize, y: isize) -> Vec3 {
+        let x = x.rem_euclid(self.width as isize) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.img[y * self.width + x]
+    }
+}
+
+impl Environment for EnvironmentMap {
+    fn radiance(&self, dir: Vec3) -> Vec3 {
+        let (u, v) = self.to_uv(dir);
+        let x = u * self.width as f32 - 0.5;
+        let y = v * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (dx, dy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+        (self.texel(x0, y0) * ((1.0 - dx) * (1.0 - dy))
+            + self.texel(x0 + 1, y0) * (dx * (1.0 - dy))
+            + self.texel(x0, y0 + 1) * ((1.0 - dx) * dy)
+            + self.texel(x0 + 1, y0 + 1) * (dx * dy))
+            * self.intensity
+    }
+
+    fn pdf(&self, dir: Vec3) -> f32 {
+        let (u, v) = self.to_uv(dir);
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+        self.pixel_pdf[y * self.width + x] / (2.0 * PI * PI * sin_theta)
+    }
+
+    // direction chosen proportionally to the brightness of the map and its solid angle density
+    fn sample(&self) -> (Vec3, f32) {
+        let y = find_interval(&self.marginal_cdf, random_f32());
+        let cdf = &self.conditional_cdf[y];
+        let x = find_interval(cdf, random_f32());
+
+        let u = (x as f32 + random_f32()) / self.width as f32;
+        let v = (y as f32 + random_f32()) / self.height as f32;
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 {
+            return (self.dir_at_uv(u, v), 0.0);
+        }
+        let pdf = self.pixel_pdf[y * self.width + x] / (2.0 * PI * PI * sin_theta);
+        (self.dir_at_uv(u, v), pdf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{f32::consts::PI, sync::Arc};
+
+    use image::ImageResult;
+
+    use crate::{
+        objects::{
+            instance::Instance, material::LAMBERTIAN, quad::Quad, sphere::Sphere,
+            texture::ConstColorTexture,
+        },
+        quaternions::Quaternion,
+        vec3::vec3::Vec3,
+        viewport::{
+            camera::Camera,
+            ray_color::{next_event_ray_color, ray_color},
+            scene::Scene,
+            Viewport,
+        },
+    };
+
+    use super::{Environment, EnvironmentMap};
+
+    fn sun_sky(width: usize, height: usize) -> EnvironmentMap {
+        let mut img = vec![];
+        for y in 0..height {
+            for x in 0..width {
+                let sun = (x as isize - width as isize / 4).abs() <= 1
+                    && (y as isize - height as isize / 4).abs() <= 1;
+                img.push(if sun {
+                    Vec3::new(200.0, 180.0, 150.0)
+                } else if y < height / 2 {
+                    Vec3::new(0.3, 0.5, 0.9)
+                } else {
+                    Vec3::new(0.2, 0.18, 0.15)
+                });
+            }
+        }
+        EnvironmentMap::new(img, width, height)
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        let env = sun_sky(64, 32).with_rotation(Quaternion::new_from_axis(0.7, Vec3::UP));
+        const N: usize = 200_000;
+        let mut sum = 0.0;
+        for _ in 0..N {
+            sum += env.pdf(Vec3::random_unit_vec()) * 4.0 * PI;
+        }
+        let integral = sum / N as f32;
+        assert!((integral - 1.0).abs() < 0.05, "integral: {}", integral);
+    }
+
+    #[test]
+    fn sample_matches_pdf() {
+        let env = sun_sky(64, 32).with_rotation(Quaternion::new_from_axis(1.3, Vec3::LEFT));
+        for _ in 0..1000 {
+            let (dir, pdf) = env.sample();
+            assert!((dir.length() - 1.0).abs() < 1e-4);
+            let expected = env.pdf(dir);
+            assert!(
+                (pdf - expected).abs() <= 1e-3 * expected.max(1.0),
+                "{} != {}",
+                pdf,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn radiance_round_trip() {
+        let env = sun_sky(64, 32).with_rotation(Quaternion::new_from_axis(0.4, Vec3::UP));
+        let dir = env.dir_at_uv(0.25 + 0.5 / 64.0, 0.25 + 0.5 / 32.0);
+        assert!(env.radiance(dir).x > 100.0);
+    }
+
+    #[test]
+    fn environment_light_test() -> ImageResult<()> {
+        const WIDTH: usize = 300;
+        const HEIGHT: usize = 200;
+        let grey = Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.7, Vec3::ZERO));
+        let scene = Scene::new(
+            vec![
+                Instance::new(Arc::new([Arc::new(Sphere {
+                    origin: Vec3::new(0.0, 0.0, 3.0),
+                    radius: 0.7,
+                    mat: LAMBERTIAN.clone(),
+                    texture: grey.clone(),
+                })])),
+                Instance::new(Arc::new([Arc::new(Quad::new(
+                    Vec3::new(-5.0, -0.7, -2.0),
+                    Vec3::new(10.0, 0.0, 0.0),
+                    Vec3::new(0.0, 0.0, 10.0),
+                    LAMBERTIAN.clone(),
+                    Vec3::ZERO,
+                    grey,
+                ))])),
+            ],
+            0.001,
+            1000.0,
+        );
+        let env = Arc::new(
+            sun_sky(256, 128)
+                .with_rotation(Quaternion::new_from_axis(PI * 0.5, Vec3::UP))
+                .with_intensity(0.8),
+        );
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::ZERO,
+            Vec3::UP,
+            Vec3::FORWARD,
+            60.0,
+            0.0,
+        );
+        for (name, rc) in [
+            (
+                "ray_color",
+                Arc::new(ray_color) as super::super::ray_color::RayColor,
+            ),
+            ("next_event", Arc::new(next_event_ray_color)),
+        ] {
+            let vp = Viewport::new(
+                cam.clone(),
+                scene.clone(),
+                rc,
+                WIDTH,
+                HEIGHT,
+                16,
+                5,
+                Vec3::ZERO,
+                2.0,
+            )
+            .with_environment(env.clone());
+            vp.render_rows_async()
+                .save(format!("test_out/environment_{}.png", name))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    OnceLock,
+};
+
+use crate::vec3::vec3::Vec3;
+
+/// Light added to any pixel of the image while rendering.
+/// Light tracing connects paths to the camera through pixels other than the one being rendered, so they are splatted here.
+pub(crate) struct SplatBuffer {
+    width: usize,
+    height: usize,
+    // f32 bits of the channels, updated with compare and swap so rows can be rendered in parallel.
+    // Allocated by the first splat, integrators that don't splat never pay for it
+    pixels: OnceLock<Vec<[AtomicU32; 3]>>,
+}
+
+impl SplatBuffer {
+    pub(crate) fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: OnceLock::new(),
+        }
+    }
+
+    pub(crate) fn is_allocated(&self) -> bool {
+        self.pixels.get().is_some()
+    }
+
+    fn add_f32(a: &AtomicU32, v: f32) {
+        let _ = a.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f32::from_bits(bits) + v).to_bits())
+        });
+    }
+
+    pub(crate) fn add(&self, x: usize, y: usize, c: Vec3) {
+        if x >= self.width || y >= self.height || !c.is_normal() {
+            return;
+        }
+        let pixels = self.pixels.get_or_init(|| {
+            (0..self.width * self.height)
+                .map(|_| [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)])
+                .collect()
+        });
+        let pix = &pixels[y * self.width + x];
+        Self::add_f32(&pix[0], c.x);
+        Self::add_f32(&pix[1], c.y);
+        Self::add_f32(&pix[2], c.z);
+    }
+
+    pub(crate) fn get(&self, x: usize, y: usize) -> Vec3 {
+        let Some(pixels) = self.pixels.get() else {
+            return Vec3::ZERO;
+        };
+        let pix = &pixels[y * self.width + x];
+        Vec3::new(
+            f32::from_bits(pix[0].load(Ordering::Relaxed)),
+            f32::from_bits(pix[1].load(Ordering::Relaxed)),
+            f32::from_bits(pix[2].load(Ordering::Relaxed)),
+        )
+    }
+}
@@ -0,0 +1,330 @@
+use std::f32::consts::PI;
+
+use crate::{sampler::random_f32, vec3::vec3::Vec3};
+
+// prescriptions are written in millimeters, scenes are in meters
+const MM: f32 = 0.001;
+
+/// One surface of a lens prescription, listed from the front of the lens to the film
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LensElement {
+    /// Radius of curvature, positive when the center is on the film side, 0 for the aperture stop
+    pub radius: f32,
+    /// Distance to the next surface along the axis
+    pub thickness: f32,
+    /// Index of refraction of the glass behind the surface, 0 or 1 for air
+    pub ior: f32,
+    /// Diameter of the surface
+    pub aperture: f32,
+}
+
+impl LensElement {
+    pub fn new(radius: f32, thickness: f32, ior: f32, aperture: f32) -> Self {
+        Self {
+            radius,
+            thickness,
+            ior,
+            aperture,
+        }
+    }
+    fn medium(&self) -> f32 {
+        if self.ior == 0.0 {
+            1.0
+        } else {
+            self.ior
+        }
+    }
+}
+
+/// Camera lens made of spherical elements that rays are traced through (Kolb et al.).
+/// Camera space has the film at z = 0 and the lens in front of it along +z, lengths are in meters.
+/// Rays blocked by the element apertures are lost, so vignetting and the cat-eye shape
+/// of out of focus highlights near the corners come from the geometry of the lens
+#[derive(Debug, Clone)]
+pub struct LensSystem {
+    elements: Vec<LensElement>,
+    // z of the vertex of every element
+    z: Vec<f32>,
+    // distance from the film to the rear element
+    film_distance: f32,
+    pub sensor_height: f32,
+}
+
+impl LensSystem {
+    /// `elements` are in millimeters, listed from the front of the lens. The thickness
+    /// of the last one is the distance to the film until the lens is focused
+    pub fn new(elements: Vec<LensElement>) -> Self {
+        let elements: Vec<LensElement> = elements
+            .into_iter()
+            .map(|e| LensElement::new(e.radius * MM, e.thickness * MM, e.ior, e.aperture * MM))
+            .collect();
+        let film_distance = elements.last().map_or(0.0, |e| e.thickness);
+        let mut lens = Self {
+            z: vec![0.0; elements.len()],
+            elements,
+            film_distance,
+            sensor_height: 24.0 * MM,
+        };
+        lens.place_elements();
+        lens
+    }
+
+    /// Parses a prescription with one `radius thickness ior aperture` line per surface,
+    /// lines starting with `#` are comments
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut elements = vec![];
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let v: Vec<f32> = line
+                .split_whitespace()
+                .map(|p| p.parse::<f32>().map_err(|e| format!("{}: {}", line, e)))
+                .collect::<Result<_, _>>()?;
+            match v[..] {
+                [radius, thickness, ior, aperture] => {
+                    elements.push(LensElement::new(radius, thickness, ior, aperture))
+                }
+                _ => return Err(format!("expected 4 values: {}", line)),
+            }
+        }
+        if elements.is_empty() {
+            return Err("the lens has no elements".to_string());
+        }
+        Ok(Self::new(elements))
+    }
+
+    /// 50mm f/2 double Gauss lens
+    pub fn double_gauss_50mm() -> Self {
+        Self::new(vec![
+            LensElement::new(29.475, 3.76, 1.67, 25.2),
+            LensElement::new(84.83, 0.12, 1.0, 25.2),
+            LensElement::new(19.275, 4.025, 1.67, 23.0),
+            LensElement::new(40.77, 3.275, 1.699, 23.0),
+            LensElement::new(12.75, 5.705, 1.0, 18.0),
+            LensElement::new(0.0, 4.5, 0.0, 17.1),
+            LensElement::new(-14.495, 1.18, 1.603, 17.0),
+            LensElement::new(40.77, 6.065, 1.658, 20.0),
+            LensElement::new(-20.385, 0.19, 1.0, 20.0),
+            LensElement::new(437.065, 3.22, 1.717, 20.0),
+            LensElement::new(-39.73, 37.4, 1.0, 20.0),
+        ])
+    }
+
+    pub fn with_sensor_height(mut self, height: f32) -> Self {
+        self.sensor_height = height;
+        self
+    }
+
+    fn place_elements(&mut self) {
+        let mut z = self.film_distance;
+        for i in (0..self.elements.len()).rev() {
+            if i + 1 < self.elements.len() {
+                z += self.elements[i].thickness;
+            }
+            self.z[i] = z;
+        }
+    }
+
+    pub fn film_distance(&self) -> f32 {
+        self.film_distance
+    }
+
+    // refracts the ray at surface `i` going from the medium `from` into `to`, `None` when it misses
+    // the surface, is blocked by its aperture or is reflected inside
+    fn surface(&self, i: usize, p: Vec3, d: Vec3, from: f32, to: f32) -> Option<(Vec3, Vec3)> {
+        let e = &self.elements[i];
+        let z = self.z[i];
+        let (hit, n) = if e.radius == 0.0 {
+            if d.z == 0.0 {
+                return None;
+            }
+            let t = (z - p.z) / d.z;
+            if t <= 0.0 {
+                return None;
+            }
+            (p + d * t, Vec3::new(0.0, 0.0, 1.0))
+        } else {
+            let center = Vec3::new(0.0, 0.0, z - e.radius);
+            let oc = p - center;
+            let a = d.length2();
+            let half_b = oc.dot(d);
+            let c = oc.length2() - e.radius * e.radius;
+            let discriminant = half_b * half_b - a * c;
+            if discriminant < 0.0 {
+                return None;
+            }
+            let sqrt = discriminant.sqrt();
+            // the side of the sphere the vertex is on
+            let t = [(-half_b - sqrt) / a, (-half_b + sqrt) / a]
+                .into_iter()
+                .filter(|&t| t > 0.0)
+                .min_by(|a, b| {
+                    let za = (p.z + d.z * a - z).abs();
+                    let zb = (p.z + d.z * b - z).abs();
+                    za.total_cmp(&zb)
+                })?;
+            let hit = p + d * t;
+            (hit, (hit - center) / e.radius.abs())
+        };
+        let r = e.aperture * 0.5;
+        if hit.x * hit.x + hit.y * hit.y > r * r {
+            return None;
+        }
+        if e.radius == 0.0 {
+            return Some((hit, d));
+        }
+
+        let d = d.unit();
+        let n = if n.dot(d) > 0.0 { -n } else { n };
+        let eta = from / to;
+        let cos_i = -n.dot(d);
+        let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+        if sin2_t > 1.0 {
+            return None;
+        }
+        Some((hit, d * eta + n * (eta * cos_i - (1.0 - sin2_t).sqrt())))
+    }
+
+    /// Traces a ray leaving the film at `p` along `d` out of the front of the lens
+    pub fn trace_from_film(&self, mut p: Vec3, mut d: Vec3) -> Option<(Vec3, Vec3)> {
+        for i in (0..self.elements.len()).rev() {
+            let to = if i == 0 {
+                1.0
+            } else {
+                self.elements[i - 1].medium()
+            };
+            (p, d) = self.surface(i, p, d, self.elements[i].medium(), to)?;
+        }
+        Some((p, d))
+    }
+
+    /// Traces a ray from the scene at `p` along `d` through the lens to the back of the rear element
+    pub fn trace_from_scene(&self, mut p: Vec3, mut d: Vec3) -> Option<(Vec3, Vec3)> {
+        for i in 0..self.elements.len() {
+            let from = if i == 0 {
+                1.0
+            } else {
+                self.elements[i - 1].medium()
+            };
+            (p, d) = self.surface(i, p, d, from, self.elements[i].medium())?;
+        }
+        Some((p, d))
+    }
+
+    // where a paraxial ray from the point on the axis `distance` in front of the film crosses the axis again
+    fn image_of(&self, distance: f32) -> Option<f32> {
+        let front = &self.elements[0];
+        let height = front.aperture * 0.02;
+        let from = Vec3::new(0.0, 0.0, distance);
+        let (p, d) = self.trace_from_scene(from, Vec3::new(height, 0.0, self.z[0]) - from)?;
+        if d.x >= 0.0 {
+            return None;
+        }
+        Some(p.z - p.x / d.x * d.z)
+    }
+
+    /// Moves the film so objects `distance` in front of it are in focus.
+    /// Keeps the film where it was when the lens can't focus that close
+    pub fn focus(&mut self, distance: f32) {
+        let previous = self.film_distance;
+        // the image moves with the film, a few steps settle it onto the film
+        for _ in 0..16 {
+            match self.image_of(distance) {
+                Some(z) if self.film_distance - z > 0.0 => {
+                    self.film_distance -= z;
+                    self.place_elements();
+                }
+                _ => {
+                    self.film_distance = previous;
+                    self.place_elements();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Effective focal length from a ray coming in parallel to the axis
+    pub fn focal_length(&self) -> f32 {
+        let height = self.elements[0].aperture * 0.02;
+        let from = Vec3::new(height, 0.0, self.z[0] + 1.0);
+        match self.trace_from_scene(from, Vec3::new(0.0, 0.0, -1.0)) {
+            Some((_, d)) if d.x < 0.0 => height * d.z / d.x,
+            _ => f32::INFINITY,
+        }
+    }
+
+    /// Ray from the film point `film` towards a random point of the rear element, traced out of the lens.
+    /// Returns the ray and its weight, `None` when the lens blocks it
+    pub fn sample_ray(&self, film: Vec3) -> Option<(Vec3, Vec3, f32)> {
+        let rear = self.elements.last()?;
+        let r = rear.aperture * 0.5 * random_f32().sqrt();
+        let phi = 2.0 * PI * random_f32();
+        let on_rear = Vec3::new(r * phi.cos(), r * phi.sin(), self.film_distance);
+        let d = (on_rear - film).unit();
+        let (p, out) = self.trace_from_film(film, d)?;
+        // light falls off with the fourth power of the cosine towards the edges of the film
+        let cos2 = d.z * d.z;
+        Some((p, out, cos2 * cos2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vec3::vec3::Vec3;
+
+    use super::LensSystem;
+
+    #[test]
+    fn double_gauss_focal_length() {
+        let lens = LensSystem::double_gauss_50mm();
+        let f = lens.focal_length();
+        assert!((f - 0.05).abs() < 0.003, "{}", f);
+    }
+
+    #[test]
+    fn focused_rays_converge() {
+        for distance in [0.5, 1.0, 3.0] {
+            let mut lens = LensSystem::double_gauss_50mm();
+            lens.focus(distance);
+            let image = lens.image_of(distance).unwrap();
+            assert!(image.abs() < 1e-4, "{} {}", distance, image);
+
+            // rays from the center of the film meet again on the axis at the focus distance
+            let mut spread: f32 = 0.0;
+            let mut hits = 0;
+            for _ in 0..200 {
+                if let Some((p, d, _)) = lens.sample_ray(Vec3::ZERO) {
+                    let t = (distance - p.z) / d.z;
+                    let q = p + d * t;
+                    spread = spread.max((q.x * q.x + q.y * q.y).sqrt());
+                    hits += 1;
+                }
+            }
+            assert!(hits > 50);
+            assert!(spread < 0.01 * distance, "{} {}", distance, spread);
+        }
+    }
+
+    #[test]
+    fn corners_are_vignetted() {
+        let mut lens = LensSystem::double_gauss_50mm();
+        lens.focus(2.0);
+        let passing = |film: Vec3| {
+            (0..2000)
+                .filter(|_| lens.sample_ray(film).is_some())
+                .count()
+        };
+        let center = passing(Vec3::ZERO);
+        let corner = passing(Vec3::new(0.018, 0.012, 0.0));
+        assert!(corner < center / 2, "{} {}", center, corner);
+    }
+
+    #[test]
+    fn parse_prescription() {
+        let lens = LensSystem::parse("# radius thickness ior aperture\n50 5 1.5 20\n-50 45 1 20\n");
+        assert_eq!(lens.unwrap().elements.len(), 2);
+        assert!(LensSystem::parse("50 5 1.5\n").is_err());
+        assert!(LensSystem::parse("").is_err());
+    }
+}
@@ -0,0 +1,492 @@
+use std::{
+    cell::RefCell,
+    f32::consts::PI,
+    rc::Rc,
+    sync::{Arc, Mutex, OnceLock},
+    thread,
+};
+
+use rand::random;
+
+use crate::{
+    sampler::{random_f32, with_source},
+    vec3::vec3::Vec3,
+};
+
+use super::{environment::luminance, ray_color::RayColor, Viewport};
+
+// one coordinate of the primary sample space
+#[derive(Debug, Clone, Copy)]
+struct PrimarySample {
+    value: f32,
+    // iteration of the last mutation
+    modified: usize,
+    backup_value: f32,
+    backup_modified: usize,
+}
+
+/// Primary sample vector of a Markov chain. Coordinates are mutated lazily when a path asks for them,
+/// so paths of any length can be mutated.
+/// Mutations are drawn with `rand::random`, `random_f32` would read the sampler back
+#[derive(Debug, Clone)]
+struct MltSampler {
+    x: Vec<PrimarySample>,
+    iteration: usize,
+    last_large_step: usize,
+    large_step: bool,
+    sigma: f32,
+    // next coordinate handed out
+    index: usize,
+    // length of `x` before the current iteration, newer coordinates are dropped on rejection
+    accepted_len: usize,
+}
+
+// standard normal number with the Box-Muller transform
+fn random_normal() -> f32 {
+    let u = 1.0 - random::<f32>();
+    (-2.0 * u.ln()).sqrt() * (2.0 * PI * random::<f32>()).cos()
+}
+
+impl MltSampler {
+    fn new(sigma: f32) -> Self {
+        Self {
+            x: vec![],
+            iteration: 0,
+            last_large_step: 0,
+            large_step: true,
+            sigma,
+            index: 0,
+            accepted_len: 0,
+        }
+    }
+    // chain starting from the coordinates of a bootstrap path
+    fn from_values(values: &[f32], sigma: f32) -> Self {
+        let mut sampler = Self::new(sigma);
+        sampler.x = values
+            .iter()
+            .map(|&value| PrimarySample {
+                value,
+                modified: 0,
+                backup_value: value,
+                backup_modified: 0,
+            })
+            .collect();
+        sampler.accepted_len = sampler.x.len();
+        sampler
+    }
+    fn values(&self) -> Vec<f32> {
+        self.x.iter().map(|s| s.value).collect()
+    }
+
+    fn start_iteration(&mut self, large_step: bool) {
+        self.iteration += 1;
+        self.large_step = large_step;
+        self.index = 0;
+        self.accepted_len = self.x.len();
+    }
+
+    fn next(&mut self) -> f32 {
+        let i = self.index;
+        self.index += 1;
+        if i >= self.x.len() {
+            // coordinates the chain has never used are independent of the rest
+            let value = random();
+            self.x.push(PrimarySample {
+                value,
+                modified: self.iteration,
+                backup_value: value,
+                backup_modified: self.iteration,
+            });
+            return value;
+        }
+
+        let s = &mut self.x[i];
+        // a large step since the last use replaced the value with a new one
+        if s.modified < self.last_large_step {
+            s.value = random();
+            s.modified = self.last_large_step;
+        }
+        s.backup_value = s.value;
+        s.backup_modified = s.modified;
+        if self.large_step {
+            s.value = random();
+        } else {
+            // the small steps skipped while the coordinate was unused add up to a wider one
+            let n = (self.iteration - s.modified) as f32;
+            s.value += random_normal() * self.sigma * n.sqrt();
+            s.value -= s.value.floor();
+            if s.value >= 1.0 {
+                s.value = 0.0;
+            }
+        }
+        s.modified = self.iteration;
+        s.value
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    fn reject(&mut self) {
+        self.x.truncate(self.accepted_len);
+        for s in self.x.iter_mut() {
+            if s.modified == self.iteration {
+                s.value = s.backup_value;
+                s.modified = s.backup_modified;
+            }
+        }
+        self.iteration -= 1;
+    }
+}
+
+// path generated from one point of the primary sample space
+#[derive(Debug, Clone, Copy)]
+struct PathSample {
+    x: usize,
+    y: usize,
+    color: Vec3,
+    // scalar contribution the chain is distributed by
+    importance: f32,
+}
+
+struct Chain {
+    sampler: MltSampler,
+    current: PathSample,
+}
+
+struct Bootstrap {
+    // mean importance of independent paths, the integral the image is scaled to
+    b: f32,
+    // primary samples of the bootstrap paths that carry light and their cumulative importance
+    seeds: Vec<Vec<f32>>,
+    cdf: Vec<f32>,
+}
+
+/// Primary sample space Metropolis light transport (Kelemen et al.).
+/// Runs Markov chains over the random numbers the `inner` integrator consumes, mutating them with
+/// small perturbations and large independent steps, so bright paths that are hard to find are explored locally.
+/// Every call of the returned integrator advances a chain once and splats both the current and
+/// the proposed path onto the image, the ray it is called with is ignored.
+/// The image is normalized by the mean contribution of `bootstrap` independent paths.
+/// `inner` has to take its random numbers from `random_f32` and must not splat light itself.
+/// A `Mlt` keeps its bootstrap and chains, it renders a single scene
+pub(crate) struct Mlt {
+    inner: RayColor,
+    pub bootstrap: usize,
+    pub large_step_probability: f32,
+    pub sigma: f32,
+    state: OnceLock<Bootstrap>,
+    chains: Mutex<Vec<Chain>>,
+}
+
+impl Mlt {
+    pub(crate) fn new(inner: RayColor) -> Self {
+        Self {
+            inner,
+            bootstrap: 100000,
+            large_step_probability: 0.3,
+            sigma: 0.01,
+            state: OnceLock::new(),
+            chains: Mutex::new(vec![]),
+        }
+    }
+    pub fn with_bootstrap(mut self, bootstrap: usize) -> Self {
+        self.bootstrap = bootstrap.max(1);
+        self
+    }
+    pub fn with_large_step_probability(mut self, p: f32) -> Self {
+        self.large_step_probability = p.clamp(0.0, 1.0);
+        self
+    }
+    pub fn with_sigma(mut self, sigma: f32) -> Self {
+        self.sigma = sigma;
+        self
+    }
+
+    /// Integrator advancing the chains of `self`
+    pub(crate) fn ray_color(self) -> RayColor {
+        let mlt = Arc::new(self);
+        Arc::new(move |_, vp, _| mlt.mutate(&vp))
+    }
+
+    // traces the path of the primary sample vector in `sampler`, which is handed back afterwards
+    fn evaluate(&self, sampler: MltSampler, vp: &Arc<Viewport>) -> (MltSampler, PathSample) {
+        let sampler = Rc::new(RefCell::new(sampler));
+        let source = sampler.clone();
+        let path = with_source(
+            move || source.borrow_mut().next(),
+            || {
+                let (u, v) = (random_f32(), random_f32());
+                let color = match vp.camera_ray(u, v) {
+                    Some((r, weight)) => {
+                        let r = r.with_spread(vp.pixel_spread());
+                        (self.inner)(r, vp.clone(), vp.recursion_depth) * weight
+                    }
+                    None => Vec3::ZERO,
+                };
+                let importance = luminance(color);
+                PathSample {
+                    x: ((u * vp.width as f32) as usize).min(vp.width - 1),
+                    y: ((v * vp.height as f32) as usize).min(vp.height - 1),
+                    color,
+                    importance: if importance.is_finite() && importance > 0.0 {
+                        importance
+                    } else {
+                        0.0
+                    },
+                }
+            },
+        );
+        let sampler = Rc::try_unwrap(sampler)
+            .expect("the sample source is dropped with `with_source`")
+            .into_inner();
+        (sampler, path)
+    }
+
+    // independent paths traced on all cores, rayon isn't used as the render rows wait for this
+    fn run_bootstrap(&self, vp: &Arc<Viewport>) -> Bootstrap {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let per_thread = self.bootstrap.div_ceil(threads);
+        let paths: Vec<(f32, Vec<f32>)> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|t| {
+                    let count = per_thread.min(self.bootstrap.saturating_sub(t * per_thread));
+                    scope.spawn(move || {
+                        (0..count)
+                            .map(|_| {
+                                let (sampler, path) =
+                                    self.evaluate(MltSampler::new(self.sigma), vp);
+                                (path.importance, sampler.values())
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().unwrap())
+                .collect()
+        });
+
+        let mut sum = 0.0;
+        let mut seeds = vec![];
+        let mut cdf = vec![];
+        for (importance, values) in paths {
+            if importance > 0.0 {
+                sum += importance;
+                seeds.push(values);
+                cdf.push(sum);
+            }
+        }
+        Bootstrap {
+            b: sum / self.bootstrap as f32,
+            seeds,
+            cdf,
+        }
+    }
+
+    // new chain starting at a bootstrap path picked proportionally to its contribution
+    fn new_chain(&self, state: &Bootstrap, vp: &Arc<Viewport>) -> Chain {
+        let x = random::<f32>() * state.cdf[state.cdf.len() - 1];
+        let i = state
+            .cdf
+            .partition_point(|&c| c <= x)
+            .min(state.seeds.len() - 1);
+        let mut sampler = MltSampler::from_values(&state.seeds[i], self.sigma);
+        // replaying the seed without mutating it gives back its path
+        sampler.sigma = 0.0;
+        sampler.start_iteration(false);
+        let (mut sampler, current) = self.evaluate(sampler, vp);
+        sampler.sigma = self.sigma;
+        sampler.accept();
+        Chain { sampler, current }
+    }
+
+    fn mutate(&self, vp: &Arc<Viewport>) -> Vec3 {
+        let state = self.state.get_or_init(|| self.run_bootstrap(vp));
+        if state.seeds.is_empty() {
+            return Vec3::ZERO;
+        }
+        let chain = self.chains.lock().unwrap().pop();
+        let Chain {
+            mut sampler,
+            mut current,
+        } = chain.unwrap_or_else(|| self.new_chain(state, vp));
+
+        sampler.start_iteration(random::<f32>() < self.large_step_probability);
+        let (mut sampler, proposed) = self.evaluate(sampler, vp);
+        let accept = if current.importance > 0.0 {
+            (proposed.importance / current.importance).min(1.0)
+        } else {
+            1.0
+        };
+
+        // both paths are recorded, weighted by their chance of being the next state
+        if accept > 0.0 && proposed.importance > 0.0 {
+            vp.splat(
+                proposed.x,
+                proposed.y,
+                proposed.color * (accept * state.b / proposed.importance),
+            );
+        }
+        if accept < 1.0 && current.importance > 0.0 {
+            vp.splat(
+                current.x,
+                current.y,
+                current.color * ((1.0 - accept) * state.b / current.importance),
+            );
+        }
+
+        if random::<f32>() < accept {
+            sampler.accept();
+            current = proposed;
+        } else {
+            sampler.reject();
+        }
+        self.chains.lock().unwrap().push(Chain { sampler, current });
+        Vec3::ZERO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use image::ImageResult;
+
+    use crate::{
+        objects::{
+            instance::Instance,
+            light::{Light, RectLight},
+            material::LAMBERTIAN,
+            quad::Quad,
+            sphere::Sphere,
+            texture::ConstColorTexture,
+        },
+        vec3::vec3::Vec3,
+        viewport::{
+            camera::Camera,
+            ray_color::next_event_ray_color,
+            scene::Scene,
+            test_scenes::{ceiling_light, mean_radiance, small_viewport, sphere_on_floor},
+            Viewport,
+        },
+    };
+
+    use super::{Mlt, MltSampler};
+
+    #[test]
+    fn rejected_mutation_restores_samples() {
+        let mut sampler = MltSampler::new(0.1);
+        sampler.start_iteration(true);
+        let first: Vec<f32> = (0..5).map(|_| sampler.next()).collect();
+        sampler.accept();
+
+        for large in [false, true] {
+            sampler.start_iteration(large);
+            let mutated: Vec<f32> = (0..8).map(|_| sampler.next()).collect();
+            assert!(mutated.iter().all(|v| (0.0..1.0).contains(v)));
+            assert_ne!(mutated[..5], first[..]);
+            sampler.reject();
+            assert_eq!(sampler.values(), first);
+        }
+    }
+
+    #[test]
+    fn mlt_matches_next_event() {
+        let scene = |rc| small_viewport(sphere_on_floor(), vec![ceiling_light()], rc);
+        let reference = mean_radiance(scene(Arc::new(next_event_ray_color)), 16);
+        let mlt = || Mlt::new(Arc::new(next_event_ray_color)).with_bootstrap(20000);
+        // only large steps sample independently, larger small steps explore further
+        for mlt in [
+            mlt(),
+            mlt().with_large_step_probability(1.0),
+            mlt().with_sigma(0.1),
+        ] {
+            let mlt = mean_radiance(scene(mlt.ray_color()), 16);
+            for (a, b) in [
+                (reference.x, mlt.x),
+                (reference.y, mlt.y),
+                (reference.z, mlt.z),
+            ] {
+                assert!((a - b).abs() <= 0.1 * a, "{:?} != {:?}", reference, mlt);
+            }
+        }
+    }
+
+    #[test]
+    fn mlt_narrow_opening_test() -> ImageResult<()> {
+        const WIDTH: usize = 300;
+        const HEIGHT: usize = 200;
+        let grey = Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.7, Vec3::ZERO));
+        let wall = |origin, u, v| {
+            Instance::new(Arc::new([Arc::new(Quad::new(
+                origin,
+                u,
+                v,
+                LAMBERTIAN.clone(),
+                Vec3::ZERO,
+                grey.clone(),
+            ))]))
+        };
+        // a room lit only through a thin gap in the ceiling
+        let objects = vec![
+            wall(
+                Vec3::new(-3.0, -1.0, 0.0),
+                Vec3::new(6.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 6.0),
+            ),
+            wall(
+                Vec3::new(-3.0, 1.0, 0.0),
+                Vec3::new(2.95, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 6.0),
+            ),
+            wall(
+                Vec3::new(0.05, 1.0, 0.0),
+                Vec3::new(2.95, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 6.0),
+            ),
+            wall(
+                Vec3::new(-3.0, -1.0, 6.0),
+                Vec3::new(6.0, 0.0, 0.0),
+                Vec3::new(0.0, 2.0, 0.0),
+            ),
+            Instance::new(Arc::new([Arc::new(Sphere {
+                origin: Vec3::new(0.0, -0.5, 3.5),
+                radius: 0.5,
+                mat: LAMBERTIAN.clone(),
+                texture: grey.clone(),
+            })])),
+        ];
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![Arc::new(RectLight::new(
+            Vec3::new(-1.0, 1.5, 2.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 2.0),
+            Vec3::WHITE * 20.0,
+        ))];
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::ZERO,
+            Vec3::UP,
+            Vec3::FORWARD,
+            70.0,
+            0.0,
+        );
+        let mlt = Mlt::new(Arc::new(next_event_ray_color));
+        Viewport::new(
+            cam,
+            Scene::new(objects, 0.001, 1000.0).with_lights(lights),
+            mlt.ray_color(),
+            WIDTH,
+            HEIGHT,
+            16,
+            5,
+            Vec3::ZERO,
+            2.2,
+        )
+        .render_rows_async()
+        .save("test_out/mlt_narrow_opening_test.png")
+    }
+}
@@ -0,0 +1,428 @@
+use std::{f32::consts::PI, sync::Arc};
+
+use image::{ImageBuffer, Rgb};
+use rayon::prelude::*;
+
+use crate::{
+    objects::{
+        aabb::{maxf, minf},
+        hit::{Hit, HitObject},
+    },
+    sampler::random_f32,
+    vec3::{ray::Ray, vec3::Vec3},
+};
+
+use super::{
+    ray_color::{area_light_emission, Bounce},
+    Viewport,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Photon {
+    pub p: Vec3,
+    // unit direction the photon was travelling in when it was stored
+    pub dir: Vec3,
+    pub power: Vec3,
+}
+
+fn axis_value(p: Vec3, axis: u8) -> f32 {
+    match axis {
+        0 => p.x,
+        1 => p.y,
+        _ => p.z,
+    }
+}
+
+/// Photons sorted in place into a kd-tree. The node of a range is its middle element,
+/// the median along the widest axis of the range
+#[derive(Debug, Clone)]
+pub struct KdTree {
+    photons: Vec<Photon>,
+    // split axis of the node stored at the same index
+    axes: Vec<u8>,
+}
+
+impl KdTree {
+    pub fn new(mut photons: Vec<Photon>) -> Self {
+        let mut axes = vec![0; photons.len()];
+        Self::build(&mut photons, &mut axes);
+        Self { photons, axes }
+    }
+
+    fn build(photons: &mut [Photon], axes: &mut [u8]) {
+        if photons.len() <= 1 {
+            return;
+        }
+        let (mut min, mut max) = (photons[0].p, photons[0].p);
+        for ph in photons.iter() {
+            min = Vec3::new(
+                minf(min.x, ph.p.x),
+                minf(min.y, ph.p.y),
+                minf(min.z, ph.p.z),
+            );
+            max = Vec3::new(
+                maxf(max.x, ph.p.x),
+                maxf(max.y, ph.p.y),
+                maxf(max.z, ph.p.z),
+            );
+        }
+        let extent = max - min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        let mid = photons.len() / 2;
+        photons.select_nth_unstable_by(mid, |a, b| {
+            axis_value(a.p, axis).total_cmp(&axis_value(b.p, axis))
+        });
+        axes[mid] = axis;
+        let (left, right) = photons.split_at_mut(mid);
+        let (left_axes, right_axes) = axes.split_at_mut(mid);
+        Self::build(left, left_axes);
+        Self::build(&mut right[1..], &mut right_axes[1..]);
+    }
+
+    /// Calls `f` with every photon closer than `radius` to `p`
+    pub fn for_each_in_radius(&self, p: Vec3, radius: f32, f: &mut impl FnMut(&Photon)) {
+        self.query(0, self.photons.len(), p, radius * radius, f);
+    }
+
+    fn query(&self, lo: usize, hi: usize, p: Vec3, r2: f32, f: &mut impl FnMut(&Photon)) {
+        if lo >= hi {
+            return;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let ph = &self.photons[mid];
+        if (ph.p - p).length2() <= r2 {
+            f(ph);
+        }
+        if hi - lo == 1 {
+            return;
+        }
+        let d = axis_value(p, self.axes[mid]) - axis_value(ph.p, self.axes[mid]);
+        let (near, far) = if d < 0.0 {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+        self.query(near.0, near.1, p, r2, f);
+        if d * d <= r2 {
+            self.query(far.0, far.1, p, r2, f);
+        }
+    }
+}
+
+/// Photons emitted from the scene lights and the glowing quads and spheres, stored at every diffuse
+/// surface they hit. Other emissive objects don't emit photons, they are only seen directly
+pub struct PhotonMap {
+    tree: KdTree,
+    // radius of the density estimation
+    pub radius: f32,
+}
+
+impl PhotonMap {
+    /// Traces `photons` photons through the scene of `vp`, at most `vp.recursion_depth` bounces each
+    pub(crate) fn new(vp: &Viewport, photons: usize, radius: f32) -> Self {
+        let stored: Vec<Photon> = (0..photons)
+            .into_par_iter()
+            .flat_map_iter(|_| Self::trace_photon(vp))
+            .map(|ph| Photon {
+                power: ph.power / photons as f32,
+                ..ph
+            })
+            .collect();
+        Self {
+            tree: KdTree::new(stored),
+            radius,
+        }
+    }
+
+    fn trace_photon(vp: &Viewport) -> Vec<Photon> {
+        let mut stored = vec![];
+        let count = vp.s.lights.len() + vp.s.emitters.len();
+        if count == 0 {
+            return stored;
+        }
+        let pick = 1.0 / count as f32;
+        let i = ((random_f32() * count as f32) as usize).min(count - 1);
+        let l = match vp.s.lights.get(i) {
+            Some(l) => l,
+            None => &vp.s.emitters[i - vp.s.lights.len()],
+        };
+        let Some(e) = l.sample_emission(vp.s.bounding_sphere()) else {
+            return stored;
+        };
+        if e.pdf_pos <= 0.0 || e.pdf_dir <= 0.0 {
+            return stored;
+        }
+        let cos = if e.normal.close_to_zero() {
+            1.0
+        } else {
+            e.normal.dot(e.ray.direction).abs()
+        };
+        let mut power = e.radiance * (cos / (pick * e.pdf_pos * e.pdf_dir));
+        let mut r = e.ray;
+        for _ in 0..vp.recursion_depth {
+            if power.close_to_zero() {
+                break;
+            }
+            let Some((h, o)) = vp.s.get_hit(r) else {
+                break;
+            };
+            let specular = o.is_specular(&h);
+            if !specular {
+                stored.push(Photon {
+                    p: h.p,
+                    dir: r.direction.unit(),
+                    power,
+                });
+            }
+            let color = o.color(&h);
+            let reflect = o.reflect(&h);
+            let throughput = if specular {
+                color.multiplied
+            } else {
+                let pdf = o.generator_pdf(&h, &reflect);
+                if pdf <= 0.0 {
+                    break;
+                }
+                color.multiplied * (o.material_pdf(&h, &reflect) / pdf)
+            };
+            // russian roulette keeps the power of the surviving photons close to the emitted one
+            let survive = maxf(maxf(throughput.x, throughput.y), throughput.z).min(1.0);
+            if survive <= 0.0 || random_f32() >= survive {
+                break;
+            }
+            power = power * throughput / survive;
+            r = reflect;
+        }
+        stored
+    }
+
+    /// Radiance leaving the diffuse hit `h` towards `h.r.origin`, estimated from the photons around it
+    pub fn radiance(&self, h: &Hit, o: &HitObject) -> Vec3 {
+        let (n, ng) = h.facing_normals();
+        let n = n.unit();
+        let mut sum = Vec3::ZERO;
+        self.tree.for_each_in_radius(h.p, self.radius, &mut |ph| {
+            // photons arriving from behind the surface don't light this side
+            let cos = -ph.dir.dot(n);
+            if ph.dir.dot(ng) >= 0.0 || cos <= 1e-4 {
+                return;
+            }
+            let r = Ray::new_with_time(h.p, -ph.dir, h.r.time);
+            sum += ph.power * (o.material_pdf(h, &r) / cos);
+        });
+        sum.field_wise_mult(o.color(h).multiplied) / (PI * self.radius * self.radius)
+    }
+}
+
+fn photon_path(r: Ray, vp: Arc<Viewport>, depth: usize, map: &PhotonMap, prev: Bounce) -> Vec3 {
+    if depth == 0 {
+        return Vec3::ZERO;
+    }
+    match vp.s.get_hit(r) {
+        Some((h, o)) => {
+            let color = o.color(&h);
+            let light = color.emmited + area_light_emission(&r, &vp, h.t, prev);
+            if !o.is_specular(&h) {
+                return light + map.radiance(&h, &o);
+            }
+            let reflect = o.reflect(&h);
+            light
+                + photon_path(reflect, vp, depth - 1, map, Bounce::Specular)
+                    .field_wise_mult(color.multiplied)
+        }
+        None => vp.background(&r) + area_light_emission(&r, &vp, f32::INFINITY, prev),
+    }
+}
+
+/// Follows `r` through specular surfaces and estimates the light at the first diffuse hit from `map`
+#[allow(unused)]
+pub(crate) fn photon_ray_color(r: Ray, vp: Arc<Viewport>, depth: usize, map: &PhotonMap) -> Vec3 {
+    photon_path(r, vp, depth, map, Bounce::Camera)
+}
+
+/// Stochastic progressive photon mapping. Every pass renders the image with a new photon map
+/// and a smaller radius, the average of the passes converges to the right image.
+#[derive(Debug, Clone, Copy)]
+pub struct ProgressivePhotonMapping {
+    pub passes: usize,
+    // photons emitted every pass
+    pub photons: usize,
+    // radius of the first pass
+    pub radius: f32,
+    // fraction of the photons kept from one pass to the next, in (0, 1)
+    pub alpha: f32,
+}
+
+impl ProgressivePhotonMapping {
+    pub fn new(passes: usize, photons: usize, radius: f32) -> Self {
+        Self {
+            passes,
+            photons,
+            radius,
+            alpha: 2.0 / 3.0,
+        }
+    }
+    pub fn with_alpha(mut self, alpha: f32) -> Self {
+        self.alpha = alpha;
+        self
+    }
+
+    /// Radius of the pass with index `pass`, counted from 0
+    pub fn radius(&self, pass: usize) -> f32 {
+        let mut r2 = self.radius * self.radius;
+        for i in 1..=pass {
+            r2 *= (i as f32 + self.alpha) / (i as f32 + 1.0);
+        }
+        r2.sqrt()
+    }
+
+    /// Pixel values before gamma correction, the integrator of `vp` is replaced by photon mapping
+    pub(crate) fn render_linear(&self, vp: &Viewport) -> Vec<Vec<Vec3>> {
+        let mut sum = vec![vec![Vec3::ZERO; vp.width]; vp.height];
+        for pass in 0..self.passes {
+            let map = PhotonMap::new(vp, self.photons, self.radius(pass));
+            let pass_vp = Viewport {
+                rc: Arc::new(move |r, vp, d| photon_ray_color(r, vp, d, &map)),
+                ..vp.clone()
+            };
+            for (row, pass_row) in sum.iter_mut().zip(pass_vp.render_linear()) {
+                for (pix, p) in row.iter_mut().zip(pass_row) {
+                    *pix += p;
+                }
+            }
+        }
+        for pix in sum.iter_mut().flatten() {
+            *pix /= self.passes.max(1) as f32;
+        }
+        sum
+    }
+
+    pub(crate) fn render(&self, vp: Viewport) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        Viewport::develop(self.render_linear(&vp), &vp.post, vp.gamma)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::random;
+
+    use crate::{
+        objects::{
+            instance::Instance,
+            light::{Light, RectLight},
+            material::LAMBERTIAN,
+            quad::Quad,
+            sphere::Sphere,
+            texture::ConstColorTexture,
+        },
+        vec3::vec3::Vec3,
+        viewport::{camera::Camera, ray_color::next_event_ray_color, scene::Scene, Viewport},
+    };
+
+    use super::*;
+
+    #[test]
+    fn kd_tree_finds_photons_in_radius() {
+        let photons: Vec<Photon> = (0..2000)
+            .map(|_| Photon {
+                p: Vec3::random(-1.0, 1.0),
+                dir: Vec3::DOWN,
+                power: Vec3::WHITE,
+            })
+            .collect();
+        let tree = KdTree::new(photons.clone());
+        for _ in 0..50 {
+            let p = Vec3::random(-1.0, 1.0);
+            let radius = random::<f32>() * 0.5;
+            let mut found = 0;
+            tree.for_each_in_radius(p, radius, &mut |_| found += 1);
+            let expected = photons
+                .iter()
+                .filter(|ph| (ph.p - p).length2() <= radius * radius)
+                .count();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn radius_shrinks() {
+        let ppm = ProgressivePhotonMapping::new(10, 1000, 0.1);
+        assert_eq!(ppm.radius(0), 0.1);
+        for pass in 1..10 {
+            assert!(ppm.radius(pass) < ppm.radius(pass - 1));
+        }
+    }
+
+    #[test]
+    fn photon_map_matches_next_event() {
+        const WIDTH: usize = 32;
+        const HEIGHT: usize = 24;
+        let grey = Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.5, Vec3::ZERO));
+        let objects = vec![
+            Instance::new(Arc::new([Arc::new(Sphere {
+                origin: Vec3::new(0.0, 0.0, 3.0),
+                radius: 0.5,
+                mat: LAMBERTIAN.clone(),
+                texture: grey.clone(),
+            })])),
+            Instance::new(Arc::new([Arc::new(Quad::new(
+                Vec3::new(-5.0, -0.5, -2.0),
+                Vec3::new(10.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 10.0),
+                LAMBERTIAN.clone(),
+                Vec3::ZERO,
+                grey,
+            ))])),
+        ];
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![Arc::new(
+            RectLight::new(
+                Vec3::new(-0.5, 1.5, 2.5),
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 1.0),
+                // tinted so that every channel carries a different flux
+                Vec3::new(1.0, 0.7, 0.4),
+            )
+            .with_visibility(false),
+        )];
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::ZERO,
+            Vec3::UP,
+            Vec3::FORWARD,
+            60.0,
+            0.0,
+        );
+        let vp = Viewport::new(
+            cam,
+            Scene::new(objects, 0.001, 1000.0).with_lights(lights),
+            Arc::new(next_event_ray_color),
+            WIDTH,
+            HEIGHT,
+            16,
+            4,
+            Vec3::ZERO,
+            1.0,
+        );
+        let mean = |img: Vec<Vec<Vec3>>| {
+            img.iter().flatten().fold(Vec3::ZERO, |a, &b| a + b) / (WIDTH * HEIGHT) as f32
+        };
+        let ppm = ProgressivePhotonMapping::new(4, 100000, 0.05);
+        let photons = mean(ppm.render_linear(&vp));
+        let reference = mean(vp.render_linear());
+        for (r, p) in [
+            (reference.x, photons.x),
+            (reference.y, photons.y),
+            (reference.z, photons.z),
+        ] {
+            assert!((r - p).abs() <= 0.1 * r, "{:?} != {:?}", reference, photons);
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use std::f32::consts::PI;
+
+use crate::onb::ONB;
+use crate::vec3::{ray::Ray, vec3::Vec3};
+
+use super::camera::{look_at, Projection};
+
+/// Parallel rays from a rectangle facing `dir`, for elevations and plans without perspective
+#[derive(Clone)]
+pub struct Orthographic {
+    origin: Vec3,
+    onb: ONB,
+    width: f32,
+    height: f32,
+}
+
+impl Orthographic {
+    /// `origin` is the middle of the image, `height` its height in scene units
+    pub fn new(aspect: f32, origin: Vec3, vup: Vec3, dir: Vec3, height: f32) -> Self {
+        Self {
+            origin,
+            onb: look_at(vup, dir),
+            width: height * aspect,
+            height,
+        }
+    }
+}
+
+impl Projection for Orthographic {
+    fn ray(&self, u: f32, v: f32) -> Option<(Ray, f32)> {
+        let origin = self.origin
+            + self.onb.u * ((u - 0.5) * self.width)
+            + self.onb.v * ((0.5 - v) * self.height);
+        Some((Ray::new(origin, -self.onb.w), 1.0))
+    }
+
+    fn spread(&self) -> f32 {
+        0.0
+    }
+}
+
+/// Equidistant fisheye, the angle from the view direction grows linearly with the distance
+/// from the middle of the image. The circle of view touches the top and bottom of the image
+#[derive(Clone)]
+pub struct Fisheye {
+    origin: Vec3,
+    onb: ONB,
+    aspect: f32,
+    // radians across the circle of view
+    fov: f32,
+}
+
+impl Fisheye {
+    /// `fov` is in degrees and can go up to 360
+    pub fn new(aspect: f32, origin: Vec3, vup: Vec3, dir: Vec3, fov: f32) -> Self {
+        Self {
+            origin,
+            onb: look_at(vup, dir),
+            aspect,
+            fov: fov.to_radians(),
+        }
+    }
+}
+
+impl Projection for Fisheye {
+    // `None` outside of the circle of view
+    fn ray(&self, u: f32, v: f32) -> Option<(Ray, f32)> {
+        let x = (2.0 * u - 1.0) * self.aspect;
+        let y = 1.0 - 2.0 * v;
+        let r = (x * x + y * y).sqrt();
+        if r > 1.0 {
+            return None;
+        }
+        let theta = r * self.fov * 0.5;
+        let phi = y.atan2(x);
+        let dir = -self.onb.w * theta.cos()
+            + (self.onb.u * phi.cos() + self.onb.v * phi.sin()) * theta.sin();
+        Some((Ray::new(self.origin, dir), 1.0))
+    }
+
+    fn spread(&self) -> f32 {
+        self.fov
+    }
+}
+
+/// Full sphere panorama in latitude and longitude, `dir` is in the middle of the image.
+/// Meant for images twice as wide as they are high
+#[derive(Clone)]
+pub struct Equirectangular {
+    origin: Vec3,
+    onb: ONB,
+}
+
+impl Equirectangular {
+    pub fn new(origin: Vec3, vup: Vec3, dir: Vec3) -> Self {
+        Self {
+            origin,
+            onb: look_at(vup, dir),
+        }
+    }
+}
+
+impl Projection for Equirectangular {
+    fn ray(&self, u: f32, v: f32) -> Option<(Ray, f32)> {
+        let longitude = (u - 0.5) * 2.0 * PI;
+        let latitude = (0.5 - v) * PI;
+        let dir = (-self.onb.w * longitude.cos() + self.onb.u * longitude.sin()) * latitude.cos()
+            + self.onb.v * latitude.sin();
+        Some((Ray::new(self.origin, dir), 1.0))
+    }
+
+    fn spread(&self) -> f32 {
+        PI
+    }
+}
+
+/// The six 90 degree views around the origin side by side in the order
+/// right, left, up, down, front and back. Meant for images six times as wide as they are high
+#[derive(Clone)]
+pub struct CubeMap {
+    origin: Vec3,
+    onb: ONB,
+}
+
+impl CubeMap {
+    pub fn new(origin: Vec3, vup: Vec3, dir: Vec3) -> Self {
+        Self {
+            origin,
+            onb: look_at(vup, dir),
+        }
+    }
+
+    // view direction, right and down of every face
+    fn face(&self, i: usize) -> (Vec3, Vec3, Vec3) {
+        let (right, up, forward) = (self.onb.u, self.onb.v, -self.onb.w);
+        match i {
+            0 => (right, -forward, -up),
+            1 => (-right, forward, -up),
+            2 => (up, right, forward),
+            3 => (-up, right, -forward),
+            4 => (forward, right, -up),
+            _ => (-forward, -right, -up),
+        }
+    }
+}
+
+impl Projection for CubeMap {
+    fn ray(&self, u: f32, v: f32) -> Option<(Ray, f32)> {
+        let i = ((u * 6.0) as usize).min(5);
+        let (dir, right, down) = self.face(i);
+        let a = 2.0 * (u * 6.0 - i as f32) - 1.0;
+        let b = 2.0 * v - 1.0;
+        Some((Ray::new(self.origin, dir + right * a + down * b), 1.0))
+    }
+
+    fn spread(&self) -> f32 {
+        PI * 0.5
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{f32::consts::PI, sync::Arc};
+
+    use image::ImageResult;
+
+    use crate::{
+        objects::{
+            instance::Instance,
+            light::{Light, PointLight},
+            material::LAMBERTIAN,
+            quad::Quad,
+            sphere::Sphere,
+            texture::ConstColorTexture,
+        },
+        vec3::vec3::Vec3,
+        viewport::{camera::Projection, ray_color::next_event_ray_color, scene::Scene, Viewport},
+    };
+
+    use super::{CubeMap, Equirectangular, Fisheye, Orthographic};
+
+    fn direction(p: &impl Projection, u: f32, v: f32) -> Vec3 {
+        p.ray(u, v).unwrap().0.direction.unit()
+    }
+
+    #[test]
+    fn orthographic_rays_are_parallel() {
+        let ortho = Orthographic::new(2.0, Vec3::ZERO, Vec3::UP, Vec3::FORWARD, 3.0);
+        let (top_left, _) = ortho.ray(0.0, 0.0).unwrap();
+        let (bottom_right, _) = ortho.ray(1.0, 1.0).unwrap();
+        assert_eq!(top_left.direction, bottom_right.direction);
+        assert_eq!(
+            bottom_right.origin - top_left.origin,
+            Vec3::RIGHT * 6.0 + Vec3::DOWN * 3.0
+        );
+        assert_eq!(ortho.ray(0.5, 0.5).unwrap().0.origin, Vec3::ZERO);
+    }
+
+    #[test]
+    fn fisheye_angles() {
+        let fisheye = Fisheye::new(1.5, Vec3::ZERO, Vec3::UP, Vec3::FORWARD, 180.0);
+        assert_eq!(direction(&fisheye, 0.5, 0.5), Vec3::FORWARD);
+        // the edge of the circle looks sideways, half way there is 45 degrees off
+        assert_eq!(direction(&fisheye, 0.5, 0.0), Vec3::UP);
+        let d = direction(&fisheye, 0.5, 0.75);
+        assert!((d.dot(Vec3::FORWARD) - (PI / 4.0).cos()).abs() < 1e-5);
+        assert!(d.dot(Vec3::DOWN) > 0.0);
+        assert!(fisheye.ray(0.0, 0.0).is_none());
+    }
+
+    #[test]
+    fn equirectangular_directions() {
+        let pano = Equirectangular::new(Vec3::ZERO, Vec3::UP, Vec3::FORWARD);
+        assert_eq!(direction(&pano, 0.5, 0.5), Vec3::FORWARD);
+        assert_eq!(direction(&pano, 0.75, 0.5), Vec3::RIGHT);
+        assert_eq!(direction(&pano, 0.25, 0.5), Vec3::LEFT);
+        assert_eq!(direction(&pano, 0.0, 0.5), Vec3::BACKWARD);
+        assert_eq!(direction(&pano, 0.3, 0.0), Vec3::UP);
+        assert_eq!(direction(&pano, 0.8, 1.0), Vec3::DOWN);
+    }
+
+    #[test]
+    fn cube_map_faces() {
+        let cube = CubeMap::new(Vec3::ZERO, Vec3::UP, Vec3::FORWARD);
+        let centers = [
+            Vec3::RIGHT,
+            Vec3::LEFT,
+            Vec3::UP,
+            Vec3::DOWN,
+            Vec3::FORWARD,
+            Vec3::BACKWARD,
+        ];
+        for (i, c) in centers.into_iter().enumerate() {
+            assert_eq!(direction(&cube, (i as f32 + 0.5) / 6.0, 0.5), c);
+        }
+        // the horizontal faces continue into each other: front, right, back, left
+        let edge = |face: usize, side: f32| direction(&cube, (face as f32 + side) / 6.0, 0.3);
+        let meet = |a: Vec3, b: Vec3| assert!(a.dot(b) > 0.9999, "{:?} {:?}", a, b);
+        meet(edge(4, 0.9999), edge(0, 0.0001));
+        meet(edge(0, 0.9999), edge(5, 0.0001));
+        meet(edge(5, 0.9999), edge(1, 0.0001));
+        meet(edge(1, 0.9999), edge(4, 0.0001));
+        // the top of the front face meets the bottom of the up face
+        meet(
+            direction(&cube, 4.5 / 6.0, 0.0),
+            direction(&cube, 2.5 / 6.0, 1.0),
+        );
+    }
+
+    // spheres all around the origin above a floor
+    fn render(
+        cam: impl Projection + Send + Sync + 'static,
+        width: usize,
+        height: usize,
+    ) -> Viewport {
+        let colors = [
+            Vec3::new(0.8, 0.2, 0.2),
+            Vec3::new(0.2, 0.8, 0.2),
+            Vec3::new(0.2, 0.2, 0.8),
+            Vec3::new(0.8, 0.8, 0.2),
+        ];
+        let mut objects: Vec<_> = [Vec3::FORWARD, Vec3::RIGHT, Vec3::BACKWARD, Vec3::LEFT]
+            .into_iter()
+            .zip(colors)
+            .map(|(d, c)| {
+                Instance::new(Arc::new([Arc::new(Sphere {
+                    origin: d * 3.0,
+                    radius: 1.0,
+                    mat: LAMBERTIAN.clone(),
+                    texture: Arc::new(ConstColorTexture::new(c, Vec3::ZERO)),
+                })]))
+            })
+            .collect();
+        objects.push(Instance::new(Arc::new([Arc::new(Quad::new(
+            Vec3::new(-10.0, -1.0, -10.0),
+            Vec3::new(20.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 20.0),
+            LAMBERTIAN.clone(),
+            Vec3::ZERO,
+            Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.5, Vec3::ZERO)),
+        ))])));
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![Arc::new(PointLight::new(
+            Vec3::UP * 5.0,
+            Vec3::WHITE * 60.0,
+        ))];
+        Viewport::new(
+            cam,
+            Scene::new(objects, 0.001, 1000.0).with_lights(lights),
+            Arc::new(next_event_ray_color),
+            width,
+            height,
+            4,
+            4,
+            Vec3::new(0.4, 0.5, 0.7),
+            2.2,
+        )
+    }
+
+    #[test]
+    fn projections_test() -> ImageResult<()> {
+        let eye = Vec3::new(0.0, 0.5, 0.0);
+        render(
+            Orthographic::new(2.0, Vec3::UP * 8.0, Vec3::FORWARD, Vec3::DOWN, 8.0),
+            160,
+            80,
+        )
+        .render()
+        .save("test_out/projection_orthographic_test.png")?;
+        render(
+            Fisheye::new(1.0, eye, Vec3::UP, Vec3::FORWARD, 220.0),
+            100,
+            100,
+        )
+        .render()
+        .save("test_out/projection_fisheye_test.png")?;
+        render(Equirectangular::new(eye, Vec3::UP, Vec3::FORWARD), 200, 100)
+            .render()
+            .save("test_out/projection_equirectangular_test.png")?;
+        render(CubeMap::new(eye, Vec3::UP, Vec3::FORWARD), 300, 50)
+            .render()
+            .save("test_out/projection_cube_map_test.png")
+    }
+}
@@ -1,7 +1,12 @@
 use std::sync::Arc;
 
 use crate::{
-    objects::{aabb::maxf, Object},
+    objects::{
+        aabb::maxf,
+        hit::{Hit, HitObject},
+        light::Light,
+        Object,
+    },
     vec3::{ray::Ray, vec3::Vec3},
 };
 
@@ -31,7 +36,7 @@ pub(crate) fn ray_color(r: Ray, vp: Arc<Viewport>, depth: usize) -> Vec3 {
         }
         None => {
             // dbg!(vp.bg_color);
-            return vp.bg_color;
+            return vp.background(&r);
         }
     }
 }
@@ -71,7 +76,7 @@ pub(crate) fn light_biased_ray_cast(
                     z: aabb.2.mid_point(),
                 };
                 let to_light = (middle_l - h.p).unit();
-                let r = Ray::new(h.p, to_light);
+                let r = Ray::new_with_time(h.p, to_light, h.r.time);
                 // debug_assert!(
                 //     r.direction.is_normal(),
                 //     "rc, dir is nan {:?} -> {:?} -> {:?}",
@@ -91,6 +96,12 @@ pub(crate) fn light_biased_ray_cast(
                     _ => {}
                 }
             }
+            for l in vp.s.lights.iter() {
+                if let Some(c) = sample_light(&h, &o, &vp, l.as_ref()) {
+                    count += 1;
+                    color += c;
+                }
+            }
             // debug_assert_ne!(count, 0, "No lights hit");
             let ret = (if count != 0 {
                 color.field_wise_mult(o_color.multiplied) / count as f32
@@ -103,7 +114,7 @@ pub(crate) fn light_biased_ray_cast(
             );
             return ret;
         }
-        None => return vp.bg_color,
+        None => return vp.background(&r),
     }
 }
 
@@ -134,7 +145,7 @@ pub(crate) fn light_biased_ray_color(
                     z: aabb.2.mid_point(),
                 };
                 let to_light = (middle_l - h.p).unit();
-                let r = Ray::new(h.p, to_light);
+                let r = Ray::new_with_time(h.p, to_light, h.r.time);
                 match (vp.s.get_hit(r), l.get_hit(r, vp.s.mint, vp.s.maxt)) {
                     (Some(hr), Some(hl)) => {
                         if hr.0 == hl {
@@ -151,6 +162,12 @@ pub(crate) fn light_biased_ray_color(
                     _ => {}
                 }
             }
+            for l in vp.s.lights.iter() {
+                if let Some(c) = sample_light(&h, &o, &vp, l.as_ref()) {
+                    count += biased_weight;
+                    color += c * biased_weight;
+                }
+            }
             // debug_assert_ne!(count, 0, "No lights hit");
             let ret = color.field_wise_mult(o_color.multiplied) / count as f32 + o_color.emmited;
             debug_assert!(
@@ -159,6 +176,185 @@ pub(crate) fn light_biased_ray_color(
             );
             return ret;
         }
-        None => return vp.bg_color,
+        None => return vp.background(&r),
+    }
+}
+
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b <= 0.0 {
+        return 0.0;
+    }
+    a / (a + b)
+}
+
+// Light arriving at `h` from the environment map, sampled with multiple importance sampling
+fn sample_environment(h: &Hit, o: &HitObject, vp: &Viewport) -> Vec3 {
+    let env = match &vp.environment {
+        Some(env) => env,
+        None => return Vec3::ZERO,
+    };
+    let (dir, light_pdf) = env.sample();
+    if light_pdf <= 0.0 {
+        return Vec3::ZERO;
+    }
+    let shadow = Ray::new_with_time(h.p, dir, h.r.time);
+    let f = o.material_pdf(h, &shadow);
+    if f <= 0.0 || vp.s.get_hit(shadow).is_some() {
+        return Vec3::ZERO;
+    }
+    let weight = power_heuristic(light_pdf, o.generator_pdf(h, &shadow));
+    env.radiance(dir) * (f * weight / light_pdf)
+}
+
+// Light arriving at `h` from one sample of `l`, weighted against bsdf sampling unless `l` is a delta light.
+// `None` when the light does not reach `h`
+fn sample_light(
+    h: &Hit,
+    o: &HitObject,
+    vp: &Viewport,
+    l: &(dyn Light + Send + Sync),
+) -> Option<Vec3> {
+    let s = l.sample(h.p)?;
+    if s.pdf <= 0.0 || s.radiance.close_to_zero() {
+        return None;
+    }
+    let shadow = Ray::new_with_time(h.p, s.dir, h.r.time);
+    let f = o.material_pdf(h, &shadow);
+    if f <= 0.0 || vp.s.occluded(shadow, s.dist) {
+        return None;
+    }
+    let weight = if l.pdf(h.p, s.dir) > 0.0 {
+        power_heuristic(s.pdf, o.generator_pdf(h, &shadow))
+    } else {
+        1.0
+    };
+    Some(s.radiance * (f * weight / s.pdf))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Bounce {
+    Camera,
+    Specular,
+    // density of the sampled direction
+    Diffuse(f32),
+}
+
+// Emission of the area lights `r` hits before `maxt`
+pub(super) fn area_light_emission(r: &Ray, vp: &Viewport, maxt: f32, prev: Bounce) -> Vec3 {
+    let mut light = Vec3::ZERO;
+    let length = r.direction.length();
+    for l in vp.s.lights.iter() {
+        let Some((t, radiance)) = l.intersect(r) else {
+            continue;
+        };
+        if t >= maxt * length {
+            continue;
+        }
+        light += match prev {
+            Bounce::Camera if !l.visible_to_camera() => Vec3::ZERO,
+            Bounce::Camera | Bounce::Specular => radiance,
+            Bounce::Diffuse(pdf) => radiance * power_heuristic(pdf, l.pdf(r.origin, r.direction)),
+        };
     }
+    light
+}
+
+/// Light carried by a camera path, split by the number of bounces it took to get there
+#[derive(Debug, Clone, Copy)]
+pub(super) struct PathLight {
+    // emitted by the first vertex, or the background behind it
+    pub emitted: Vec3,
+    // from the lights after a single bounce
+    pub direct: Vec3,
+    // after two or more bounces
+    pub indirect: Vec3,
+}
+
+impl PathLight {
+    pub(super) const ZERO: PathLight = PathLight {
+        emitted: Vec3::ZERO,
+        direct: Vec3::ZERO,
+        indirect: Vec3::ZERO,
+    };
+
+    pub fn total(&self) -> Vec3 {
+        self.emitted + self.direct + self.indirect
+    }
+}
+
+// `prev` is how the ray was generated, it decides the weight of lights hit by the ray
+pub(super) fn next_event_path(r: Ray, vp: Arc<Viewport>, depth: usize, prev: Bounce) -> PathLight {
+    if depth == 0 {
+        return PathLight::ZERO;
+    }
+    next_event_hit(r, vp.s.get_hit(r), vp.clone(), depth, prev)
+}
+
+/// `next_event_path` with the closest hit of `r` already found, `depth` has to be at least 1
+pub(super) fn next_event_hit(
+    r: Ray,
+    hit: Option<(Hit, HitObject)>,
+    vp: Arc<Viewport>,
+    depth: usize,
+    prev: Bounce,
+) -> PathLight {
+    match hit {
+        Some((h, o)) => {
+            let color = o.color(&h);
+            let mut light = PathLight {
+                emitted: color.emmited + area_light_emission(&r, &vp, h.t, prev),
+                ..PathLight::ZERO
+            };
+
+            let specular = o.is_specular(&h);
+            if !specular {
+                let mut direct = sample_environment(&h, &o, &vp);
+                for l in vp.s.lights.iter() {
+                    direct += sample_light(&h, &o, &vp, l.as_ref()).unwrap_or(Vec3::ZERO);
+                }
+                light.direct = direct.field_wise_mult(color.multiplied);
+            }
+
+            let reflect = o.reflect(&h);
+            let (throughput, next) = if specular {
+                (color.multiplied, Bounce::Specular)
+            } else {
+                let pdf = o.generator_pdf(&h, &reflect);
+                if pdf <= 0.0 {
+                    return light;
+                }
+                (
+                    color.multiplied * (o.material_pdf(&h, &reflect) / pdf),
+                    Bounce::Diffuse(pdf),
+                )
+            };
+            if throughput.close_to_zero() {
+                return light;
+            }
+            let rest = next_event_path(reflect, vp, depth - 1, next);
+            light.direct += rest.emitted.field_wise_mult(throughput);
+            light.indirect = (rest.direct + rest.indirect).field_wise_mult(throughput);
+            light
+        }
+        None => {
+            let background = vp.background(&r);
+            let background = match (prev, &vp.environment) {
+                (Bounce::Diffuse(pdf), Some(env)) => {
+                    background * power_heuristic(pdf, env.pdf(r.direction))
+                }
+                _ => background,
+            };
+            PathLight {
+                emitted: background + area_light_emission(&r, &vp, f32::INFINITY, prev),
+                ..PathLight::ZERO
+            }
+        }
+    }
+}
+
+/// Path tracer with next event estimation of the environment map and the scene lights
+#[allow(unused)]
+pub(crate) fn next_event_ray_color(r: Ray, vp: Arc<Viewport>, depth: usize) -> Vec3 {
+    next_event_path(r, vp, depth, Bounce::Camera).total()
 }
@@ -1,8 +1,17 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use crate::{
-    objects::{aabb::AABB, hit::Hit, instance::Instance, Object},
-    vec3::ray::Ray,
+    objects::{
+        aabb::{maxf, Interval, AABB},
+        hit::{Hit, HitObject},
+        instance::Instance,
+        light::{EmitterLight, Light},
+        prototype::Numbered,
+    },
+    vec3::{ray::Ray, vec3::Vec3},
 };
 
 #[derive(Clone)]
@@ -11,18 +20,96 @@ pub struct Scene {
     aabb: AABB,
     pub(crate) mint: f32,
     pub(crate) maxt: f32,
+    pub(crate) lights: Vec<Arc<dyn Light + Send + Sync>>,
+    // objects with glowing textures, light tracing starts on them as well
+    pub(crate) emitters: Vec<Arc<dyn Light + Send + Sync>>,
+    ids: Arc<Ids>,
+}
+
+// numbers of the objects and materials for the id AOVs, counted from 1 in the order the
+// scene was built so they are the same in every render of it
+#[derive(Default)]
+struct Ids {
+    objects: HashMap<usize, usize>,
+    materials: HashMap<usize, usize>,
+}
+
+impl Ids {
+    fn add(&mut self, n: Numbered) {
+        let (ids, key) = match n {
+            Numbered::Object(key) => (&mut self.objects, key),
+            Numbered::Material(key) => (&mut self.materials, key),
+        };
+        let next = ids.len() + 1;
+        ids.entry(key).or_insert(next);
+    }
 }
+
 impl Scene {
-    pub fn get_hit(&self, r: Ray) -> Option<(Hit, Arc<dyn Object + Send + Sync>)> {
-        self.aabb.get_hit(r, self)
+    pub fn get_hit(&self, r: Ray) -> Option<(Hit, HitObject<'_>)> {
+        self.aabb.get_hit(r, self.mint, self.maxt)
     }
 
     pub(crate) fn new(objects: Vec<Instance>, mint: f32, maxt: f32) -> Self {
+        // moving instances emit from where they are at time 0
+        let mut emitters = vec![];
+        for i in objects.iter() {
+            i.prototype().emitters(i.matrix_at(0.0), &mut |s, m| {
+                if let Some(l) = EmitterLight::new(s.clone(), m) {
+                    emitters.push(Arc::new(l) as Arc<dyn Light + Send + Sync>);
+                }
+            });
+        }
+        let mut ids = Ids::default();
+        let mut seen = HashSet::new();
+        for i in objects.iter() {
+            i.number(&mut seen, &mut |n| ids.add(n));
+        }
         Self {
             // objects: objects.clone(),
             aabb: AABB::new(objects),
             mint,
             maxt,
+            lights: vec![],
+            emitters,
+            ids: Arc::new(ids),
+        }
+    }
+
+    /// Number of the object hit, 0 for objects that weren't in the scene when it was built
+    pub(crate) fn object_id(&self, o: &HitObject) -> usize {
+        let key = o.identity() as usize;
+        self.ids.objects.get(&key).copied().unwrap_or(0)
+    }
+
+    /// Number of the material of the object hit, 0 when it has none
+    pub(crate) fn material_id(&self, o: &HitObject) -> usize {
+        o.material()
+            .and_then(|m| {
+                let key = Arc::as_ptr(m) as *const () as usize;
+                self.ids.materials.get(&key).copied()
+            })
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn with_lights(mut self, lights: Vec<Arc<dyn Light + Send + Sync>>) -> Self {
+        self.lights = lights;
+        self
+    }
+
+    /// Whether anything in the scene blocks the segment from `r.origin` along `r.direction` up to `dist`
+    pub(crate) fn occluded(&self, r: Ray, dist: f32) -> bool {
+        match self.get_hit(r) {
+            Some((h, _)) => h.t * r.direction.length() < dist * (1.0 - 1e-4),
+            None => false,
         }
     }
+
+    /// Center and radius of a sphere around all objects
+    pub(crate) fn bounding_sphere(&self) -> (Vec3, f32) {
+        let (min, max) =
+            Interval::intervals_to_bounding_vecs(self.aabb.x, self.aabb.y, self.aabb.z);
+        let center = (min + max) * 0.5;
+        (center, maxf((max - center).length(), 1e-3))
+    }
 }
@@ -0,0 +1,136 @@
+use std::f32::consts::PI;
+
+use crate::{onb::ONB, sampler::random_f32, vec3::vec3::Vec3};
+
+use super::environment::Environment;
+
+// the sky model is shared with the Rust crate
+pub use raytracing_shared::sky::PreethamSky;
+
+/// The sun is a small cone light that is sampled together with the sky
+impl Environment for PreethamSky {
+    fn radiance(&self, dir: Vec3) -> Vec3 {
+        PreethamSky::radiance(self, dir).into()
+    }
+
+    fn pdf(&self, dir: Vec3) -> f32 {
+        let dir = dir.unit();
+        let p_sun = self.sun_probability();
+        let mut pdf = (1.0 - p_sun) / (4.0 * PI);
+        if self.in_sun(dir) {
+            pdf += p_sun / (2.0 * PI * self.sun_cone());
+        }
+        pdf
+    }
+
+    fn sample(&self) -> (Vec3, f32) {
+        let dir = if random_f32() < self.sun_probability() {
+            // uniform direction inside the sun cone
+            let one_minus_cos = random_f32() * self.sun_cone();
+            let cos = 1.0 - one_minus_cos;
+            let sin = (one_minus_cos * (2.0 - one_minus_cos)).max(0.0).sqrt();
+            let phi = random_f32() * 2.0 * PI;
+            ONB::new_from_w(self.sun_dir().into()).from_local(Vec3::new(
+                sin * phi.cos(),
+                sin * phi.sin(),
+                cos,
+            ))
+        } else {
+            Vec3::random_unit_vec()
+        };
+        (dir, self.pdf(dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use image::ImageResult;
+
+    use crate::{
+        objects::{
+            instance::Instance, material::LAMBERTIAN, quad::Quad, sphere::Sphere,
+            texture::ConstColorTexture,
+        },
+        vec3::vec3::Vec3,
+        viewport::{
+            camera::Camera, environment::Environment, ray_color::next_event_ray_color,
+            scene::Scene, Viewport,
+        },
+    };
+
+    use super::PreethamSky;
+
+    #[test]
+    fn sun_samples_match_pdf() {
+        let sky =
+            PreethamSky::new(Vec3::new(0.3, 0.2, 1.0), 4.0, Vec3::WHITE * 0.3).with_sun(0.01, 1.0);
+        let mut in_sun = 0;
+        for _ in 0..1000 {
+            let (dir, pdf) = sky.sample();
+            assert!((pdf - sky.pdf(dir)).abs() <= 1e-3 * pdf);
+            if sky.in_sun(dir) {
+                in_sun += 1;
+            }
+        }
+        assert!(in_sun > 400 && in_sun < 600, "{}", in_sun);
+    }
+
+    #[test]
+    fn sky_test() -> ImageResult<()> {
+        const WIDTH: usize = 300;
+        const HEIGHT: usize = 200;
+        let grey = Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.7, Vec3::ZERO));
+        let scene = Scene::new(
+            vec![
+                Instance::new(Arc::new([Arc::new(Sphere {
+                    origin: Vec3::new(0.0, 0.0, 3.0),
+                    radius: 0.7,
+                    mat: LAMBERTIAN.clone(),
+                    texture: grey.clone(),
+                })])),
+                Instance::new(Arc::new([Arc::new(Quad::new(
+                    Vec3::new(-5.0, -0.7, -2.0),
+                    Vec3::new(10.0, 0.0, 0.0),
+                    Vec3::new(0.0, 0.0, 10.0),
+                    LAMBERTIAN.clone(),
+                    Vec3::ZERO,
+                    grey,
+                ))])),
+            ],
+            0.001,
+            1000.0,
+        );
+        let cam = Camera::new(
+            WIDTH as f32 / HEIGHT as f32,
+            Vec3::ZERO,
+            Vec3::UP,
+            Vec3::FORWARD + Vec3::UP * 0.1,
+            70.0,
+            0.0,
+        );
+        for (name, sun) in [
+            ("noon", Vec3::new(0.5, 2.0, 0.5)),
+            ("sunset", Vec3::new(-1.0, 0.08, 0.6)),
+        ] {
+            let vp = Viewport::new(
+                cam.clone(),
+                scene.clone(),
+                Arc::new(next_event_ray_color),
+                WIDTH,
+                HEIGHT,
+                16,
+                5,
+                Vec3::ZERO,
+                2.0,
+            )
+            .with_environment(Arc::new(
+                PreethamSky::new(sun, 3.0, Vec3::new(0.3, 0.25, 0.2)).with_sun(0.02, 1.0),
+            ));
+            vp.render_rows_async()
+                .save(format!("test_out/sky_{}.png", name))?;
+        }
+        Ok(())
+    }
+}
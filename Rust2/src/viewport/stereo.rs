@@ -0,0 +1,274 @@
+use std::{f32::consts::PI, sync::Arc};
+
+use crate::onb::ONB;
+use crate::vec3::{ray::Ray, vec3::Vec3};
+
+use super::camera::{look_at, Camera, Projection};
+
+/// How the views are placed next to each other in the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    SideBySide,
+    TopBottom,
+}
+
+/// Several projections rendered into one image, each one gets an equal slice of it.
+/// Every view should have the aspect ratio of its slice
+#[derive(Clone)]
+pub struct MultiView {
+    views: Vec<Arc<dyn Projection + Send + Sync>>,
+    layout: Layout,
+}
+
+impl MultiView {
+    pub fn new(views: Vec<Arc<dyn Projection + Send + Sync>>, layout: Layout) -> Self {
+        assert!(
+            !views.is_empty(),
+            "a multi view image needs at least one view"
+        );
+        Self { views, layout }
+    }
+
+    /// Left and right eye views of `cam` `interocular` apart, objects `convergence` away
+    /// from the camera appear at the depth of the screen
+    pub fn stereo(cam: &Camera, interocular: f32, convergence: f32, layout: Layout) -> Self {
+        let half = interocular * 0.5;
+        Self::new(
+            vec![
+                Arc::new(cam.eye(-half, convergence)),
+                Arc::new(cam.eye(half, convergence)),
+            ],
+            layout,
+        )
+    }
+
+    /// Omni-directional stereo panoramas of both eyes for viewing in VR headsets
+    pub fn omni_stereo(
+        origin: Vec3,
+        vup: Vec3,
+        dir: Vec3,
+        interocular: f32,
+        layout: Layout,
+    ) -> Self {
+        let half = interocular * 0.5;
+        Self::new(
+            vec![
+                Arc::new(OmniStereo::new(origin, vup, dir, -half)),
+                Arc::new(OmniStereo::new(origin, vup, dir, half)),
+            ],
+            layout,
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.views.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.views.is_empty()
+    }
+}
+
+impl Projection for MultiView {
+    fn ray(&self, u: f32, v: f32) -> Option<(Ray, f32)> {
+        let n = self.views.len() as f32;
+        match self.layout {
+            Layout::SideBySide => {
+                let i = ((u * n) as usize).min(self.views.len() - 1);
+                self.views[i].ray(u * n - i as f32, v)
+            }
+            Layout::TopBottom => {
+                let i = ((v * n) as usize).min(self.views.len() - 1);
+                self.views[i].ray(u, v * n - i as f32)
+            }
+        }
+    }
+
+    fn spread(&self) -> f32 {
+        match self.layout {
+            Layout::SideBySide => self.views[0].spread(),
+            // every view only gets a part of the height
+            Layout::TopBottom => self.views[0].spread() * self.views.len() as f32,
+        }
+    }
+}
+
+/// Equirectangular panorama of one eye, rays start on the circle the eyes sweep while turning
+/// the head around `origin`. `offset` is the distance of the eye to the right of the middle.
+/// The eyes move together towards the poles where the head would have to tilt
+#[derive(Clone)]
+pub struct OmniStereo {
+    origin: Vec3,
+    onb: ONB,
+    offset: f32,
+}
+
+impl OmniStereo {
+    pub fn new(origin: Vec3, vup: Vec3, dir: Vec3, offset: f32) -> Self {
+        Self {
+            origin,
+            onb: look_at(vup, dir),
+            offset,
+        }
+    }
+}
+
+impl Projection for OmniStereo {
+    fn ray(&self, u: f32, v: f32) -> Option<(Ray, f32)> {
+        let longitude = (u - 0.5) * 2.0 * PI;
+        let latitude = (0.5 - v) * PI;
+        let forward = -self.onb.w * longitude.cos() + self.onb.u * longitude.sin();
+        let right = self.onb.u * longitude.cos() + self.onb.w * longitude.sin();
+        let dir = forward * latitude.cos() + self.onb.v * latitude.sin();
+        let origin = self.origin + right * (self.offset * latitude.cos());
+        Some((Ray::new(origin, dir), 1.0))
+    }
+
+    fn spread(&self) -> f32 {
+        PI
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use image::ImageResult;
+
+    use crate::{
+        objects::{
+            instance::Instance,
+            light::{Light, PointLight},
+            material::LAMBERTIAN,
+            quad::Quad,
+            sphere::Sphere,
+            texture::ConstColorTexture,
+        },
+        vec3::{ray::Ray, vec3::Vec3},
+        viewport::{
+            camera::{Camera, Projection},
+            ray_color::next_event_ray_color,
+            scene::Scene,
+            Viewport,
+        },
+    };
+
+    use super::{Layout, MultiView, OmniStereo};
+
+    fn ray(p: &impl Projection, u: f32, v: f32) -> Ray {
+        p.ray(u, v).unwrap().0
+    }
+
+    fn camera(aspect: f32) -> Camera {
+        Camera::new(aspect, Vec3::ZERO, Vec3::UP, Vec3::FORWARD, 60.0, 0.0)
+    }
+
+    #[test]
+    fn eyes_converge() {
+        let stereo = MultiView::stereo(&camera(1.0), 0.064, 2.0, Layout::SideBySide);
+        let left = ray(&stereo, 0.25, 0.5);
+        let right = ray(&stereo, 0.75, 0.5);
+        assert_eq!(left.origin, Vec3::LEFT * 0.032);
+        assert_eq!(right.origin, Vec3::RIGHT * 0.032);
+        // the middle of both images is the same point at the convergence distance
+        let meet = |r: Ray| r.at(2.0 / r.direction.dot(Vec3::FORWARD));
+        assert!((meet(left) - Vec3::FORWARD * 2.0).length() < 1e-5);
+        assert!((meet(right) - Vec3::FORWARD * 2.0).length() < 1e-5);
+        // the views stay parallel
+        let top_left = ray(&stereo, 0.0, 0.0).direction;
+        let top_right = ray(&stereo, 0.5, 0.0).direction;
+        assert!((top_left.y - top_right.y).abs() < 1e-5);
+    }
+
+    #[test]
+    fn layouts() {
+        let views: Vec<Arc<dyn Projection + Send + Sync>> = vec![
+            Arc::new(camera(1.0)),
+            Arc::new(camera(1.0).eye(1.0, 1.0)),
+            Arc::new(camera(1.0).eye(2.0, 1.0)),
+        ];
+        let row = MultiView::new(views.clone(), Layout::SideBySide);
+        let column = MultiView::new(views, Layout::TopBottom);
+        for i in 0..3 {
+            let middle = (i as f32 + 0.5) / 3.0;
+            assert_eq!(ray(&row, middle, 0.5).origin, Vec3::RIGHT * i as f32);
+            assert_eq!(ray(&column, 0.5, middle).origin, Vec3::RIGHT * i as f32);
+        }
+        assert_eq!(column.spread(), row.spread() * 3.0);
+    }
+
+    #[test]
+    fn omni_stereo_eyes() {
+        let half = 0.032;
+        let left = OmniStereo::new(Vec3::ZERO, Vec3::UP, Vec3::FORWARD, -half);
+        let right = OmniStereo::new(Vec3::ZERO, Vec3::UP, Vec3::FORWARD, half);
+        for u in [0.1, 0.3, 0.5, 0.8] {
+            let (l, r) = (ray(&left, u, 0.5), ray(&right, u, 0.5));
+            // both eyes look the same way from opposite sides of the head
+            assert_eq!(l.direction, r.direction);
+            assert!(((l.origin - r.origin).length() - 2.0 * half).abs() < 1e-6);
+            assert!((l.origin - r.origin).dot(l.direction).abs() < 1e-6);
+        }
+        // looking forward the right eye is on the right
+        assert_eq!(ray(&right, 0.5, 0.5).origin, Vec3::RIGHT * half);
+        // and both eyes meet at the poles
+        assert_eq!(ray(&left, 0.2, 0.0).origin, Vec3::ZERO);
+        assert_eq!(ray(&right, 0.7, 1.0).direction.unit(), Vec3::DOWN);
+    }
+
+    fn scene(cam: MultiView, width: usize, height: usize) -> Viewport {
+        let colors = [
+            Vec3::new(0.8, 0.2, 0.2),
+            Vec3::new(0.2, 0.8, 0.2),
+            Vec3::new(0.2, 0.2, 0.8),
+        ];
+        let mut objects: Vec<_> = colors
+            .into_iter()
+            .enumerate()
+            .map(|(i, c)| {
+                Instance::new(Arc::new([Arc::new(Sphere {
+                    origin: Vec3::new(i as f32 - 1.0, -0.5, 1.5 + i as f32),
+                    radius: 0.5,
+                    mat: LAMBERTIAN.clone(),
+                    texture: Arc::new(ConstColorTexture::new(c, Vec3::ZERO)),
+                })]))
+            })
+            .collect();
+        objects.push(Instance::new(Arc::new([Arc::new(Quad::new(
+            Vec3::new(-10.0, -1.0, -10.0),
+            Vec3::new(20.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 20.0),
+            LAMBERTIAN.clone(),
+            Vec3::ZERO,
+            Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.5, Vec3::ZERO)),
+        ))])));
+        let lights: Vec<Arc<dyn Light + Send + Sync>> = vec![Arc::new(PointLight::new(
+            Vec3::new(0.0, 4.0, 1.0),
+            Vec3::WHITE * 40.0,
+        ))];
+        Viewport::new(
+            cam,
+            Scene::new(objects, 0.001, 1000.0).with_lights(lights),
+            Arc::new(next_event_ray_color),
+            width,
+            height,
+            4,
+            4,
+            Vec3::new(0.4, 0.5, 0.7),
+            2.2,
+        )
+    }
+
+    #[test]
+    fn stereo_test() -> ImageResult<()> {
+        let stereo = MultiView::stereo(&camera(1.0), 0.2, 2.5, Layout::SideBySide);
+        scene(stereo, 200, 100)
+            .render()
+            .save("test_out/stereo_side_by_side_test.png")?;
+        let ods =
+            MultiView::omni_stereo(Vec3::ZERO, Vec3::UP, Vec3::FORWARD, 0.2, Layout::TopBottom);
+        scene(ods, 160, 160)
+            .render()
+            .save("test_out/stereo_omni_directional_test.png")
+    }
+}
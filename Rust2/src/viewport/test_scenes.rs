@@ -0,0 +1,108 @@
+use std::sync::Arc;
+
+use rand::random;
+
+use crate::{
+    objects::{
+        instance::Instance,
+        light::{Light, RectLight},
+        material::LAMBERTIAN,
+        quad::Quad,
+        sphere::Sphere,
+        texture::ConstColorTexture,
+    },
+    vec3::vec3::Vec3,
+    viewport::{camera::Camera, ray_color::RayColor, scene::Scene, Viewport},
+};
+
+// size of the images the integrators are compared on
+pub(crate) const WIDTH: usize = 32;
+pub(crate) const HEIGHT: usize = 24;
+
+/// Grey sphere 3 units in front of the origin, resting on a grey floor
+pub(crate) fn sphere_on_floor() -> Vec<Instance> {
+    let grey = Arc::new(ConstColorTexture::new(Vec3::WHITE * 0.5, Vec3::ZERO));
+    vec![
+        Instance::new(Arc::new([Arc::new(Sphere {
+            origin: Vec3::new(0.0, 0.0, 3.0),
+            radius: 0.5,
+            mat: LAMBERTIAN.clone(),
+            texture: grey.clone(),
+        })])),
+        Instance::new(Arc::new([Arc::new(Quad::new(
+            Vec3::new(-5.0, -0.5, -2.0),
+            Vec3::new(10.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 10.0),
+            LAMBERTIAN.clone(),
+            Vec3::ZERO,
+            grey,
+        ))])),
+    ]
+}
+
+/// Square light facing down above the sphere, the camera doesn't see it
+pub(crate) fn ceiling_light() -> Arc<dyn Light + Send + Sync> {
+    Arc::new(
+        RectLight::new(
+            Vec3::new(-0.5, 1.5, 2.5),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::WHITE,
+        )
+        .with_visibility(false),
+    )
+}
+
+/// `WIDTH` x `HEIGHT` view from the origin along `FORWARD`, 16 samples and 4 bounces
+pub(crate) fn small_viewport(
+    objects: Vec<Instance>,
+    lights: Vec<Arc<dyn Light + Send + Sync>>,
+    rc: RayColor,
+) -> Viewport {
+    let cam = Camera::new(
+        WIDTH as f32 / HEIGHT as f32,
+        Vec3::ZERO,
+        Vec3::UP,
+        Vec3::FORWARD,
+        60.0,
+        0.0,
+    );
+    Viewport::new(
+        cam,
+        Scene::new(objects, 0.001, 1000.0).with_lights(lights),
+        rc,
+        WIDTH,
+        HEIGHT,
+        16,
+        4,
+        Vec3::ZERO,
+        1.0,
+    )
+}
+
+/// Mean radiance over the image of a `small_viewport`, including the light splatted onto it
+pub(crate) fn mean_radiance(mut vp: Viewport, samples: usize) -> Vec3 {
+    vp.reset_splats();
+    let vp = Arc::new(vp);
+    let mut sum = Vec3::ZERO;
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            for _ in 0..samples {
+                let (r, _) = vp
+                    .cam
+                    .ray(
+                        (x as f32 + random::<f32>()) / WIDTH as f32,
+                        (y as f32 + random::<f32>()) / HEIGHT as f32,
+                    )
+                    .unwrap();
+                sum += (vp.rc)(r, vp.clone(), vp.recursion_depth);
+            }
+        }
+    }
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            sum += vp.splat_at(x, y);
+        }
+    }
+    sum / (WIDTH * HEIGHT * samples) as f32
+}
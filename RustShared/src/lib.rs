@@ -0,0 +1,3 @@
+pub mod sky;
+mod vec3;
+pub mod video;
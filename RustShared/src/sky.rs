@@ -0,0 +1,253 @@
+use std::f32::consts::{FRAC_PI_2, PI};
+
+use crate::vec3::Vec3;
+
+// Perez distribution coefficients A..E as `[slope, offset]` for the turbidity, from Preetham et al. 1999
+const PEREZ_Y: [[f32; 2]; 5] = [
+    [0.1787, -1.4630],
+    [-0.3554, 0.4275],
+    [-0.0227, 5.3251],
+    [0.1206, -2.5771],
+    [-0.0670, 0.3703],
+];
+const PEREZ_X: [[f32; 2]; 5] = [
+    [-0.0193, -0.2592],
+    [-0.0665, 0.0008],
+    [-0.0004, 0.2125],
+    [-0.0641, -0.8989],
+    [-0.0033, 0.0452],
+];
+const PEREZ_YC: [[f32; 2]; 5] = [
+    [-0.0167, -0.2608],
+    [-0.0950, 0.0092],
+    [-0.0079, 0.2102],
+    [-0.0441, -1.6537],
+    [-0.0109, 0.0529],
+];
+// zenith chromaticity, rows are multiplied by T^2, T, 1 and columns by theta^3, theta^2, theta, 1
+const ZENITH_X: [[f32; 4]; 3] = [
+    [0.00166, -0.00375, 0.00209, 0.0],
+    [-0.02903, 0.06377, -0.03202, 0.00394],
+    [0.11693, -0.21196, 0.06052, 0.25886],
+];
+const ZENITH_Y: [[f32; 4]; 3] = [
+    [0.00275, -0.00610, 0.00317, 0.0],
+    [-0.04214, 0.08970, -0.04153, 0.00516],
+    [0.15346, -0.26756, 0.06670, 0.26688],
+];
+// extraterrestrial solar illuminance in klux, the same unit as the zenith luminance in kcd/m^2
+const SOLAR_ILLUMINANCE: f32 = 128.0;
+
+fn perez(c: &[f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+    (1.0 + c[0] * (c[1] / cos_theta).exp())
+        * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos() * gamma.cos())
+}
+
+fn xyy_to_rgb(x: f32, y: f32, lum: f32) -> Vec3 {
+    let cx = x / y * lum;
+    let cz = (1.0 - x - y) / y * lum;
+    Vec3::new(
+        3.2406 * cx - 1.5372 * lum - 0.4986 * cz,
+        -0.9689 * cx + 1.8758 * lum + 0.0415 * cz,
+        0.0557 * cx - 0.2040 * lum + 1.0570 * cz,
+    )
+}
+
+/// Preetham daylight sky with a sun disk, +y is the zenith. Directions and colors are `[f32; 3]`
+#[derive(Clone, Debug)]
+pub struct PreethamSky {
+    sun_dir: Vec3,
+    turbidity: f32,
+    ground_albedo: Vec3,
+    intensity: f32,
+    // angular radius of the sun disk in radians
+    sun_radius: f32,
+    sun_scale: f32,
+
+    // precomputed from the parameters above
+    coefficients: [[f32; 5]; 3],
+    // zenith value divided by the Perez function at the zenith, for Y, x and y
+    zenith: [f32; 3],
+    sun_radiance: Vec3,
+    ground_radiance: Vec3,
+    // 1 - cos of the sun radius
+    sun_cone: f32,
+}
+
+impl PreethamSky {
+    pub fn new(
+        sun_dir: impl Into<[f32; 3]>,
+        turbidity: f32,
+        ground_albedo: impl Into<[f32; 3]>,
+    ) -> Self {
+        let mut sky = Self {
+            sun_dir: Vec3::from(sun_dir.into()).unit(),
+            turbidity: turbidity.clamp(1.7, 10.0),
+            ground_albedo: ground_albedo.into().into(),
+            intensity: 0.05,
+            sun_radius: 0.00465,
+            sun_scale: 1.0,
+            coefficients: [[0.0; 5]; 3],
+            zenith: [0.0; 3],
+            sun_radiance: Vec3::ZERO,
+            ground_radiance: Vec3::ZERO,
+            sun_cone: 0.0,
+        };
+        sky.precompute();
+        sky
+    }
+
+    /// Scales the whole sky, the default maps the zenith luminance in kcd/m^2 to about 0.05 per unit
+    pub fn with_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self.precompute();
+        self
+    }
+    /// Angular radius of the sun in radians and a multiplier of its brightness, 0 removes the sun
+    pub fn with_sun(mut self, radius: f32, scale: f32) -> Self {
+        self.sun_radius = radius.max(1e-4);
+        self.sun_scale = scale;
+        self.precompute();
+        self
+    }
+
+    fn sun_theta(&self) -> f32 {
+        self.sun_dir.y.clamp(0.0, 1.0).acos()
+    }
+
+    fn precompute(&mut self) {
+        let t = self.turbidity;
+        let theta_s = self.sun_theta();
+
+        for (dst, src) in self
+            .coefficients
+            .iter_mut()
+            .zip([&PEREZ_Y, &PEREZ_X, &PEREZ_YC])
+        {
+            for (c, [slope, offset]) in dst.iter_mut().zip(src.iter()) {
+                *c = slope * t + offset;
+            }
+        }
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_lum = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+        let chromaticity = |m: &[[f32; 4]; 3]| {
+            let ts = [t * t, t, 1.0];
+            let th = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.0];
+            let mut acc = 0.0;
+            for (tw, row) in ts.iter().zip(m) {
+                for (thw, c) in th.iter().zip(row) {
+                    acc += tw * c * thw;
+                }
+            }
+            acc
+        };
+        let zenith = [zenith_lum, chromaticity(&ZENITH_X), chromaticity(&ZENITH_Y)];
+        for ((dst, z), c) in self.zenith.iter_mut().zip(zenith).zip(&self.coefficients) {
+            *dst = z / perez(c, 1.0, theta_s);
+        }
+
+        // attenuation of the direct sunlight by Rayleigh and aerosol scattering
+        let theta_deg = theta_s.to_degrees();
+        let air_mass = 1.0 / (theta_s.cos() + 0.15 * (93.885 - theta_deg).powf(-1.253));
+        let beta = 0.04608 * t - 0.04586;
+        let transmittance = |lambda: f32| {
+            (-air_mass * (0.008735 * lambda.powf(-4.08) + beta * lambda.powf(-1.3))).exp()
+        };
+        let half = 0.5 * self.sun_radius;
+        self.sun_cone = 2.0 * half.sin() * half.sin();
+        let solid_angle = 2.0 * PI * self.sun_cone;
+        self.sun_radiance = Vec3::new(
+            transmittance(0.68),
+            transmittance(0.55),
+            transmittance(0.44),
+        ) * (SOLAR_ILLUMINANCE * self.sun_scale * self.intensity / solid_angle);
+        if self.sun_dir.y <= 0.0 {
+            self.sun_radiance = Vec3::ZERO;
+        }
+
+        // ground is a diffuse plane lit by the sky and the sun
+        const STEPS: usize = 32;
+        let mut irradiance = Vec3::ZERO;
+        for i in 0..STEPS {
+            let theta = (i as f32 + 0.5) / STEPS as f32 * FRAC_PI_2;
+            for j in 0..2 * STEPS {
+                let phi = (j as f32 + 0.5) / (2 * STEPS) as f32 * 2.0 * PI;
+                let dir = Vec3::new(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                );
+                let d_omega = theta.sin() * (FRAC_PI_2 / STEPS as f32) * (PI / STEPS as f32);
+                irradiance += self.sky_radiance(dir) * (theta.cos() * d_omega);
+            }
+        }
+        irradiance += self.sun_radiance * (solid_angle * self.sun_dir.y.max(0.0));
+        self.ground_radiance = self.ground_albedo * irradiance * (1.0 / PI);
+    }
+
+    // sky without the sun disk, directions below the horizon get the horizon color
+    fn sky_radiance(&self, dir: Vec3) -> Vec3 {
+        let cos_theta = dir.y.max(0.01);
+        let gamma = dir.dot(self.sun_dir).clamp(-1.0, 1.0).acos();
+        let lum = self.zenith[0] * perez(&self.coefficients[0], cos_theta, gamma);
+        let x = self.zenith[1] * perez(&self.coefficients[1], cos_theta, gamma);
+        let y = self.zenith[2] * perez(&self.coefficients[2], cos_theta, gamma);
+        let rgb = xyy_to_rgb(x, y, lum) * self.intensity;
+        Vec3::new(rgb.x.max(0.0), rgb.y.max(0.0), rgb.z.max(0.0))
+    }
+
+    /// Radiance arriving from direction `dir`, the ground below the horizon
+    pub fn radiance(&self, dir: impl Into<[f32; 3]>) -> [f32; 3] {
+        let dir = Vec3::from(dir.into()).unit();
+        if dir.y < 0.0 {
+            return self.ground_radiance.into();
+        }
+        let sky = self.sky_radiance(dir);
+        if self.in_cone(dir) {
+            (sky + self.sun_radiance).into()
+        } else {
+            sky.into()
+        }
+    }
+
+    /// Unit direction towards the sun
+    pub fn sun_dir(&self) -> [f32; 3] {
+        self.sun_dir.into()
+    }
+    /// 1 - cos of the angular radius of the sun
+    pub fn sun_cone(&self) -> f32 {
+        self.sun_cone
+    }
+    /// Whether the unit direction `dir` points into a visible sun disk
+    pub fn in_sun(&self, dir: impl Into<[f32; 3]>) -> bool {
+        self.in_cone(dir.into().into())
+    }
+    fn in_cone(&self, dir: Vec3) -> bool {
+        self.sun_radiance != Vec3::ZERO && 1.0 - dir.dot(self.sun_dir) <= self.sun_cone
+    }
+    /// Chance of sampling the sun cone instead of the whole sphere
+    pub fn sun_probability(&self) -> f32 {
+        if self.sun_radiance == Vec3::ZERO {
+            0.0
+        } else {
+            0.5
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PreethamSky;
+
+    #[test]
+    fn sky_colors() {
+        let sky = PreethamSky::new([0.0, 0.5, 1.0], 3.0, [0.3, 0.3, 0.3]);
+        let zenith = sky.radiance([0.0, 1.0, 0.0]);
+        assert!(zenith[2] > zenith[0], "zenith should be blue {:?}", zenith);
+        let sun = sky.radiance([0.0, 0.5, 1.0]);
+        assert!(sun[0] > 100.0 * zenith[0], "{:?}", sun);
+        let ground = sky.radiance([0.0, -1.0, 0.0]);
+        assert!(ground[0] > 0.0 && ground[0] < sun[0]);
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use image::{
+    codecs::gif::{GifDecoder, GifEncoder, Repeat},
+    error::{ParameterError, ParameterErrorKind},
+    AnimationDecoder, Delay, DynamicImage, Frame, ImageError, ImageResult, RgbImage,
+};
+
+fn parameter_error(msg: String) -> ImageError {
+    ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::Generic(msg)))
+}
+
+/// Frame rate as a fraction, to a thousandth of a frame per second
+fn fps_ratio(fps: f32) -> (u32, u32) {
+    let gcd = |mut a: u32, mut b: u32| {
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+        a
+    };
+    let num = (fps * 1000.0).round().max(1.0) as u32;
+    let d = gcd(num, 1000);
+    (num / d, 1000 / d)
+}
+
+/// Raw YUV4MPEG2 stream with full resolution 4:4:4 chroma that external encoders can read,
+/// e.g. `ffmpeg -i video.y4m video.mp4`. Colors are converted with BT.601 in the video range
+pub struct Y4mWriter<W: Write> {
+    w: W,
+    width: u32,
+    height: u32,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    pub fn new(mut w: W, width: u32, height: u32, fps: f32) -> ImageResult<Self> {
+        w.write_all(Self::header(width, height, fps).as_bytes())?;
+        Ok(Self { w, width, height })
+    }
+
+    // continues a stream that already has its header
+    fn resume(w: W, width: u32, height: u32) -> Self {
+        Self { w, width, height }
+    }
+
+    fn header(width: u32, height: u32, fps: f32) -> String {
+        let (num, den) = fps_ratio(fps);
+        format!(
+            "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444\n",
+            width, height, num, den
+        )
+    }
+
+    /// Bytes every frame takes up in the stream
+    pub fn frame_size(width: u32, height: u32) -> u64 {
+        b"FRAME\n".len() as u64 + 3 * width as u64 * height as u64
+    }
+
+    pub fn write_frame(&mut self, img: &RgbImage) -> ImageResult<()> {
+        if img.dimensions() != (self.width, self.height) {
+            return Err(parameter_error(format!(
+                "frame is {:?}, the video is {}x{}",
+                img.dimensions(),
+                self.width,
+                self.height
+            )));
+        }
+        let n = (self.width * self.height) as usize;
+        let mut planes = vec![0u8; 3 * n];
+        for (i, p) in img.pixels().enumerate() {
+            let [r, g, b] = p.0.map(|c| c as f32 / 255.0);
+            planes[i] = (16.0 + 65.481 * r + 128.553 * g + 24.966 * b).round() as u8;
+            planes[n + i] = (128.0 - 37.797 * r - 74.203 * g + 112.0 * b).round() as u8;
+            planes[2 * n + i] = (128.0 + 112.0 * r - 93.786 * g - 18.214 * b).round() as u8;
+        }
+        self.w.write_all(b"FRAME\n")?;
+        self.w.write_all(&planes)?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+/// Container frames of an animation are encoded into
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VideoFormat {
+    /// Looping animated GIF, colors are reduced to a palette of 256 per frame
+    Gif,
+    /// Raw stream for an external encoder
+    Y4m,
+}
+
+impl VideoFormat {
+    /// Format from the extension of `path`
+    pub fn from_path(path: &Path) -> ImageResult<Self> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("gif") => Ok(Self::Gif),
+            Some(e) if e.eq_ignore_ascii_case("y4m") => Ok(Self::Y4m),
+            _ => Err(parameter_error(format!(
+                "no video format for {}, use .gif or .y4m",
+                path.display()
+            ))),
+        }
+    }
+}
+
+/// Writes frames one by one into a video file so finished frames are kept
+/// when rendering stops, and rendering can go on from a later frame
+pub enum VideoWriter {
+    Gif(GifEncoder<BufWriter<File>>, Delay),
+    Y4m(Y4mWriter<BufWriter<File>>),
+}
+
+impl VideoWriter {
+    /// Starts the video at `path`, keeping its first `start_frame` frames when it's more than 0.
+    /// The frames that are kept need to have the same size
+    pub fn create(
+        path: impl AsRef<Path>,
+        width: u32,
+        height: u32,
+        fps: f32,
+        start_frame: usize,
+    ) -> ImageResult<Self> {
+        let path = path.as_ref();
+        match VideoFormat::from_path(path)? {
+            VideoFormat::Gif => {
+                // gifs can't be appended to, the kept frames are written again
+                let kept = if start_frame > 0 {
+                    let decoder = GifDecoder::new(BufReader::new(File::open(path)?))?;
+                    let frames = decoder.into_frames().collect_frames()?;
+                    if frames.len() < start_frame {
+                        return Err(parameter_error(format!(
+                            "{} has {} frames, can't continue from frame {}",
+                            path.display(),
+                            frames.len(),
+                            start_frame
+                        )));
+                    }
+                    frames.into_iter().take(start_frame).collect()
+                } else {
+                    vec![]
+                };
+                let mut encoder =
+                    GifEncoder::new_with_speed(BufWriter::new(File::create(path)?), 10);
+                encoder.set_repeat(Repeat::Infinite)?;
+                for frame in kept {
+                    encoder.encode_frame(frame)?;
+                }
+                let (num, den) = fps_ratio(fps);
+                Ok(Self::Gif(
+                    encoder,
+                    Delay::from_numer_denom_ms(1000 * den, num),
+                ))
+            }
+            VideoFormat::Y4m if start_frame > 0 => {
+                let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+                let mut header = String::new();
+                BufReader::new(&mut file).read_line(&mut header)?;
+                if header != Y4mWriter::<File>::header(width, height, fps) {
+                    return Err(parameter_error(format!(
+                        "{} was started with different settings: {}",
+                        path.display(),
+                        header.trim_end()
+                    )));
+                }
+                // drops a frame that was only partly written
+                let end = header.len() as u64
+                    + start_frame as u64 * Y4mWriter::<File>::frame_size(width, height);
+                if fs::metadata(path)?.len() < end {
+                    return Err(parameter_error(format!(
+                        "{} ends before frame {}",
+                        path.display(),
+                        start_frame
+                    )));
+                }
+                file.set_len(end)?;
+                file.seek(SeekFrom::Start(end))?;
+                Ok(Self::Y4m(Y4mWriter::resume(
+                    BufWriter::new(file),
+                    width,
+                    height,
+                )))
+            }
+            VideoFormat::Y4m => Ok(Self::Y4m(Y4mWriter::new(
+                BufWriter::new(File::create(path)?),
+                width,
+                height,
+                fps,
+            )?)),
+        }
+    }
+
+    pub fn write_frame(&mut self, img: &RgbImage) -> ImageResult<()> {
+        match self {
+            Self::Gif(encoder, delay) => encoder.encode_frame(Frame::from_parts(
+                DynamicImage::ImageRgb8(img.clone()).into_rgba8(),
+                0,
+                0,
+                *delay,
+            )),
+            Self::Y4m(writer) => writer.write_frame(img),
+        }
+    }
+
+    /// Writes out everything buffered, frames written before stay in the file anyway
+    pub fn finish(self) -> ImageResult<()> {
+        match self {
+            // the trailer is written when the encoder is dropped
+            Self::Gif(encoder, _) => drop(encoder),
+            Self::Y4m(writer) => writer.into_inner().flush()?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::BufReader};
+
+    use image::{codecs::gif::GifDecoder, AnimationDecoder, Rgb, RgbImage};
+
+    use super::{fps_ratio, VideoWriter, Y4mWriter};
+
+    fn frame(c: u8) -> RgbImage {
+        RgbImage::from_pixel(4, 2, Rgb([c, c, c]))
+    }
+
+    #[test]
+    fn y4m_stream() {
+        assert_eq!(fps_ratio(24.0), (24, 1));
+        assert_eq!(fps_ratio(29.97), (2997, 100));
+        let mut writer = Y4mWriter::new(vec![], 4, 2, 24.0).unwrap();
+        writer.write_frame(&frame(255)).unwrap();
+        writer.write_frame(&frame(0)).unwrap();
+        assert!(writer.write_frame(&RgbImage::new(2, 2)).is_err());
+        let bytes = writer.into_inner();
+        let header = "YUV4MPEG2 W4 H2 F24:1 Ip A1:1 C444\n";
+        assert!(bytes.starts_with(header.as_bytes()));
+        assert_eq!(
+            bytes.len() as u64,
+            header.len() as u64 + 2 * Y4mWriter::<Vec<u8>>::frame_size(4, 2)
+        );
+        // white and black are the ends of the video range, without color
+        let white = &bytes[header.len() + 6..];
+        assert_eq!(&white[..8], &[235; 8]);
+        assert_eq!(&white[8..24], &[128; 16]);
+        let black = &bytes[header.len() + 6 + 24 + 6..];
+        assert_eq!(&black[..8], &[16; 8]);
+    }
+
+    #[test]
+    fn resume_y4m() {
+        std::fs::create_dir_all("test_out").unwrap();
+        let path = "test_out/video_resume_test.y4m";
+        let mut video = VideoWriter::create(path, 4, 2, 12.0, 0).unwrap();
+        for c in [10, 20, 30] {
+            video.write_frame(&frame(c)).unwrap();
+        }
+        video.finish().unwrap();
+        // the last frame is written again from frame 2
+        let mut video = VideoWriter::create(path, 4, 2, 12.0, 2).unwrap();
+        video.write_frame(&frame(200)).unwrap();
+        video.finish().unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        let size = Y4mWriter::<Vec<u8>>::frame_size(4, 2) as usize;
+        let start = bytes.len() - 3 * size;
+        assert!(bytes[start + 6] < bytes[start + 2 * size + 6]);
+        assert!(bytes[start + size + 6] < bytes[start + 2 * size + 6]);
+        // other settings don't continue the video
+        assert!(VideoWriter::create(path, 4, 2, 24.0, 2).is_err());
+        assert!(VideoWriter::create(path, 4, 2, 12.0, 4).is_err());
+    }
+
+    #[test]
+    fn resume_gif() {
+        std::fs::create_dir_all("test_out").unwrap();
+        let path = "test_out/video_resume_test.gif";
+        let mut video = VideoWriter::create(path, 4, 2, 10.0, 0).unwrap();
+        for c in [0, 100, 200] {
+            video.write_frame(&frame(c)).unwrap();
+        }
+        video.finish().unwrap();
+        let mut video = VideoWriter::create(path, 4, 2, 10.0, 1).unwrap();
+        video.write_frame(&frame(255)).unwrap();
+        video.finish().unwrap();
+
+        let decoder = GifDecoder::new(BufReader::new(File::open(path).unwrap())).unwrap();
+        let frames = decoder.into_frames().collect_frames().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].buffer().get_pixel(0, 0).0[0], 0);
+        assert_eq!(frames[1].buffer().get_pixel(0, 0).0[0], 255);
+        assert_eq!(frames[1].delay().numer_denom_ms(), (100, 1));
+    }
+}